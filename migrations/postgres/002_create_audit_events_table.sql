@@ -0,0 +1,14 @@
+CREATE TABLE audit_events (
+    id TEXT PRIMARY KEY NOT NULL,
+    action TEXT NOT NULL,
+    outcome TEXT NOT NULL,
+    status_code BIGINT NOT NULL,
+    file_id TEXT,
+    actor TEXT NOT NULL,
+    ip_address TEXT,
+    user_agent TEXT,
+    created_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX idx_audit_events_file_id ON audit_events (file_id);
+CREATE INDEX idx_audit_events_created_at ON audit_events (created_at);
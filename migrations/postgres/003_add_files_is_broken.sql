@@ -0,0 +1 @@
+ALTER TABLE files ADD COLUMN is_broken BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,3 @@
+ALTER TABLE files ADD COLUMN encryption_key_id TEXT;
+ALTER TABLE files ADD COLUMN wrapped_key TEXT;
+CREATE INDEX idx_files_encryption_key_id ON files (encryption_key_id);
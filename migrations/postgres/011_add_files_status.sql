@@ -0,0 +1,6 @@
+ALTER TABLE files ADD COLUMN status TEXT NOT NULL DEFAULT 'ready';
+ALTER TABLE files ADD COLUMN job_id TEXT;
+ALTER TABLE files ADD COLUMN status_error TEXT;
+ALTER TABLE files ADD COLUMN sha256 TEXT;
+
+CREATE INDEX idx_files_status ON files (status);
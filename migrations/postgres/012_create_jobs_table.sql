@@ -0,0 +1,21 @@
+CREATE TABLE jobs (
+    id TEXT PRIMARY KEY NOT NULL,
+    kind TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    status TEXT NOT NULL,
+    attempts BIGINT NOT NULL DEFAULT 0,
+    run_at TIMESTAMPTZ NOT NULL,
+    last_error TEXT,
+    created_at TIMESTAMPTZ NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL,
+    completed_at TIMESTAMPTZ
+);
+
+CREATE INDEX idx_jobs_due ON jobs (status, run_at);
+CREATE INDEX idx_jobs_created_at ON jobs (created_at);
+
+-- Files still pending from before the queue existed get a job.
+INSERT INTO jobs (id, kind, payload, status, attempts, run_at, created_at, updated_at)
+SELECT job_id, 'process_file', '{"file_id":"' || id || '"}', 'queued', 0, updated_at, updated_at, updated_at
+FROM files
+WHERE status IN ('pending', 'processing') AND job_id IS NOT NULL;
@@ -0,0 +1,10 @@
+ALTER TABLE files ADD COLUMN folder TEXT NOT NULL DEFAULT '';
+CREATE INDEX idx_files_folder ON files (folder);
+
+CREATE TABLE folders (
+    path TEXT PRIMARY KEY NOT NULL,
+    parent TEXT NOT NULL,
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX idx_folders_parent ON folders (parent);
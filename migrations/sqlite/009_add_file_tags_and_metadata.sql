@@ -0,0 +1,16 @@
+CREATE TABLE file_tags (
+    file_id TEXT NOT NULL REFERENCES files (id) ON DELETE CASCADE,
+    tag TEXT NOT NULL,
+    PRIMARY KEY (file_id, tag)
+);
+
+CREATE INDEX idx_file_tags_tag ON file_tags (tag);
+
+CREATE TABLE file_metadata (
+    file_id TEXT NOT NULL REFERENCES files (id) ON DELETE CASCADE,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (file_id, key)
+);
+
+CREATE INDEX idx_file_metadata_key_value ON file_metadata (key, value);
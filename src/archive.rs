@@ -0,0 +1,243 @@
+use anyhow::Result;
+use async_compression::tokio::write::GzipEncoder;
+use async_zip::{tokio::write::ZipFileWriter, Compression, ZipEntryBuilder};
+use futures::{AsyncWriteExt as _, StreamExt};
+use std::{
+    collections::HashSet,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::io::{AsyncWriteExt as _, DuplexStream};
+use tokio_tar::{EntryType, Header};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::{
+    config::mime_matches,
+    models::{join_folder, normalize_folder, File},
+    storage::{ByteStream, StorageRegistry},
+};
+
+/// Bytes of the archive buffered ahead of the client.
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    TarGz,
+}
+
+impl ArchiveFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "zip" => Some(ArchiveFormat::Zip),
+            "tar.gz" | "tgz" => Some(ArchiveFormat::TarGz),
+            _ => None,
+        }
+    }
+
+    /// The format of an archive called `name`, by its extension.
+    pub fn from_file_name(name: &str) -> Option<Self> {
+        let name = name.to_ascii_lowercase();
+        if name.ends_with(".zip") {
+            Some(ArchiveFormat::Zip)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ArchiveFormat::TarGz)
+        } else {
+            None
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::TarGz => "tar.gz",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "application/zip",
+            ArchiveFormat::TarGz => "application/gzip",
+        }
+    }
+}
+
+/// A file and its path in an archive.
+#[derive(Debug)]
+pub struct ArchiveEntry {
+    pub path: String,
+    pub file: File,
+}
+
+/// Gives each file a path in the archive: its name, below its folder
+/// relative to `base` when archiving a folder. Names that repeat within a
+/// folder, ignoring case, get a number, as in `report (2).pdf`.
+pub fn entry_paths(files: Vec<File>, base: Option<&str>) -> Vec<ArchiveEntry> {
+    let mut taken = HashSet::new();
+    files
+        .into_iter()
+        .map(|file| {
+            let folder = match base {
+                Some(base) => file
+                    .folder
+                    .strip_prefix(base)
+                    .unwrap_or(&file.folder)
+                    .trim_start_matches('/'),
+                None => "",
+            };
+            let name = match file.name.replace(['/', '\\'], "_") {
+                name if matches!(name.as_str(), "" | "." | "..") => file.id.clone(),
+                name => name,
+            };
+            let (stem, extension) = match name.rfind('.') {
+                Some(dot) if dot > 0 => name.split_at(dot),
+                _ => (name.as_str(), ""),
+            };
+
+            let mut path = join_folder(folder, &name);
+            let mut copy = 1;
+            while !taken.insert(path.to_lowercase()) {
+                copy += 1;
+                path = join_folder(folder, &format!("{} ({}){}", stem, copy, extension));
+            }
+            ArchiveEntry { path, file }
+        })
+        .collect()
+}
+
+/// The path of an entry read from an archive, normalized as a folder path,
+/// or `None` if it is absolute or climbs out with `..` as in zip-slip
+/// attacks. The archive's own root, such as `./`, is the empty path.
+pub fn entry_path(raw: &str) -> Option<String> {
+    if raw.starts_with('/') {
+        return None;
+    }
+    let mut path = raw;
+    while let Some(rest) = path.strip_prefix("./") {
+        path = rest;
+    }
+    normalize_folder(path.trim_end_matches('/'))
+}
+
+/// Streams an archive of `entries`, reading each file from storage as the
+/// archive is consumed. Zip entries of `compressible` types are deflated and
+/// the others stored. A failure ends the stream with an error, so that the
+/// client sees an aborted transfer rather than a truncated archive.
+pub fn stream(
+    storage: Arc<StorageRegistry>,
+    entries: Vec<ArchiveEntry>,
+    format: ArchiveFormat,
+    compressible: Vec<String>,
+) -> ByteStream<'static> {
+    let (writer, reader) = tokio::io::duplex(PIPE_CAPACITY);
+    let task = tokio::spawn(async move {
+        match format {
+            ArchiveFormat::Zip => write_zip(&storage, &entries, &compressible, writer).await,
+            ArchiveFormat::TarGz => write_tar_gz(&storage, &entries, writer).await,
+        }
+    });
+
+    let outcome = futures::stream::once(async move {
+        let result = match task.await {
+            Ok(result) => result,
+            Err(e) => Err(e.into()),
+        };
+        result.err().map(|e| {
+            tracing::error!("Failed to build archive: {:#}", e);
+            Err(io::Error::other(e))
+        })
+    })
+    .filter_map(futures::future::ready);
+
+    ReaderStream::new(reader).chain(outcome).boxed()
+}
+
+async fn write_zip(
+    storage: &StorageRegistry,
+    entries: &[ArchiveEntry],
+    compressible: &[String],
+    writer: DuplexStream,
+) -> Result<()> {
+    let mut zip = ZipFileWriter::with_tokio(writer);
+    for entry in entries {
+        let mime_type = mime_guess::from_path(&entry.file.name).first_or_octet_stream();
+        let compression = if compressible
+            .iter()
+            .any(|pattern| mime_matches(pattern, mime_type.as_ref()))
+        {
+            Compression::Deflate
+        } else {
+            Compression::Stored
+        };
+        let builder = ZipEntryBuilder::new(entry.path.clone().into(), compression)
+            .last_modification_date(entry.file.updated_at.into())
+            .unix_permissions(0o644);
+
+        let mut content = read_content(storage, &entry.file).await?;
+        let mut writer = zip.write_entry_stream(builder).await?;
+        while let Some(chunk) = content.next().await {
+            writer.write_all(&chunk?).await?;
+        }
+        writer.close().await?;
+    }
+
+    zip.close().await?.into_inner().shutdown().await?;
+    Ok(())
+}
+
+async fn write_tar_gz(
+    storage: &StorageRegistry,
+    entries: &[ArchiveEntry],
+    writer: DuplexStream,
+) -> Result<()> {
+    let mut tar = tokio_tar::Builder::new_non_terminated(GzipEncoder::new(writer));
+    for entry in entries {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Regular);
+        header.set_size(entry.file.size as u64);
+        header.set_mode(0o644);
+        header.set_mtime(entry.file.updated_at.timestamp().max(0) as u64);
+
+        let content = read_content(storage, &entry.file).await?;
+        tar.append_data(&mut header, &entry.path, StreamReader::new(content))
+            .await?;
+    }
+
+    tar.into_inner().await?.shutdown().await?;
+    Ok(())
+}
+
+/// The original content of `file`, failing at the end if it is not exactly
+/// `file.size` bytes so that a damaged blob does not end up as a short entry.
+async fn read_content(storage: &StorageRegistry, file: &File) -> Result<ByteStream<'static>> {
+    let content = storage
+        .require(&file.storage_type)?
+        .get_content(&file.path, file.blob_key().as_ref(), file.codec())
+        .await?;
+
+    let expected = file.size as u64;
+    let read = Arc::new(AtomicU64::new(0));
+    let counter = read.clone();
+    let check = futures::stream::once(async move {
+        let read = read.load(Ordering::Relaxed);
+        (read != expected).then(|| {
+            Err(io::Error::other(format!(
+                "content is {} bytes, expected {}",
+                read, expected
+            )))
+        })
+    })
+    .filter_map(futures::future::ready);
+
+    Ok(content
+        .inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            }
+        })
+        .chain(check)
+        .boxed())
+}
@@ -0,0 +1,130 @@
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header, request::Parts, StatusCode},
+};
+use sha2::{Digest, Sha256};
+use std::{convert::Infallible, net::SocketAddr, time::Duration};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    database::{create_audit_event, delete_audit_events_before, DbPool},
+    handlers::AppState,
+    middleware::Actor,
+    models::{AuditAction, AuditEvent},
+};
+
+/// Who made a request and from where, captured for the audit log.
+#[derive(Debug, Clone)]
+pub struct AuditContext {
+    pub actor: String,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for AuditContext {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let headers = &parts.headers;
+
+        // Prefer the identity established by the auth middleware. Otherwise
+        // bearer tokens are never stored, only a short fingerprint of them.
+        let authenticated = parts
+            .extensions
+            .get::<Actor>()
+            .map(|Actor(actor)| actor.clone());
+        let actor = authenticated
+            .or_else(|| {
+                headers
+                    .get(header::AUTHORIZATION)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.strip_prefix("Bearer "))
+                    .map(|token| format!("token:{}", token_fingerprint(token)))
+            })
+            .unwrap_or_else(|| "anonymous".to_string());
+
+        let forwarded_for = state
+            .config
+            .trust_forwarded_for
+            .then(|| headers.get("x-forwarded-for"))
+            .flatten()
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .map(|ip| ip.trim().to_string());
+
+        let ip_address = forwarded_for.or_else(|| {
+            parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip().to_string())
+        });
+
+        let user_agent = headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+
+        Ok(Self {
+            actor,
+            ip_address,
+            user_agent,
+        })
+    }
+}
+
+fn token_fingerprint(token: &str) -> String {
+    let digest = Sha256::digest(token.as_bytes());
+    hex::encode(&digest[..6])
+}
+
+/// Records an audit event. Failures are logged rather than surfaced so that
+/// a broken audit table never blocks file access.
+pub async fn record(
+    db: &DbPool,
+    context: &AuditContext,
+    action: AuditAction,
+    status: StatusCode,
+    file_id: Option<&str>,
+) {
+    let event = AuditEvent::new(
+        action,
+        status.as_u16(),
+        file_id.map(str::to_string),
+        context.actor.clone(),
+        context.ip_address.clone(),
+        context.user_agent.clone(),
+    );
+
+    if let Err(e) = create_audit_event(db, &event).await {
+        tracing::error!("Failed to record audit event: {}", e);
+    }
+}
+
+/// Deletes audit events older than the retention period.
+pub async fn purge_expired(db: &DbPool, retention_days: u64) -> anyhow::Result<u64> {
+    let cutoff = chrono::Utc::now() - chrono::Duration::days(retention_days as i64);
+    delete_audit_events_before(db, cutoff).await
+}
+
+/// Periodically purges expired audit events until `shutdown` is cancelled.
+pub async fn run_retention(db: DbPool, retention_days: u64, shutdown: CancellationToken) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.cancelled() => return,
+        }
+
+        match purge_expired(&db, retention_days).await {
+            Ok(0) => {}
+            Ok(deleted) => tracing::info!("Purged {} expired audit events", deleted),
+            Err(e) => tracing::error!("Failed to purge audit events: {}", e),
+        }
+    }
+}
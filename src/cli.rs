@@ -0,0 +1,217 @@
+use anyhow::Result;
+use axum::Json;
+use clap::{Parser, Subcommand};
+use metrics_exporter_prometheus::PrometheusBuilder;
+use serde::Serialize;
+use std::path::PathBuf;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    audit::AuditContext,
+    config::{Config, StorageConfig},
+    database::{create_api_key, create_pool, get_file_by_id, get_files, DbPool},
+    handlers::{files::delete_files, AppState},
+    models::{ApiKey, File, FileFilter},
+    ops::{
+        export, fsck, gc, import,
+        migrate_storage::{self, MigrationOptions, MigrationTracker},
+        rotate_keys, stats,
+    },
+    server,
+    storage::{Keyring, StorageRegistry},
+};
+
+#[derive(Debug, Parser)]
+#[clap(name = "file-server", about = "A modern file server written in Rust")]
+pub struct Cli {
+    #[clap(flatten)]
+    pub config: Config,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the HTTP server (the default when no command is given)
+    Serve,
+    /// Apply pending database migrations and exit
+    Migrate,
+    /// Ingest every file below a directory into storage and the files table
+    Import { dir: PathBuf },
+    /// Write all stored files and a manifest.json into a directory
+    Export { dir: PathBuf },
+    /// Remove orphaned blobs, partial uploads and expired audit events
+    Gc,
+    /// Compare the files table against the storage backend
+    Fsck {
+        /// Delete orphaned blobs and mark rows with missing or mismatched blobs as broken
+        #[clap(long)]
+        repair: bool,
+    },
+    /// Copy every file from one storage backend to another and update its row
+    MigrateStorage(MigrationOptions),
+    /// Re-wrap every data key with the current encryption key
+    RotateKeys {
+        /// Also encrypt files stored before encryption was enabled
+        #[clap(long)]
+        encrypt_existing: bool,
+    },
+    /// Create an API key for the upload and admin endpoints
+    CreateKey {
+        #[clap(long)]
+        name: String,
+        /// Also issue credentials for the S3 API
+        #[clap(long)]
+        s3: bool,
+    },
+    /// List the most recent files
+    List {
+        #[clap(long, default_value = "50")]
+        limit: i64,
+        #[clap(long)]
+        tag: Option<String>,
+    },
+    /// Delete a file and its blob
+    Delete { id: String },
+    /// Show file counts and sizes per storage backend
+    Stats,
+}
+
+impl Cli {
+    pub fn parse_args() -> Self {
+        Self::parse()
+    }
+}
+
+pub async fn run(cli: Cli) -> Result<()> {
+    let config = cli.config;
+
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => server::run(config).await,
+        Command::Migrate => {
+            let db = create_pool(config.database_url.expose()).await?;
+            tracing::info!("Migrations applied");
+            db.close().await;
+            Ok(())
+        }
+        Command::Import { dir } => {
+            let (db, storages) = connect(&config).await?;
+            print_json(&import::run(&db, &storages, &config, &dir).await?)
+        }
+        Command::Export { dir } => {
+            let (db, storages) = connect(&config).await?;
+            print_json(&export::run(&db, &storages, &dir).await?)
+        }
+        Command::Gc => {
+            let (db, storages) = connect(&config).await?;
+            print_json(&gc::run(&db, &storages, &config).await?)
+        }
+        Command::Fsck { repair } => {
+            let (db, storages) = connect(&config).await?;
+            let mut reports = Vec::new();
+            for storage in storages.backends() {
+                reports.push(fsck::run(&db, storage, repair).await?);
+            }
+            print_json(&reports)
+        }
+        Command::MigrateStorage(options) => {
+            let (db, storages) = connect(&config).await?;
+            let source = storages.require(&options.from)?;
+            let target = storages.require(&options.to)?;
+
+            let tracker = MigrationTracker::default();
+            tracker.start(&options)?;
+            print_json(&migrate_storage::run(&db, source, target, &options, &tracker).await?)
+        }
+        Command::RotateKeys { encrypt_existing } => {
+            let (db, storages) = connect(&config).await?;
+            print_json(&rotate_keys::run(&db, &storages, encrypt_existing).await?)
+        }
+        Command::CreateKey { name, s3 } => {
+            let db = create_pool(config.database_url.expose()).await?;
+            let (mut api_key, key) = ApiKey::generate(name);
+            let s3_secret = s3.then(|| api_key.generate_s3_secret());
+            create_api_key(&db, &api_key).await?;
+
+            println!("Created API key {} ({})", api_key.id, api_key.name);
+            println!("{}", key);
+            if let Some(s3_secret) = s3_secret {
+                println!("S3 access key id: {}", api_key.id);
+                println!("S3 secret access key: {}", s3_secret);
+            }
+            eprintln!("Store this key now, it cannot be shown again.");
+            Ok(())
+        }
+        Command::List { limit, tag } => {
+            let db = create_pool(config.database_url.expose()).await?;
+            let filter = FileFilter {
+                tag,
+                ..Default::default()
+            };
+            for file in get_files(&db, &filter, limit).await? {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    file.id,
+                    file.created_at.format("%Y-%m-%d %H:%M:%S"),
+                    file.size,
+                    file.storage_type,
+                    file.name
+                );
+            }
+            Ok(())
+        }
+        Command::Delete { id } => {
+            let (db, storages) = connect(&config).await?;
+            let file = delete(&db, storages, config, &id).await?;
+            println!("Deleted {} ({})", file.id, file.name);
+            Ok(())
+        }
+        Command::Stats => {
+            let db = create_pool(config.database_url.expose()).await?;
+            print_json(&stats::collect(&db).await?)
+        }
+    }
+}
+
+async fn connect(config: &Config) -> Result<(DbPool, StorageRegistry)> {
+    let db = create_pool(config.database_url.expose()).await?;
+    let mut storages = StorageRegistry::from_config(&StorageConfig::load(config)?).await?;
+    if let Some(keyring) = Keyring::from_config(config)? {
+        storages.set_keyring(keyring);
+    }
+    Ok((db, storages))
+}
+
+/// Deletes a file the way the API does, so the deletion is audited and its
+/// webhooks are queued for the server to deliver.
+async fn delete(db: &DbPool, storages: StorageRegistry, config: Config, id: &str) -> Result<File> {
+    let file = get_file_by_id(db, id)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("File {} not found", id))?;
+
+    let metrics = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new(
+        db.clone(),
+        storages,
+        config,
+        metrics,
+        CancellationToken::new(),
+    );
+    let audit_context = AuditContext {
+        actor: "cli".to_string(),
+        ip_address: None,
+        user_agent: None,
+    };
+    delete_files(&state, &audit_context, std::slice::from_ref(&file))
+        .await
+        .map_err(|(status, Json(body))| {
+            anyhow::anyhow!("Failed to delete {}: {} ({})", id, body["error"], status)
+        })?;
+    Ok(file)
+}
+
+fn print_json<T: Serialize>(value: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
@@ -1,11 +1,19 @@
-use clap::Parser;
+mod secret;
+mod storage;
+
+use clap::Args;
 use std::path::PathBuf;
 
-#[derive(Debug, Clone, Parser)]
-#[clap(name = "file-server", about = "A modern file server written in Rust")]
+use crate::storage::Codec;
+
+pub use secret::Secret;
+pub(crate) use storage::mime_matches;
+pub use storage::{BackendConfig, BackendKind, RoutingRule, S3ServerSideEncryption, StorageConfig};
+
+#[derive(Debug, Clone, Args)]
 pub struct Config {
     #[clap(long, env = "DATABASE_URL", default_value = "sqlite:./files.db")]
-    pub database_url: String,
+    pub database_url: Secret,
 
     #[clap(long, env = "HOST", default_value = "0.0.0.0")]
     pub host: String,
@@ -19,18 +27,88 @@ pub struct Config {
     #[clap(long, env = "FILE_SERVER_ALLOWED_FILE_TYPES", default_value = "*")]
     pub allowed_file_types: Option<String>,
 
+    #[clap(long, env = "FILE_SERVER_COMPRESSION")]
+    pub compression: Option<Codec>,
+
+    #[clap(long, env = "FILE_SERVER_COMPRESSION_LEVEL")]
+    pub compression_level: Option<i32>,
+
+    #[clap(
+        long,
+        env = "FILE_SERVER_COMPRESSIBLE_TYPES",
+        default_value = "text/*,application/json,application/xml,application/javascript,image/svg+xml"
+    )]
+    pub compressible_types: String,
+
     #[clap(long, env = "FILE_SERVER_STORAGE_TYPE", default_value = "local")]
     pub storage_type: StorageType,
 
     #[clap(long, env = "FILE_SERVER_STORAGE_PATH", default_value = "./files")]
     pub storage_path: PathBuf,
 
+    #[clap(long, env = "FILE_SERVER_STORAGE_CONFIG")]
+    pub storage_config: Option<PathBuf>,
+
     #[clap(long, env = "FILE_SERVER_AUTH_TOKEN")]
-    pub auth_token: Option<String>,
+    pub auth_token: Option<Secret>,
 
     #[clap(long, env = "FILE_SERVER_DISABLE_UPLOAD_PAGE")]
     pub disable_upload_page: bool,
 
+    #[clap(long, env = "FILE_SERVER_SHUTDOWN_TIMEOUT", default_value = "30")]
+    pub shutdown_timeout: u64,
+
+    #[clap(long, env = "FILE_SERVER_LOG_FORMAT", default_value = "text")]
+    pub log_format: LogFormat,
+
+    #[clap(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+
+    #[clap(long, env = "OTEL_EXPORTER_OTLP_PROTOCOL", default_value = "grpc")]
+    pub otlp_protocol: OtlpProtocol,
+
+    #[clap(long, env = "OTEL_SERVICE_NAME", default_value = "file-server-rs")]
+    pub otel_service_name: String,
+
+    #[clap(long, env = "FILE_SERVER_AUDIT_RETENTION_DAYS")]
+    pub audit_retention_days: Option<u64>,
+
+    #[clap(long, env = "FILE_SERVER_WEBHOOK_TIMEOUT", default_value = "10")]
+    pub webhook_timeout: u64,
+
+    #[clap(long, env = "FILE_SERVER_WEBHOOK_MAX_ATTEMPTS", default_value = "10")]
+    pub webhook_max_attempts: u32,
+
+    #[clap(long, env = "FILE_SERVER_JOB_CONCURRENCY", default_value = "4")]
+    pub job_concurrency: usize,
+
+    #[clap(long, env = "FILE_SERVER_JOB_MAX_ATTEMPTS", default_value = "5")]
+    pub job_max_attempts: u32,
+
+    #[clap(long, env = "FILE_SERVER_TRUST_FORWARDED_FOR")]
+    pub trust_forwarded_for: bool,
+
+    #[clap(long, env = "FILE_SERVER_ENABLE_WEBDAV")]
+    pub enable_webdav: bool,
+
+    #[clap(long, env = "FILE_SERVER_ENABLE_S3_API")]
+    pub enable_s3_api: bool,
+
+    #[clap(long, env = "FILE_SERVER_S3_API_BUCKET", default_value = "files")]
+    pub s3_api_bucket: String,
+
+    #[clap(long, env = "FILE_SERVER_ENCRYPTION_KEY")]
+    pub encryption_key: Option<Secret>,
+
+    #[clap(long, env = "FILE_SERVER_ENCRYPTION_KEY_FILE")]
+    pub encryption_key_file: Option<PathBuf>,
+
+    #[clap(long, env = "FILE_SERVER_PREVIOUS_ENCRYPTION_KEYS")]
+    pub previous_encryption_keys: Option<Secret>,
+
+    #[clap(long, env = "FILE_SERVER_MIXED_READS")]
+    pub mixed_reads: bool,
+
     #[clap(long, env = "AWS_S3_BUCKET")]
     pub s3_bucket: Option<String>,
 
@@ -41,16 +119,90 @@ pub struct Config {
     pub aws_access_key_id: Option<String>,
 
     #[clap(long, env = "AWS_SECRET_ACCESS_KEY")]
-    pub aws_secret_access_key: Option<String>,
+    pub aws_secret_access_key: Option<Secret>,
 
     #[clap(long, env = "AWS_ENDPOINT_URL")]
     pub aws_endpoint_url: Option<String>,
+
+    #[clap(long, env = "FILE_SERVER_S3_SSE")]
+    pub s3_sse: Option<S3ServerSideEncryption>,
+
+    #[clap(long, env = "FILE_SERVER_S3_SSE_KMS_KEY_ID")]
+    pub s3_sse_kms_key_id: Option<String>,
+
+    #[clap(long, env = "FILE_SERVER_S3_SSE_CUSTOMER_KEY")]
+    pub s3_sse_customer_key: Option<Secret>,
+
+    #[clap(long, env = "FILE_SERVER_GCS_BUCKET")]
+    pub gcs_bucket: Option<String>,
+
+    #[clap(long, env = "GOOGLE_APPLICATION_CREDENTIALS")]
+    pub gcs_credentials_file: Option<PathBuf>,
+
+    #[clap(long, env = "FILE_SERVER_GCS_ENDPOINT_URL")]
+    pub gcs_endpoint_url: Option<String>,
+
+    #[clap(long, env = "AZURE_STORAGE_ACCOUNT")]
+    pub azure_storage_account: Option<String>,
+
+    #[clap(long, env = "AZURE_STORAGE_KEY")]
+    pub azure_storage_key: Option<Secret>,
+
+    #[clap(long, env = "FILE_SERVER_AZURE_CONTAINER")]
+    pub azure_container: Option<String>,
+
+    #[clap(long, env = "FILE_SERVER_AZURE_ENDPOINT_URL")]
+    pub azure_endpoint_url: Option<String>,
+
+    #[clap(long, env = "FILE_SERVER_WEBDAV_URL")]
+    pub webdav_url: Option<String>,
+
+    #[clap(long, env = "FILE_SERVER_WEBDAV_USERNAME")]
+    pub webdav_username: Option<String>,
+
+    #[clap(long, env = "FILE_SERVER_WEBDAV_PASSWORD")]
+    pub webdav_password: Option<Secret>,
+
+    #[clap(long, env = "FILE_SERVER_WEBDAV_PREFIX", default_value = "uploads")]
+    pub webdav_prefix: String,
+
+    #[clap(long, env = "FILE_SERVER_SFTP_HOST")]
+    pub sftp_host: Option<String>,
+
+    #[clap(long, env = "FILE_SERVER_SFTP_PORT", default_value = "22")]
+    pub sftp_port: u16,
+
+    #[clap(long, env = "FILE_SERVER_SFTP_USERNAME")]
+    pub sftp_username: Option<String>,
+
+    #[clap(long, env = "FILE_SERVER_SFTP_PASSWORD")]
+    pub sftp_password: Option<Secret>,
+
+    #[clap(long, env = "FILE_SERVER_SFTP_PRIVATE_KEY")]
+    pub sftp_private_key: Option<PathBuf>,
+
+    #[clap(long, env = "FILE_SERVER_SFTP_KNOWN_HOSTS")]
+    pub sftp_known_hosts: Option<PathBuf>,
+
+    #[clap(long, env = "FILE_SERVER_SFTP_PREFIX", default_value = "uploads")]
+    pub sftp_prefix: String,
+
+    #[clap(long, env = "FILE_SERVER_STORAGE_POOL_SIZE", default_value = "4")]
+    pub storage_pool_size: usize,
+
+    #[clap(long, env = "FILE_SERVER_STORAGE_RETRIES", default_value = "3")]
+    pub storage_retries: u32,
 }
 
-#[derive(Debug, Clone, clap::ValueEnum)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum StorageType {
     Local,
     S3,
+    Gcs,
+    Azure,
+    Webdav,
+    Sftp,
 }
 
 impl std::fmt::Display for StorageType {
@@ -58,32 +210,107 @@ impl std::fmt::Display for StorageType {
         match self {
             StorageType::Local => write!(f, "local"),
             StorageType::S3 => write!(f, "s3"),
+            StorageType::Gcs => write!(f, "gcs"),
+            StorageType::Azure => write!(f, "azure"),
+            StorageType::Webdav => write!(f, "webdav"),
+            StorageType::Sftp => write!(f, "sftp"),
         }
     }
 }
 
-impl Config {
-    pub fn parse_args() -> Self {
-        Self::parse()
-    }
+#[derive(Debug, Clone, clap::ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
 
+#[derive(Debug, Clone, clap::ValueEnum)]
+pub enum OtlpProtocol {
+    Grpc,
+    #[value(name = "http/protobuf")]
+    HttpProtobuf,
+}
+
+impl Config {
     pub fn validate(&self) -> anyhow::Result<()> {
-        if matches!(self.storage_type, StorageType::S3) {
-            if self.s3_bucket.is_none() {
-                anyhow::bail!("S3 bucket must be specified when using S3 storage");
-            }
-            if self.s3_region.is_none() {
-                anyhow::bail!("S3 region must be specified when using S3 storage");
+        if self.storage_config.is_none() {
+            match self.storage_type {
+                StorageType::Local => {}
+                StorageType::S3 => {
+                    if self.s3_bucket.is_none() {
+                        anyhow::bail!("S3 bucket must be specified when using S3 storage");
+                    }
+                    if self.s3_region.is_none() {
+                        anyhow::bail!("S3 region must be specified when using S3 storage");
+                    }
+                }
+                StorageType::Gcs => {
+                    if self.gcs_bucket.is_none() {
+                        anyhow::bail!("GCS bucket must be specified when using GCS storage");
+                    }
+                }
+                StorageType::Azure => {
+                    if self.azure_storage_account.is_none() {
+                        anyhow::bail!(
+                            "Azure storage account must be specified when using Azure storage"
+                        );
+                    }
+                    if self.azure_storage_key.is_none() {
+                        anyhow::bail!(
+                            "Azure storage key must be specified when using Azure storage"
+                        );
+                    }
+                    if self.azure_container.is_none() {
+                        anyhow::bail!("Azure container must be specified when using Azure storage");
+                    }
+                }
+                StorageType::Webdav => {
+                    if self.webdav_url.is_none() {
+                        anyhow::bail!("WebDAV URL must be specified when using WebDAV storage");
+                    }
+                }
+                StorageType::Sftp => {
+                    if self.sftp_host.is_none() {
+                        anyhow::bail!("SFTP host must be specified when using SFTP storage");
+                    }
+                    if self.sftp_username.is_none() {
+                        anyhow::bail!("SFTP username must be specified when using SFTP storage");
+                    }
+                }
             }
         }
 
-        if !self.storage_path.exists() && matches!(self.storage_type, StorageType::Local) {
-            std::fs::create_dir_all(&self.storage_path)?;
+        if self.webhook_timeout == 0 || self.webhook_max_attempts == 0 {
+            anyhow::bail!(
+                "FILE_SERVER_WEBHOOK_TIMEOUT and FILE_SERVER_WEBHOOK_MAX_ATTEMPTS must be at least 1"
+            );
+        }
+
+        if self.job_concurrency == 0 || self.job_max_attempts == 0 {
+            anyhow::bail!(
+                "FILE_SERVER_JOB_CONCURRENCY and FILE_SERVER_JOB_MAX_ATTEMPTS must be at least 1"
+            );
+        }
+
+        for backend in StorageConfig::load(self)?.backends {
+            if let BackendKind::Local { path } = backend.kind {
+                if !path.exists() {
+                    std::fs::create_dir_all(&path)?;
+                }
+            }
         }
 
         Ok(())
     }
 
+    pub fn compressible_types_vec(&self) -> Vec<String> {
+        self.compressible_types
+            .split(',')
+            .map(|mime_type| mime_type.trim().to_string())
+            .filter(|mime_type| !mime_type.is_empty())
+            .collect()
+    }
+
     pub fn allowed_file_types_vec(&self) -> Vec<String> {
         match &self.allowed_file_types {
             Some(types) => types.split(',').map(|s| s.trim().to_string()).collect(),
@@ -0,0 +1,397 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
+use std::{collections::HashSet, path::PathBuf};
+
+use super::{Config, Secret, StorageType};
+use crate::storage::ROUTING_READ_AHEAD_LIMIT;
+
+/// Named storage backends and the rules that pick one for each upload, read
+/// from the TOML file at `FILE_SERVER_STORAGE_CONFIG`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct StorageConfig {
+    /// Backend for uploads that match no rule.
+    pub default: String,
+    pub backends: Vec<BackendConfig>,
+    #[serde(default)]
+    pub rules: Vec<RoutingRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BackendConfig {
+    /// Stored in `files.storage_type`, so renaming a backend orphans its rows.
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: BackendKind,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BackendKind {
+    Local {
+        path: PathBuf,
+    },
+    S3 {
+        bucket: String,
+        region: Option<String>,
+        endpoint_url: Option<String>,
+        /// Falls back to the default AWS credential chain when unset.
+        access_key_id: Option<String>,
+        secret_access_key: Option<Secret>,
+        /// Server-side encryption with keys managed by S3 or KMS.
+        sse: Option<S3ServerSideEncryption>,
+        /// KMS key for `aws:kms`; S3 uses the bucket's default key when unset.
+        sse_kms_key_id: Option<String>,
+        /// Base64 256-bit key for SSE-C, sent with every request. Rules out
+        /// presigned URLs.
+        sse_customer_key: Option<Secret>,
+    },
+    /// Google Cloud Storage through the JSON API.
+    Gcs {
+        bucket: String,
+        /// E.g. a fake-gcs-server URL; requests are unauthenticated when set
+        /// without a credentials file.
+        endpoint_url: Option<String>,
+        /// Service account key file. Falls back to the metadata server.
+        credentials_file: Option<PathBuf>,
+    },
+    Azure {
+        account: String,
+        /// Base64 account key.
+        access_key: Secret,
+        container: String,
+        /// E.g. an Azurite URL including the account name.
+        endpoint_url: Option<String>,
+    },
+    /// A WebDAV share, e.g. on a NAS.
+    #[serde(rename = "webdav")]
+    WebDav {
+        url: String,
+        /// Collection under `url` that blobs are written to.
+        #[serde(default = "default_prefix")]
+        prefix: String,
+        username: Option<String>,
+        password: Option<Secret>,
+        /// Idle connections kept open to the server.
+        #[serde(default = "default_pool_size")]
+        pool_size: usize,
+        #[serde(default = "default_retries")]
+        retries: u32,
+    },
+    Sftp {
+        host: String,
+        #[serde(default = "default_sftp_port")]
+        port: u16,
+        username: String,
+        password: Option<Secret>,
+        /// Unencrypted private key; the SSH agent is used without a key or
+        /// password.
+        private_key: Option<PathBuf>,
+        /// Verify the server's host key against this OpenSSH file.
+        known_hosts: Option<PathBuf>,
+        /// Directory blobs are written to, relative to the login directory
+        /// unless absolute.
+        #[serde(default = "default_prefix")]
+        prefix: String,
+        /// Maximum number of concurrent SSH sessions.
+        #[serde(default = "default_pool_size")]
+        pool_size: usize,
+        #[serde(default = "default_retries")]
+        retries: u32,
+    },
+    /// Blobs are lost on restart; meant for tests.
+    Memory,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+pub enum S3ServerSideEncryption {
+    #[value(name = "AES256")]
+    #[serde(rename = "AES256")]
+    Aes256,
+    #[value(name = "aws:kms")]
+    #[serde(rename = "aws:kms")]
+    AwsKms,
+}
+
+fn default_prefix() -> String {
+    "uploads".to_string()
+}
+
+fn default_pool_size() -> usize {
+    4
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+fn default_sftp_port() -> u16 {
+    22
+}
+
+/// Sends matching uploads to `backend`. Every condition that is set must hold;
+/// rules are tried in order and the first match wins.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RoutingRule {
+    pub backend: String,
+    /// Minimum size in bytes (inclusive).
+    pub min_size: Option<u64>,
+    /// Maximum size in bytes (inclusive).
+    pub max_size: Option<u64>,
+    /// MIME types such as `image/png`, `image/*` or `*`.
+    #[serde(default)]
+    pub mime_types: Vec<String>,
+    /// Actors as recorded in the audit log, e.g. `token` or `key:<id>`.
+    #[serde(default)]
+    pub uploaders: Vec<String>,
+}
+
+impl RoutingRule {
+    pub fn matches(&self, size: u64, mime_type: &str, uploader: Option<&str>) -> bool {
+        if self.min_size.is_some_and(|min| size < min) {
+            return false;
+        }
+        if self.max_size.is_some_and(|max| size > max) {
+            return false;
+        }
+        if !self.mime_types.is_empty()
+            && !self
+                .mime_types
+                .iter()
+                .any(|pattern| mime_matches(pattern, mime_type))
+        {
+            return false;
+        }
+        if !self.uploaders.is_empty()
+            && !uploader.is_some_and(|uploader| self.uploaders.iter().any(|u| u == uploader))
+        {
+            return false;
+        }
+        true
+    }
+}
+
+/// Matches a MIME type against a pattern such as `image/png`, `image/*` or
+/// `*`.
+pub(crate) fn mime_matches(pattern: &str, mime_type: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(prefix) => mime_type
+            .split_once('/')
+            .is_some_and(|(top_level, _)| top_level.eq_ignore_ascii_case(prefix)),
+        None => pattern == "*" || pattern.eq_ignore_ascii_case(mime_type),
+    }
+}
+
+impl StorageConfig {
+    /// Loads the storage configuration file, or derives a single-backend
+    /// configuration from the `FILE_SERVER_STORAGE_TYPE` settings when no file
+    /// is given. Backends are then named `local` and `s3`, matching rows
+    /// written before named backends existed.
+    pub fn load(config: &Config) -> anyhow::Result<Self> {
+        let storage_config = match &config.storage_config {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path).map_err(|e| {
+                    anyhow::anyhow!("Failed to read storage config {}: {}", path.display(), e)
+                })?;
+                toml::from_str(&contents).map_err(|e| {
+                    anyhow::anyhow!("Invalid storage config {}: {}", path.display(), e)
+                })?
+            }
+            None => Self::from_env(config),
+        };
+
+        storage_config.validate()?;
+        Ok(storage_config)
+    }
+
+    fn from_env(config: &Config) -> Self {
+        let local = BackendConfig {
+            name: StorageType::Local.to_string(),
+            kind: BackendKind::Local {
+                path: config.storage_path.clone(),
+            },
+        };
+        let s3 = config.s3_bucket.as_ref().map(|bucket| BackendConfig {
+            name: StorageType::S3.to_string(),
+            kind: BackendKind::S3 {
+                bucket: bucket.clone(),
+                region: config.s3_region.clone(),
+                endpoint_url: config.aws_endpoint_url.clone(),
+                access_key_id: None,
+                secret_access_key: None,
+                sse: config.s3_sse,
+                sse_kms_key_id: config.s3_sse_kms_key_id.clone(),
+                sse_customer_key: config.s3_sse_customer_key.clone(),
+            },
+        });
+
+        let gcs = config.gcs_bucket.as_ref().map(|bucket| BackendConfig {
+            name: StorageType::Gcs.to_string(),
+            kind: BackendKind::Gcs {
+                bucket: bucket.clone(),
+                endpoint_url: config.gcs_endpoint_url.clone(),
+                credentials_file: config.gcs_credentials_file.clone(),
+            },
+        });
+        let azure = match (
+            &config.azure_storage_account,
+            &config.azure_storage_key,
+            &config.azure_container,
+        ) {
+            (Some(account), Some(access_key), Some(container)) => Some(BackendConfig {
+                name: StorageType::Azure.to_string(),
+                kind: BackendKind::Azure {
+                    account: account.clone(),
+                    access_key: access_key.clone(),
+                    container: container.clone(),
+                    endpoint_url: config.azure_endpoint_url.clone(),
+                },
+            }),
+            _ => None,
+        };
+
+        let webdav = config.webdav_url.as_ref().map(|url| BackendConfig {
+            name: StorageType::Webdav.to_string(),
+            kind: BackendKind::WebDav {
+                url: url.clone(),
+                prefix: config.webdav_prefix.clone(),
+                username: config.webdav_username.clone(),
+                password: config.webdav_password.clone(),
+                pool_size: config.storage_pool_size,
+                retries: config.storage_retries,
+            },
+        });
+        let sftp = config
+            .sftp_host
+            .as_ref()
+            .zip(config.sftp_username.as_ref())
+            .map(|(host, username)| BackendConfig {
+                name: StorageType::Sftp.to_string(),
+                kind: BackendKind::Sftp {
+                    host: host.clone(),
+                    port: config.sftp_port,
+                    username: username.clone(),
+                    password: config.sftp_password.clone(),
+                    private_key: config.sftp_private_key.clone(),
+                    known_hosts: config.sftp_known_hosts.clone(),
+                    prefix: config.sftp_prefix.clone(),
+                    pool_size: config.storage_pool_size,
+                    retries: config.storage_retries,
+                },
+            });
+
+        // Other backends are only needed for reads while migrating.
+        let backends = [Some(local), s3, gcs, azure, webdav, sftp]
+            .into_iter()
+            .flatten()
+            .filter(|backend| config.mixed_reads || backend.name == config.storage_type.to_string())
+            .collect();
+
+        Self {
+            default: config.storage_type.to_string(),
+            backends,
+            rules: Vec::new(),
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let mut names = HashSet::new();
+        for backend in &self.backends {
+            if backend.name.is_empty() {
+                anyhow::bail!("Storage backend names must not be empty");
+            }
+            if !names.insert(backend.name.as_str()) {
+                anyhow::bail!("Storage backend {} is defined more than once", backend.name);
+            }
+            match &backend.kind {
+                BackendKind::S3 {
+                    access_key_id,
+                    secret_access_key,
+                    ..
+                } if access_key_id.is_some() != secret_access_key.is_some() => {
+                    anyhow::bail!(
+                        "Storage backend {} must set both access_key_id and secret_access_key",
+                        backend.name
+                    );
+                }
+                BackendKind::S3 {
+                    sse: Some(_),
+                    sse_customer_key: Some(_),
+                    ..
+                } => {
+                    anyhow::bail!(
+                        "Storage backend {} must not set both sse and sse_customer_key",
+                        backend.name
+                    );
+                }
+                BackendKind::S3 {
+                    sse,
+                    sse_kms_key_id: Some(_),
+                    ..
+                } if *sse != Some(S3ServerSideEncryption::AwsKms) => {
+                    anyhow::bail!(
+                        "Storage backend {} sets sse_kms_key_id without sse = \"aws:kms\"",
+                        backend.name
+                    );
+                }
+                BackendKind::S3 {
+                    sse_customer_key: Some(key),
+                    ..
+                } if STANDARD
+                    .decode(key.expose())
+                    .map_or(true, |key| key.len() != 32) =>
+                {
+                    anyhow::bail!(
+                        "Storage backend {} must have an sse_customer_key of 32 base64-encoded bytes",
+                        backend.name
+                    );
+                }
+                BackendKind::Azure { access_key, .. }
+                    if STANDARD.decode(access_key.expose()).is_err() =>
+                {
+                    anyhow::bail!(
+                        "Storage backend {} has an access_key that is not valid base64",
+                        backend.name
+                    );
+                }
+                BackendKind::WebDav { url, .. } if reqwest::Url::parse(url).is_err() => {
+                    anyhow::bail!(
+                        "Storage backend {} has an invalid url {}",
+                        backend.name,
+                        url
+                    );
+                }
+                BackendKind::WebDav { pool_size: 0, .. }
+                | BackendKind::Sftp { pool_size: 0, .. } => {
+                    anyhow::bail!(
+                        "Storage backend {} must have a pool_size of at least 1",
+                        backend.name
+                    );
+                }
+                _ => {}
+            }
+        }
+
+        if !names.contains(self.default.as_str()) {
+            anyhow::bail!("Default storage backend {} is not defined", self.default);
+        }
+        for rule in &self.rules {
+            if !names.contains(rule.backend.as_str()) {
+                anyhow::bail!("Routing rule refers to unknown backend {}", rule.backend);
+            }
+            // Uploads are buffered in memory until they pass every threshold.
+            if [rule.min_size, rule.max_size]
+                .into_iter()
+                .flatten()
+                .any(|size| size > ROUTING_READ_AHEAD_LIMIT)
+            {
+                anyhow::bail!(
+                    "Routing rule for backend {} has a size threshold above {} bytes",
+                    rule.backend,
+                    ROUTING_READ_AHEAD_LIMIT
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,88 +1,1370 @@
-use crate::models::File;
+use crate::models::{
+    file::{STATUS_PENDING, STATUS_PROCESSING},
+    job::{JOB_COMPLETED, JOB_DEAD, JOB_PROCESS_FILE, JOB_QUEUED, JOB_RUNNING},
+    webhook::{DELIVERY_FAILED, DELIVERY_PENDING},
+    ApiKey, AuditEvent, AuditEventFilter, File, FileFilter, FileLabels, Folder, Job, JobFilter,
+    Webhook, WebhookDelivery,
+};
+use crate::storage::WrappedKey;
 use anyhow::Result;
-use sqlx::{Pool, Sqlite, SqlitePool};
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, QueryBuilder, SqlitePool};
+use std::collections::HashMap;
 use tokio::fs;
 
-pub type DbPool = Pool<Sqlite>;
+/// Connection pool for the database named by `DATABASE_URL`.
+#[derive(Debug, Clone)]
+pub enum DbPool {
+    Sqlite(SqlitePool),
+    Postgres(PgPool),
+}
 
-pub async fn create_pool(database_url: &str) -> Result<DbPool> {
-    // Create the database file if it doesn't exist
-    if database_url.starts_with("sqlite:") {
-        let db_path = database_url.strip_prefix("sqlite:").unwrap_or(database_url);
-        if let Some(parent) = std::path::Path::new(db_path).parent() {
-            tokio::fs::create_dir_all(parent).await?;
+impl DbPool {
+    pub fn size(&self) -> u32 {
+        match self {
+            DbPool::Sqlite(pool) => pool.size(),
+            DbPool::Postgres(pool) => pool.size(),
         }
-        if !std::path::Path::new(db_path).exists() {
-            fs::File::create(db_path).await?;
+    }
+
+    pub fn num_idle(&self) -> usize {
+        match self {
+            DbPool::Sqlite(pool) => pool.num_idle(),
+            DbPool::Postgres(pool) => pool.num_idle(),
         }
     }
 
-    let pool = SqlitePool::connect(database_url).await?;
+    pub async fn close(&self) {
+        match self {
+            DbPool::Sqlite(pool) => pool.close().await,
+            DbPool::Postgres(pool) => pool.close().await,
+        }
+    }
+}
 
-    sqlx::migrate!("./migrations").run(&pool).await?;
+/// Evaluates `$body` with `$pool` rebound to the concrete pool. The body is
+/// compiled once per database, so its SQL has to be valid for both: `$N`
+/// placeholders, `||` for concatenation and explicit casts where Postgres
+/// would widen a type (e.g. `SUM` of a `BIGINT`).
+macro_rules! with_pool {
+    ($pool:expr, |$conn:ident| $body:expr) => {
+        match $pool {
+            DbPool::Sqlite($conn) => $body,
+            DbPool::Postgres($conn) => $body,
+        }
+    };
+}
 
-    Ok(pool)
+/// Inserts `$job` through `$executor`, typically a transaction inside
+/// [`with_pool!`], so that work is queued together with the change causing it.
+macro_rules! insert_job {
+    ($executor:expr, $job:expr) => {
+        sqlx::query(
+            r#"
+            INSERT INTO jobs (id, kind, payload, status, attempts, run_at, last_error, created_at, updated_at, completed_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            "#,
+        )
+        .bind(&$job.id)
+        .bind(&$job.kind)
+        .bind(&$job.payload)
+        .bind(&$job.status)
+        .bind($job.attempts)
+        .bind($job.run_at)
+        .bind(&$job.last_error)
+        .bind($job.created_at)
+        .bind($job.updated_at)
+        .bind($job.completed_at)
+        .execute($executor)
+        .await
+    };
 }
 
-pub async fn create_file(pool: &DbPool, file: &File) -> Result<File> {
-    let result = sqlx::query_as::<_, File>(
-        r#"
-        INSERT INTO files (id, path, name, size, storage_type, is_private, created_at, updated_at)
-        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
-        RETURNING id, path, name, size, storage_type, is_private, created_at, updated_at
-        "#,
-    )
-    .bind(&file.id)
-    .bind(&file.path)
-    .bind(&file.name)
-    .bind(file.size)
-    .bind(&file.storage_type)
-    .bind(file.is_private)
-    .bind(&file.created_at)
-    .bind(&file.updated_at)
-    .fetch_one(pool)
-    .await?;
+/// The job processing a pending file's content.
+fn processing_job(file: &File) -> Option<Job> {
+    if file.status != STATUS_PENDING {
+        return None;
+    }
+    Some(Job::new(
+        file.job_id.clone()?,
+        JOB_PROCESS_FILE,
+        serde_json::json!({ "file_id": file.id }),
+    ))
+}
+
+/// Connects to the database and applies pending migrations. The dialect is
+/// chosen by the scheme of `database_url`; each has its own migrations under
+/// `migrations/`.
+#[tracing::instrument(skip_all)]
+pub async fn create_pool(database_url: &str) -> Result<DbPool> {
+    let scheme = database_url.split(':').next().unwrap_or_default();
+    match scheme {
+        "sqlite" => {
+            // Create the database file if it doesn't exist
+            let db_path = database_url.strip_prefix("sqlite:").unwrap_or(database_url);
+            if let Some(parent) = std::path::Path::new(db_path).parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            if !std::path::Path::new(db_path).exists() {
+                fs::File::create(db_path).await?;
+            }
+
+            let pool = SqlitePool::connect(database_url).await?;
+            sqlx::migrate!("./migrations/sqlite").run(&pool).await?;
+            Ok(DbPool::Sqlite(pool))
+        }
+        "postgres" | "postgresql" => {
+            let pool = PgPool::connect(database_url).await?;
+            // Takes an advisory lock, so replicas starting together are safe.
+            sqlx::migrate!("./migrations/postgres").run(&pool).await?;
+            Ok(DbPool::Postgres(pool))
+        }
+        "mysql" | "mariadb" => anyhow::bail!("MySQL is not supported yet, use SQLite or Postgres"),
+        _ => anyhow::bail!("Unsupported DATABASE_URL scheme: {}", scheme),
+    }
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn ping(pool: &DbPool) -> Result<()> {
+    with_pool!(pool, |pool| {
+        sqlx::query("SELECT 1").execute(pool).await?;
+    });
+    Ok(())
+}
+
+/// Inserts the row for a new file along with its tags and metadata, and its
+/// processing job if it is pending.
+#[tracing::instrument(skip_all, fields(file_id = %file.id))]
+pub async fn create_file(pool: &DbPool, file: &File, labels: &FileLabels) -> Result<File> {
+    let result = with_pool!(pool, |pool| {
+        let mut tx = pool.begin().await?;
+
+        let created = sqlx::query_as::<_, File>(
+            r#"
+            INSERT INTO files (id, path, name, size, storage_type, is_private, folder, encryption_key_id, wrapped_key, compression, compressed_size, status, job_id, status_error, sha256, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17)
+            RETURNING id, path, name, size, storage_type, is_private, is_broken, folder, encryption_key_id, wrapped_key, compression, compressed_size, status, job_id, status_error, sha256, created_at, updated_at
+            "#,
+        )
+        .bind(&file.id)
+        .bind(&file.path)
+        .bind(&file.name)
+        .bind(file.size)
+        .bind(&file.storage_type)
+        .bind(file.is_private)
+        .bind(&file.folder)
+        .bind(&file.encryption_key_id)
+        .bind(&file.wrapped_key)
+        .bind(&file.compression)
+        .bind(file.compressed_size)
+        .bind(&file.status)
+        .bind(&file.job_id)
+        .bind(&file.status_error)
+        .bind(&file.sha256)
+        .bind(file.created_at)
+        .bind(file.updated_at)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        for tag in &labels.tags {
+            sqlx::query("INSERT INTO file_tags (file_id, tag) VALUES ($1, $2)")
+                .bind(&file.id)
+                .bind(tag)
+                .execute(&mut *tx)
+                .await?;
+        }
+        for (key, value) in &labels.metadata {
+            sqlx::query("INSERT INTO file_metadata (file_id, key, value) VALUES ($1, $2, $3)")
+                .bind(&file.id)
+                .bind(key)
+                .bind(value)
+                .execute(&mut *tx)
+                .await?;
+        }
+        if let Some(job) = processing_job(file) {
+            insert_job!(&mut *tx, job)?;
+        }
+
+        tx.commit().await?;
+        created
+    });
 
     Ok(result)
 }
 
+#[tracing::instrument(skip(pool))]
 pub async fn get_file_by_id(pool: &DbPool, id: &str) -> Result<Option<File>> {
-    let file = sqlx::query_as::<_, File>(
-        r#"
-        SELECT id, path, name, size, storage_type, is_private, created_at, updated_at
-        FROM files
-        WHERE id = ?1
-        "#,
-    )
-    .bind(id)
-    .fetch_optional(pool)
-    .await?;
+    let file = with_pool!(pool, |pool| {
+        sqlx::query_as::<_, File>(
+            r#"
+            SELECT id, path, name, size, storage_type, is_private, is_broken, folder, encryption_key_id, wrapped_key, compression, compressed_size, status, job_id, status_error, sha256, created_at, updated_at
+            FROM files
+            WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(pool)
+        .await?
+    });
 
     Ok(file)
 }
 
-pub async fn get_files(pool: &DbPool, limit: i64) -> Result<Vec<File>> {
-    let files = sqlx::query_as::<_, File>(
-        r#"
-        SELECT id, path, name, size, storage_type, is_private, created_at, updated_at
-        FROM files
-        WHERE is_private = false
-        ORDER BY created_at DESC
-        LIMIT ?1
-        "#,
-    )
-    .bind(limit)
-    .fetch_all(pool)
-    .await?;
+/// Visible files matching `filter`, newest first.
+#[tracing::instrument(skip(pool))]
+pub async fn get_files(pool: &DbPool, filter: &FileFilter, limit: i64) -> Result<Vec<File>> {
+    let files = with_pool!(pool, |pool| {
+        let mut query = QueryBuilder::new(
+            "SELECT id, path, name, size, storage_type, is_private, is_broken, folder, encryption_key_id, wrapped_key, compression, compressed_size, status, job_id, status_error, sha256, created_at, updated_at FROM files WHERE is_private = false AND is_broken = false",
+        );
+
+        if let Some(tag) = &filter.tag {
+            query
+                .push(" AND id IN (SELECT file_id FROM file_tags WHERE tag = ")
+                .push_bind(tag)
+                .push(")");
+        }
+        if let Some((key, value)) = &filter.metadata {
+            query
+                .push(" AND id IN (SELECT file_id FROM file_metadata WHERE key = ")
+                .push_bind(key)
+                .push(" AND value = ")
+                .push_bind(value)
+                .push(")");
+        }
+
+        query
+            .push(" ORDER BY created_at DESC LIMIT ")
+            .push_bind(limit);
+
+        query.build_query_as::<File>().fetch_all(pool).await?
+    });
+
+    Ok(files)
+}
+
+/// Visible files among `ids`, in no particular order.
+#[tracing::instrument(skip_all)]
+pub async fn get_files_by_ids(pool: &DbPool, ids: &[&str]) -> Result<Vec<File>> {
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let files = with_pool!(pool, |pool| {
+        let mut query = QueryBuilder::new(
+            "SELECT id, path, name, size, storage_type, is_private, is_broken, folder, encryption_key_id, wrapped_key, compression, compressed_size, status, job_id, status_error, sha256, created_at, updated_at FROM files WHERE is_private = false AND is_broken = false AND id IN (",
+        );
+        let mut separated = query.separated(", ");
+        for id in ids {
+            separated.push_bind(*id);
+        }
+        query.push(")");
+        query.build_query_as::<File>().fetch_all(pool).await?
+    });
+
+    Ok(files)
+}
+
+/// Tags and metadata of each of `ids` that has any.
+#[tracing::instrument(skip_all)]
+pub async fn get_file_labels(pool: &DbPool, ids: &[&str]) -> Result<HashMap<String, FileLabels>> {
+    let mut labels: HashMap<String, FileLabels> = HashMap::new();
+    if ids.is_empty() {
+        return Ok(labels);
+    }
+
+    let (tags, metadata) = with_pool!(pool, |pool| {
+        let mut query = QueryBuilder::new("SELECT file_id, tag FROM file_tags WHERE file_id IN (");
+        let mut separated = query.separated(", ");
+        for id in ids {
+            separated.push_bind(*id);
+        }
+        query.push(")");
+        let tags = query
+            .build_query_as::<(String, String)>()
+            .fetch_all(pool)
+            .await?;
+
+        let mut query =
+            QueryBuilder::new("SELECT file_id, key, value FROM file_metadata WHERE file_id IN (");
+        let mut separated = query.separated(", ");
+        for id in ids {
+            separated.push_bind(*id);
+        }
+        query.push(")");
+        let metadata = query
+            .build_query_as::<(String, String, String)>()
+            .fetch_all(pool)
+            .await?;
+
+        (tags, metadata)
+    });
+
+    for (file_id, tag) in tags {
+        labels.entry(file_id).or_default().tags.insert(tag);
+    }
+    for (file_id, key, value) in metadata {
+        labels
+            .entry(file_id)
+            .or_default()
+            .metadata
+            .insert(key, value);
+    }
+
+    Ok(labels)
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn get_all_files(pool: &DbPool) -> Result<Vec<File>> {
+    let files = with_pool!(pool, |pool| {
+        sqlx::query_as::<_, File>(
+            r#"
+            SELECT id, path, name, size, storage_type, is_private, is_broken, folder, encryption_key_id, wrapped_key, compression, compressed_size, status, job_id, status_error, sha256, created_at, updated_at
+            FROM files
+            ORDER BY created_at
+            "#,
+        )
+        .fetch_all(pool)
+        .await?
+    });
+
+    Ok(files)
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn get_files_by_storage_type(pool: &DbPool, storage_type: &str) -> Result<Vec<File>> {
+    let files = with_pool!(pool, |pool| {
+        sqlx::query_as::<_, File>(
+            r#"
+            SELECT id, path, name, size, storage_type, is_private, is_broken, folder, encryption_key_id, wrapped_key, compression, compressed_size, status, job_id, status_error, sha256, created_at, updated_at
+            FROM files
+            WHERE storage_type = $1
+            ORDER BY created_at
+            "#,
+        )
+        .bind(storage_type)
+        .fetch_all(pool)
+        .await?
+    });
+
+    Ok(files)
+}
+
+/// Points a row at a new blob, provided it still refers to `old_path`.
+#[tracing::instrument(skip(pool))]
+pub async fn update_file_location(
+    pool: &DbPool,
+    id: &str,
+    old_path: &str,
+    new_path: &str,
+    storage_type: &str,
+) -> Result<bool> {
+    let rows_affected = with_pool!(pool, |pool| {
+        sqlx::query(
+            r#"
+            UPDATE files
+            SET path = $3, storage_type = $4, updated_at = $5
+            WHERE id = $1 AND path = $2
+            "#,
+        )
+        .bind(id)
+        .bind(old_path)
+        .bind(new_path)
+        .bind(storage_type)
+        .bind(Utc::now())
+        .execute(pool)
+        .await?
+        .rows_affected()
+    });
+
+    Ok(rows_affected > 0)
+}
+
+/// Visible files directly in `folder`, newest first.
+#[tracing::instrument(skip(pool))]
+pub async fn get_files_in_folder(pool: &DbPool, folder: &str) -> Result<Vec<File>> {
+    let files = with_pool!(pool, |pool| {
+        sqlx::query_as::<_, File>(
+            r#"
+            SELECT id, path, name, size, storage_type, is_private, is_broken, folder, encryption_key_id, wrapped_key, compression, compressed_size, status, job_id, status_error, sha256, created_at, updated_at
+            FROM files
+            WHERE folder = $1 AND is_private = false AND is_broken = false
+            ORDER BY created_at DESC
+            "#,
+        )
+        .bind(folder)
+        .fetch_all(pool)
+        .await?
+    });
+
+    Ok(files)
+}
+
+/// Visible files called `name` in `folder`, newest first.
+#[tracing::instrument(skip(pool))]
+pub async fn get_files_by_name(pool: &DbPool, folder: &str, name: &str) -> Result<Vec<File>> {
+    let files = with_pool!(pool, |pool| {
+        sqlx::query_as::<_, File>(
+            r#"
+            SELECT id, path, name, size, storage_type, is_private, is_broken, folder, encryption_key_id, wrapped_key, compression, compressed_size, status, job_id, status_error, sha256, created_at, updated_at
+            FROM files
+            WHERE folder = $1 AND name = $2 AND is_private = false AND is_broken = false
+            ORDER BY created_at DESC
+            "#,
+        )
+        .bind(folder)
+        .bind(name)
+        .fetch_all(pool)
+        .await?
+    });
 
     Ok(files)
 }
 
+/// Every file in `folder` or any folder below it, including private and
+/// broken ones.
+#[tracing::instrument(skip(pool))]
+pub async fn get_files_under_folder(pool: &DbPool, folder: &str) -> Result<Vec<File>> {
+    let files = with_pool!(pool, |pool| {
+        sqlx::query_as::<_, File>(
+            r#"
+            SELECT id, path, name, size, storage_type, is_private, is_broken, folder, encryption_key_id, wrapped_key, compression, compressed_size, status, job_id, status_error, sha256, created_at, updated_at
+            FROM files
+            WHERE $1 = '' OR folder = $1 OR substr(folder, 1, length($1) + 1) = $1 || '/'
+            ORDER BY folder, created_at
+            "#,
+        )
+        .bind(folder)
+        .fetch_all(pool)
+        .await?
+    });
+
+    Ok(files)
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn move_file(pool: &DbPool, id: &str, folder: &str, name: &str) -> Result<bool> {
+    let rows_affected = with_pool!(pool, |pool| {
+        sqlx::query("UPDATE files SET folder = $2, name = $3, updated_at = $4 WHERE id = $1")
+            .bind(id)
+            .bind(folder)
+            .bind(name)
+            .bind(Utc::now())
+            .execute(pool)
+            .await?
+            .rows_affected()
+    });
+
+    Ok(rows_affected > 0)
+}
+
+/// Points a row at a new blob holding new content for the same file, and
+/// queues its processing job if it is pending.
+#[tracing::instrument(skip_all, fields(file_id = %file.id))]
+pub async fn replace_file_content(pool: &DbPool, file: &File) -> Result<bool> {
+    let rows_affected = with_pool!(pool, |pool| {
+        let mut tx = pool.begin().await?;
+
+        let rows_affected = sqlx::query(
+            r#"
+            UPDATE files
+            SET path = $2, size = $3, storage_type = $4, is_broken = $5,
+                encryption_key_id = $6, wrapped_key = $7, compression = $8, compressed_size = $9,
+                status = $10, job_id = $11, status_error = $12, sha256 = $13, updated_at = $14
+            WHERE id = $1
+            "#,
+        )
+        .bind(&file.id)
+        .bind(&file.path)
+        .bind(file.size)
+        .bind(&file.storage_type)
+        .bind(file.is_broken)
+        .bind(&file.encryption_key_id)
+        .bind(&file.wrapped_key)
+        .bind(&file.compression)
+        .bind(file.compressed_size)
+        .bind(&file.status)
+        .bind(&file.job_id)
+        .bind(&file.status_error)
+        .bind(&file.sha256)
+        .bind(file.updated_at)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+
+        if rows_affected > 0 {
+            if let Some(job) = processing_job(file) {
+                insert_job!(&mut *tx, job)?;
+            }
+        }
+
+        tx.commit().await?;
+        rows_affected
+    });
+
+    Ok(rows_affected > 0)
+}
+
+/// Marks a file as processing by `job_id`. Returns false when the file was
+/// deleted or its content replaced since the job was queued.
+#[tracing::instrument(skip(pool))]
+pub async fn start_file_processing(pool: &DbPool, id: &str, job_id: &str) -> Result<bool> {
+    let rows_affected = with_pool!(pool, |pool| {
+        sqlx::query(
+            "UPDATE files SET status = $3, status_error = NULL WHERE id = $1 AND job_id = $2",
+        )
+        .bind(id)
+        .bind(job_id)
+        .bind(STATUS_PROCESSING)
+        .execute(pool)
+        .await?
+        .rows_affected()
+    });
+
+    Ok(rows_affected > 0)
+}
+
+/// Records the outcome of processing job `job_id`, unless the content has
+/// been replaced in the meantime.
+#[tracing::instrument(skip(pool, sha256, error))]
+pub async fn finish_file_processing(
+    pool: &DbPool,
+    id: &str,
+    job_id: &str,
+    status: &str,
+    sha256: Option<&str>,
+    error: Option<&str>,
+) -> Result<bool> {
+    let rows_affected = with_pool!(pool, |pool| {
+        sqlx::query(
+            r#"
+            UPDATE files
+            SET status = $3, sha256 = $4, status_error = $5
+            WHERE id = $1 AND job_id = $2
+            "#,
+        )
+        .bind(id)
+        .bind(job_id)
+        .bind(status)
+        .bind(sha256)
+        .bind(error)
+        .execute(pool)
+        .await?
+        .rows_affected()
+    });
+
+    Ok(rows_affected > 0)
+}
+
+/// Files whose blob is not encrypted under the master key `key_id`,
+/// including unencrypted ones.
+#[tracing::instrument(skip(pool))]
+pub async fn get_files_not_encrypted_with(pool: &DbPool, key_id: &str) -> Result<Vec<File>> {
+    let files = with_pool!(pool, |pool| {
+        sqlx::query_as::<_, File>(
+            r#"
+            SELECT id, path, name, size, storage_type, is_private, is_broken, folder, encryption_key_id, wrapped_key, compression, compressed_size, status, job_id, status_error, sha256, created_at, updated_at
+            FROM files
+            WHERE encryption_key_id IS NULL OR encryption_key_id != $1
+            ORDER BY created_at
+            "#,
+        )
+        .bind(key_id)
+        .fetch_all(pool)
+        .await?
+    });
+
+    Ok(files)
+}
+
+/// Records a new data key for a file, and its new blob if the content was
+/// re-encrypted, provided the row still refers to `old_path`. The content is
+/// unchanged, so `updated_at` is kept.
+#[tracing::instrument(skip(pool, key))]
+pub async fn update_file_key(
+    pool: &DbPool,
+    id: &str,
+    old_path: &str,
+    new_path: &str,
+    key: &WrappedKey,
+) -> Result<bool> {
+    let rows_affected = with_pool!(pool, |pool| {
+        sqlx::query(
+            r#"
+            UPDATE files
+            SET path = $3, encryption_key_id = $4, wrapped_key = $5
+            WHERE id = $1 AND path = $2
+            "#,
+        )
+        .bind(id)
+        .bind(old_path)
+        .bind(new_path)
+        .bind(&key.key_id)
+        .bind(&key.wrapped)
+        .execute(pool)
+        .await?
+        .rows_affected()
+    });
+
+    Ok(rows_affected > 0)
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn mark_file_broken(pool: &DbPool, id: &str) -> Result<bool> {
+    let rows_affected = with_pool!(pool, |pool| {
+        sqlx::query("UPDATE files SET is_broken = true, updated_at = $2 WHERE id = $1")
+            .bind(id)
+            .bind(Utc::now())
+            .execute(pool)
+            .await?
+            .rows_affected()
+    });
+
+    Ok(rows_affected > 0)
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn get_file_totals_by_storage_type(pool: &DbPool) -> Result<Vec<(String, i64, i64)>> {
+    let totals = with_pool!(pool, |pool| {
+        sqlx::query_as::<_, (String, i64, i64)>(
+            "SELECT storage_type, COUNT(*), CAST(COALESCE(SUM(size), 0) AS BIGINT) FROM files GROUP BY storage_type",
+        )
+        .fetch_all(pool)
+        .await?
+    });
+
+    Ok(totals)
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn get_file_totals(pool: &DbPool) -> Result<(i64, i64)> {
+    let totals = with_pool!(pool, |pool| {
+        sqlx::query_as::<_, (i64, i64)>(
+            "SELECT COUNT(*), CAST(COALESCE(SUM(size), 0) AS BIGINT) FROM files",
+        )
+        .fetch_one(pool)
+        .await?
+    });
+
+    Ok(totals)
+}
+
+#[tracing::instrument(skip(pool))]
 pub async fn delete_file_by_id(pool: &DbPool, id: &str) -> Result<bool> {
-    let result = sqlx::query("DELETE FROM files WHERE id = ?1 AND is_private = false")
+    let rows_affected = with_pool!(pool, |pool| {
+        sqlx::query("DELETE FROM files WHERE id = $1 AND is_private = false")
+            .bind(id)
+            .execute(pool)
+            .await?
+            .rows_affected()
+    });
+
+    Ok(rows_affected > 0)
+}
+
+/// Creates a folder, returning false if it already exists.
+#[tracing::instrument(skip_all, fields(folder = %folder.path))]
+pub async fn create_folder(pool: &DbPool, folder: &Folder) -> Result<bool> {
+    let rows_affected = with_pool!(pool, |pool| {
+        sqlx::query(
+            "INSERT INTO folders (path, parent, created_at) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+        )
+        .bind(&folder.path)
+        .bind(&folder.parent)
+        .bind(folder.created_at)
+        .execute(pool)
+        .await?
+        .rows_affected()
+    });
+
+    Ok(rows_affected > 0)
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn get_folder(pool: &DbPool, path: &str) -> Result<Option<Folder>> {
+    let folder = with_pool!(pool, |pool| {
+        sqlx::query_as::<_, Folder>("SELECT path, parent, created_at FROM folders WHERE path = $1")
+            .bind(path)
+            .fetch_optional(pool)
+            .await?
+    });
+
+    Ok(folder)
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn get_subfolders(pool: &DbPool, parent: &str) -> Result<Vec<Folder>> {
+    let folders = with_pool!(pool, |pool| {
+        sqlx::query_as::<_, Folder>(
+            "SELECT path, parent, created_at FROM folders WHERE parent = $1 ORDER BY path",
+        )
+        .bind(parent)
+        .fetch_all(pool)
+        .await?
+    });
+
+    Ok(folders)
+}
+
+/// `folder` and every folder below it, parents before children.
+#[tracing::instrument(skip(pool))]
+pub async fn get_folder_tree(pool: &DbPool, folder: &str) -> Result<Vec<Folder>> {
+    let folders = with_pool!(pool, |pool| {
+        sqlx::query_as::<_, Folder>(
+            r#"
+            SELECT path, parent, created_at
+            FROM folders
+            WHERE $1 = '' OR path = $1 OR substr(path, 1, length($1) + 1) = $1 || '/'
+            ORDER BY length(path), path
+            "#,
+        )
+        .bind(folder)
+        .fetch_all(pool)
+        .await?
+    });
+
+    Ok(folders)
+}
+
+/// Renames `from` to `to` along with every folder and file below it. The
+/// destination must not exist yet.
+#[tracing::instrument(skip(pool))]
+pub async fn move_folder(pool: &DbPool, from: &str, to: &str, to_parent: &str) -> Result<()> {
+    with_pool!(pool, |pool| {
+        let mut tx = pool.begin().await?;
+
+        sqlx::query(
+            r#"
+            UPDATE folders
+            SET path = $2 || substr(path, length($1) + 1),
+                parent = CASE WHEN path = $1 THEN $3 ELSE $2 || substr(parent, length($1) + 1) END
+            WHERE path = $1 OR substr(path, 1, length($1) + 1) = $1 || '/'
+            "#,
+        )
+        .bind(from)
+        .bind(to)
+        .bind(to_parent)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+            UPDATE files
+            SET folder = $2 || substr(folder, length($1) + 1), updated_at = $3
+            WHERE folder = $1 OR substr(folder, 1, length($1) + 1) = $1 || '/'
+            "#,
+        )
+        .bind(from)
+        .bind(to)
+        .bind(Utc::now())
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+    });
+    Ok(())
+}
+
+/// Deletes `folder` and every folder below it. Files must be removed first.
+#[tracing::instrument(skip(pool))]
+pub async fn delete_folder_tree(pool: &DbPool, folder: &str) -> Result<u64> {
+    let rows_affected = with_pool!(pool, |pool| {
+        sqlx::query(
+            "DELETE FROM folders WHERE path = $1 OR substr(path, 1, length($1) + 1) = $1 || '/'",
+        )
+        .bind(folder)
+        .execute(pool)
+        .await?
+        .rows_affected()
+    });
+
+    Ok(rows_affected)
+}
+
+#[tracing::instrument(skip_all, fields(action = %event.action))]
+pub async fn create_audit_event(pool: &DbPool, event: &AuditEvent) -> Result<()> {
+    with_pool!(pool, |pool| {
+        sqlx::query(
+            r#"
+            INSERT INTO audit_events (id, action, outcome, status_code, file_id, actor, ip_address, user_agent, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            "#,
+        )
+        .bind(&event.id)
+        .bind(&event.action)
+        .bind(&event.outcome)
+        .bind(event.status_code)
+        .bind(&event.file_id)
+        .bind(&event.actor)
+        .bind(&event.ip_address)
+        .bind(&event.user_agent)
+        .bind(event.created_at)
+        .execute(pool)
+        .await?;
+    });
+
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn get_audit_events(
+    pool: &DbPool,
+    filter: &AuditEventFilter,
+    limit: i64,
+) -> Result<Vec<AuditEvent>> {
+    let events = with_pool!(pool, |pool| {
+        let mut query = QueryBuilder::new(
+            "SELECT id, action, outcome, status_code, file_id, actor, ip_address, user_agent, created_at FROM audit_events WHERE 1 = 1",
+        );
+
+        if let Some(action) = &filter.action {
+            query.push(" AND action = ").push_bind(action);
+        }
+        if let Some(outcome) = &filter.outcome {
+            query.push(" AND outcome = ").push_bind(outcome);
+        }
+        if let Some(file_id) = &filter.file_id {
+            query.push(" AND file_id = ").push_bind(file_id);
+        }
+        if let Some(actor) = &filter.actor {
+            query.push(" AND actor = ").push_bind(actor);
+        }
+        if let Some(ip_address) = &filter.ip_address {
+            query.push(" AND ip_address = ").push_bind(ip_address);
+        }
+        if let Some(since) = filter.since {
+            query.push(" AND created_at >= ").push_bind(since);
+        }
+        if let Some(until) = filter.until {
+            query.push(" AND created_at < ").push_bind(until);
+        }
+
+        query
+            .push(" ORDER BY created_at DESC LIMIT ")
+            .push_bind(limit);
+
+        query.build_query_as::<AuditEvent>().fetch_all(pool).await?
+    });
+
+    Ok(events)
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn delete_audit_events_before(pool: &DbPool, cutoff: DateTime<Utc>) -> Result<u64> {
+    let rows_affected = with_pool!(pool, |pool| {
+        sqlx::query("DELETE FROM audit_events WHERE created_at < $1")
+            .bind(cutoff)
+            .execute(pool)
+            .await?
+            .rows_affected()
+    });
+
+    Ok(rows_affected)
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn count_audit_events(pool: &DbPool) -> Result<i64> {
+    let count = with_pool!(pool, |pool| {
+        sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM audit_events")
+            .fetch_one(pool)
+            .await?
+    });
+
+    Ok(count)
+}
+
+#[tracing::instrument(skip_all, fields(api_key_id = %api_key.id))]
+pub async fn create_api_key(pool: &DbPool, api_key: &ApiKey) -> Result<()> {
+    with_pool!(pool, |pool| {
+        sqlx::query(
+            r#"
+            INSERT INTO api_keys (id, name, key_hash, s3_secret, created_at, last_used_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            "#,
+        )
+        .bind(&api_key.id)
+        .bind(&api_key.name)
+        .bind(&api_key.key_hash)
+        .bind(&api_key.s3_secret)
+        .bind(api_key.created_at)
+        .bind(api_key.last_used_at)
+        .execute(pool)
+        .await?;
+    });
+
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn get_api_key_by_hash(pool: &DbPool, key_hash: &str) -> Result<Option<ApiKey>> {
+    let api_key = with_pool!(pool, |pool| {
+        sqlx::query_as::<_, ApiKey>(
+            r#"
+            SELECT id, name, key_hash, s3_secret, created_at, last_used_at
+            FROM api_keys
+            WHERE key_hash = $1
+            "#,
+        )
+        .bind(key_hash)
+        .fetch_optional(pool)
+        .await?
+    });
+
+    Ok(api_key)
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn get_api_key_by_id(pool: &DbPool, id: &str) -> Result<Option<ApiKey>> {
+    let api_key = with_pool!(pool, |pool| {
+        sqlx::query_as::<_, ApiKey>(
+            r#"
+            SELECT id, name, key_hash, s3_secret, created_at, last_used_at
+            FROM api_keys
+            WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(pool)
+        .await?
+    });
+
+    Ok(api_key)
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn touch_api_key(pool: &DbPool, id: &str) -> Result<()> {
+    with_pool!(pool, |pool| {
+        sqlx::query("UPDATE api_keys SET last_used_at = $2 WHERE id = $1")
+            .bind(id)
+            .bind(Utc::now())
+            .execute(pool)
+            .await?;
+    });
+
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn count_api_keys(pool: &DbPool) -> Result<i64> {
+    let count = with_pool!(pool, |pool| {
+        sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM api_keys")
+            .fetch_one(pool)
+            .await?
+    });
+
+    Ok(count)
+}
+
+#[tracing::instrument(skip_all, fields(webhook_id = %webhook.id))]
+pub async fn create_webhook(pool: &DbPool, webhook: &Webhook) -> Result<()> {
+    with_pool!(pool, |pool| {
+        sqlx::query(
+            "INSERT INTO webhooks (id, url, secret, events, created_at) VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(&webhook.id)
+        .bind(&webhook.url)
+        .bind(&webhook.secret)
+        .bind(&webhook.events)
+        .bind(webhook.created_at)
+        .execute(pool)
+        .await?;
+    });
+
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn get_webhooks(pool: &DbPool) -> Result<Vec<Webhook>> {
+    let webhooks = with_pool!(pool, |pool| {
+        sqlx::query_as::<_, Webhook>(
+            "SELECT id, url, secret, events, created_at FROM webhooks ORDER BY created_at",
+        )
+        .fetch_all(pool)
+        .await?
+    });
+
+    Ok(webhooks)
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn get_webhook(pool: &DbPool, id: &str) -> Result<Option<Webhook>> {
+    let webhook = with_pool!(pool, |pool| {
+        sqlx::query_as::<_, Webhook>(
+            "SELECT id, url, secret, events, created_at FROM webhooks WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_optional(pool)
+        .await?
+    });
+
+    Ok(webhook)
+}
+
+/// Deletes a webhook along with its pending deliveries and delivery log.
+#[tracing::instrument(skip(pool))]
+pub async fn delete_webhook(pool: &DbPool, id: &str) -> Result<bool> {
+    let rows_affected = with_pool!(pool, |pool| {
+        sqlx::query("DELETE FROM webhooks WHERE id = $1")
+            .bind(id)
+            .execute(pool)
+            .await?
+            .rows_affected()
+    });
+
+    Ok(rows_affected > 0)
+}
+
+#[tracing::instrument(skip_all, fields(delivery_id = %delivery.id))]
+pub async fn create_webhook_delivery(pool: &DbPool, delivery: &WebhookDelivery) -> Result<()> {
+    with_pool!(pool, |pool| {
+        sqlx::query(
+            r#"
+            INSERT INTO webhook_deliveries (id, webhook_id, event, file_id, payload, status, attempts, next_attempt_at, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            "#,
+        )
+        .bind(&delivery.id)
+        .bind(&delivery.webhook_id)
+        .bind(&delivery.event)
+        .bind(&delivery.file_id)
+        .bind(&delivery.payload)
+        .bind(&delivery.status)
+        .bind(delivery.attempts)
+        .bind(delivery.next_attempt_at)
+        .bind(delivery.created_at)
+        .execute(pool)
+        .await?;
+    });
+
+    Ok(())
+}
+
+/// Pending deliveries whose next attempt is due, oldest first.
+#[tracing::instrument(skip(pool))]
+pub async fn get_due_webhook_deliveries(
+    pool: &DbPool,
+    now: DateTime<Utc>,
+    limit: i64,
+) -> Result<Vec<WebhookDelivery>> {
+    let deliveries = with_pool!(pool, |pool| {
+        sqlx::query_as::<_, WebhookDelivery>(
+            r#"
+            SELECT id, webhook_id, event, file_id, payload, status, attempts, next_attempt_at, last_status_code, last_error, created_at, delivered_at
+            FROM webhook_deliveries
+            WHERE status = $1 AND next_attempt_at <= $2
+            ORDER BY next_attempt_at
+            LIMIT $3
+            "#,
+        )
+        .bind(DELIVERY_PENDING)
+        .bind(now)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?
+    });
+
+    Ok(deliveries)
+}
+
+/// Claims a due delivery for one attempt by counting the attempt and pushing
+/// its next attempt to `lease_until`. Returns false if another worker claimed
+/// it first. Should the attempt never finish, the delivery becomes due again
+/// once the lease expires.
+#[tracing::instrument(skip_all, fields(delivery_id = %delivery.id))]
+pub async fn claim_webhook_delivery(
+    pool: &DbPool,
+    delivery: &WebhookDelivery,
+    lease_until: DateTime<Utc>,
+) -> Result<bool> {
+    let rows_affected = with_pool!(pool, |pool| {
+        sqlx::query(
+            r#"
+            UPDATE webhook_deliveries
+            SET attempts = attempts + 1, next_attempt_at = $3
+            WHERE id = $1 AND attempts = $2 AND status = $4
+            "#,
+        )
+        .bind(&delivery.id)
+        .bind(delivery.attempts)
+        .bind(lease_until)
+        .bind(DELIVERY_PENDING)
+        .execute(pool)
+        .await?
+        .rows_affected()
+    });
+
+    Ok(rows_affected > 0)
+}
+
+/// Records the outcome of an attempt: `status`, plus when to try again for
+/// deliveries that stay pending.
+#[tracing::instrument(skip_all, fields(delivery_id = %delivery.id))]
+pub async fn update_webhook_delivery(pool: &DbPool, delivery: &WebhookDelivery) -> Result<()> {
+    with_pool!(pool, |pool| {
+        sqlx::query(
+            r#"
+            UPDATE webhook_deliveries
+            SET status = $2, next_attempt_at = $3, last_status_code = $4, last_error = $5, delivered_at = $6
+            WHERE id = $1
+            "#,
+        )
+        .bind(&delivery.id)
+        .bind(&delivery.status)
+        .bind(delivery.next_attempt_at)
+        .bind(delivery.last_status_code)
+        .bind(&delivery.last_error)
+        .bind(delivery.delivered_at)
+        .execute(pool)
+        .await?;
+    });
+
+    Ok(())
+}
+
+/// The delivery log of a webhook, newest first.
+#[tracing::instrument(skip(pool))]
+pub async fn get_webhook_deliveries(
+    pool: &DbPool,
+    webhook_id: &str,
+    status: Option<&str>,
+    limit: i64,
+) -> Result<Vec<WebhookDelivery>> {
+    let deliveries = with_pool!(pool, |pool| {
+        sqlx::query_as::<_, WebhookDelivery>(
+            r#"
+            SELECT id, webhook_id, event, file_id, payload, status, attempts, next_attempt_at, last_status_code, last_error, created_at, delivered_at
+            FROM webhook_deliveries
+            WHERE webhook_id = $1 AND ($2 IS NULL OR status = $2)
+            ORDER BY created_at DESC
+            LIMIT $3
+            "#,
+        )
+        .bind(webhook_id)
+        .bind(status)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?
+    });
+
+    Ok(deliveries)
+}
+
+/// Puts a failed delivery back in the outbox for another round of attempts.
+#[tracing::instrument(skip(pool))]
+pub async fn retry_webhook_delivery(pool: &DbPool, webhook_id: &str, id: &str) -> Result<bool> {
+    let rows_affected = with_pool!(pool, |pool| {
+        sqlx::query(
+            r#"
+            UPDATE webhook_deliveries
+            SET status = $3, attempts = 0, next_attempt_at = $4
+            WHERE id = $1 AND webhook_id = $2 AND status = $5
+            "#,
+        )
         .bind(id)
+        .bind(webhook_id)
+        .bind(DELIVERY_PENDING)
+        .bind(Utc::now())
+        .bind(DELIVERY_FAILED)
+        .execute(pool)
+        .await?
+        .rows_affected()
+    });
+
+    Ok(rows_affected > 0)
+}
+
+/// Jobs that are due: queued ones whose time has come, and running ones
+/// whose claim has expired because their worker went away.
+#[tracing::instrument(skip(pool))]
+pub async fn get_due_jobs(pool: &DbPool, now: DateTime<Utc>, limit: i64) -> Result<Vec<Job>> {
+    let jobs = with_pool!(pool, |pool| {
+        sqlx::query_as::<_, Job>(
+            r#"
+            SELECT id, kind, payload, status, attempts, run_at, last_error, created_at, updated_at, completed_at
+            FROM jobs
+            WHERE status IN ($1, $2) AND run_at <= $3
+            ORDER BY run_at
+            LIMIT $4
+            "#,
+        )
+        .bind(JOB_QUEUED)
+        .bind(JOB_RUNNING)
+        .bind(now)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?
+    });
+
+    Ok(jobs)
+}
+
+/// Claims a due job until `lease_until` and counts the attempt. Returns false
+/// when another worker claimed it first.
+#[tracing::instrument(skip_all, fields(job_id = %job.id))]
+pub async fn claim_job(pool: &DbPool, job: &Job, lease_until: DateTime<Utc>) -> Result<bool> {
+    let rows_affected = with_pool!(pool, |pool| {
+        sqlx::query(
+            r#"
+            UPDATE jobs
+            SET status = $3, attempts = attempts + 1, run_at = $4, updated_at = $5
+            WHERE id = $1 AND attempts = $2 AND status IN ($6, $3)
+            "#,
+        )
+        .bind(&job.id)
+        .bind(job.attempts)
+        .bind(JOB_RUNNING)
+        .bind(lease_until)
+        .bind(Utc::now())
+        .bind(JOB_QUEUED)
+        .execute(pool)
+        .await?
+        .rows_affected()
+    });
+
+    Ok(rows_affected > 0)
+}
+
+/// Records the outcome of an attempt: the job's status, next run and error.
+#[tracing::instrument(skip_all, fields(job_id = %job.id))]
+pub async fn update_job(pool: &DbPool, job: &Job) -> Result<()> {
+    with_pool!(pool, |pool| {
+        sqlx::query(
+            r#"
+            UPDATE jobs
+            SET status = $2, run_at = $3, last_error = $4, updated_at = $5, completed_at = $6
+            WHERE id = $1
+            "#,
+        )
+        .bind(&job.id)
+        .bind(&job.status)
+        .bind(job.run_at)
+        .bind(&job.last_error)
+        .bind(job.updated_at)
+        .bind(job.completed_at)
         .execute(pool)
         .await?;
+    });
+
+    Ok(())
+}
+
+/// Queues running jobs again right away, for jobs a worker was interrupted
+/// in at shutdown.
+#[tracing::instrument(skip_all)]
+pub async fn release_jobs(pool: &DbPool, ids: &[String]) -> Result<()> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+
+    with_pool!(pool, |pool| {
+        let mut query = QueryBuilder::new("UPDATE jobs SET status = ");
+        query
+            .push_bind(JOB_QUEUED)
+            .push(", run_at = ")
+            .push_bind(Utc::now())
+            .push(" WHERE status = ")
+            .push_bind(JOB_RUNNING)
+            .push(" AND id IN (");
+        let mut separated = query.separated(", ");
+        for id in ids {
+            separated.push_bind(id);
+        }
+        separated.push_unseparated(")");
+        query.build().execute(pool).await?;
+    });
+
+    Ok(())
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn get_job(pool: &DbPool, id: &str) -> Result<Option<Job>> {
+    let job = with_pool!(pool, |pool| {
+        sqlx::query_as::<_, Job>(
+            r#"
+            SELECT id, kind, payload, status, attempts, run_at, last_error, created_at, updated_at, completed_at
+            FROM jobs
+            WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(pool)
+        .await?
+    });
+
+    Ok(job)
+}
+
+/// Jobs matching `filter`, newest first.
+#[tracing::instrument(skip(pool))]
+pub async fn get_jobs(pool: &DbPool, filter: &JobFilter, limit: i64) -> Result<Vec<Job>> {
+    let jobs = with_pool!(pool, |pool| {
+        let mut query = QueryBuilder::new(
+            "SELECT id, kind, payload, status, attempts, run_at, last_error, created_at, updated_at, completed_at FROM jobs WHERE 1 = 1",
+        );
+
+        if let Some(status) = &filter.status {
+            query.push(" AND status = ").push_bind(status);
+        }
+        if let Some(kind) = &filter.kind {
+            query.push(" AND kind = ").push_bind(kind);
+        }
+
+        query
+            .push(" ORDER BY created_at DESC LIMIT ")
+            .push_bind(limit);
+
+        query.build_query_as::<Job>().fetch_all(pool).await?
+    });
+
+    Ok(jobs)
+}
+
+/// Queues a dead job again with a fresh set of attempts.
+#[tracing::instrument(skip(pool))]
+pub async fn retry_job(pool: &DbPool, id: &str) -> Result<bool> {
+    let now = Utc::now();
+    let rows_affected = with_pool!(pool, |pool| {
+        sqlx::query(
+            r#"
+            UPDATE jobs
+            SET status = $2, attempts = 0, run_at = $3, updated_at = $3
+            WHERE id = $1 AND status = $4
+            "#,
+        )
+        .bind(id)
+        .bind(JOB_QUEUED)
+        .bind(now)
+        .bind(JOB_DEAD)
+        .execute(pool)
+        .await?
+        .rows_affected()
+    });
+
+    Ok(rows_affected > 0)
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn delete_completed_jobs_before(pool: &DbPool, cutoff: DateTime<Utc>) -> Result<u64> {
+    let rows_affected = with_pool!(pool, |pool| {
+        sqlx::query("DELETE FROM jobs WHERE status = $1 AND completed_at < $2")
+            .bind(JOB_COMPLETED)
+            .bind(cutoff)
+            .execute(pool)
+            .await?
+            .rows_affected()
+    });
+
+    Ok(rows_affected)
+}
+
+/// Number of jobs in each status.
+#[tracing::instrument(skip(pool))]
+pub async fn count_jobs_by_status(pool: &DbPool) -> Result<Vec<(String, i64)>> {
+    let counts = with_pool!(pool, |pool| {
+        sqlx::query_as::<_, (String, i64)>("SELECT status, COUNT(*) FROM jobs GROUP BY status")
+            .fetch_all(pool)
+            .await?
+    });
 
-    Ok(result.rows_affected() > 0)
+    Ok(counts)
 }
@@ -0,0 +1,74 @@
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::models::{FileResponse, WebhookEvent};
+
+/// Events a subscriber can fall behind by before it is told to resync.
+const CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileChange {
+    Created,
+    /// New content, a new name or folder, or a new processing status.
+    Updated,
+    Deleted,
+}
+
+impl FileChange {
+    /// The SSE event name.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FileChange::Created => "created",
+            FileChange::Updated => "updated",
+            FileChange::Deleted => "deleted",
+        }
+    }
+}
+
+impl From<WebhookEvent> for FileChange {
+    fn from(event: WebhookEvent) -> Self {
+        match event {
+            WebhookEvent::Upload => FileChange::Created,
+            WebhookEvent::Version | WebhookEvent::Move => FileChange::Updated,
+            WebhookEvent::Delete => FileChange::Deleted,
+        }
+    }
+}
+
+/// A change to a listed file, as pushed to `/events` subscribers.
+#[derive(Debug)]
+pub struct FileEvent {
+    pub change: FileChange,
+    pub file: FileResponse,
+}
+
+/// In-process feed of file changes. Only changes made through this replica
+/// are seen by its subscribers.
+#[derive(Debug, Clone)]
+pub struct FileEvents {
+    sender: broadcast::Sender<Arc<FileEvent>>,
+    shutdown: CancellationToken,
+}
+
+impl FileEvents {
+    /// Subscriptions end once `shutdown` is cancelled, so that they do not
+    /// hold up the graceful shutdown.
+    pub fn new(shutdown: CancellationToken) -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        Self { sender, shutdown }
+    }
+
+    pub fn publish(&self, event: FileEvent) {
+        // Sending only fails when nobody is subscribed.
+        let _ = self.sender.send(Arc::new(event));
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<FileEvent>> {
+        self.sender.subscribe()
+    }
+
+    pub fn shutdown(&self) -> &CancellationToken {
+        &self.shutdown
+    }
+}
@@ -0,0 +1,179 @@
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    database::get_audit_events,
+    models::AuditEventFilter,
+    ops::{
+        fsck,
+        migrate_storage::{self, MigrationOptions, MigrationProgress},
+    },
+};
+
+use super::upload::AppState;
+
+#[derive(Deserialize)]
+pub struct AuditEventsQuery {
+    action: Option<String>,
+    outcome: Option<String>,
+    file_id: Option<String>,
+    actor: Option<String>,
+    ip_address: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    limit: Option<i64>,
+    format: Option<String>,
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn list_audit_events(
+    Query(params): Query<AuditEventsQuery>,
+    State(state): State<AppState>,
+) -> Result<Response, (StatusCode, Json<Value>)> {
+    let limit = params.limit.unwrap_or(100).min(10_000);
+    let filter = AuditEventFilter {
+        action: params.action,
+        outcome: params.outcome,
+        file_id: params.file_id,
+        actor: params.actor,
+        ip_address: params.ip_address,
+        since: params.since,
+        until: params.until,
+    };
+
+    let events = get_audit_events(&state.db, &filter, limit)
+        .await
+        .map_err(|e| {
+            tracing::error!("Database error: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "Failed to fetch audit events"})),
+            )
+        })?;
+
+    match params.format.as_deref() {
+        None | Some("json") => Ok(Json(events).into_response()),
+        Some("csv") => {
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            for event in &events {
+                writer.serialize(event).map_err(|e| {
+                    tracing::error!("Failed to encode audit event: {}", e);
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Json(json!({"error": "Failed to export audit events"})),
+                    )
+                })?;
+            }
+            let body = writer.into_inner().map_err(|e| {
+                tracing::error!("Failed to encode audit events: {}", e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({"error": "Failed to export audit events"})),
+                )
+            })?;
+
+            Ok((
+                [
+                    (header::CONTENT_TYPE, "text/csv"),
+                    (
+                        header::CONTENT_DISPOSITION,
+                        "attachment; filename=\"audit-events.csv\"",
+                    ),
+                ],
+                body,
+            )
+                .into_response())
+        }
+        Some(_) => Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "Unsupported format, expected json or csv"})),
+        )),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct FsckQuery {
+    repair: Option<bool>,
+}
+
+/// Checks every configured backend and returns one report per backend.
+#[tracing::instrument(skip_all)]
+pub async fn run_fsck(
+    Query(params): Query<FsckQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<fsck::FsckReport>>, (StatusCode, Json<Value>)> {
+    let mut reports = Vec::new();
+    for storage in state.storage.backends() {
+        let report = fsck::run(&state.db, storage, params.repair.unwrap_or(false))
+            .await
+            .map_err(|e| {
+                tracing::error!("fsck failed: {}", e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({"error": "Consistency check failed"})),
+                )
+            })?;
+        reports.push(report);
+    }
+
+    Ok(Json(reports))
+}
+
+/// Starts a storage migration in the background. Progress is reported by
+/// `GET /admin/storage-migrations`.
+#[tracing::instrument(skip_all, fields(from = %options.from, to = %options.to))]
+pub async fn start_storage_migration(
+    State(state): State<AppState>,
+    Json(options): Json<MigrationOptions>,
+) -> Result<(StatusCode, Json<MigrationProgress>), (StatusCode, Json<Value>)> {
+    if options.from == options.to {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "Source and target storage must differ"})),
+        ));
+    }
+
+    let backend = |name: &str| {
+        state.storage.require(name).cloned().map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": e.to_string()})),
+            )
+        })
+    };
+    let source = backend(&options.from)?;
+    let target = backend(&options.to)?;
+
+    let tracker = state.storage_migration.clone();
+    tracker
+        .start(&options)
+        .map_err(|e| (StatusCode::CONFLICT, Json(json!({"error": e.to_string()}))))?;
+    let progress = tracker.snapshot().expect("migration progress is set");
+
+    tokio::spawn(async move {
+        if let Err(e) = migrate_storage::run(&state.db, &source, &target, &options, &tracker).await
+        {
+            tracing::error!("Storage migration failed: {}", e);
+        }
+    });
+
+    Ok((StatusCode::ACCEPTED, Json(progress)))
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn get_storage_migration(
+    State(state): State<AppState>,
+) -> Result<Json<MigrationProgress>, (StatusCode, Json<Value>)> {
+    state.storage_migration.snapshot().map(Json).ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "No storage migration has been started"})),
+        )
+    })
+}
@@ -0,0 +1,224 @@
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    archive::{self, ArchiveFormat},
+    audit::{self, AuditContext},
+    database::{get_files, get_files_by_ids, get_files_under_folder, get_folder},
+    metrics,
+    models::{normalize_folder, AuditAction, File, FileFilter, FileStatusResponse},
+};
+
+use super::upload::AppState;
+
+/// Files one archive can hold.
+const MAX_ARCHIVE_FILES: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct ArchiveRequest {
+    ids: Option<Vec<String>>,
+    folder: Option<String>,
+    tag: Option<String>,
+    /// `zip` (the default) or `tar.gz`.
+    format: Option<String>,
+}
+
+/// Streams an archive of the given files, of every file in a folder and the
+/// folders below it, or of every file with a tag. Only files that can be
+/// downloaded are included; listing one that cannot is an error.
+#[tracing::instrument(skip_all)]
+pub async fn create_archive(
+    State(state): State<AppState>,
+    audit_context: AuditContext,
+    Json(request): Json<ArchiveRequest>,
+) -> Result<Response, (StatusCode, Json<Value>)> {
+    let format = match request.format.as_deref() {
+        None => ArchiveFormat::Zip,
+        Some(name) => ArchiveFormat::parse(name)
+            .ok_or_else(|| bad_request("Unsupported format, expected zip or tar.gz".to_string()))?,
+    };
+
+    let (name, files, base) = match (request.ids, request.folder, request.tag) {
+        (Some(ids), None, None) => ("files".to_string(), select_ids(&state, ids).await?, None),
+        (None, Some(folder), None) => {
+            let folder = normalize_folder(&folder)
+                .ok_or_else(|| bad_request("Invalid folder".to_string()))?;
+            let files = select_folder(&state, &folder).await?;
+            let name = match folder.rsplit('/').next() {
+                Some("") | None => "files".to_string(),
+                Some(name) => name.to_string(),
+            };
+            (name, files, Some(folder))
+        }
+        (None, None, Some(tag)) => {
+            let files = select_tag(&state, &tag).await?;
+            (tag, files, None)
+        }
+        _ => {
+            return Err(bad_request(
+                "Give exactly one of ids, folder or tag".to_string(),
+            ))
+        }
+    };
+
+    if files.is_empty() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "No files to archive"})),
+        ));
+    }
+    if files.len() > MAX_ARCHIVE_FILES {
+        return Err(bad_request(format!(
+            "An archive can hold at most {} files",
+            MAX_ARCHIVE_FILES
+        )));
+    }
+
+    let mut size = 0;
+    for file in &files {
+        size += file.size as u64;
+        audit::record(
+            &state.db,
+            &audit_context,
+            AuditAction::Download,
+            StatusCode::OK,
+            Some(&file.id),
+        )
+        .await;
+    }
+    metrics::record_download(size);
+
+    let entries = archive::entry_paths(files, base.as_deref());
+    let body = archive::stream(
+        state.storage.clone(),
+        entries,
+        format,
+        state.config.compressible_types_vec(),
+    );
+    let disposition = format!(
+        "attachment; filename=\"{}.{}\"",
+        attachment_name(&name),
+        format.extension()
+    );
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        Body::from_stream(body),
+    )
+        .into_response())
+}
+
+/// The listed files, in the order given. Each must exist and be ready.
+async fn select_ids(
+    state: &AppState,
+    mut ids: Vec<String>,
+) -> Result<Vec<File>, (StatusCode, Json<Value>)> {
+    let mut seen = std::collections::HashSet::new();
+    ids.retain(|id| seen.insert(id.clone()));
+    if ids.len() > MAX_ARCHIVE_FILES {
+        return Err(bad_request(format!(
+            "An archive can hold at most {} files",
+            MAX_ARCHIVE_FILES
+        )));
+    }
+
+    let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+    let mut found = get_files_by_ids(&state.db, &ids)
+        .await
+        .map_err(database_error)?;
+
+    let mut files = Vec::with_capacity(ids.len());
+    for id in ids {
+        let Some(index) = found.iter().position(|file| file.id == id) else {
+            return Err((
+                StatusCode::NOT_FOUND,
+                Json(json!({"error": "File not found", "id": id})),
+            ));
+        };
+        let file = found.swap_remove(index);
+        if !file.is_ready() {
+            return Err((
+                StatusCode::CONFLICT,
+                Json(json!({
+                    "error": "File is not ready",
+                    "status": FileStatusResponse::from(&file),
+                })),
+            ));
+        }
+        files.push(file);
+    }
+    Ok(files)
+}
+
+/// Ready files in `folder` and below, by folder.
+async fn select_folder(
+    state: &AppState,
+    folder: &str,
+) -> Result<Vec<File>, (StatusCode, Json<Value>)> {
+    if !folder.is_empty()
+        && get_folder(&state.db, folder)
+            .await
+            .map_err(database_error)?
+            .is_none()
+    {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "Folder not found"})),
+        ));
+    }
+
+    let files = get_files_under_folder(&state.db, folder)
+        .await
+        .map_err(database_error)?;
+    Ok(files
+        .into_iter()
+        .filter(|file| !file.is_private && !file.is_broken && file.is_ready())
+        .collect())
+}
+
+/// Ready files with `tag`, newest first.
+async fn select_tag(state: &AppState, tag: &str) -> Result<Vec<File>, (StatusCode, Json<Value>)> {
+    let filter = FileFilter {
+        tag: Some(tag.to_string()),
+        ..Default::default()
+    };
+    // One more than fits, so that too large a selection is reported.
+    let files = get_files(&state.db, &filter, MAX_ARCHIVE_FILES as i64 + 1)
+        .await
+        .map_err(database_error)?;
+    Ok(files.into_iter().filter(File::is_ready).collect())
+}
+
+/// `name` reduced to characters that are safe in a quoted header value.
+fn attachment_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ' ') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn bad_request(message: String) -> (StatusCode, Json<Value>) {
+    (StatusCode::BAD_REQUEST, Json(json!({"error": message})))
+}
+
+fn database_error(e: anyhow::Error) -> (StatusCode, Json<Value>) {
+    tracing::error!("Database error: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({"error": "Database error"})),
+    )
+}
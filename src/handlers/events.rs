@@ -0,0 +1,121 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Json,
+    },
+};
+use futures::{Stream, StreamExt};
+use serde_json::Value;
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    database::get_file_by_id,
+    events::{FileChange, FileEvent},
+    models::FileStatusResponse,
+};
+
+use super::{files::get_visible_file, upload::AppState};
+
+/// Streams file changes as server-sent events: `created`, `updated` and
+/// `deleted`, each carrying the file as listed by `/files/uploads`. A
+/// `resync` event means events were missed and the list should be fetched
+/// again.
+pub async fn file_events(
+    State(state): State<AppState>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let receiver = state.events.subscribe();
+    let stream = futures::stream::unfold(receiver, |mut receiver| async move {
+        let event = match receiver.recv().await {
+            Ok(event) => Event::default()
+                .event(event.change.as_str())
+                .json_data(&event.file),
+            Err(RecvError::Lagged(missed)) => {
+                Ok(Event::default().event("resync").data(missed.to_string()))
+            }
+            Err(RecvError::Closed) => return None,
+        };
+        Some((event, receiver))
+    })
+    .take_until(state.events.shutdown().clone().cancelled_owned());
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// Streams the processing status of one file as `status` events, starting
+/// with the current one and ending once it is `ready` or `failed`. A
+/// `deleted` event ends the stream if the file is deleted first.
+#[tracing::instrument(skip_all, fields(file_id = %id))]
+pub async fn file_status_events(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, (StatusCode, Json<Value>)> {
+    // Subscribe before reading the status so that no change is missed.
+    let receiver = state.events.subscribe();
+    let file = get_visible_file(&state, &id).await?;
+    let initial = FileStatusResponse::from(&file);
+
+    let shutdown = state.events.shutdown().clone();
+    let watch = StatusWatch {
+        done: initial.is_final(),
+        last: (initial.status.clone(), initial.job_id.clone()),
+        state,
+        id,
+        receiver,
+    };
+    let stream =
+        futures::stream::once(async move { Event::default().event("status").json_data(&initial) })
+            .chain(futures::stream::unfold(watch, StatusWatch::next))
+            .take_until(shutdown.cancelled_owned());
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+struct StatusWatch {
+    state: AppState,
+    id: String,
+    receiver: broadcast::Receiver<Arc<FileEvent>>,
+    /// Status and job of the last event sent.
+    last: (String, Option<String>),
+    done: bool,
+}
+
+impl StatusWatch {
+    async fn next(mut self) -> Option<(Result<Event, axum::Error>, Self)> {
+        while !self.done {
+            match self.receiver.recv().await {
+                Ok(event) if event.file.id != self.id => continue,
+                Ok(event) if event.change == FileChange::Deleted => return Some(self.deleted()),
+                // A missed event may have been about this file, so check.
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return None,
+            }
+
+            let file = match get_file_by_id(&self.state.db, &self.id).await {
+                Ok(Some(file)) => file,
+                Ok(None) => return Some(self.deleted()),
+                Err(e) => {
+                    tracing::error!("Failed to fetch {}: {}", self.id, e);
+                    continue;
+                }
+            };
+            let status = FileStatusResponse::from(&file);
+            let current = (status.status.clone(), status.job_id.clone());
+            if current == self.last {
+                continue;
+            }
+            self.last = current;
+            self.done = status.is_final();
+            return Some((Event::default().event("status").json_data(&status), self));
+        }
+        None
+    }
+
+    fn deleted(mut self) -> (Result<Event, axum::Error>, Self) {
+        self.done = true;
+        let event = Event::default().event("deleted").data(&self.id);
+        (Ok(event), self)
+    }
+}
@@ -0,0 +1,434 @@
+use async_compression::tokio::bufread::GzipDecoder;
+use async_zip::tokio::read::seek::ZipFileReader;
+use axum::{http::StatusCode, response::Json};
+use futures::StreamExt;
+use serde_json::{json, Value};
+use std::{collections::HashSet, path::PathBuf, sync::atomic::Ordering};
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio_util::{compat::FuturesAsyncReadCompatExt, io::ReaderStream};
+use uuid::Uuid;
+
+use crate::{
+    archive::{entry_path, ArchiveFormat},
+    audit::{self, AuditContext},
+    database::{create_file, create_folder},
+    metrics,
+    models::{
+        file::{ENTRY_CREATED, ENTRY_FAILED, ENTRY_FOLDER, ENTRY_SKIPPED},
+        join_folder, parent_folder, AuditAction, ExtractResponse, ExtractedEntry, FileLabels,
+        FileResponse, Folder, WebhookEvent,
+    },
+    storage::{self, ByteStream},
+};
+
+use super::upload::{metadata_error, read_error, store_data, AppState};
+
+/// Entries, files and folders alike, extracted from one archive.
+const MAX_ENTRIES: usize = 1000;
+/// How many times its own size an archive may expand to.
+const MAX_RATIO: u64 = 100;
+
+/// Mask and value of the file type bits of a Unix mode for symbolic links.
+const S_IFMT: u16 = 0o170000;
+const S_IFLNK: u16 = 0o120000;
+
+/// An uploaded archive, kept in a temporary file while it is extracted and
+/// removed once dropped.
+pub(crate) struct SpooledArchive {
+    name: String,
+    format: ArchiveFormat,
+    path: PathBuf,
+    size: u64,
+}
+
+impl SpooledArchive {
+    /// Writes `data` to a temporary file, enforcing the maximum file size.
+    pub async fn receive(
+        state: &AppState,
+        name: String,
+        data: ByteStream<'_>,
+    ) -> Result<Self, (StatusCode, Json<Value>)> {
+        let format = ArchiveFormat::from_file_name(&name).ok_or_else(|| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Only .zip and .tar.gz archives can be extracted"})),
+            )
+        })?;
+        let id = Uuid::new_v7(uuid::timestamp::Timestamp::now(uuid::NoContext));
+        let mut archive = Self {
+            path: std::env::temp_dir().join(format!("file-server-{}.{}", id, format.extension())),
+            name,
+            format,
+            size: 0,
+        };
+
+        let (mut data, bytes_read) = storage::limit_stream(data, state.config.max_file_size);
+        let result: std::io::Result<()> = async {
+            let mut file = tokio::fs::File::create(&archive.path).await?;
+            while let Some(chunk) = data.next().await {
+                file.write_all(&chunk?).await?;
+            }
+            file.flush().await
+        }
+        .await;
+
+        archive.size = bytes_read.load(Ordering::Relaxed);
+        if let Err(e) = result {
+            let too_large = archive.size > state.config.max_file_size;
+            if !too_large {
+                tracing::warn!("Failed to receive archive {}: {}", archive.name, e);
+            }
+            return Err(read_error(too_large));
+        }
+        Ok(archive)
+    }
+}
+
+impl Drop for SpooledArchive {
+    /// Removes the file on the blocking pool when dropped on the runtime,
+    /// whose worker threads must not wait on the file system.
+    fn drop(&mut self) {
+        let path = std::mem::take(&mut self.path);
+        let remove = move || {
+            if let Err(e) = std::fs::remove_file(&path) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    tracing::warn!(
+                        "Failed to remove received archive {}: {}",
+                        path.display(),
+                        e
+                    );
+                }
+            }
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(remove)),
+            Err(_) => remove(),
+        }
+    }
+}
+
+/// Creates one file per entry of `archive`, in folders following the
+/// entries' paths, each with `labels` and audited as an upload. Unsafe paths
+/// and links are skipped. Extraction stops once the archive expands beyond
+/// the maximum file size or `MAX_RATIO` times its own size, or has more than
+/// `MAX_ENTRIES` entries; files created until then are kept.
+pub(crate) async fn extract_archive(
+    state: &AppState,
+    audit_context: &AuditContext,
+    archive: SpooledArchive,
+    labels: FileLabels,
+) -> Result<ExtractResponse, (StatusCode, Json<Value>)> {
+    let limit = state
+        .config
+        .max_file_size
+        .min(archive.size.saturating_mul(MAX_RATIO));
+    let mut extraction = Extraction {
+        state,
+        audit_context,
+        labels: &labels,
+        limit,
+        remaining: limit,
+        entries: Vec::new(),
+        folders: HashSet::new(),
+    };
+
+    let file = tokio::fs::File::open(&archive.path).await.map_err(|e| {
+        tracing::error!("Failed to open received archive: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": "Failed to extract archive"})),
+        )
+    })?;
+    let result = match archive.format {
+        ArchiveFormat::Zip => extract_zip(&mut extraction, file).await,
+        ArchiveFormat::TarGz => extract_tar_gz(&mut extraction, file).await,
+    };
+
+    let entries = extraction.entries;
+    let created = entries
+        .iter()
+        .filter(|entry| entry.status == ENTRY_CREATED)
+        .count();
+    if created > 0 {
+        state.jobs.wake();
+    }
+    // An archive that could not be read at all is rejected as a whole.
+    if let Err(error) = &result {
+        if entries.is_empty() {
+            return Err((StatusCode::BAD_REQUEST, Json(json!({"error": error}))));
+        }
+    }
+
+    Ok(ExtractResponse {
+        archive: archive.name.clone(),
+        created,
+        entries,
+        error: result.err(),
+    })
+}
+
+async fn extract_zip(extraction: &mut Extraction<'_>, file: tokio::fs::File) -> Result<(), String> {
+    let mut zip = ZipFileReader::with_tokio(BufReader::new(file))
+        .await
+        .map_err(invalid_archive)?;
+
+    // Sizes in the directory can be checked up front; the actual sizes are
+    // enforced while reading.
+    let declared = zip.file().entries().iter().fold(0u64, |total, entry| {
+        total.saturating_add(entry.uncompressed_size())
+    });
+    if declared > extraction.remaining {
+        return Err(extraction.too_large());
+    }
+
+    for index in 0..zip.file().entries().len() {
+        extraction.admit()?;
+
+        let entry = &zip.file().entries()[index];
+        let Ok(raw) = entry.filename().as_str().map(str::to_string) else {
+            let raw = String::from_utf8_lossy(entry.filename().as_bytes()).into_owned();
+            extraction.skip(&raw, "Entry name is not UTF-8");
+            continue;
+        };
+        let is_link = entry
+            .unix_permissions()
+            .is_some_and(|mode| mode & S_IFMT == S_IFLNK);
+        let size = entry.uncompressed_size();
+
+        if raw.ends_with('/') {
+            extraction.add_folder(&raw).await;
+        } else if is_link {
+            extraction.skip(&raw, "Links are not extracted");
+        } else {
+            let reader = zip
+                .reader_without_entry(index)
+                .await
+                .map_err(invalid_archive)?;
+            let data = ReaderStream::new(reader.compat()).boxed();
+            extraction.add_file(&raw, data, Some(size)).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn extract_tar_gz(
+    extraction: &mut Extraction<'_>,
+    file: tokio::fs::File,
+) -> Result<(), String> {
+    let mut archive = tokio_tar::Archive::new(GzipDecoder::new(BufReader::new(file)));
+    let mut entries = archive.entries().map_err(invalid_archive)?;
+
+    while let Some(entry) = entries.next().await {
+        let mut entry = entry.map_err(invalid_archive)?;
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_pax_global_extensions() {
+            continue;
+        }
+        extraction.admit()?;
+
+        let raw = match entry.path() {
+            Ok(path) => path.to_string_lossy().into_owned(),
+            Err(e) => return Err(invalid_archive(e)),
+        };
+        if entry_type.is_dir() {
+            extraction.add_folder(&raw).await;
+        } else if entry_type.is_symlink() || entry_type.is_hard_link() {
+            extraction.skip(&raw, "Links are not extracted");
+        } else if entry_type.is_file() || entry_type.is_contiguous() {
+            let data = ReaderStream::new(&mut entry).boxed();
+            extraction.add_file(&raw, data, None).await?;
+        } else {
+            extraction.skip(&raw, "Unsupported entry type");
+        }
+    }
+    Ok(())
+}
+
+fn invalid_archive(e: impl std::fmt::Display) -> String {
+    format!("Invalid archive: {}", e)
+}
+
+struct Extraction<'a> {
+    state: &'a AppState,
+    audit_context: &'a AuditContext,
+    labels: &'a FileLabels,
+    /// Bytes the whole archive may expand to.
+    limit: u64,
+    /// Bytes the rest of the archive may expand to.
+    remaining: u64,
+    entries: Vec<ExtractedEntry>,
+    /// Folders created or found to exist so far.
+    folders: HashSet<String>,
+}
+
+impl Extraction<'_> {
+    fn admit(&self) -> Result<(), String> {
+        if self.entries.len() >= MAX_ENTRIES {
+            return Err(format!(
+                "Archives are extracted up to {} entries",
+                MAX_ENTRIES
+            ));
+        }
+        Ok(())
+    }
+
+    fn too_large(&self) -> String {
+        format!("Archive expands beyond the limit of {} bytes", self.limit)
+    }
+
+    fn record(
+        &mut self,
+        path: &str,
+        status: &str,
+        file: Option<FileResponse>,
+        error: Option<String>,
+    ) {
+        self.entries.push(ExtractedEntry {
+            path: path.to_string(),
+            status: status.to_string(),
+            file,
+            error,
+        });
+    }
+
+    fn skip(&mut self, path: &str, reason: &str) {
+        self.record(path, ENTRY_SKIPPED, None, Some(reason.to_string()));
+    }
+
+    async fn add_folder(&mut self, raw: &str) {
+        match entry_path(raw) {
+            // The archive's root.
+            Some(path) if path.is_empty() => {}
+            Some(path) => match self.ensure_folder(&path).await {
+                Ok(()) => self.record(raw, ENTRY_FOLDER, None, None),
+                Err(e) => {
+                    tracing::error!("Failed to create folder {}: {}", path, e);
+                    self.record(
+                        raw,
+                        ENTRY_FAILED,
+                        None,
+                        Some("Failed to create folder".to_string()),
+                    );
+                }
+            },
+            None => self.skip(raw, "Unsafe path"),
+        }
+    }
+
+    /// Stores one entry as a file. Fails once the archive expands beyond
+    /// its limit, or the entry beyond the `declared` size listed for it.
+    async fn add_file(
+        &mut self,
+        raw: &str,
+        data: ByteStream<'_>,
+        declared: Option<u64>,
+    ) -> Result<(), String> {
+        let Some(path) = entry_path(raw).filter(|path| !path.is_empty()) else {
+            self.skip(raw, "Unsafe path");
+            return Ok(());
+        };
+        let folder = parent_folder(&path).to_string();
+        let name = path.rsplit('/').next().unwrap_or_default().to_string();
+
+        if let Err(e) = self.ensure_folder(&folder).await {
+            tracing::error!("Failed to create folder {}: {}", folder, e);
+            self.record(
+                raw,
+                ENTRY_FAILED,
+                None,
+                Some("Failed to create folder".to_string()),
+            );
+            return Ok(());
+        }
+
+        let declared = declared.unwrap_or(u64::MAX);
+        let (data, bytes_read) = storage::limit_stream(data, self.remaining.min(declared));
+        let result = self.store(&folder, &name, data).await;
+        let read = bytes_read.load(Ordering::Relaxed);
+        let over_limit = read > self.remaining || read > declared;
+        self.remaining = self.remaining.saturating_sub(read);
+
+        let result = match result {
+            Err(_) if read > declared => Err((
+                StatusCode::BAD_REQUEST,
+                "Entry is larger than listed in the archive".to_string(),
+            )),
+            Err(_) if over_limit => Err((StatusCode::PAYLOAD_TOO_LARGE, self.too_large())),
+            Err((status, Json(body))) => Err((
+                status,
+                body["error"].as_str().unwrap_or_default().to_string(),
+            )),
+            Ok(file) => Ok(file),
+        };
+        match result {
+            Ok(file) => self.record(raw, ENTRY_CREATED, Some(file), None),
+            Err((status, error)) => {
+                audit::record(
+                    &self.state.db,
+                    self.audit_context,
+                    AuditAction::Upload,
+                    status,
+                    None,
+                )
+                .await;
+                self.record(raw, ENTRY_FAILED, None, Some(error.clone()));
+                if over_limit {
+                    return Err(error);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn store(
+        &self,
+        folder: &str,
+        name: &str,
+        data: ByteStream<'_>,
+    ) -> Result<FileResponse, (StatusCode, Json<Value>)> {
+        let state = self.state;
+        let upload = store_data(state, name, data, &self.audit_context.actor).await?;
+        let pending_upload = state
+            .pending_uploads
+            .track(upload.storage, &upload.blob.path);
+        let size = upload.size;
+
+        let mut file = upload.into_file(name.to_string());
+        file.folder = folder.to_string();
+        let file = create_file(&state.db, &file, self.labels)
+            .await
+            .map_err(metadata_error)?;
+
+        pending_upload.commit();
+        metrics::record_upload(size);
+        audit::record(
+            &state.db,
+            self.audit_context,
+            AuditAction::Upload,
+            StatusCode::OK,
+            Some(&file.id),
+        )
+        .await;
+        state
+            .file_changed(WebhookEvent::Upload, &file, self.labels.clone())
+            .await;
+
+        Ok(FileResponse {
+            labels: self.labels.clone(),
+            ..FileResponse::from(file)
+        })
+    }
+
+    /// Creates `folder` and the folders above it that were not seen yet.
+    async fn ensure_folder(&mut self, folder: &str) -> anyhow::Result<()> {
+        let mut path = String::new();
+        for segment in folder.split('/').filter(|segment| !segment.is_empty()) {
+            path = join_folder(&path, segment);
+            if !self.folders.contains(&path) {
+                create_folder(&self.state.db, &Folder::new(path.clone())).await?;
+                self.folders.insert(path.clone());
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,14 +1,22 @@
 use axum::{
+    body::Body,
     extract::{Path, Query, State},
     http::{header, HeaderMap, StatusCode},
-    response::{Json, Response},
+    response::{IntoResponse, Json, Response},
 };
 use serde::Deserialize;
 use serde_json::{json, Value};
 
 use crate::{
-    database::{delete_file_by_id, get_file_by_id, get_files},
-    models::FileResponse,
+    audit::{self, AuditContext},
+    database::{delete_file_by_id, get_file_by_id, get_file_labels, get_files},
+    metrics,
+    models::{
+        file::NotReady, AuditAction, File, FileFilter, FileResponse, FileStatusResponse,
+        WebhookEvent,
+    },
+    storage::{compression, Codec},
+    webhooks,
 };
 
 use super::upload::AppState;
@@ -16,13 +24,42 @@ use super::upload::AppState;
 #[derive(Deserialize)]
 pub struct FilesQuery {
     limit: Option<i64>,
+    tag: Option<String>,
+    /// `key=value`
+    metadata: Option<String>,
 }
 
+#[tracing::instrument(skip_all, fields(file_id = %id))]
 pub async fn get_file_by_id_handler(
     Path(id): Path<String>,
     State(state): State<AppState>,
+    audit_context: AuditContext,
+    request_headers: HeaderMap,
 ) -> Result<Response, (StatusCode, Json<Value>)> {
-    let file = get_file_by_id(&state.db, &id).await.map_err(|e| {
+    let result = serve_file(&id, &state, &request_headers).await;
+
+    let status = match &result {
+        Ok(response) => response.status(),
+        Err((status, _)) => *status,
+    };
+    audit::record(
+        &state.db,
+        &audit_context,
+        AuditAction::Download,
+        status,
+        Some(&id),
+    )
+    .await;
+
+    result
+}
+
+async fn serve_file(
+    id: &str,
+    state: &AppState,
+    request_headers: &HeaderMap,
+) -> Result<Response, (StatusCode, Json<Value>)> {
+    let file = get_file_by_id(&state.db, id).await.map_err(|e| {
         tracing::error!("Database error: {}", e);
         (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -44,59 +81,245 @@ pub async fn get_file_by_id_handler(
         ));
     }
 
-    let file_data = state.storage.get_file(&file.path).await.map_err(|e| {
-        tracing::error!("Failed to retrieve file: {}", e);
-        (
-            StatusCode::NOT_FOUND,
-            Json(json!({"error": "File not found"})),
-        )
-    })?;
+    if file.is_broken {
+        return Err((
+            StatusCode::GONE,
+            Json(json!({"error": "File content is missing"})),
+        ));
+    }
+
+    match file.check_ready() {
+        Ok(()) => {}
+        Err(NotReady::Failed) => {
+            return Err((
+                StatusCode::CONFLICT,
+                Json(json!({
+                    "error": "File processing failed",
+                    "status": FileStatusResponse::from(&file),
+                })),
+            ));
+        }
+        Err(NotReady::Processing) => {
+            return Ok((
+                StatusCode::ACCEPTED,
+                [(header::RETRY_AFTER, "1")],
+                Json(FileStatusResponse::from(&file)),
+            )
+                .into_response());
+        }
+    }
+
+    let storage = state.storage_for(&file.storage_type)?;
 
-    let content_type = state.storage.get_mime_type(&file.path);
+    // Compressed blobs are sent as they are stored when the client accepts
+    // the codec, and decompressed otherwise. Each variant has its own tag,
+    // and tags change with the content since the id can be reused.
+    let encoding = file
+        .codec()
+        .filter(|codec| accepts_encoding(request_headers, *codec));
+    let etag = match encoding {
+        Some(codec) => format!("{}-{}\"", file.etag().trim_end_matches('"'), codec),
+        None => file.etag(),
+    };
 
     let mut headers = HeaderMap::new();
+    headers.insert(header::CACHE_CONTROL, "no-cache".parse().unwrap());
+    headers.insert(header::ETAG, etag.parse().unwrap());
+    if file.codec().is_some() {
+        headers.insert(header::VARY, "accept-encoding".parse().unwrap());
+    }
+
+    if if_none_match(request_headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
+
+    let file_data = storage
+        .get_stream(&file.path, file.blob_key().as_ref())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to retrieve file: {}", e);
+            (
+                StatusCode::NOT_FOUND,
+                Json(json!({"error": "File not found"})),
+            )
+        })?;
+
+    let content_type = storage.get_mime_type(&file.path);
     headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+    headers.insert(header::ACCEPT_RANGES, "bytes".parse().unwrap());
+
+    let (file_data, content_length) = match (encoding, file.codec()) {
+        (Some(codec), _) => {
+            headers.insert(header::CONTENT_ENCODING, codec.as_str().parse().unwrap());
+            (file_data, file.compressed_size.unwrap_or(file.size))
+        }
+        (None, Some(codec)) => (compression::decompress(file_data, codec), file.size),
+        (None, None) => (file_data, file.size),
+    };
     headers.insert(
         header::CONTENT_LENGTH,
-        file.size.to_string().parse().unwrap(),
+        content_length.to_string().parse().unwrap(),
     );
-    headers.insert(
-        header::CACHE_CONTROL,
-        "public, max-age=31536000".parse().unwrap(),
-    );
-    headers.insert(header::ACCEPT_RANGES, "bytes".parse().unwrap());
-    headers.insert(header::ETAG, format!("\"{}\"", file.id).parse().unwrap());
 
-    Ok(Response::builder()
-        .status(StatusCode::OK)
-        .body(file_data.into())
-        .unwrap())
+    metrics::record_download(content_length as u64);
+
+    Ok((headers, Body::from_stream(file_data)).into_response())
+}
+
+/// Where processing of an upload stands. Downloads are served once the
+/// status is `ready`.
+#[tracing::instrument(skip_all, fields(file_id = %id))]
+pub async fn get_file_status(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Json<FileStatusResponse>, (StatusCode, Json<Value>)> {
+    let file = get_visible_file(&state, &id).await?;
+    Ok(Json(FileStatusResponse::from(&file)))
 }
 
+/// A file that is neither private nor broken, or a `404`.
+pub(crate) async fn get_visible_file(
+    state: &AppState,
+    id: &str,
+) -> Result<File, (StatusCode, Json<Value>)> {
+    let file = get_file_by_id(&state.db, id).await.map_err(|e| {
+        tracing::error!("Database error: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": "Database error"})),
+        )
+    })?;
+
+    file.filter(|file| !file.is_private && !file.is_broken)
+        .ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                Json(json!({"error": "File not found"})),
+            )
+        })
+}
+
+/// Whether the client's `If-None-Match` lists `etag`, or is `*`. Weak tags
+/// match too, as the comparison for `GET` is weak.
+fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Whether the client's `Accept-Encoding` allows `codec`, either by name or
+/// through `*`, with a non-zero quality.
+fn accepts_encoding(headers: &HeaderMap, codec: Codec) -> bool {
+    let Some(accept) = headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+
+    let quality = |name: &str| {
+        accept.split(',').find_map(|coding| {
+            let mut params = coding.split(';');
+            if !params.next()?.trim().eq_ignore_ascii_case(name) {
+                return None;
+            }
+            let quality = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some(quality)
+        })
+    };
+
+    quality(codec.as_str())
+        .or_else(|| quality("*"))
+        .is_some_and(|quality| quality > 0.0)
+}
+
+#[tracing::instrument(skip_all)]
 pub async fn list_files(
     Query(params): Query<FilesQuery>,
     State(state): State<AppState>,
 ) -> Result<Json<Vec<FileResponse>>, (StatusCode, Json<Value>)> {
     let limit = params.limit.unwrap_or(10).min(500);
 
-    let files = get_files(&state.db, limit).await.map_err(|e| {
+    let metadata = match params.metadata {
+        Some(metadata) => {
+            let (key, value) = metadata.split_once('=').ok_or_else(|| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"error": "metadata must be key=value"})),
+                )
+            })?;
+            Some((key.to_string(), value.to_string()))
+        }
+        None => None,
+    };
+    let filter = FileFilter {
+        tag: params.tag,
+        metadata,
+    };
+
+    let database_error = |e: anyhow::Error| {
         tracing::error!("Database error: {}", e);
         (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(json!({"error": "Failed to fetch files"})),
         )
-    })?;
+    };
 
-    let file_responses: Vec<FileResponse> = files.into_iter().map(FileResponse::from).collect();
+    let files = get_files(&state.db, &filter, limit)
+        .await
+        .map_err(database_error)?;
+    let ids: Vec<&str> = files.iter().map(|file| file.id.as_str()).collect();
+    let mut labels = get_file_labels(&state.db, &ids)
+        .await
+        .map_err(database_error)?;
+
+    let file_responses: Vec<FileResponse> = files
+        .into_iter()
+        .map(|file| {
+            let file_labels = labels.remove(&file.id).unwrap_or_default();
+            FileResponse {
+                labels: file_labels,
+                ..FileResponse::from(file)
+            }
+        })
+        .collect();
 
     Ok(Json(file_responses))
 }
 
+#[tracing::instrument(skip_all, fields(file_id = %id))]
 pub async fn delete_file(
     Path(id): Path<String>,
     State(state): State<AppState>,
+    audit_context: AuditContext,
 ) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
-    let file = get_file_by_id(&state.db, &id).await.map_err(|e| {
+    let result = remove_file(&id, &state).await;
+
+    let status = match &result {
+        Ok(_) => StatusCode::OK,
+        Err((status, _)) => *status,
+    };
+    audit::record(
+        &state.db,
+        &audit_context,
+        AuditAction::Delete,
+        status,
+        Some(&id),
+    )
+    .await;
+
+    result
+}
+
+async fn remove_file(id: &str, state: &AppState) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let file = get_file_by_id(&state.db, id).await.map_err(|e| {
         tracing::error!("Database error: {}", e);
         (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -118,7 +341,11 @@ pub async fn delete_file(
         ));
     }
 
-    state.storage.delete_file(&file.path).await.map_err(|e| {
+    let storage = state.storage_for(&file.storage_type)?;
+    // Labels go with the row, so fetch them while it still exists.
+    let labels = webhooks::fetch_labels(&state.db, &file).await;
+
+    storage.delete_file(&file.path).await.map_err(|e| {
         tracing::error!("Failed to delete file from storage: {}", e);
         (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -126,7 +353,7 @@ pub async fn delete_file(
         )
     })?;
 
-    let deleted = delete_file_by_id(&state.db, &id).await.map_err(|e| {
+    let deleted = delete_file_by_id(&state.db, id).await.map_err(|e| {
         tracing::error!("Database error: {}", e);
         (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -141,5 +368,57 @@ pub async fn delete_file(
         ));
     }
 
+    state
+        .file_changed(WebhookEvent::Delete, &file, labels)
+        .await;
+
     Ok(Json(json!({"message": "File deleted successfully"})))
 }
+
+/// Deletes each file's blob and row, auditing every deletion, and stops at
+/// the first failure.
+pub(crate) async fn delete_files(
+    state: &AppState,
+    audit_context: &AuditContext,
+    files: &[File],
+) -> Result<(), (StatusCode, Json<Value>)> {
+    for file in files {
+        let labels = webhooks::fetch_labels(&state.db, file).await;
+        let result = async {
+            let storage = state.storage_for(&file.storage_type)?;
+            storage.delete_file(&file.path).await.map_err(|e| {
+                tracing::error!("Failed to delete file from storage: {}", e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({"error": "Failed to delete file"})),
+                )
+            })?;
+            delete_file_by_id(&state.db, &file.id).await.map_err(|e| {
+                tracing::error!("Database error: {}", e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({"error": "Database error"})),
+                )
+            })
+        }
+        .await;
+
+        let status = match &result {
+            Ok(_) => StatusCode::OK,
+            Err((status, _)) => *status,
+        };
+        audit::record(
+            &state.db,
+            audit_context,
+            AuditAction::Delete,
+            status,
+            Some(&file.id),
+        )
+        .await;
+        if result? {
+            state.file_changed(WebhookEvent::Delete, file, labels).await;
+        }
+    }
+
+    Ok(())
+}
@@ -15,10 +15,10 @@ pub async fn serve_style_css() -> Result<Response, StatusCode> {
     let css_content =
         fs::read_to_string("static/style.css").map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    Ok(Response::builder()
+    Response::builder()
         .status(StatusCode::OK)
         .header(header::CONTENT_TYPE, "text/css")
         .header(header::CACHE_CONTROL, "public, max-age=3600")
         .body(css_content.into())
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 }
@@ -0,0 +1,86 @@
+use axum::{extract::State, http::StatusCode, response::Json};
+use serde_json::{json, Map, Value};
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use crate::database::ping;
+
+use super::upload::AppState;
+
+/// How long a probe may take before the check counts as failed.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub async fn liveness() -> Json<Value> {
+    Json(json!({"status": "ok"}))
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn readiness(State(state): State<AppState>) -> (StatusCode, Json<Value>) {
+    // Probes run concurrently, so a hung backend costs at most one timeout.
+    let backends = state.storage.backends();
+    let (database, checks) = futures::future::join(
+        run_check("database", ping(&state.db)),
+        futures::future::join_all(
+            backends
+                .iter()
+                .map(|backend| run_check(backend.name(), backend.check_ready())),
+        ),
+    )
+    .await;
+    let (database_ok, database) = database;
+
+    let mut storage_ok = true;
+    let mut storage = Map::new();
+    for (backend, (ok, check)) in backends.iter().zip(checks) {
+        storage_ok &= ok;
+        storage.insert(backend.name().to_string(), check);
+    }
+
+    let ready = database_ok && storage_ok;
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(json!({
+            "status": if ready { "ready" } else { "not_ready" },
+            "checks": {
+                "database": database,
+                "storage": storage,
+            },
+        })),
+    )
+}
+
+/// Runs one probe under [`CHECK_TIMEOUT`]. The endpoint is public, so
+/// errors are only logged and the response just says how the check failed.
+async fn run_check<F>(name: &str, check: F) -> (bool, Value)
+where
+    F: Future<Output = anyhow::Result<()>>,
+{
+    let start = Instant::now();
+    let result = tokio::time::timeout(CHECK_TIMEOUT, check).await;
+    let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    let status = match result {
+        Ok(Ok(())) => return (true, json!({"status": "ok", "latency_ms": latency_ms})),
+        Ok(Err(e)) => {
+            tracing::warn!("Readiness check {} failed: {:#}", name, e);
+            "error"
+        }
+        Err(_) => {
+            tracing::warn!(
+                "Readiness check {} timed out after {:?}",
+                name,
+                CHECK_TIMEOUT
+            );
+            "timeout"
+        }
+    };
+    (false, json!({"status": status, "latency_ms": latency_ms}))
+}
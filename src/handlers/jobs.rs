@@ -0,0 +1,89 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::Json,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    database::{get_job, get_jobs, retry_job},
+    models::{job::JOB_DEAD, JobFilter, JobResponse},
+};
+
+use super::upload::AppState;
+
+#[derive(Deserialize)]
+pub struct JobsQuery {
+    status: Option<String>,
+    kind: Option<String>,
+    limit: Option<i64>,
+}
+
+/// Background jobs, newest first.
+#[tracing::instrument(skip_all)]
+pub async fn list_jobs(
+    Query(params): Query<JobsQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<JobResponse>>, (StatusCode, Json<Value>)> {
+    let filter = JobFilter {
+        status: params.status,
+        kind: params.kind,
+    };
+    let limit = params.limit.unwrap_or(100).min(1000);
+    let jobs = get_jobs(&state.db, &filter, limit)
+        .await
+        .map_err(database_error)?;
+    Ok(Json(jobs.into_iter().map(JobResponse::from).collect()))
+}
+
+#[tracing::instrument(skip_all, fields(job_id = %id))]
+pub async fn get_job_handler(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Json<JobResponse>, (StatusCode, Json<Value>)> {
+    let job = get_job(&state.db, &id)
+        .await
+        .map_err(database_error)?
+        .ok_or_else(not_found)?;
+    Ok(Json(job.into()))
+}
+
+/// Queues a dead job again, with a fresh set of attempts.
+#[tracing::instrument(skip_all, fields(job_id = %id))]
+pub async fn retry_job_handler(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<StatusCode, (StatusCode, Json<Value>)> {
+    if !retry_job(&state.db, &id).await.map_err(database_error)? {
+        let job = get_job(&state.db, &id)
+            .await
+            .map_err(database_error)?
+            .ok_or_else(not_found)?;
+        return Err((
+            StatusCode::CONFLICT,
+            Json(json!({
+                "error": format!("Only {} jobs can be retried", JOB_DEAD),
+                "status": job.status,
+            })),
+        ));
+    }
+
+    state.jobs.wake();
+    Ok(StatusCode::ACCEPTED)
+}
+
+fn not_found() -> (StatusCode, Json<Value>) {
+    (
+        StatusCode::NOT_FOUND,
+        Json(json!({"error": "Job not found"})),
+    )
+}
+
+fn database_error(e: anyhow::Error) -> (StatusCode, Json<Value>) {
+    tracing::error!("Database error: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({"error": "Database error"})),
+    )
+}
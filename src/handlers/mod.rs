@@ -1,7 +1,25 @@
+pub mod admin;
+pub mod archive;
+pub mod events;
+pub mod extract;
 pub mod files;
 pub mod frontend;
+pub mod health;
+pub mod jobs;
+pub mod s3;
 pub mod upload;
+pub mod webdav;
+pub mod webhooks;
 
-pub use files::{delete_file, get_file_by_id_handler, list_files};
+pub use admin::{get_storage_migration, list_audit_events, run_fsck, start_storage_migration};
+pub use archive::create_archive;
+pub use events::{file_events, file_status_events};
+pub use files::{delete_file, get_file_by_id_handler, get_file_status, list_files};
 pub use frontend::{serve_style_css, serve_upload_page};
+pub use health::{liveness, readiness};
+pub use jobs::{get_job_handler, list_jobs, retry_job_handler};
 pub use upload::{upload_file, AppState};
+pub use webhooks::{
+    create_webhook_handler, delete_webhook_handler, list_webhook_deliveries, list_webhooks,
+    retry_webhook_delivery_handler,
+};
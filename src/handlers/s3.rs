@@ -0,0 +1,657 @@
+use axum::{
+    body::Body,
+    extract::{Path, Query, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Json, Response},
+    Extension,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use futures::{StreamExt, TryStreamExt};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use quick_xml::escape::escape;
+use serde_json::Value;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
+
+use crate::{
+    audit::{self, AuditContext},
+    database::{
+        create_folder, delete_folder_tree, get_files_by_name, get_files_under_folder,
+        get_folder_tree, get_subfolders,
+    },
+    metrics,
+    middleware::Payload,
+    models::{file::NotReady, join_folder, normalize_folder, AuditAction, File, Folder},
+    storage::slice_stream,
+};
+
+use super::{
+    files::delete_files,
+    upload::{store_named, AppState},
+};
+
+/// Where the S3 API is served. Clients use path-style requests with this
+/// as the endpoint, e.g. `--endpoint-url http://localhost:3000/s3`.
+pub const MOUNT: &str = "/s3";
+
+const XMLNS: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+
+const MAX_KEYS: usize = 1000;
+
+/// The MD5 of no content, which S3 returns for empty objects.
+const EMPTY_ETAG: &str = "\"d41d8cd98f00b204e9800998ecf8427e\"";
+
+/// Escaping for keys listed with `encoding-type=url`.
+const KEY_ENCODE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~')
+    .remove(b'/');
+
+/// An error in the XML format S3 clients expect.
+#[derive(Debug, Clone)]
+pub struct S3Error {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl S3Error {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn access_denied(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, "AccessDenied", message)
+    }
+
+    pub fn invalid_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "InvalidRequest", message)
+    }
+
+    pub fn signature_mismatch() -> Self {
+        Self::new(
+            StatusCode::FORBIDDEN,
+            "SignatureDoesNotMatch",
+            "The request signature does not match the one calculated by the server",
+        )
+    }
+
+    pub fn internal() -> Self {
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "InternalError",
+            "The server encountered an internal error",
+        )
+    }
+
+    fn invalid_argument(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "InvalidArgument", message)
+    }
+
+    fn no_such_key() -> Self {
+        Self::new(
+            StatusCode::NOT_FOUND,
+            "NoSuchKey",
+            "The specified key does not exist",
+        )
+    }
+
+    fn not_ready(reason: NotReady) -> Self {
+        match reason {
+            NotReady::Processing => Self::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "ServiceUnavailable",
+                "The object is still being processed",
+            ),
+            NotReady::Failed => Self::new(
+                StatusCode::FORBIDDEN,
+                "InvalidObjectState",
+                "Processing of the object failed",
+            ),
+        }
+    }
+
+    fn not_implemented() -> Self {
+        Self::new(
+            StatusCode::NOT_IMPLEMENTED,
+            "NotImplemented",
+            "Only PutObject, GetObject, HeadObject, DeleteObject and ListObjectsV2 are supported",
+        )
+    }
+}
+
+impl fmt::Display for S3Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl IntoResponse for S3Error {
+    fn into_response(self) -> Response {
+        let body = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?><Error><Code>{}</Code><Message>{}</Message></Error>"#,
+            self.code,
+            escape(self.message.as_str())
+        );
+        (
+            self.status,
+            [(header::CONTENT_TYPE, "application/xml")],
+            body,
+        )
+            .into_response()
+    }
+}
+
+/// Maps the errors of the shared upload and delete code.
+impl From<(StatusCode, Json<Value>)> for S3Error {
+    fn from((status, Json(body)): (StatusCode, Json<Value>)) -> Self {
+        let message = body["error"].as_str().unwrap_or_default().to_string();
+        match status {
+            StatusCode::BAD_REQUEST => Self::invalid_argument(message),
+            StatusCode::PAYLOAD_TOO_LARGE => Self::new(status, "EntityTooLarge", message),
+            StatusCode::NOT_FOUND => Self::no_such_key(),
+            _ => Self::new(status, "InternalError", message),
+        }
+    }
+}
+
+fn database_error(e: anyhow::Error) -> S3Error {
+    tracing::error!("Database error: {}", e);
+    S3Error::internal()
+}
+
+/// A key split into its folder and name. Keys ending in `/` are folder
+/// markers, which tools create for empty folders.
+struct ObjectKey {
+    folder: String,
+    name: String,
+    marker: bool,
+}
+
+impl ObjectKey {
+    fn path(&self) -> String {
+        join_folder(&self.folder, &self.name)
+    }
+}
+
+fn require_bucket(state: &AppState, bucket: &str) -> Result<(), S3Error> {
+    if bucket == state.config.s3_api_bucket {
+        Ok(())
+    } else {
+        Err(S3Error::new(
+            StatusCode::NOT_FOUND,
+            "NoSuchBucket",
+            "The specified bucket does not exist",
+        ))
+    }
+}
+
+fn object_key(state: &AppState, bucket: &str, key: &str) -> Result<ObjectKey, S3Error> {
+    require_bucket(state, bucket)?;
+
+    let path = normalize_folder(key)
+        .filter(|path| !path.is_empty())
+        .ok_or_else(|| S3Error::invalid_argument("Invalid object key"))?;
+    let (folder, name) = path.rsplit_once('/').unwrap_or(("", &path));
+
+    Ok(ObjectKey {
+        folder: folder.to_string(),
+        name: name.to_string(),
+        marker: key.ends_with('/'),
+    })
+}
+
+/// Sub-resources such as `?uploads` or `?tagging` select operations that are
+/// not supported. Signature and response override parameters are fine, as is
+/// the `x-id` operation name some SDKs append.
+fn reject_subresources(params: &HashMap<String, String>) -> Result<(), S3Error> {
+    let supported = |name: &str| {
+        name.to_ascii_lowercase().starts_with("x-amz-")
+            || name.starts_with("response-")
+            || name == "x-id"
+    };
+    if params.keys().all(|name| supported(name)) {
+        Ok(())
+    } else {
+        Err(S3Error::not_implemented())
+    }
+}
+
+/// The newest visible file stored under a key, if its content can be read.
+async fn find_object(state: &AppState, key: &ObjectKey) -> Result<File, S3Error> {
+    if key.marker {
+        return Err(S3Error::no_such_key());
+    }
+
+    let file = get_files_by_name(&state.db, &key.folder, &key.name)
+        .await
+        .map_err(database_error)?
+        .into_iter()
+        .next()
+        .ok_or_else(S3Error::no_such_key)?;
+    file.check_ready().map_err(S3Error::not_ready)?;
+    Ok(file)
+}
+
+fn object_headers(file: &File) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let header_values = [
+        (
+            header::CONTENT_TYPE,
+            mime_guess::from_path(&file.name)
+                .first_or_octet_stream()
+                .to_string(),
+        ),
+        (header::ETAG, file.etag()),
+        (
+            header::LAST_MODIFIED,
+            file.updated_at
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+                .to_string(),
+        ),
+        (header::ACCEPT_RANGES, "bytes".to_string()),
+    ];
+    for (name, value) in header_values {
+        if let Ok(value) = value.parse() {
+            headers.insert(name, value);
+        }
+    }
+    headers
+}
+
+/// Parses a single `bytes=` range into inclusive offsets. Lists of ranges
+/// are served in full, as S3 does.
+fn parse_range(value: &HeaderValue, size: u64) -> Result<Option<(u64, u64)>, S3Error> {
+    let invalid = || {
+        S3Error::new(
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            "InvalidRange",
+            "The requested range is not satisfiable",
+        )
+    };
+
+    let Some(range) = value
+        .to_str()
+        .ok()
+        .and_then(|value| value.strip_prefix("bytes="))
+    else {
+        return Ok(None);
+    };
+    if range.contains(',') {
+        return Ok(None);
+    }
+    let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+
+    let (start, end) = match (start.trim().parse::<u64>(), end.trim().parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+        (Ok(start), Err(_)) if end.trim().is_empty() => (start, size.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if start.trim().is_empty() && suffix > 0 => {
+            (size.saturating_sub(suffix), size.saturating_sub(1))
+        }
+        _ => return Err(invalid()),
+    };
+    if start >= size {
+        return Err(invalid());
+    }
+
+    Ok(Some((start, end)))
+}
+
+#[tracing::instrument(skip_all, fields(key = %key))]
+pub async fn get_object(
+    State(state): State<AppState>,
+    audit_context: AuditContext,
+    Path((bucket, key)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response, S3Error> {
+    reject_subresources(&params)?;
+    let file = find_object(&state, &object_key(&state, &bucket, &key)?).await?;
+    let size = file.size as u64;
+    let range = match headers.get(header::RANGE) {
+        Some(value) => parse_range(value, size)?,
+        None => None,
+    };
+
+    let result = async {
+        let storage = state.storage_for(&file.storage_type)?;
+        storage
+            .get_content(&file.path, file.blob_key().as_ref(), file.codec())
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to retrieve file: {}", e);
+                S3Error::internal()
+            })
+    }
+    .await;
+
+    let status = match (&result, range) {
+        (Ok(_), Some(_)) => StatusCode::PARTIAL_CONTENT,
+        (Ok(_), None) => StatusCode::OK,
+        (Err(e), _) => e.status,
+    };
+    audit::record(
+        &state.db,
+        &audit_context,
+        AuditAction::Download,
+        status,
+        Some(&file.id),
+    )
+    .await;
+    let data = result?;
+
+    let mut response_headers = object_headers(&file);
+    let (data, len) = match range {
+        Some((start, end)) => {
+            let content_range = format!("bytes {}-{}/{}", start, end, size);
+            if let Ok(value) = content_range.parse() {
+                response_headers.insert(header::CONTENT_RANGE, value);
+            }
+            (slice_stream(data, start, end - start + 1), end - start + 1)
+        }
+        None => (data, size),
+    };
+    response_headers.insert(header::CONTENT_LENGTH, len.into());
+
+    metrics::record_download(len);
+    Ok((status, response_headers, Body::from_stream(data)).into_response())
+}
+
+#[tracing::instrument(skip_all, fields(key = %key))]
+pub async fn head_object(
+    State(state): State<AppState>,
+    Path((bucket, key)): Path<(String, String)>,
+) -> Result<Response, S3Error> {
+    let file = find_object(&state, &object_key(&state, &bucket, &key)?).await?;
+
+    let mut headers = object_headers(&file);
+    headers.insert(header::CONTENT_LENGTH, (file.size as u64).into());
+    Ok((headers, Body::empty()).into_response())
+}
+
+/// Stores an object, replacing the content of the newest file of that name
+/// while keeping its id. Parent folders are created as needed.
+#[tracing::instrument(skip_all, fields(key = %key))]
+pub async fn put_object(
+    State(state): State<AppState>,
+    audit_context: AuditContext,
+    Path((bucket, key)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+    Extension(payload): Extension<Payload>,
+    request: Request,
+) -> Result<Response, S3Error> {
+    reject_subresources(&params)?;
+    if request.headers().contains_key("x-amz-copy-source") {
+        return Err(S3Error::not_implemented());
+    }
+    let key = object_key(&state, &bucket, &key)?;
+
+    if key.marker {
+        create_folders(&state, &key.path()).await?;
+        return Ok((StatusCode::OK, [(header::ETAG, EMPTY_ETAG)]).into_response());
+    }
+    create_folders(&state, &key.folder).await?;
+
+    let existing = get_files_by_name(&state.db, &key.folder, &key.name)
+        .await
+        .map_err(database_error)?
+        .into_iter()
+        .next();
+
+    let data = request
+        .into_body()
+        .into_data_stream()
+        .map_err(std::io::Error::other)
+        .boxed();
+    let (data, check) = payload.verify(data);
+    let file = store_named(
+        &state,
+        &audit_context,
+        &key.folder,
+        &key.name,
+        existing.as_ref(),
+        data,
+    )
+    .await
+    .map_err(|e| check.error().unwrap_or_else(|| e.into()))?;
+
+    Ok((StatusCode::OK, [(header::ETAG, file.etag())]).into_response())
+}
+
+/// Creates `path` and the folders above it.
+async fn create_folders(state: &AppState, path: &str) -> Result<(), S3Error> {
+    let mut folder = String::new();
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        folder = join_folder(&folder, segment);
+        create_folder(&state.db, &Folder::new(folder.clone()))
+            .await
+            .map_err(database_error)?;
+    }
+    Ok(())
+}
+
+/// Deletes every file stored under the key. A folder marker only removes
+/// the folder while it is empty. Missing keys are not an error.
+#[tracing::instrument(skip_all, fields(key = %key))]
+pub async fn delete_object(
+    State(state): State<AppState>,
+    audit_context: AuditContext,
+    Path((bucket, key)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, S3Error> {
+    reject_subresources(&params)?;
+    let key = object_key(&state, &bucket, &key)?;
+
+    if key.marker {
+        let path = key.path();
+        let files = get_files_under_folder(&state.db, &path)
+            .await
+            .map_err(database_error)?;
+        let subfolders = get_subfolders(&state.db, &path)
+            .await
+            .map_err(database_error)?;
+        if files.is_empty() && subfolders.is_empty() {
+            delete_folder_tree(&state.db, &path)
+                .await
+                .map_err(database_error)?;
+        }
+    } else {
+        let files = get_files_by_name(&state.db, &key.folder, &key.name)
+            .await
+            .map_err(database_error)?;
+        delete_files(&state, &audit_context, &files).await?;
+    }
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Every visible file that could match `prefix` by key, with the newest
+/// file winning for duplicate names, plus the folders as `path/` without a
+/// file so that empty folders show up as common prefixes.
+async fn bucket_entries(
+    state: &AppState,
+    prefix: &str,
+) -> Result<BTreeMap<String, Option<File>>, S3Error> {
+    let mut entries = BTreeMap::new();
+    let scope = prefix.rsplit_once('/').map_or("", |(folder, _)| folder);
+    let Some(scope) = normalize_folder(scope) else {
+        return Ok(entries);
+    };
+
+    let files = get_files_under_folder(&state.db, &scope)
+        .await
+        .map_err(database_error)?;
+    for file in files {
+        if !file.is_private && !file.is_broken {
+            entries.insert(join_folder(&file.folder, &file.name), Some(file));
+        }
+    }
+
+    let folders = get_folder_tree(&state.db, &scope)
+        .await
+        .map_err(database_error)?;
+    for folder in folders {
+        entries.insert(format!("{}/", folder.path), None);
+    }
+
+    Ok(entries)
+}
+
+/// ListObjectsV2 on the bucket named by `FILE_SERVER_S3_API_BUCKET`. Keys
+/// are `folder/name` paths.
+#[tracing::instrument(skip_all, fields(bucket = %bucket))]
+pub async fn list_objects(
+    State(state): State<AppState>,
+    Path(bucket): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, S3Error> {
+    require_bucket(&state, &bucket)?;
+    if params.get("list-type").map(String::as_str) != Some("2") {
+        return Err(S3Error::not_implemented());
+    }
+
+    let prefix = params.get("prefix").cloned().unwrap_or_default();
+    let delimiter = params
+        .get("delimiter")
+        .filter(|delimiter| !delimiter.is_empty());
+    let max_keys = match params.get("max-keys") {
+        Some(max_keys) => max_keys
+            .parse::<usize>()
+            .map_err(|_| S3Error::invalid_argument("Invalid max-keys"))?
+            .min(MAX_KEYS),
+        None => MAX_KEYS,
+    };
+    let continuation_token = params.get("continuation-token");
+    let start_after = match continuation_token {
+        Some(token) => Some(
+            URL_SAFE_NO_PAD
+                .decode(token)
+                .ok()
+                .and_then(|key| String::from_utf8(key).ok())
+                .ok_or_else(|| S3Error::invalid_argument("Invalid continuation token"))?,
+        ),
+        None => params.get("start-after").cloned(),
+    };
+    let url_encoded = match params.get("encoding-type").map(String::as_str) {
+        None => false,
+        Some("url") => true,
+        Some(_) => return Err(S3Error::invalid_argument("Invalid encoding type")),
+    };
+
+    let entries = bucket_entries(&state, &prefix).await?;
+
+    let mut contents = Vec::new();
+    let mut common_prefixes: Vec<String> = Vec::new();
+    let mut last_key: Option<String> = None;
+    let mut truncated = false;
+    for (key, file) in entries.range(prefix.clone()..) {
+        if !key.starts_with(&prefix) {
+            break;
+        }
+
+        let common_prefix = delimiter.and_then(|delimiter| {
+            key[prefix.len()..]
+                .find(delimiter.as_str())
+                .map(|index| key[..prefix.len() + index + delimiter.len()].to_string())
+        });
+        let listed = match (&common_prefix, file) {
+            (Some(common_prefix), _) => common_prefix,
+            (None, Some(_)) => key,
+            (None, None) => continue,
+        };
+        if start_after
+            .as_deref()
+            .is_some_and(|start_after| listed.as_str() <= start_after)
+            || last_key.as_ref() == Some(listed)
+        {
+            continue;
+        }
+
+        if contents.len() + common_prefixes.len() >= max_keys {
+            truncated = true;
+            break;
+        }
+        last_key = Some(listed.clone());
+        match (common_prefix, file) {
+            (Some(common_prefix), _) => common_prefixes.push(common_prefix),
+            (None, Some(file)) => contents.push((key, file)),
+            (None, None) => {}
+        }
+    }
+
+    let encode = |value: &str| -> String {
+        let value = if url_encoded {
+            Cow::Owned(utf8_percent_encode(value, KEY_ENCODE).to_string())
+        } else {
+            Cow::Borrowed(value)
+        };
+        escape(value.as_ref()).into_owned()
+    };
+
+    let mut body = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?><ListBucketResult xmlns="{}"><Name>{}</Name><Prefix>{}</Prefix><MaxKeys>{}</MaxKeys><KeyCount>{}</KeyCount><IsTruncated>{}</IsTruncated>"#,
+        XMLNS,
+        escape(bucket.as_str()),
+        encode(&prefix),
+        max_keys,
+        contents.len() + common_prefixes.len(),
+        truncated
+    );
+    if let Some(delimiter) = delimiter {
+        body.push_str(&format!("<Delimiter>{}</Delimiter>", encode(delimiter)));
+    }
+    if url_encoded {
+        body.push_str("<EncodingType>url</EncodingType>");
+    }
+    if let Some(token) = continuation_token {
+        body.push_str(&format!(
+            "<ContinuationToken>{}</ContinuationToken>",
+            escape(token.as_str())
+        ));
+    } else if let Some(start_after) = &start_after {
+        body.push_str(&format!("<StartAfter>{}</StartAfter>", encode(start_after)));
+    }
+    if let Some(last_key) = last_key.filter(|_| truncated) {
+        body.push_str(&format!(
+            "<NextContinuationToken>{}</NextContinuationToken>",
+            URL_SAFE_NO_PAD.encode(last_key)
+        ));
+    }
+    for (key, file) in contents {
+        body.push_str(&format!(
+            "<Contents><Key>{}</Key><LastModified>{}</LastModified><ETag>{}</ETag><Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+            encode(key),
+            file.updated_at.format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+            escape(file.etag().as_str()),
+            file.size
+        ));
+    }
+    for common_prefix in common_prefixes {
+        body.push_str(&format!(
+            "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>",
+            encode(&common_prefix)
+        ));
+    }
+    body.push_str("</ListBucketResult>");
+
+    Ok((
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/xml")],
+        body,
+    )
+        .into_response())
+}
+
+/// Answers operations outside the supported subset.
+pub async fn not_implemented() -> S3Error {
+    S3Error::not_implemented()
+}
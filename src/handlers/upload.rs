@@ -1,122 +1,504 @@
 use axum::{
-    extract::{Multipart, State},
+    extract::{Multipart, Query, State},
     http::StatusCode,
-    response::Json,
+    response::{IntoResponse, Json, Response},
 };
-use bytes::Bytes;
+use chrono::Utc;
+use futures::StreamExt;
+use metrics_exporter_prometheus::PrometheusHandle;
+use serde::Deserialize;
 use serde_json::{json, Value};
-use std::sync::Arc;
+use std::sync::{atomic::Ordering, Arc};
+use tokio_util::sync::CancellationToken;
 
 use crate::{
+    audit::{self, AuditContext},
     config::Config,
-    database::{create_file, DbPool},
-    models::{File, UploadResponse},
-    storage::Storage,
+    database::{create_file, replace_file_content, DbPool},
+    events::{FileEvent, FileEvents},
+    jobs::Jobs,
+    metrics,
+    models::{
+        AuditAction, ExtractResponse, File, FileLabels, FileResponse, UploadResponse, WebhookEvent,
+    },
+    ops::migrate_storage::MigrationTracker,
+    shutdown::{PendingUpload, PendingUploads},
+    storage::{self, ByteStream, Codec, Compression, Storage, StorageRegistry, StoredBlob},
+    webhooks::{self, Webhooks},
 };
 
+use super::extract::{extract_archive, SpooledArchive};
+
 #[derive(Clone)]
 pub struct AppState {
     pub db: DbPool,
-    pub storage: Storage,
+    pub storage: Arc<StorageRegistry>,
     pub config: Arc<Config>,
+    pub metrics: PrometheusHandle,
+    pub pending_uploads: PendingUploads,
+    pub storage_migration: MigrationTracker,
+    pub webhooks: Webhooks,
+    pub events: FileEvents,
+    pub jobs: Jobs,
+}
+
+impl AppState {
+    /// State with nothing pending or running yet. The live feed ends when
+    /// `shutdown` is cancelled.
+    pub fn new(
+        db: DbPool,
+        storage: StorageRegistry,
+        config: Config,
+        metrics: PrometheusHandle,
+        shutdown: CancellationToken,
+    ) -> Self {
+        Self {
+            db,
+            storage: Arc::new(storage),
+            config: Arc::new(config),
+            metrics,
+            pending_uploads: Default::default(),
+            storage_migration: Default::default(),
+            webhooks: Default::default(),
+            events: FileEvents::new(shutdown),
+            jobs: Default::default(),
+        }
+    }
+
+    /// Announces a change to `file` on the live feed and to webhooks.
+    pub async fn file_changed(&self, event: WebhookEvent, file: &File, labels: FileLabels) {
+        let response = FileResponse {
+            labels,
+            ..FileResponse::from(file.clone())
+        };
+        self.webhooks.dispatch(&self.db, event, &response).await;
+        // The feed mirrors the public listing.
+        if !file.is_private {
+            self.events.publish(FileEvent {
+                change: event.into(),
+                file: response,
+            });
+        }
+    }
+
+    /// Returns the backend holding a file, based on the row's `storage_type`.
+    pub fn storage_for(&self, storage_type: &str) -> Result<&Storage, (StatusCode, Json<Value>)> {
+        self.storage.get(storage_type).ok_or_else(|| {
+            tracing::error!(
+                "File is stored on backend {} which is not configured",
+                storage_type
+            );
+            (
+                StatusCode::NOT_FOUND,
+                Json(json!({"error": "File not found"})),
+            )
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UploadQuery {
+    /// Unpack an uploaded `.zip` or `.tar.gz` into one file per entry.
+    extract: Option<bool>,
 }
 
+#[tracing::instrument(skip_all)]
 pub async fn upload_file(
     State(state): State<AppState>,
+    Query(query): Query<UploadQuery>,
+    audit_context: AuditContext,
+    multipart: Multipart,
+) -> Result<Response, (StatusCode, Json<Value>)> {
+    if query.extract.unwrap_or(false) {
+        // Each extracted entry is audited on its own.
+        let result = extract_upload(&state, &audit_context, multipart).await;
+        if let Err((status, _)) = &result {
+            audit::record(
+                &state.db,
+                &audit_context,
+                AuditAction::Upload,
+                *status,
+                None,
+            )
+            .await;
+        }
+        return result.map(|response| Json(response).into_response());
+    }
+
+    let result = store_upload(&state, &audit_context.actor, multipart).await;
+
+    match &result {
+        Ok(response) => {
+            audit::record(
+                &state.db,
+                &audit_context,
+                AuditAction::Upload,
+                StatusCode::OK,
+                Some(&response.data.id),
+            )
+            .await
+        }
+        Err((status, _)) => {
+            audit::record(
+                &state.db,
+                &audit_context,
+                AuditAction::Upload,
+                *status,
+                None,
+            )
+            .await
+        }
+    }
+
+    result.map(IntoResponse::into_response)
+}
+
+async fn store_upload(
+    state: &AppState,
+    uploader: &str,
     mut multipart: Multipart,
 ) -> Result<Json<UploadResponse>, (StatusCode, Json<Value>)> {
-    let mut file_data: Option<(String, Bytes, u64)> = None;
+    let mut stored: Option<(String, StoredUpload, PendingUpload)> = None;
+    let mut labels = FileLabels::default();
+
+    // Tags and metadata may come before or after the file. If they turn out
+    // to be invalid, dropping the pending upload removes the stored blob.
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|_| invalid_multipart())?
+    {
+        match field.name() {
+            Some("file") if stored.is_none() => {
+                let filename = field
+                    .file_name()
+                    .ok_or_else(|| {
+                        (
+                            StatusCode::BAD_REQUEST,
+                            Json(json!({"error": "No filename provided"})),
+                        )
+                    })?
+                    .to_string();
+
+                // The file is streamed to storage; the size limit is enforced
+                // while reading.
+                let data = field
+                    .map(|chunk| chunk.map_err(std::io::Error::other))
+                    .boxed();
+                let upload = store_data(state, &filename, data, uploader).await?;
+                let pending_upload = state
+                    .pending_uploads
+                    .track(upload.storage, &upload.blob.path);
 
-    while let Some(field) = multipart.next_field().await.map_err(|_| {
+                stored = Some((filename, upload, pending_upload));
+            }
+            Some("tags") => {
+                let tags = field.text().await.map_err(|_| invalid_multipart())?;
+                labels.tags = FileLabels::parse_tags(&tags).map_err(invalid_labels)?;
+            }
+            Some("metadata") => {
+                let metadata = field.text().await.map_err(|_| invalid_multipart())?;
+                labels.metadata = FileLabels::parse_metadata(&metadata).map_err(invalid_labels)?;
+            }
+            _ => {}
+        }
+    }
+
+    let (filename, upload, pending_upload) = stored.ok_or_else(|| {
         (
             StatusCode::BAD_REQUEST,
-            Json(json!({"error": "Invalid multipart data"})),
+            Json(json!({"error": "No file provided"})),
+        )
+    })?;
+
+    let size = upload.size;
+
+    let file = upload.into_file(filename);
+
+    let created_file = create_file(&state.db, &file, &labels).await.map_err(|e| {
+        tracing::error!("Failed to save file metadata: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": "Failed to save file metadata"})),
         )
-    })? {
-        if field.name() == Some("file") {
-            let filename = field
-                .file_name()
-                .ok_or_else(|| {
-                    (
-                        StatusCode::BAD_REQUEST,
-                        Json(json!({"error": "No filename provided"})),
-                    )
-                })?
-                .to_string();
-
-            let data = field.bytes().await.map_err(|_| {
-                (
-                    StatusCode::BAD_REQUEST,
-                    Json(json!({"error": "Failed to read file data"})),
-                )
-            })?;
-
-            let size = data.len() as u64;
-            file_data = Some((filename, data, size));
-            break;
+    })?;
+
+    pending_upload.commit();
+    metrics::record_upload(size);
+    state.jobs.wake();
+    state
+        .file_changed(WebhookEvent::Upload, &created_file, labels)
+        .await;
+
+    let response = UploadResponse {
+        file_path: format!("/files/uploads/{}", created_file.id),
+        status_path: format!("/files/uploads/{}/status", created_file.id),
+        storage_type: created_file.storage_type.clone(),
+        data: created_file,
+    };
+
+    Ok(Json(response))
+}
+
+/// Receives an archive and its labels, then extracts it with
+/// [`extract_archive`].
+async fn extract_upload(
+    state: &AppState,
+    audit_context: &AuditContext,
+    mut multipart: Multipart,
+) -> Result<ExtractResponse, (StatusCode, Json<Value>)> {
+    let mut archive: Option<SpooledArchive> = None;
+    let mut labels = FileLabels::default();
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|_| invalid_multipart())?
+    {
+        match field.name() {
+            Some("file") if archive.is_none() => {
+                let filename = field
+                    .file_name()
+                    .ok_or_else(|| {
+                        (
+                            StatusCode::BAD_REQUEST,
+                            Json(json!({"error": "No filename provided"})),
+                        )
+                    })?
+                    .to_string();
+                let data = field
+                    .map(|chunk| chunk.map_err(std::io::Error::other))
+                    .boxed();
+                archive = Some(SpooledArchive::receive(state, filename, data).await?);
+            }
+            Some("tags") => {
+                let tags = field.text().await.map_err(|_| invalid_multipart())?;
+                labels.tags = FileLabels::parse_tags(&tags).map_err(invalid_labels)?;
+            }
+            Some("metadata") => {
+                let metadata = field.text().await.map_err(|_| invalid_multipart())?;
+                labels.metadata = FileLabels::parse_metadata(&metadata).map_err(invalid_labels)?;
+            }
+            _ => {}
         }
     }
 
-    let (filename, data, size) = file_data.ok_or_else(|| {
+    let archive = archive.ok_or_else(|| {
         (
             StatusCode::BAD_REQUEST,
             Json(json!({"error": "No file provided"})),
         )
     })?;
+    extract_archive(state, audit_context, archive, labels).await
+}
 
-    if size > state.config.max_file_size {
-        return Err((
-            StatusCode::PAYLOAD_TOO_LARGE,
-            Json(json!({"error": "File too large"})),
-        ));
+/// An upload written to storage by [`store_data`].
+pub(crate) struct StoredUpload<'a> {
+    pub storage: &'a Storage,
+    pub blob: StoredBlob,
+    /// Size of the upload as received.
+    pub size: u64,
+    /// Codec and compressed size, if the upload was compressed.
+    pub compression: Option<(Codec, u64)>,
+}
+
+impl StoredUpload<'_> {
+    /// A new row for the upload, in the root folder, pending processing.
+    pub(crate) fn into_file(self, name: String) -> File {
+        let mut file = File::new(
+            self.blob.path,
+            name,
+            self.size as i64,
+            self.storage.name().to_string(),
+        );
+        file.set_blob_key(self.blob.key);
+        file.set_compression(self.compression);
+        file.start_processing();
+        file
     }
+}
+
+/// Checks the type of `filename` against the allowed file types, then
+/// streams `data` to the backend picked by the routing rules while enforcing
+/// the maximum file size. Compressible types are compressed on the way.
+pub(crate) async fn store_data<'a>(
+    state: &'a AppState,
+    filename: &str,
+    data: ByteStream<'_>,
+    uploader: &str,
+) -> Result<StoredUpload<'a>, (StatusCode, Json<Value>)> {
+    let file_mime = mime_guess::from_path(filename)
+        .first_or_octet_stream()
+        .to_string();
 
     let allowed_types = state.config.allowed_file_types_vec();
-    if !allowed_types.contains(&"*".to_string()) {
-        let file_mime = mime_guess::from_path(&filename)
-            .first_or_octet_stream()
-            .to_string();
-
-        if !allowed_types.contains(&file_mime) {
-            return Err((
-                StatusCode::BAD_REQUEST,
-                Json(json!({"error": "File type not allowed"})),
-            ));
-        }
+    if !allowed_types.contains(&"*".to_string()) && !allowed_types.contains(&file_mime) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "File type not allowed"})),
+        ));
     }
 
-    let file_path = state
+    let (data, bytes_read) = storage::limit_stream(data, state.config.max_file_size);
+    let too_large = || bytes_read.load(Ordering::Relaxed) > state.config.max_file_size;
+
+    let (storage, data) = state
         .storage
-        .store_file(&filename, data)
+        .route_stream(data, &file_mime, Some(uploader))
         .await
-        .map_err(|e| {
-            tracing::error!("Failed to store file: {}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({"error": "Failed to upload file"})),
-            )
-        })?;
+        .map_err(|_| read_error(too_large()))?;
 
-    let file = File::new(
-        file_path,
-        filename,
-        size as i64,
-        state.storage.storage_type(),
-    );
+    let compression = Compression::from_config(&state.config)
+        .filter(|compression| compression.applies_to(&file_mime));
+    let (data, compressed_size) = match &compression {
+        Some(compression) => {
+            let (data, compressed_size) = compression.compress(data);
+            (data, Some(compressed_size))
+        }
+        None => (data, None),
+    };
 
-    let created_file = create_file(&state.db, &file).await.map_err(|e| {
-        tracing::error!("Failed to save file metadata: {}", e);
+    let blob = storage.store_stream(filename, data).await.map_err(|e| {
+        if too_large() {
+            return read_error(true);
+        }
+        tracing::error!("Failed to store file: {}", e);
         (
             StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({"error": "Failed to save file metadata"})),
+            Json(json!({"error": "Failed to upload file"})),
         )
     })?;
 
-    let response = UploadResponse {
-        file_path: format!("/files/uploads/{}", created_file.id),
-        storage_type: created_file.storage_type.clone(),
-        data: created_file,
+    Ok(StoredUpload {
+        storage,
+        blob,
+        size: bytes_read.load(Ordering::Relaxed),
+        compression: compression
+            .zip(compressed_size)
+            .map(|(compression, size)| (compression.codec, size.load(Ordering::Relaxed))),
+    })
+}
+
+/// Stores `data` as `name` in `folder`, or as the new content of `existing`
+/// while keeping its id. The upload is audited, and the replaced blob is only
+/// removed once the row points at the new one.
+pub(crate) async fn store_named(
+    state: &AppState,
+    audit_context: &AuditContext,
+    folder: &str,
+    name: &str,
+    existing: Option<&File>,
+    data: ByteStream<'_>,
+) -> Result<File, (StatusCode, Json<Value>)> {
+    let result = async {
+        let upload = store_data(state, name, data, &audit_context.actor).await?;
+        let pending_upload = state
+            .pending_uploads
+            .track(upload.storage, &upload.blob.path);
+        let size = upload.size;
+
+        let file = match existing {
+            Some(existing) => {
+                let mut file = existing.clone();
+                file.path = upload.blob.path;
+                file.set_blob_key(upload.blob.key);
+                file.set_compression(upload.compression);
+                file.size = size as i64;
+                file.storage_type = upload.storage.name().to_string();
+                file.is_broken = false;
+                file.start_processing();
+                file.updated_at = Utc::now();
+
+                let replaced = replace_file_content(&state.db, &file)
+                    .await
+                    .map_err(metadata_error)?;
+                if !replaced {
+                    return Err((
+                        StatusCode::NOT_FOUND,
+                        Json(json!({"error": "File not found"})),
+                    ));
+                }
+                file
+            }
+            None => {
+                let mut file = upload.into_file(name.to_string());
+                file.folder = folder.to_string();
+                create_file(&state.db, &file, &FileLabels::default())
+                    .await
+                    .map_err(metadata_error)?
+            }
+        };
+
+        pending_upload.commit();
+        metrics::record_upload(size);
+        state.jobs.wake();
+        Ok(file)
+    }
+    .await;
+
+    let (status, file_id) = match &result {
+        Ok(file) => (StatusCode::OK, Some(file.id.as_str())),
+        Err((status, _)) => (*status, None),
     };
+    audit::record(
+        &state.db,
+        audit_context,
+        AuditAction::Upload,
+        status,
+        file_id,
+    )
+    .await;
+    let file = result?;
 
-    Ok(Json(response))
+    let event = match existing {
+        Some(_) => WebhookEvent::Version,
+        None => WebhookEvent::Upload,
+    };
+    let labels = webhooks::fetch_labels(&state.db, &file).await;
+    state.file_changed(event, &file, labels).await;
+
+    if let Some(existing) = existing {
+        let deleted = match state.storage.require(&existing.storage_type) {
+            Ok(storage) => storage.delete_file(&existing.path).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = deleted {
+            tracing::error!("Failed to delete blob {}: {}", existing.path, e);
+        }
+    }
+
+    Ok(file)
+}
+
+pub(super) fn metadata_error(e: anyhow::Error) -> (StatusCode, Json<Value>) {
+    tracing::error!("Failed to save file metadata: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({"error": "Failed to save file metadata"})),
+    )
+}
+
+fn invalid_multipart() -> (StatusCode, Json<Value>) {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({"error": "Invalid multipart data"})),
+    )
+}
+
+fn invalid_labels(message: String) -> (StatusCode, Json<Value>) {
+    (StatusCode::BAD_REQUEST, Json(json!({"error": message})))
+}
+
+pub(super) fn read_error(too_large: bool) -> (StatusCode, Json<Value>) {
+    if too_large {
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            Json(json!({"error": "File too large"})),
+        )
+    } else {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "Failed to read file data"})),
+        )
+    }
 }
@@ -0,0 +1,703 @@
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, TryStreamExt};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use quick_xml::escape::escape;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use uuid::Uuid;
+
+use crate::{
+    audit::{self, AuditContext},
+    database::{
+        create_file, create_folder, delete_folder_tree, get_file_by_id, get_file_labels,
+        get_files_by_name, get_files_in_folder, get_files_under_folder, get_folder,
+        get_folder_tree, get_subfolders, move_file, move_folder,
+    },
+    metrics,
+    models::{
+        file::NotReady, join_folder, normalize_folder, parent_folder, AuditAction, File, Folder,
+        WebhookEvent,
+    },
+    webhooks,
+};
+
+use super::{
+    files::delete_files,
+    upload::{store_named, AppState},
+};
+
+/// Where the WebDAV tree is served.
+pub const MOUNT: &str = "/dav";
+
+/// Characters left unescaped in hrefs.
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+const ALLOW: &str =
+    "OPTIONS, GET, HEAD, PUT, DELETE, PROPFIND, PROPPATCH, MKCOL, MOVE, COPY, LOCK, UNLOCK";
+
+const SUPPORTED_LOCK: &str = "<D:supportedlock><D:lockentry><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry></D:supportedlock>";
+
+type HandlerError = (StatusCode, Json<Value>);
+
+/// What a WebDAV path refers to. Files with the same name in one folder
+/// appear as a single resource, the most recently uploaded one.
+enum Resource {
+    /// The root folder has no row.
+    Folder(Option<Folder>),
+    File(Box<File>),
+}
+
+/// Serves the `files` table as a WebDAV tree under [`MOUNT`]. Folders map
+/// to the `folders` table and files to their `name` within a folder.
+#[tracing::instrument(skip_all, fields(method = %request.method()))]
+pub async fn handle(
+    State(state): State<AppState>,
+    audit_context: AuditContext,
+    request: Request,
+) -> Result<Response, HandlerError> {
+    let path = resource_path(request.uri().path())?;
+    let headers = request.headers().clone();
+
+    match request.method().as_str() {
+        "OPTIONS" => Ok(options()),
+        "PROPFIND" => propfind(&state, &path, &headers).await,
+        "PROPPATCH" => proppatch(&state, &path).await,
+        "GET" | "HEAD" => {
+            get(
+                &state,
+                &audit_context,
+                &path,
+                request.method() == Method::HEAD,
+            )
+            .await
+        }
+        "PUT" => put(&state, &audit_context, &path, request).await,
+        "DELETE" => delete(&state, &audit_context, &path).await,
+        "MKCOL" => mkcol(&state, &path).await,
+        "MOVE" => transfer(&state, &audit_context, &path, &headers, false).await,
+        "COPY" => transfer(&state, &audit_context, &path, &headers, true).await,
+        "LOCK" => Ok(lock(&path)),
+        "UNLOCK" => Ok(StatusCode::NO_CONTENT.into_response()),
+        _ => Err(error(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed")),
+    }
+}
+
+/// Asks for Basic credentials when the auth middleware rejects a request,
+/// so that desktop clients prompt for them.
+pub async fn challenge(request: Request, next: Next) -> Response {
+    let mut response = next.run(request).await;
+    if response.status() == StatusCode::UNAUTHORIZED {
+        response.headers_mut().insert(
+            header::WWW_AUTHENTICATE,
+            HeaderValue::from_static("Basic realm=\"file-server-rs\""),
+        );
+    }
+    response
+}
+
+fn error(status: StatusCode, message: &str) -> HandlerError {
+    (status, Json(json!({"error": message})))
+}
+
+fn database_error(e: anyhow::Error) -> HandlerError {
+    tracing::error!("Database error: {}", e);
+    error(StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+}
+
+/// The path below the mount, decoded and normalized, e.g. `docs/a.txt`.
+fn resource_path(uri_path: &str) -> Result<String, HandlerError> {
+    let path = uri_path.strip_prefix(MOUNT).unwrap_or(uri_path);
+    percent_decode_str(path)
+        .decode_utf8()
+        .ok()
+        .and_then(|path| normalize_folder(&path))
+        .ok_or_else(|| error(StatusCode::BAD_REQUEST, "Invalid path"))
+}
+
+fn href(path: &str, collection: bool) -> String {
+    let mut href = MOUNT.to_string();
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        href.push('/');
+        href.extend(utf8_percent_encode(segment, PATH_SEGMENT));
+    }
+    if collection {
+        href.push('/');
+    }
+    href
+}
+
+fn http_date(date: DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+async fn resolve(state: &AppState, path: &str) -> Result<Option<Resource>, HandlerError> {
+    if path.is_empty() {
+        return Ok(Some(Resource::Folder(None)));
+    }
+
+    if let Some(folder) = get_folder(&state.db, path).await.map_err(database_error)? {
+        return Ok(Some(Resource::Folder(Some(folder))));
+    }
+
+    let (folder, name) = split_path(path);
+    let files = get_files_by_name(&state.db, folder, name)
+        .await
+        .map_err(database_error)?;
+    Ok(files
+        .into_iter()
+        .next()
+        .map(|file| Resource::File(Box::new(file))))
+}
+
+fn split_path(path: &str) -> (&str, &str) {
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+/// Fails with 409 unless the folder `path` would be placed in exists.
+async fn require_parent(state: &AppState, path: &str) -> Result<(), HandlerError> {
+    let parent = parent_folder(path);
+    if parent.is_empty()
+        || get_folder(&state.db, parent)
+            .await
+            .map_err(database_error)?
+            .is_some()
+    {
+        Ok(())
+    } else {
+        Err(error(StatusCode::CONFLICT, "Parent folder does not exist"))
+    }
+}
+
+fn options() -> Response {
+    (
+        StatusCode::OK,
+        [
+            (header::ALLOW, ALLOW),
+            (header::HeaderName::from_static("dav"), "1, 2"),
+            (header::HeaderName::from_static("ms-author-via"), "DAV"),
+        ],
+    )
+        .into_response()
+}
+
+fn multistatus(responses: String) -> Response {
+    (
+        StatusCode::MULTI_STATUS,
+        [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?><D:multistatus xmlns:D="DAV:">{}</D:multistatus>"#,
+            responses
+        ),
+    )
+        .into_response()
+}
+
+fn folder_entry(path: &str, folder: Option<&Folder>) -> String {
+    let name = folder.map(Folder::name).unwrap_or_default();
+    let created_at = folder.map(|folder| folder.created_at);
+    let dates = created_at
+        .map(|date| {
+            format!(
+                "<D:creationdate>{}</D:creationdate><D:getlastmodified>{}</D:getlastmodified>",
+                date.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                http_date(date)
+            )
+        })
+        .unwrap_or_default();
+
+    format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop><D:displayname>{}</D:displayname><D:resourcetype><D:collection/></D:resourcetype>{}{}</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+        escape(href(path, true).as_str()),
+        escape(name),
+        dates,
+        SUPPORTED_LOCK
+    )
+}
+
+fn file_entry(path: &str, file: &File) -> String {
+    format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop><D:displayname>{}</D:displayname><D:resourcetype/><D:getcontentlength>{}</D:getcontentlength><D:getcontenttype>{}</D:getcontenttype><D:getetag>{}</D:getetag><D:creationdate>{}</D:creationdate><D:getlastmodified>{}</D:getlastmodified>{}</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+        escape(href(path, false).as_str()),
+        escape(file.name.as_str()),
+        file.size,
+        escape(
+            mime_guess::from_path(&file.name)
+                .first_or_octet_stream()
+                .as_ref()
+        ),
+        escape(file.etag().as_str()),
+        file.created_at
+            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        http_date(file.updated_at),
+        SUPPORTED_LOCK
+    )
+}
+
+/// Every property is returned whatever the request body asks for. A depth of
+/// `infinity` is answered like `1`.
+async fn propfind(
+    state: &AppState,
+    path: &str,
+    headers: &HeaderMap,
+) -> Result<Response, HandlerError> {
+    let resource = resolve(state, path)
+        .await?
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, "Not found"))?;
+    let depth_zero = headers
+        .get("depth")
+        .is_some_and(|depth| depth.as_bytes() == b"0");
+
+    let folder = match resource {
+        Resource::File(file) => return Ok(multistatus(file_entry(path, &file))),
+        Resource::Folder(folder) => folder,
+    };
+
+    let mut responses = folder_entry(path, folder.as_ref());
+    if !depth_zero {
+        let subfolders = get_subfolders(&state.db, path)
+            .await
+            .map_err(database_error)?;
+        let files = get_files_in_folder(&state.db, path)
+            .await
+            .map_err(database_error)?;
+
+        let mut names: HashSet<String> = HashSet::new();
+        for subfolder in &subfolders {
+            names.insert(subfolder.name().to_string());
+            responses.push_str(&folder_entry(&subfolder.path, Some(subfolder)));
+        }
+        // Newest first, so the first file of each name is the one served.
+        for file in &files {
+            if names.insert(file.name.clone()) {
+                responses.push_str(&file_entry(&join_folder(path, &file.name), file));
+            }
+        }
+    }
+
+    Ok(multistatus(responses))
+}
+
+/// Properties cannot be changed, but clients that set timestamps after an
+/// upload expect the request to succeed.
+async fn proppatch(state: &AppState, path: &str) -> Result<Response, HandlerError> {
+    let collection = match resolve(state, path).await? {
+        Some(Resource::Folder(_)) => true,
+        Some(Resource::File(_)) => false,
+        None => return Err(error(StatusCode::NOT_FOUND, "Not found")),
+    };
+
+    Ok(multistatus(format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop/><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+        escape(href(path, collection).as_str())
+    )))
+}
+
+async fn get(
+    state: &AppState,
+    audit_context: &AuditContext,
+    path: &str,
+    head: bool,
+) -> Result<Response, HandlerError> {
+    let file = match resolve(state, path).await? {
+        Some(Resource::File(file)) => file,
+        Some(Resource::Folder(_)) => {
+            return Err(error(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "Folders cannot be downloaded",
+            ))
+        }
+        None => return Err(error(StatusCode::NOT_FOUND, "Not found")),
+    };
+    match file.check_ready() {
+        Ok(()) => {}
+        Err(NotReady::Processing) => {
+            return Err(error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "File is still being processed",
+            ))
+        }
+        Err(NotReady::Failed) => return Err(error(StatusCode::CONFLICT, "File processing failed")),
+    }
+
+    let mut headers = HeaderMap::new();
+    let header_values = [
+        (
+            header::CONTENT_TYPE,
+            mime_guess::from_path(&file.name)
+                .first_or_octet_stream()
+                .to_string(),
+        ),
+        (header::CONTENT_LENGTH, file.size.to_string()),
+        (header::ETAG, file.etag()),
+        (header::LAST_MODIFIED, http_date(file.updated_at)),
+    ];
+    for (name, value) in header_values {
+        if let Ok(value) = value.parse() {
+            headers.insert(name, value);
+        }
+    }
+
+    if head {
+        return Ok((headers, Body::empty()).into_response());
+    }
+
+    let result = async {
+        let storage = state.storage_for(&file.storage_type)?;
+        storage
+            .get_content(&file.path, file.blob_key().as_ref(), file.codec())
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to retrieve file: {}", e);
+                error(StatusCode::NOT_FOUND, "Not found")
+            })
+    }
+    .await;
+
+    let status = match &result {
+        Ok(_) => StatusCode::OK,
+        Err((status, _)) => *status,
+    };
+    audit::record(
+        &state.db,
+        audit_context,
+        AuditAction::Download,
+        status,
+        Some(&file.id),
+    )
+    .await;
+
+    let data = result?;
+    metrics::record_download(file.size as u64);
+    Ok((headers, Body::from_stream(data)).into_response())
+}
+
+/// Stores a new file, or replaces the content of the existing file of that
+/// name while keeping its id.
+async fn put(
+    state: &AppState,
+    audit_context: &AuditContext,
+    path: &str,
+    request: Request,
+) -> Result<Response, HandlerError> {
+    let (folder, name) = split_path(path);
+    let existing = match resolve(state, path).await? {
+        Some(Resource::Folder(_)) => {
+            return Err(error(StatusCode::METHOD_NOT_ALLOWED, "Path is a folder"))
+        }
+        Some(Resource::File(file)) => Some(file),
+        None => None,
+    };
+    require_parent(state, path).await?;
+
+    let data = request
+        .into_body()
+        .into_data_stream()
+        .map_err(std::io::Error::other)
+        .boxed();
+    store_named(
+        state,
+        audit_context,
+        folder,
+        name,
+        existing.as_deref(),
+        data,
+    )
+    .await?;
+
+    Ok(match existing {
+        Some(_) => StatusCode::NO_CONTENT,
+        None => StatusCode::CREATED,
+    }
+    .into_response())
+}
+
+async fn delete(
+    state: &AppState,
+    audit_context: &AuditContext,
+    path: &str,
+) -> Result<Response, HandlerError> {
+    match resolve(state, path).await? {
+        Some(Resource::Folder(None)) => Err(error(
+            StatusCode::FORBIDDEN,
+            "The root folder cannot be deleted",
+        )),
+        Some(Resource::Folder(Some(folder))) => {
+            let files = get_files_under_folder(&state.db, &folder.path)
+                .await
+                .map_err(database_error)?;
+            if files.iter().any(|file| file.is_private) {
+                return Err(error(
+                    StatusCode::FORBIDDEN,
+                    "Folder contains private files",
+                ));
+            }
+
+            delete_files(state, audit_context, &files).await?;
+            delete_folder_tree(&state.db, &folder.path)
+                .await
+                .map_err(database_error)?;
+            Ok(StatusCode::NO_CONTENT.into_response())
+        }
+        Some(Resource::File(file)) => {
+            // Older files of the same name would otherwise reappear.
+            let files = get_files_by_name(&state.db, &file.folder, &file.name)
+                .await
+                .map_err(database_error)?;
+            delete_files(state, audit_context, &files).await?;
+            Ok(StatusCode::NO_CONTENT.into_response())
+        }
+        None => Err(error(StatusCode::NOT_FOUND, "Not found")),
+    }
+}
+
+async fn mkcol(state: &AppState, path: &str) -> Result<Response, HandlerError> {
+    if resolve(state, path).await?.is_some() {
+        return Err(error(StatusCode::METHOD_NOT_ALLOWED, "Path already exists"));
+    }
+    require_parent(state, path).await?;
+
+    create_folder(&state.db, &Folder::new(path.to_string()))
+        .await
+        .map_err(database_error)?;
+    Ok(StatusCode::CREATED.into_response())
+}
+
+/// The path below the mount named by a `Destination` header.
+fn destination_path(headers: &HeaderMap) -> Result<String, HandlerError> {
+    let destination = headers
+        .get("destination")
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| error(StatusCode::BAD_REQUEST, "Missing Destination header"))?;
+
+    let path = match reqwest::Url::parse(destination) {
+        Ok(url) => url.path().to_string(),
+        Err(_) => destination.to_string(),
+    };
+    if path != MOUNT && !path.starts_with(&format!("{}/", MOUNT)) {
+        return Err(error(
+            StatusCode::BAD_GATEWAY,
+            "Destination is outside this server",
+        ));
+    }
+    resource_path(&path)
+}
+
+/// Handles MOVE and COPY. Files of the same name move together; only the
+/// visible one is copied.
+async fn transfer(
+    state: &AppState,
+    audit_context: &AuditContext,
+    path: &str,
+    headers: &HeaderMap,
+    copy: bool,
+) -> Result<Response, HandlerError> {
+    let destination = destination_path(headers)?;
+    let overwrite = headers
+        .get("overwrite")
+        .is_none_or(|value| !value.as_bytes().eq_ignore_ascii_case(b"F"));
+    let shallow = headers
+        .get("depth")
+        .is_some_and(|depth| depth.as_bytes() == b"0");
+
+    let source = resolve(state, path)
+        .await?
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, "Not found"))?;
+    if path.is_empty() || destination.is_empty() {
+        return Err(error(
+            StatusCode::FORBIDDEN,
+            "The root folder cannot be moved or copied",
+        ));
+    }
+    if destination == path || destination.starts_with(&format!("{}/", path)) {
+        return Err(error(
+            StatusCode::FORBIDDEN,
+            "Destination is inside the source",
+        ));
+    }
+    require_parent(state, &destination).await?;
+
+    let replaced = resolve(state, &destination).await?.is_some();
+    if replaced {
+        if !overwrite {
+            return Err(error(
+                StatusCode::PRECONDITION_FAILED,
+                "Destination already exists",
+            ));
+        }
+        delete(state, audit_context, &destination).await?;
+    }
+
+    let (folder, name) = split_path(&destination);
+    match (source, copy) {
+        (Resource::File(file), false) => {
+            let files = get_files_by_name(&state.db, &file.folder, &file.name)
+                .await
+                .map_err(database_error)?;
+            let mut moved = Vec::with_capacity(files.len());
+            for file in files {
+                move_file(&state.db, &file.id, folder, name)
+                    .await
+                    .map_err(database_error)?;
+                moved.extend(
+                    get_file_by_id(&state.db, &file.id)
+                        .await
+                        .map_err(database_error)?,
+                );
+            }
+            announce_moves(state, moved).await;
+        }
+        (Resource::File(file), true) => copy_file(state, &file, folder, name).await?,
+        (Resource::Folder(_), false) => {
+            move_folder(&state.db, path, &destination, folder)
+                .await
+                .map_err(database_error)?;
+            let moved = get_files_under_folder(&state.db, &destination)
+                .await
+                .map_err(database_error)?;
+            announce_moves(state, moved).await;
+        }
+        (Resource::Folder(_), true) => copy_folder(state, path, &destination, shallow).await?,
+    }
+
+    Ok(if replaced {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::CREATED
+    }
+    .into_response())
+}
+
+/// Tells webhooks and the live feed about files that got a new path.
+async fn announce_moves(state: &AppState, files: Vec<File>) {
+    for file in files {
+        let labels = webhooks::fetch_labels(&state.db, &file).await;
+        state.file_changed(WebhookEvent::Move, &file, labels).await;
+    }
+}
+
+async fn copy_file(
+    state: &AppState,
+    file: &File,
+    folder: &str,
+    name: &str,
+) -> Result<(), HandlerError> {
+    let storage = state.storage_for(&file.storage_type)?;
+    let blob_path = storage.copy_file(&file.path, name).await.map_err(|e| {
+        tracing::error!("Failed to copy file: {}", e);
+        error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to copy file")
+    })?;
+    let pending_upload = state.pending_uploads.track(storage, &blob_path);
+
+    let mut copy = File::new(
+        blob_path,
+        name.to_string(),
+        file.size,
+        file.storage_type.clone(),
+    );
+    // The blob is copied as stored, so it keeps the same data key.
+    copy.set_blob_key(file.blob_key());
+    copy.compression = file.compression.clone();
+    copy.compressed_size = file.compressed_size;
+    copy.folder = folder.to_string();
+    // Same content, so a processed source need not be processed again.
+    if file.is_ready() {
+        copy.sha256 = file.sha256.clone();
+    } else {
+        copy.start_processing();
+    }
+    let labels = get_file_labels(&state.db, &[&file.id])
+        .await
+        .map_err(database_error)?
+        .remove(&file.id)
+        .unwrap_or_default();
+    let copy = create_file(&state.db, &copy, &labels)
+        .await
+        .map_err(database_error)?;
+
+    pending_upload.commit();
+    state.jobs.wake();
+    state
+        .file_changed(WebhookEvent::Upload, &copy, labels)
+        .await;
+    Ok(())
+}
+
+async fn copy_folder(
+    state: &AppState,
+    from: &str,
+    to: &str,
+    shallow: bool,
+) -> Result<(), HandlerError> {
+    let relocate = |path: &str| format!("{}{}", to, &path[from.len()..]);
+
+    create_folder(&state.db, &Folder::new(to.to_string()))
+        .await
+        .map_err(database_error)?;
+    if shallow {
+        return Ok(());
+    }
+
+    for folder in get_folder_tree(&state.db, from)
+        .await
+        .map_err(database_error)?
+        .iter()
+        .skip(1)
+    {
+        create_folder(&state.db, &Folder::new(relocate(&folder.path)))
+            .await
+            .map_err(database_error)?;
+    }
+
+    let files = get_files_under_folder(&state.db, from)
+        .await
+        .map_err(database_error)?;
+    let mut copied = HashSet::new();
+    // Oldest first, so keep the last file of each name.
+    for file in files.iter().rev() {
+        if file.is_private || file.is_broken || !copied.insert((&file.folder, &file.name)) {
+            continue;
+        }
+        copy_file(state, file, &relocate(&file.folder), &file.name).await?;
+    }
+
+    Ok(())
+}
+
+fn lock(path: &str) -> Response {
+    let token = format!(
+        "opaquelocktoken:{}",
+        Uuid::new_v7(uuid::timestamp::Timestamp::now(uuid::NoContext))
+    );
+    let body = format!(
+        r#"<?xml version="1.0" encoding="utf-8"?><D:prop xmlns:D="DAV:"><D:lockdiscovery><D:activelock><D:locktype><D:write/></D:locktype><D:lockscope><D:exclusive/></D:lockscope><D:depth>infinity</D:depth><D:timeout>Second-3600</D:timeout><D:locktoken><D:href>{}</D:href></D:locktoken><D:lockroot><D:href>{}</D:href></D:lockroot></D:activelock></D:lockdiscovery></D:prop>"#,
+        token,
+        escape(href(path, false).as_str())
+    );
+
+    (
+        StatusCode::OK,
+        [
+            (
+                header::CONTENT_TYPE,
+                "application/xml; charset=utf-8".to_string(),
+            ),
+            (
+                header::HeaderName::from_static("lock-token"),
+                format!("<{}>", token),
+            ),
+        ],
+        body,
+    )
+        .into_response()
+}
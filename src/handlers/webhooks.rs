@@ -0,0 +1,153 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::Json,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    database::{
+        create_webhook, delete_webhook, get_webhook, get_webhook_deliveries, get_webhooks,
+        retry_webhook_delivery,
+    },
+    models::{Webhook, WebhookDelivery, WebhookEvent, WebhookResponse},
+};
+
+use super::upload::AppState;
+
+#[derive(Deserialize)]
+pub struct CreateWebhookRequest {
+    url: String,
+    events: Vec<String>,
+}
+
+/// Subscribes a URL to file events. The response carries the signing secret,
+/// which is not shown again.
+#[tracing::instrument(skip_all)]
+pub async fn create_webhook_handler(
+    State(state): State<AppState>,
+    Json(request): Json<CreateWebhookRequest>,
+) -> Result<(StatusCode, Json<WebhookResponse>), (StatusCode, Json<Value>)> {
+    let url = reqwest::Url::parse(&request.url)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .ok_or_else(|| bad_request("url must be an http or https URL".to_string()))?;
+
+    if request.events.is_empty() {
+        return Err(bad_request("events must not be empty".to_string()));
+    }
+    let mut events = Vec::new();
+    for name in &request.events {
+        let event = WebhookEvent::parse(name).ok_or_else(|| {
+            bad_request(format!(
+                "Unknown event {}, expected upload, delete, version or move",
+                name
+            ))
+        })?;
+        if !events.contains(&event) {
+            events.push(event);
+        }
+    }
+
+    let webhook = Webhook::new(url.to_string(), &events);
+    create_webhook(&state.db, &webhook)
+        .await
+        .map_err(database_error)?;
+
+    let secret = webhook.secret.clone();
+    Ok((
+        StatusCode::CREATED,
+        Json(WebhookResponse {
+            secret: Some(secret),
+            ..WebhookResponse::from(webhook)
+        }),
+    ))
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn list_webhooks(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<WebhookResponse>>, (StatusCode, Json<Value>)> {
+    let webhooks = get_webhooks(&state.db).await.map_err(database_error)?;
+    Ok(Json(
+        webhooks.into_iter().map(WebhookResponse::from).collect(),
+    ))
+}
+
+#[tracing::instrument(skip_all, fields(webhook_id = %id))]
+pub async fn delete_webhook_handler(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<StatusCode, (StatusCode, Json<Value>)> {
+    if !delete_webhook(&state.db, &id)
+        .await
+        .map_err(database_error)?
+    {
+        return Err(not_found("Webhook not found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+pub struct DeliveriesQuery {
+    status: Option<String>,
+    limit: Option<i64>,
+}
+
+/// The delivery log of a webhook: one entry per event, with the number of
+/// attempts and the outcome of the latest one.
+#[tracing::instrument(skip_all, fields(webhook_id = %id))]
+pub async fn list_webhook_deliveries(
+    Path(id): Path<String>,
+    Query(params): Query<DeliveriesQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<WebhookDelivery>>, (StatusCode, Json<Value>)> {
+    if get_webhook(&state.db, &id)
+        .await
+        .map_err(database_error)?
+        .is_none()
+    {
+        return Err(not_found("Webhook not found"));
+    }
+
+    let limit = params.limit.unwrap_or(100).min(1000);
+    let deliveries = get_webhook_deliveries(&state.db, &id, params.status.as_deref(), limit)
+        .await
+        .map_err(database_error)?;
+    Ok(Json(deliveries))
+}
+
+/// Sends a delivery that ran out of attempts again, with a fresh set of
+/// attempts.
+#[tracing::instrument(skip_all, fields(webhook_id = %id, delivery_id = %delivery_id))]
+pub async fn retry_webhook_delivery_handler(
+    Path((id, delivery_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Result<StatusCode, (StatusCode, Json<Value>)> {
+    if !retry_webhook_delivery(&state.db, &id, &delivery_id)
+        .await
+        .map_err(database_error)?
+    {
+        return Err(not_found("Failed delivery not found"));
+    }
+
+    state.webhooks.wake();
+    Ok(StatusCode::ACCEPTED)
+}
+
+fn bad_request(message: String) -> (StatusCode, Json<Value>) {
+    (StatusCode::BAD_REQUEST, Json(json!({"error": message})))
+}
+
+fn not_found(message: &str) -> (StatusCode, Json<Value>) {
+    (StatusCode::NOT_FOUND, Json(json!({"error": message})))
+}
+
+fn database_error(e: anyhow::Error) -> (StatusCode, Json<Value>) {
+    tracing::error!("Database error: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({"error": "Database error"})),
+    )
+}
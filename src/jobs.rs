@@ -0,0 +1,186 @@
+use anyhow::Result;
+use chrono::{TimeDelta, Utc};
+use futures::StreamExt;
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    database::{claim_job, delete_completed_jobs_before, get_due_jobs, release_jobs, update_job},
+    handlers::AppState,
+    metrics,
+    models::{
+        job::{JOB_COMPLETED, JOB_DEAD, JOB_PROCESS_FILE, JOB_QUEUED},
+        Job,
+    },
+    processing,
+};
+
+/// Queue checks without a wakeup, which pick up retries that became due and
+/// jobs queued by other replicas.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const BATCH_SIZE: i64 = 50;
+/// How long a claimed job may run before another worker takes it over.
+const LEASE: TimeDelta = TimeDelta::minutes(15);
+const FIRST_RETRY_DELAY: TimeDelta = TimeDelta::seconds(10);
+const MAX_RETRY_DELAY: TimeDelta = TimeDelta::hours(1);
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Completed jobs are kept this long for inspection.
+const COMPLETED_RETENTION: TimeDelta = TimeDelta::days(7);
+
+/// Wakes the job worker once work has been queued.
+#[derive(Debug, Clone, Default)]
+pub struct Jobs {
+    wakeup: Arc<Notify>,
+}
+
+impl Jobs {
+    pub fn wake(&self) {
+        self.wakeup.notify_one();
+    }
+}
+
+/// Jobs this worker has claimed and not finished yet.
+type Running = Mutex<HashSet<String>>;
+
+/// Runs queued jobs until `shutdown` is cancelled. Every replica runs this;
+/// each attempt is claimed before it starts, so only one runs it.
+pub async fn run(state: AppState, shutdown: CancellationToken) {
+    let running = Running::default();
+
+    let work = async {
+        let mut last_purge = Instant::now();
+        loop {
+            if let Err(e) = run_due(&state, &running).await {
+                tracing::error!("Failed to run jobs: {}", e);
+            }
+
+            if last_purge.elapsed() >= PURGE_INTERVAL {
+                last_purge = Instant::now();
+                match delete_completed_jobs_before(&state.db, Utc::now() - COMPLETED_RETENTION)
+                    .await
+                {
+                    Ok(0) => {}
+                    Ok(deleted) => tracing::info!("Purged {} completed jobs", deleted),
+                    Err(e) => tracing::error!("Failed to purge completed jobs: {}", e),
+                }
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                _ = state.jobs.wakeup.notified() => {}
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = work => {}
+        _ = shutdown.cancelled() => {}
+    }
+
+    // Interrupted jobs are queued again right away instead of waiting for
+    // their claim to expire.
+    let interrupted: Vec<String> = running.lock().unwrap().drain().collect();
+    if let Err(e) = release_jobs(&state.db, &interrupted).await {
+        tracing::error!("Failed to release interrupted jobs: {}", e);
+    }
+}
+
+async fn run_due(state: &AppState, running: &Running) -> Result<()> {
+    loop {
+        let due = get_due_jobs(&state.db, Utc::now(), BATCH_SIZE).await?;
+        let count = due.len();
+
+        futures::stream::iter(due)
+            .for_each_concurrent(state.config.job_concurrency, |job| async move {
+                let id = job.id.clone();
+                if let Err(e) = attempt(state, running, job).await {
+                    tracing::error!("Failed to record the outcome of job {}: {}", id, e);
+                }
+            })
+            .await;
+
+        if count < BATCH_SIZE as usize {
+            return Ok(());
+        }
+    }
+}
+
+async fn attempt(state: &AppState, running: &Running, mut job: Job) -> Result<()> {
+    if !claim_job(&state.db, &job, Utc::now() + LEASE).await? {
+        return Ok(());
+    }
+    job.attempts += 1;
+
+    running.lock().unwrap().insert(job.id.clone());
+    let result = execute(state, &job).await;
+    running.lock().unwrap().remove(&job.id);
+
+    let now = Utc::now();
+    job.updated_at = now;
+    let outcome = match result {
+        Ok(()) => {
+            job.status = JOB_COMPLETED.to_string();
+            job.completed_at = Some(now);
+            job.last_error = None;
+            JOB_COMPLETED
+        }
+        Err(e) if job.attempts >= state.config.job_max_attempts as i64 => {
+            tracing::warn!(
+                "Job {} ({}) failed after {} attempts: {:#}",
+                job.id,
+                job.kind,
+                job.attempts,
+                e
+            );
+            job.status = JOB_DEAD.to_string();
+            job.last_error = Some(format!("{:#}", e));
+            JOB_DEAD
+        }
+        Err(e) => {
+            tracing::info!("Job {} ({}) failed, retrying: {:#}", job.id, job.kind, e);
+            job.status = JOB_QUEUED.to_string();
+            job.run_at = now + retry_delay(job.attempts);
+            job.last_error = Some(format!("{:#}", e));
+            "retry"
+        }
+    };
+    metrics::record_job(&job.kind, outcome);
+
+    update_job(&state.db, &job).await?;
+    if job.status == JOB_DEAD {
+        dead_lettered(state, &job).await;
+    }
+    Ok(())
+}
+
+async fn execute(state: &AppState, job: &Job) -> Result<()> {
+    match job.kind.as_str() {
+        JOB_PROCESS_FILE => processing::process_file(state, job).await,
+        kind => anyhow::bail!("Unknown job kind {}", kind),
+    }
+}
+
+/// Lets the work a job was for know that it will not be done.
+async fn dead_lettered(state: &AppState, job: &Job) {
+    let result = match job.kind.as_str() {
+        JOB_PROCESS_FILE => processing::fail_file(state, job).await,
+        _ => Ok(()),
+    };
+    if let Err(e) = result {
+        tracing::error!("Failed to handle dead job {}: {}", job.id, e);
+    }
+}
+
+/// Doubles with every failed attempt, starting at ten seconds.
+fn retry_delay(attempts: i64) -> TimeDelta {
+    let exponent = (attempts - 1).clamp(0, 20) as u32;
+    FIRST_RETRY_DELAY
+        .checked_mul(2i32.pow(exponent))
+        .unwrap_or(MAX_RETRY_DELAY)
+        .min(MAX_RETRY_DELAY)
+}
@@ -0,0 +1,18 @@
+pub mod archive;
+pub mod audit;
+pub mod cli;
+pub mod config;
+pub mod database;
+pub mod events;
+pub mod handlers;
+pub mod jobs;
+pub mod metrics;
+pub mod middleware;
+pub mod models;
+pub mod ops;
+pub mod processing;
+pub mod server;
+pub mod shutdown;
+pub mod storage;
+pub mod telemetry;
+pub mod webhooks;
@@ -0,0 +1,146 @@
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::{future::Future, time::Instant};
+
+use crate::{
+    database::{count_jobs_by_status, get_file_totals},
+    handlers::AppState,
+    models::job::{JOB_COMPLETED, JOB_DEAD, JOB_QUEUED, JOB_RUNNING},
+};
+
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+const SIZE_BUCKETS: &[f64] = &[
+    1024.0,
+    16384.0,
+    131072.0,
+    1048576.0,
+    8388608.0,
+    67108864.0,
+    536870912.0,
+    4294967296.0,
+];
+
+pub fn install_recorder() -> anyhow::Result<PrometheusHandle> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Suffix("duration_seconds".to_string()),
+            LATENCY_BUCKETS,
+        )?
+        .set_buckets_for_metric(
+            Matcher::Full("file_server_upload_size_bytes".to_string()),
+            SIZE_BUCKETS,
+        )?
+        .install_recorder()?;
+
+    Ok(handle)
+}
+
+/// Records request counts and latencies labelled by route template and status.
+pub async fn track_http(req: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let response = next.run(req).await;
+
+    let labels = [
+        ("method", method),
+        ("route", route),
+        ("status", response.status().as_u16().to_string()),
+    ];
+    metrics::counter!("http_requests_total", &labels).increment(1);
+    metrics::histogram!("http_request_duration_seconds", &labels)
+        .record(start.elapsed().as_secs_f64());
+
+    response
+}
+
+/// Times a storage backend call, counting it as an error if it fails.
+pub async fn observe_storage<T, F>(
+    backend: &str,
+    operation: &'static str,
+    future: F,
+) -> anyhow::Result<T>
+where
+    F: Future<Output = anyhow::Result<T>>,
+{
+    let start = Instant::now();
+    let result = future.await;
+
+    let labels = [
+        ("backend", backend.to_string()),
+        ("operation", operation.to_string()),
+    ];
+    metrics::histogram!("storage_operation_duration_seconds", &labels)
+        .record(start.elapsed().as_secs_f64());
+    if result.is_err() {
+        metrics::counter!("storage_operation_errors_total", &labels).increment(1);
+    }
+
+    result
+}
+
+pub fn record_upload(size: u64) {
+    metrics::counter!("file_server_upload_bytes_total").increment(size);
+    metrics::histogram!("file_server_upload_size_bytes").record(size as f64);
+}
+
+pub fn record_download(size: u64) {
+    metrics::counter!("file_server_download_bytes_total").increment(size);
+}
+
+/// `outcome` is `delivered`, `retry` or `failed`.
+pub fn record_webhook_delivery(outcome: &'static str) {
+    metrics::counter!("file_server_webhook_deliveries_total", "outcome" => outcome).increment(1);
+}
+
+/// `outcome` is `completed`, `retry` or `dead`.
+pub fn record_job(kind: &str, outcome: &'static str) {
+    metrics::counter!("file_server_jobs_total", "kind" => kind.to_string(), "outcome" => outcome)
+        .increment(1);
+}
+
+pub async fn serve_metrics(State(state): State<AppState>) -> Response {
+    metrics::gauge!("db_pool_connections").set(state.db.size() as f64);
+    metrics::gauge!("db_pool_idle_connections").set(state.db.num_idle() as f64);
+
+    match get_file_totals(&state.db).await {
+        Ok((count, bytes)) => {
+            metrics::gauge!("file_server_files_total").set(count as f64);
+            metrics::gauge!("file_server_files_bytes").set(bytes as f64);
+        }
+        Err(e) => tracing::error!("Failed to collect file totals: {}", e),
+    }
+
+    match count_jobs_by_status(&state.db).await {
+        Ok(counts) => {
+            for status in [JOB_QUEUED, JOB_RUNNING, JOB_COMPLETED, JOB_DEAD] {
+                let count = counts
+                    .iter()
+                    .find(|(counted, _)| counted == status)
+                    .map_or(0, |(_, count)| *count);
+                metrics::gauge!("file_server_jobs", "status" => status).set(count as f64);
+            }
+        }
+        Err(e) => tracing::error!("Failed to collect job counts: {}", e),
+    }
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(),
+    )
+        .into_response()
+}
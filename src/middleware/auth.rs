@@ -1,40 +1,110 @@
 use axum::{
-    extract::Request,
+    extract::{Request, State},
     http::{header::AUTHORIZATION, StatusCode},
     middleware::Next,
-    response::Response,
+    response::{Json, Response},
 };
 
-pub fn create_auth_middleware(
-    required_token: String,
-) -> impl Fn(
-    Request,
-    Next,
-) -> std::pin::Pin<
-    Box<dyn std::future::Future<Output = Result<Response, StatusCode>> + Send>,
-> + Clone {
-    move |req: Request, next: Next| {
-        let required_token = required_token.clone();
-        Box::pin(async move {
-            let auth_header = req
-                .headers()
-                .get(AUTHORIZATION)
-                .and_then(|header| header.to_str().ok());
-
-            let token = if let Some(auth_header) = auth_header {
-                if auth_header.starts_with("Bearer ") {
-                    Some(auth_header.trim_start_matches("Bearer "))
-                } else {
-                    None
-                }
-            } else {
-                None
-            };
-
-            match token {
-                Some(provided_token) if provided_token == required_token => Ok(next.run(req).await),
-                _ => Err(StatusCode::UNAUTHORIZED),
-            }
-        })
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::{json, Value};
+
+use crate::{
+    database::{count_api_keys, get_api_key_by_hash, touch_api_key},
+    handlers::AppState,
+    models::ApiKey,
+};
+
+/// The authenticated caller, inserted into request extensions by [`require_auth`].
+#[derive(Debug, Clone)]
+pub struct Actor(pub String);
+
+/// Accepts either the configured `FILE_SERVER_AUTH_TOKEN` or an API key
+/// created with `file-server-rs create-key`, as a bearer token or as the
+/// password of HTTP Basic credentials, which is all WebDAV clients can send.
+///
+/// While neither a token nor any API key exists, requests pass without
+/// credentials. This is checked on every request, so the first key created
+/// on a running server turns auth on.
+pub async fn require_auth(
+    State(state): State<AppState>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, (StatusCode, Json<Value>)> {
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(credential);
+
+    if let Some(token) = token.as_deref() {
+        if let Some(actor) = authenticate(&state, token).await? {
+            req.extensions_mut().insert(actor);
+            return Ok(next.run(req).await);
+        }
+    }
+
+    if state.config.auth_token.is_none() {
+        let api_key_count = count_api_keys(&state.db).await.map_err(database_error)?;
+        if api_key_count == 0 {
+            return Ok(next.run(req).await);
+        }
+    }
+
+    Err(unauthorized())
+}
+
+/// The caller `token` belongs to, if it is the configured token or an API key.
+async fn authenticate(
+    state: &AppState,
+    token: &str,
+) -> Result<Option<Actor>, (StatusCode, Json<Value>)> {
+    // Tokens are compared by their hashes, as API keys are looked up, so the
+    // time a comparison takes tells nothing about the token itself.
+    let hash = ApiKey::hash(token);
+    if let Some(auth_token) = &state.config.auth_token {
+        if hash == ApiKey::hash(auth_token.expose()) {
+            return Ok(Some(Actor("token".to_string())));
+        }
     }
+
+    let Some(api_key) = get_api_key_by_hash(&state.db, &hash)
+        .await
+        .map_err(database_error)?
+    else {
+        return Ok(None);
+    };
+
+    if let Err(e) = touch_api_key(&state.db, &api_key.id).await {
+        tracing::warn!("Failed to update API key usage: {}", e);
+    }
+    Ok(Some(Actor(format!("key:{}", api_key.id))))
+}
+
+fn database_error(e: anyhow::Error) -> (StatusCode, Json<Value>) {
+    tracing::error!("Failed to look up API key: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({"error": "Database error"})),
+    )
+}
+
+fn unauthorized() -> (StatusCode, Json<Value>) {
+    (
+        StatusCode::UNAUTHORIZED,
+        Json(json!({"error": "Unauthorized"})),
+    )
+}
+
+/// The token in an `Authorization` header. The user name of Basic
+/// credentials is ignored.
+fn credential(header: &str) -> Option<String> {
+    if let Some(token) = header.strip_prefix("Bearer ") {
+        return Some(token.to_string());
+    }
+
+    let encoded = header.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    decoded
+        .split_once(':')
+        .map(|(_, password)| password.to_string())
 }
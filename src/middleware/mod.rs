@@ -1,3 +1,7 @@
 pub mod auth;
+pub mod request_id;
+pub mod sigv4;
 
-pub use auth::create_auth_middleware;
+pub use auth::{require_auth, Actor};
+pub use request_id::{attach_request_id, make_request_span, REQUEST_ID_HEADER};
+pub use sigv4::{require_sigv4, Payload};
@@ -0,0 +1,75 @@
+use axum::{
+    body::{to_bytes, Body},
+    extract::Request,
+    http::{header, HeaderName},
+    middleware::Next,
+    response::Response,
+};
+use serde_json::Value;
+use tracing::Span;
+
+use crate::telemetry;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Error bodies are small JSON objects; anything larger is passed through untouched.
+const MAX_ERROR_BODY_SIZE: usize = 64 * 1024;
+
+fn request_id<B>(request: &axum::http::Request<B>) -> &str {
+    request
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+}
+
+/// Root span for each request, carrying the request ID so that every event
+/// logged while handling the request can be correlated.
+pub fn make_request_span<B>(request: &axum::http::Request<B>) -> Span {
+    let span = tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        version = ?request.version(),
+        request_id = %request_id(request),
+    );
+    telemetry::set_remote_parent(&span, request);
+    span
+}
+
+/// Adds the request ID to JSON error bodies so clients can quote it when
+/// reporting a problem.
+pub async fn attach_request_id(req: Request, next: Next) -> Response {
+    let request_id = request_id(&req).to_string();
+    let response = next.run(req).await;
+
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+
+    if request_id.is_empty() || !is_json || response.status().as_u16() < 400 {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match to_bytes(body, MAX_ERROR_BODY_SIZE).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::warn!("Failed to read error body: {}", e);
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+
+    let body = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(Value::Object(mut object)) => {
+            object.insert("request_id".to_string(), Value::String(request_id));
+            parts.headers.remove(header::CONTENT_LENGTH);
+            Body::from(Value::Object(object).to_string())
+        }
+        _ => Body::from(bytes),
+    };
+
+    Response::from_parts(parts, body)
+}
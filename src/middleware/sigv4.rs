@@ -0,0 +1,561 @@
+use axum::{
+    extract::{Request, State},
+    http::{request::Parts, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use bytes::{Bytes, BytesMut};
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use futures::StreamExt;
+use hmac::{Hmac, Mac};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, OnceLock};
+
+use crate::{
+    database::{get_api_key_by_id, touch_api_key},
+    handlers::{s3::S3Error, AppState},
+    middleware::Actor,
+    storage::ByteStream,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+const STREAMING_PAYLOAD: &str = "STREAMING-AWS4-HMAC-SHA256-PAYLOAD";
+const STREAMING_PAYLOAD_TRAILER: &str = "STREAMING-AWS4-HMAC-SHA256-PAYLOAD-TRAILER";
+const STREAMING_UNSIGNED_PAYLOAD_TRAILER: &str = "STREAMING-UNSIGNED-PAYLOAD-TRAILER";
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+const MAX_CLOCK_SKEW: TimeDelta = TimeDelta::minutes(15);
+const MAX_PRESIGNED_EXPIRY: i64 = 7 * 24 * 60 * 60;
+
+/// Limits for `aws-chunked` framing; SDKs send chunks of 64 KiB to 8 MiB.
+const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+const MAX_CHUNK_HEADER: usize = 4096;
+
+/// Everything but unreserved characters is escaped when signing.
+const URI_ENCODE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// How a verified request's body is protected, inserted into request
+/// extensions by [`require_sigv4`]. Bodies are only checked while they are
+/// read, see [`Payload::verify`].
+#[derive(Debug, Clone)]
+pub enum Payload {
+    Unsigned,
+    /// The hex SHA-256 the body must hash to.
+    Sha256(String),
+    /// An `aws-chunked` body, whose chunks are signed unless the signer is
+    /// missing.
+    Chunked(Option<ChunkSigner>),
+}
+
+/// Chains the signatures of `aws-chunked` chunks, starting from the
+/// signature of the request itself.
+#[derive(Debug, Clone)]
+pub struct ChunkSigner {
+    key: Vec<u8>,
+    timestamp: String,
+    scope: String,
+    previous: String,
+}
+
+/// Holds the error that made a body fail verification, since the upload
+/// code only sees a failed stream.
+#[derive(Debug, Clone, Default)]
+pub struct PayloadCheck(Arc<OnceLock<S3Error>>);
+
+/// The signature fields of a request, from either the `Authorization` header
+/// or the query string of a presigned URL.
+struct Signature {
+    access_key: String,
+    date: String,
+    region: String,
+    signed_headers: Vec<String>,
+    signature: String,
+    timestamp: String,
+    expires: Option<i64>,
+}
+
+/// Authenticates S3 requests with AWS Signature Version 4. The access key
+/// id is the id of an API key created with `create-key --s3`, and the
+/// secret access key its S3 secret. Any region is accepted.
+pub async fn require_sigv4(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let (mut parts, body) = request.into_parts();
+    match authenticate(&state, &mut parts).await {
+        Ok(()) => next.run(Request::from_parts(parts, body)).await,
+        Err(e) => e.into_response(),
+    }
+}
+
+async fn authenticate(state: &AppState, parts: &mut Parts) -> Result<(), S3Error> {
+    let query = query_pairs(parts.uri.query().unwrap_or_default());
+    let presigned = query.iter().any(|(name, _)| name == "X-Amz-Signature");
+    let signature = if presigned {
+        presigned_signature(&query)?
+    } else {
+        header_signature(parts)?
+    };
+
+    let timestamp = NaiveDateTime::parse_from_str(&signature.timestamp, "%Y%m%dT%H%M%SZ")
+        .map_err(|_| S3Error::access_denied("Invalid X-Amz-Date"))?
+        .and_utc();
+    if !signature.timestamp.starts_with(&signature.date) {
+        return Err(S3Error::access_denied(
+            "Credential date does not match X-Amz-Date",
+        ));
+    }
+    let now = Utc::now();
+    match signature.expires {
+        Some(expires) => {
+            if now + MAX_CLOCK_SKEW < timestamp || now > timestamp + TimeDelta::seconds(expires) {
+                return Err(S3Error::access_denied("Request has expired"));
+            }
+        }
+        None => {
+            if (now - timestamp).abs() > MAX_CLOCK_SKEW {
+                return Err(S3Error::new(
+                    StatusCode::FORBIDDEN,
+                    "RequestTimeTooSkewed",
+                    "The difference between the request time and the server's time is too large",
+                ));
+            }
+        }
+    }
+
+    let api_key = get_api_key_by_id(&state.db, &signature.access_key)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to look up API key: {}", e);
+            S3Error::internal()
+        })?;
+    let Some(secret) = api_key.as_ref().and_then(|key| key.s3_secret.as_deref()) else {
+        return Err(S3Error::new(
+            StatusCode::FORBIDDEN,
+            "InvalidAccessKeyId",
+            "The access key id does not exist",
+        ));
+    };
+
+    let payload_hash = if presigned {
+        UNSIGNED_PAYLOAD.to_string()
+    } else {
+        parts
+            .headers
+            .get("x-amz-content-sha256")
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| S3Error::invalid_request("Missing x-amz-content-sha256"))?
+            .to_string()
+    };
+
+    let canonical_request = canonical_request(parts, &query, &signature, &payload_hash);
+    let scope = format!("{}/{}/s3/aws4_request", signature.date, signature.region);
+    let string_to_sign = format!(
+        "{}\n{}\n{}\n{}",
+        ALGORITHM,
+        signature.timestamp,
+        scope,
+        sha256_hex(canonical_request.as_bytes())
+    );
+    let key = signing_key(secret, &signature.date, &signature.region);
+    if !verify(&key, &string_to_sign, &signature.signature) {
+        return Err(S3Error::signature_mismatch());
+    }
+
+    let payload = match payload_hash.as_str() {
+        UNSIGNED_PAYLOAD => Payload::Unsigned,
+        STREAMING_PAYLOAD | STREAMING_PAYLOAD_TRAILER => Payload::Chunked(Some(ChunkSigner {
+            key,
+            timestamp: signature.timestamp,
+            scope,
+            previous: signature.signature,
+        })),
+        STREAMING_UNSIGNED_PAYLOAD_TRAILER => Payload::Chunked(None),
+        hash if hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit()) => {
+            Payload::Sha256(hash.to_ascii_lowercase())
+        }
+        _ => {
+            return Err(S3Error::invalid_request(
+                "Unsupported x-amz-content-sha256 value",
+            ))
+        }
+    };
+
+    let api_key_id = signature.access_key;
+    if let Err(e) = touch_api_key(&state.db, &api_key_id).await {
+        tracing::warn!("Failed to update API key usage: {}", e);
+    }
+
+    parts
+        .extensions
+        .insert(Actor(format!("key:{}", api_key_id)));
+    parts.extensions.insert(payload);
+    Ok(())
+}
+
+/// Parses `AWS4-HMAC-SHA256 Credential=.., SignedHeaders=.., Signature=..`.
+fn header_signature(parts: &Parts) -> Result<Signature, S3Error> {
+    let header = |name: &str| {
+        parts
+            .headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+
+    let authorization =
+        header("authorization").ok_or_else(|| S3Error::access_denied("Requests must be signed"))?;
+    let fields = authorization
+        .strip_prefix(ALGORITHM)
+        .filter(|fields| fields.starts_with(' '))
+        .ok_or_else(|| S3Error::invalid_request("Only AWS4-HMAC-SHA256 is supported"))?;
+
+    let (mut credential, mut signed_headers, mut signature) = (None, None, None);
+    for field in fields.split(',') {
+        match field.trim().split_once('=') {
+            Some(("Credential", value)) => credential = Some(value),
+            Some(("SignedHeaders", value)) => signed_headers = Some(value),
+            Some(("Signature", value)) => signature = Some(value),
+            _ => {}
+        }
+    }
+    let (Some(credential), Some(signed_headers), Some(signature)) =
+        (credential, signed_headers, signature)
+    else {
+        return Err(S3Error::invalid_request("Malformed Authorization header"));
+    };
+
+    let timestamp = header("x-amz-date")
+        .ok_or_else(|| S3Error::access_denied("Missing X-Amz-Date"))?
+        .to_string();
+
+    signature_fields(credential, signed_headers, signature, timestamp, None)
+}
+
+/// Reads the `X-Amz-*` parameters of a presigned URL.
+fn presigned_signature(query: &[(String, String)]) -> Result<Signature, S3Error> {
+    let param = |name: &str| {
+        query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+            .ok_or_else(|| S3Error::access_denied(format!("Missing {}", name)))
+    };
+
+    if param("X-Amz-Algorithm")? != ALGORITHM {
+        return Err(S3Error::invalid_request(
+            "Only AWS4-HMAC-SHA256 is supported",
+        ));
+    }
+    let expires = param("X-Amz-Expires")?
+        .parse::<i64>()
+        .ok()
+        .filter(|expires| (1..=MAX_PRESIGNED_EXPIRY).contains(expires))
+        .ok_or_else(|| S3Error::access_denied("Invalid X-Amz-Expires"))?;
+
+    signature_fields(
+        param("X-Amz-Credential")?,
+        param("X-Amz-SignedHeaders")?,
+        param("X-Amz-Signature")?,
+        param("X-Amz-Date")?.to_string(),
+        Some(expires),
+    )
+}
+
+fn signature_fields(
+    credential: &str,
+    signed_headers: &str,
+    signature: &str,
+    timestamp: String,
+    expires: Option<i64>,
+) -> Result<Signature, S3Error> {
+    let [access_key, date, region, "s3", "aws4_request"] =
+        credential.split('/').collect::<Vec<_>>()[..]
+    else {
+        return Err(S3Error::access_denied("Malformed credential scope"));
+    };
+
+    Ok(Signature {
+        access_key: access_key.to_string(),
+        date: date.to_string(),
+        region: region.to_string(),
+        signed_headers: signed_headers
+            .split(';')
+            .map(|name| name.to_ascii_lowercase())
+            .collect(),
+        signature: signature.to_ascii_lowercase(),
+        timestamp,
+        expires,
+    })
+}
+
+fn canonical_request(
+    parts: &Parts,
+    query: &[(String, String)],
+    signature: &Signature,
+    payload_hash: &str,
+) -> String {
+    let uri = parts
+        .uri
+        .path()
+        .split('/')
+        .map(|segment| {
+            utf8_percent_encode(&percent_decode_str(segment).decode_utf8_lossy(), URI_ENCODE)
+                .to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+
+    let mut query = query
+        .iter()
+        .filter(|(name, _)| name != "X-Amz-Signature")
+        .map(|(name, value)| {
+            (
+                utf8_percent_encode(name, URI_ENCODE).to_string(),
+                utf8_percent_encode(value, URI_ENCODE).to_string(),
+            )
+        })
+        .collect::<Vec<_>>();
+    query.sort();
+    let query = query
+        .into_iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    let mut headers = String::new();
+    for name in &signature.signed_headers {
+        let values = parts
+            .headers
+            .get_all(name.as_str())
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .map(|value| value.split_whitespace().collect::<Vec<_>>().join(" "))
+            .collect::<Vec<_>>();
+        headers.push_str(&format!("{}:{}\n", name, values.join(",")));
+    }
+
+    format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        parts.method,
+        uri,
+        query,
+        headers,
+        signature.signed_headers.join(";"),
+        payload_hash
+    )
+}
+
+fn query_pairs(query: &str) -> Vec<(String, String)> {
+    let decode = |value: &str| percent_decode_str(value).decode_utf8_lossy().into_owned();
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(name), decode(value))
+        })
+        .collect()
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Compares in constant time.
+fn verify(key: &[u8], data: &str, signature: &str) -> bool {
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data.as_bytes());
+    mac.verify_slice(&signature).is_ok()
+}
+
+fn signing_key(secret: &str, date: &str, region: &str) -> Vec<u8> {
+    let key = hmac(format!("AWS4{}", secret).as_bytes(), date);
+    let key = hmac(&key, region);
+    let key = hmac(&key, "s3");
+    hmac(&key, "aws4_request")
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+impl ChunkSigner {
+    /// Checks the signature of the next chunk, which covers the previous
+    /// chunk's signature.
+    fn verify(&mut self, chunk: &[u8], signature: &str) -> bool {
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256-PAYLOAD\n{}\n{}\n{}\n{}\n{}",
+            self.timestamp,
+            self.scope,
+            self.previous,
+            EMPTY_SHA256,
+            sha256_hex(chunk)
+        );
+        if !verify(&self.key, &string_to_sign, signature) {
+            return false;
+        }
+        self.previous = signature.to_ascii_lowercase();
+        true
+    }
+}
+
+impl PayloadCheck {
+    /// Why the body was rejected, once the stream has failed.
+    pub fn error(&self) -> Option<S3Error> {
+        self.0.get().cloned()
+    }
+
+    fn fail(&self, error: S3Error) -> std::io::Error {
+        let message = error.to_string();
+        let _ = self.0.set(error);
+        std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+    }
+}
+
+impl Payload {
+    /// Wraps a request body so that it fails unless it matches its signature,
+    /// and strips `aws-chunked` framing. Trailing checksums are not checked.
+    pub fn verify<'a>(self, data: ByteStream<'a>) -> (ByteStream<'a>, PayloadCheck) {
+        let check = PayloadCheck::default();
+        let stream = match self {
+            Payload::Unsigned => data,
+            Payload::Sha256(expected) => {
+                let state = (data, Sha256::new(), expected, check.clone());
+                futures::stream::try_unfold(
+                    state,
+                    |(mut data, mut hasher, expected, check)| async move {
+                        match data.next().await {
+                            Some(chunk) => {
+                                let chunk = chunk?;
+                                hasher.update(&chunk);
+                                Ok(Some((chunk, (data, hasher, expected, check))))
+                            }
+                            None if hex::encode(hasher.finalize()) == expected => Ok(None),
+                            None => Err(check.fail(S3Error::new(
+                                StatusCode::BAD_REQUEST,
+                                "XAmzContentSHA256Mismatch",
+                                "The body does not match x-amz-content-sha256",
+                            ))),
+                        }
+                    },
+                )
+                .boxed()
+            }
+            Payload::Chunked(signer) => {
+                let body = ChunkedBody {
+                    data,
+                    buffer: BytesMut::new(),
+                    signer,
+                    check: check.clone(),
+                    finished: false,
+                };
+                futures::stream::try_unfold(body, |mut body| async move {
+                    Ok(body.next_chunk().await?.map(|chunk| (chunk, body)))
+                })
+                .boxed()
+            }
+        };
+        (stream, check)
+    }
+}
+
+/// Decodes `aws-chunked` framing: `<hex size>[;chunk-signature=<sig>]\r\n`,
+/// the data and `\r\n`, ending with a zero-sized chunk and optional
+/// trailers.
+struct ChunkedBody<'a> {
+    data: ByteStream<'a>,
+    buffer: BytesMut,
+    signer: Option<ChunkSigner>,
+    check: PayloadCheck,
+    finished: bool,
+}
+
+impl ChunkedBody<'_> {
+    async fn next_chunk(&mut self) -> std::io::Result<Option<Bytes>> {
+        while !self.finished {
+            if let Some(chunk) = self.parse_chunk()? {
+                if chunk.is_empty() {
+                    self.finished = true;
+                    break;
+                }
+                return Ok(Some(chunk));
+            }
+
+            match self.data.next().await {
+                Some(chunk) => self.buffer.extend_from_slice(&chunk?),
+                None => {
+                    return Err(self.check.fail(S3Error::new(
+                        StatusCode::BAD_REQUEST,
+                        "IncompleteBody",
+                        "The body ended before the final chunk",
+                    )))
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Takes the next complete chunk from the buffer, if there is one.
+    fn parse_chunk(&mut self) -> std::io::Result<Option<Bytes>> {
+        let Some(line_end) = self.buffer.windows(2).position(|bytes| bytes == b"\r\n") else {
+            if self.buffer.len() > MAX_CHUNK_HEADER {
+                return Err(self.malformed());
+            }
+            return Ok(None);
+        };
+
+        let line = std::str::from_utf8(&self.buffer[..line_end]).map_err(|_| self.malformed())?;
+        let (size, signature) = match line.split_once(';') {
+            Some((size, extension)) => (size, extension.strip_prefix("chunk-signature=")),
+            None => (line, None),
+        };
+        let size = usize::from_str_radix(size.trim(), 16)
+            .ok()
+            .filter(|size| *size <= MAX_CHUNK_SIZE)
+            .ok_or_else(|| self.malformed())?;
+        let signature = signature.map(str::to_string);
+
+        // The final chunk is followed by trailers rather than a line break.
+        let frame_len = line_end + 2 + size + if size == 0 { 0 } else { 2 };
+        if self.buffer.len() < frame_len {
+            return Ok(None);
+        }
+
+        let mut frame = self.buffer.split_to(frame_len);
+        let _ = frame.split_to(line_end + 2);
+        let chunk = frame.split_to(size).freeze();
+        if size > 0 && frame[..] != b"\r\n"[..] {
+            return Err(self.malformed());
+        }
+
+        if let Some(signer) = self.signer.as_mut() {
+            let valid = signature
+                .as_deref()
+                .is_some_and(|signature| signer.verify(&chunk, signature));
+            if !valid {
+                return Err(self.check.fail(S3Error::signature_mismatch()));
+            }
+        }
+
+        Ok(Some(chunk))
+    }
+
+    fn malformed(&self) -> std::io::Error {
+        self.check
+            .fail(S3Error::invalid_request("Malformed aws-chunked body"))
+    }
+}
@@ -0,0 +1,57 @@
+use chrono::{DateTime, Utc};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::FromRow;
+use uuid::Uuid;
+
+const KEY_PREFIX: &str = "fsk_";
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    #[serde(skip_serializing)]
+    pub key_hash: String,
+    /// Secret access key for the S3 API. SigV4 signatures can only be checked
+    /// with the secret itself, so unlike the key it is stored as is.
+    #[serde(skip_serializing)]
+    pub s3_secret: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    /// Generates a new key, returning the row to store and the plaintext key.
+    /// Only a hash of the key is persisted, so the plaintext cannot be recovered later.
+    pub fn generate(name: String) -> (Self, String) {
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        let key = format!("{}{}", KEY_PREFIX, hex::encode(secret));
+
+        let api_key = Self {
+            id: Uuid::new_v7(uuid::timestamp::Timestamp::now(uuid::NoContext)).to_string(),
+            name,
+            key_hash: Self::hash(&key),
+            s3_secret: None,
+            created_at: Utc::now(),
+            last_used_at: None,
+        };
+
+        (api_key, key)
+    }
+
+    /// Issues S3 credentials for the key: its id is the access key id and the
+    /// returned secret the secret access key.
+    pub fn generate_s3_secret(&mut self) -> String {
+        let mut secret = [0u8; 20];
+        OsRng.fill_bytes(&mut secret);
+        let secret = hex::encode(secret);
+        self.s3_secret = Some(secret.clone());
+        secret
+    }
+
+    pub fn hash(key: &str) -> String {
+        hex::encode(Sha256::digest(key.as_bytes()))
+    }
+}
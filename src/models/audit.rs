@@ -0,0 +1,79 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditAction {
+    Upload,
+    Download,
+    Delete,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Upload => "upload",
+            AuditAction::Download => "download",
+            AuditAction::Delete => "delete",
+        }
+    }
+}
+
+impl std::fmt::Display for AuditAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct AuditEvent {
+    pub id: String,
+    pub action: String,
+    pub outcome: String,
+    pub status_code: i64,
+    pub file_id: Option<String>,
+    pub actor: String,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl AuditEvent {
+    pub fn new(
+        action: AuditAction,
+        status_code: u16,
+        file_id: Option<String>,
+        actor: String,
+        ip_address: Option<String>,
+        user_agent: Option<String>,
+    ) -> Self {
+        let outcome = if status_code < 400 {
+            "success"
+        } else {
+            "failure"
+        };
+        Self {
+            id: Uuid::new_v7(uuid::timestamp::Timestamp::now(uuid::NoContext)).to_string(),
+            action: action.to_string(),
+            outcome: outcome.to_string(),
+            status_code: status_code as i64,
+            file_id,
+            actor,
+            ip_address,
+            user_agent,
+            created_at: Utc::now(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditEventFilter {
+    pub action: Option<String>,
+    pub outcome: Option<String>,
+    pub file_id: Option<String>,
+    pub actor: Option<String>,
+    pub ip_address: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
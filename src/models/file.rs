@@ -3,6 +3,9 @@ use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 use uuid::Uuid;
 
+use super::FileLabels;
+use crate::storage::{encrypted_size, Codec, WrappedKey};
+
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
 pub struct File {
     pub id: String,
@@ -11,17 +14,57 @@ pub struct File {
     pub size: i64,
     pub storage_type: String,
     pub is_private: bool,
+    pub is_broken: bool,
+    /// Folder path, empty for the root.
+    pub folder: String,
+    /// Master key the data key is wrapped with; unset for unencrypted blobs.
+    #[serde(skip_serializing)]
+    pub encryption_key_id: Option<String>,
+    #[serde(skip_serializing)]
+    pub wrapped_key: Option<String>,
+    /// Codec the blob is compressed with, e.g. `gzip` or `zstd`.
+    pub compression: Option<String>,
+    /// Size of the compressed content; `size` is always the original size.
+    pub compressed_size: Option<i64>,
+    /// One of the `STATUS_*` values.
+    pub status: String,
+    /// Processing job of the latest upload of the file's content.
+    pub job_id: Option<String>,
+    /// Why processing failed.
+    pub status_error: Option<String>,
+    /// Hex SHA-256 of the content, set once it has been processed.
+    pub sha256: Option<String>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
+/// Processing states of a file's content. Only ready files are served by
+/// `/files/uploads/:id`.
+pub const STATUS_PENDING: &str = "pending";
+pub const STATUS_PROCESSING: &str = "processing";
+pub const STATUS_READY: &str = "ready";
+pub const STATUS_FAILED: &str = "failed";
+
+/// Why the content of a file cannot be downloaded yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotReady {
+    /// Pending or processing.
+    Processing,
+    Failed,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FileResponse {
     pub id: String,
     pub name: String,
+    pub folder: String,
     pub size: i64,
     pub storage_type: String,
+    pub status: String,
+    pub sha256: Option<String>,
     pub created_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub labels: FileLabels,
 }
 
 impl From<File> for FileResponse {
@@ -29,20 +72,93 @@ impl From<File> for FileResponse {
         Self {
             id: file.id,
             name: file.name,
+            folder: file.folder,
             size: file.size,
             storage_type: file.storage_type,
+            status: file.status,
+            sha256: file.sha256,
             created_at: file.created_at,
+            labels: FileLabels::default(),
+        }
+    }
+}
+
+/// Where a file's processing stands, as returned by the status endpoint.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileStatusResponse {
+    pub id: String,
+    pub job_id: Option<String>,
+    pub status: String,
+    pub error: Option<String>,
+    pub sha256: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl FileStatusResponse {
+    /// Whether processing is over, successfully or not.
+    pub fn is_final(&self) -> bool {
+        matches!(self.status.as_str(), STATUS_READY | STATUS_FAILED)
+    }
+}
+
+impl From<&File> for FileStatusResponse {
+    fn from(file: &File) -> Self {
+        Self {
+            id: file.id.clone(),
+            job_id: file.job_id.clone(),
+            status: file.status.clone(),
+            error: file.status_error.clone(),
+            sha256: file.sha256.clone(),
+            updated_at: file.updated_at,
         }
     }
 }
 
+/// Narrows a file listing to files with a tag and/or a metadata entry.
+#[derive(Debug, Default)]
+pub struct FileFilter {
+    pub tag: Option<String>,
+    pub metadata: Option<(String, String)>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UploadResponse {
     pub file_path: String,
+    pub status_path: String,
     pub storage_type: String,
     pub data: File,
 }
 
+/// Outcomes of the entries of an archive uploaded with `extract=true`.
+pub const ENTRY_CREATED: &str = "created";
+pub const ENTRY_FOLDER: &str = "folder";
+pub const ENTRY_SKIPPED: &str = "skipped";
+pub const ENTRY_FAILED: &str = "failed";
+
+/// What became of one archive entry.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExtractedEntry {
+    /// The entry's path in the archive.
+    pub path: String,
+    /// One of the `ENTRY_*` values.
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<FileResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExtractResponse {
+    pub archive: String,
+    /// Files created from the archive.
+    pub created: usize,
+    pub entries: Vec<ExtractedEntry>,
+    /// Why extraction stopped before the end of the archive.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
 impl File {
     pub fn new(path: String, name: String, size: i64, storage_type: String) -> Self {
         let now = Utc::now();
@@ -53,8 +169,82 @@ impl File {
             size,
             storage_type,
             is_private: false,
+            is_broken: false,
+            folder: String::new(),
+            encryption_key_id: None,
+            wrapped_key: None,
+            compression: None,
+            compressed_size: None,
+            status: STATUS_READY.to_string(),
+            job_id: None,
+            status_error: None,
+            sha256: None,
             created_at: now,
             updated_at: now,
         }
     }
+
+    /// The data key the blob is encrypted with, if it is encrypted.
+    pub fn blob_key(&self) -> Option<WrappedKey> {
+        Some(WrappedKey {
+            key_id: self.encryption_key_id.clone()?,
+            wrapped: self.wrapped_key.clone()?,
+        })
+    }
+
+    pub fn set_blob_key(&mut self, key: Option<WrappedKey>) {
+        (self.encryption_key_id, self.wrapped_key) = match key {
+            Some(key) => (Some(key.key_id), Some(key.wrapped)),
+            None => (None, None),
+        };
+    }
+
+    pub fn codec(&self) -> Option<Codec> {
+        self.compression.as_deref().and_then(Codec::parse)
+    }
+
+    pub fn set_compression(&mut self, compression: Option<(Codec, u64)>) {
+        (self.compression, self.compressed_size) = match compression {
+            Some((codec, size)) => (Some(codec.to_string()), Some(size as i64)),
+            None => (None, None),
+        };
+    }
+
+    /// Size of the blob in storage, which differs from `size` when it is
+    /// compressed or encrypted.
+    pub fn stored_size(&self) -> i64 {
+        let size = self.compressed_size.unwrap_or(self.size);
+        match self.encryption_key_id {
+            Some(_) => encrypted_size(size as u64) as i64,
+            None => size,
+        }
+    }
+
+    /// Marks the content as awaiting processing by a new job.
+    pub fn start_processing(&mut self) {
+        self.status = STATUS_PENDING.to_string();
+        self.job_id =
+            Some(Uuid::new_v7(uuid::timestamp::Timestamp::now(uuid::NoContext)).to_string());
+        self.status_error = None;
+        self.sha256 = None;
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.status == STATUS_READY
+    }
+
+    /// Whether the content can be downloaded, which it can only once
+    /// processing succeeded, whatever the protocol.
+    pub fn check_ready(&self) -> Result<(), NotReady> {
+        match self.status.as_str() {
+            STATUS_READY => Ok(()),
+            STATUS_FAILED => Err(NotReady::Failed),
+            _ => Err(NotReady::Processing),
+        }
+    }
+
+    /// Changes whenever the file is replaced, renamed or moved.
+    pub fn etag(&self) -> String {
+        format!("\"{}-{}\"", self.id, self.updated_at.timestamp_micros())
+    }
 }
@@ -0,0 +1,57 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+/// A folder that files can be placed in. Paths are `/`-separated without a
+/// leading or trailing slash; the root folder is the empty path and has no
+/// row.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Folder {
+    pub path: String,
+    pub parent: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Folder {
+    pub fn new(path: String) -> Self {
+        Self {
+            parent: parent_folder(&path).to_string(),
+            path,
+            created_at: Utc::now(),
+        }
+    }
+
+    /// The last segment of the path.
+    pub fn name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or_default()
+    }
+}
+
+/// The folder containing `path`, or the root for top-level paths.
+pub fn parent_folder(path: &str) -> &str {
+    path.rsplit_once('/')
+        .map(|(parent, _)| parent)
+        .unwrap_or("")
+}
+
+/// Joins a folder and a name into a path.
+pub fn join_folder(folder: &str, name: &str) -> String {
+    if folder.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", folder, name)
+    }
+}
+
+/// Normalizes a user-supplied folder path, rejecting empty, `.` and `..`
+/// segments.
+pub fn normalize_folder(path: &str) -> Option<String> {
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        return Some(String::new());
+    }
+
+    path.split('/')
+        .all(|segment| !matches!(segment, "" | "." | "..") && !segment.contains('\\'))
+        .then(|| path.to_string())
+}
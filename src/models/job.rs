@@ -0,0 +1,88 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::FromRow;
+
+/// Job states. Queued and running jobs form the queue; a job that runs out of
+/// attempts is dead until it is retried.
+pub const JOB_QUEUED: &str = "queued";
+pub const JOB_RUNNING: &str = "running";
+pub const JOB_COMPLETED: &str = "completed";
+pub const JOB_DEAD: &str = "dead";
+
+/// Reads back, checks and hashes an uploaded file. The job id is the file's
+/// `job_id`.
+pub const JOB_PROCESS_FILE: &str = "process_file";
+
+/// A unit of background work, stored in the database until it completes.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Job {
+    pub id: String,
+    pub kind: String,
+    /// JSON arguments of the job, depending on its kind.
+    pub payload: String,
+    pub status: String,
+    pub attempts: i64,
+    /// When the job is due, or when the claim of a running job expires.
+    pub run_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+impl Job {
+    pub fn new(id: String, kind: &str, payload: Value) -> Self {
+        let now = Utc::now();
+        Self {
+            id,
+            kind: kind.to_string(),
+            payload: payload.to_string(),
+            status: JOB_QUEUED.to_string(),
+            attempts: 0,
+            run_at: now,
+            last_error: None,
+            created_at: now,
+            updated_at: now,
+            completed_at: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobResponse {
+    pub id: String,
+    pub kind: String,
+    pub payload: Value,
+    pub status: String,
+    pub attempts: i64,
+    pub run_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+impl From<Job> for JobResponse {
+    fn from(job: Job) -> Self {
+        Self {
+            payload: serde_json::from_str(&job.payload).unwrap_or(Value::String(job.payload)),
+            id: job.id,
+            kind: job.kind,
+            status: job.status,
+            attempts: job.attempts,
+            run_at: job.run_at,
+            last_error: job.last_error,
+            created_at: job.created_at,
+            updated_at: job.updated_at,
+            completed_at: job.completed_at,
+        }
+    }
+}
+
+/// Narrows a job listing.
+#[derive(Debug, Default)]
+pub struct JobFilter {
+    pub status: Option<String>,
+    pub kind: Option<String>,
+}
@@ -0,0 +1,75 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Most tags, and most metadata entries, a single file can have.
+pub const MAX_LABELS: usize = 64;
+const MAX_NAME_LEN: usize = 128;
+const MAX_VALUE_LEN: usize = 1024;
+
+/// Tags and key/value metadata attached to a file, e.g. the commit and build
+/// number an artifact was produced by.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileLabels {
+    pub tags: BTreeSet<String>,
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl FileLabels {
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty() && self.metadata.is_empty()
+    }
+
+    /// Parses the `tags` upload field, a JSON array of strings. Duplicates
+    /// are dropped.
+    pub fn parse_tags(json: &str) -> Result<BTreeSet<String>, String> {
+        let tags: Vec<String> = serde_json::from_str(json)
+            .map_err(|_| "tags must be a JSON array of strings".to_string())?;
+        if tags.len() > MAX_LABELS {
+            return Err(format!("A file can have at most {} tags", MAX_LABELS));
+        }
+
+        tags.into_iter()
+            .map(|tag| {
+                let tag = tag.trim().to_string();
+                valid_name(&tag)
+                    .then_some(tag)
+                    .ok_or_else(|| format!("Tags must be 1 to {} characters", MAX_NAME_LEN))
+            })
+            .collect()
+    }
+
+    /// Parses the `metadata` upload field, a JSON object with string values.
+    pub fn parse_metadata(json: &str) -> Result<BTreeMap<String, String>, String> {
+        let metadata: BTreeMap<String, String> = serde_json::from_str(json)
+            .map_err(|_| "metadata must be a JSON object with string values".to_string())?;
+        if metadata.len() > MAX_LABELS {
+            return Err(format!(
+                "A file can have at most {} metadata entries",
+                MAX_LABELS
+            ));
+        }
+
+        for (key, value) in &metadata {
+            // Keys cannot contain `=`, which separates them from the value in
+            // the `metadata` list filter.
+            if !valid_name(key) || key.contains('=') || key.trim() != key {
+                return Err(format!(
+                    "Metadata keys must be 1 to {} characters without '=' or surrounding spaces",
+                    MAX_NAME_LEN
+                ));
+            }
+            if value.chars().count() > MAX_VALUE_LEN {
+                return Err(format!(
+                    "Metadata values must be at most {} characters",
+                    MAX_VALUE_LEN
+                ));
+            }
+        }
+
+        Ok(metadata)
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().count() <= MAX_NAME_LEN && !name.chars().any(char::is_control)
+}
@@ -1,3 +1,18 @@
+pub mod api_key;
+pub mod audit;
 pub mod file;
+pub mod folder;
+pub mod job;
+pub mod labels;
+pub mod webhook;
 
-pub use file::{File, FileResponse, UploadResponse};
+pub use api_key::ApiKey;
+pub use audit::{AuditAction, AuditEvent, AuditEventFilter};
+pub use file::{
+    ExtractResponse, ExtractedEntry, File, FileFilter, FileResponse, FileStatusResponse,
+    UploadResponse,
+};
+pub use folder::{join_folder, normalize_folder, parent_folder, Folder};
+pub use job::{Job, JobFilter, JobResponse};
+pub use labels::FileLabels;
+pub use webhook::{Webhook, WebhookDelivery, WebhookEvent, WebhookResponse};
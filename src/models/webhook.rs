@@ -0,0 +1,152 @@
+use chrono::{DateTime, Utc};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use uuid::Uuid;
+
+const SECRET_PREFIX: &str = "whsec_";
+
+/// File lifecycle events webhooks can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebhookEvent {
+    /// A new file was stored.
+    Upload,
+    /// A file was deleted.
+    Delete,
+    /// An existing file got new content, keeping its id.
+    Version,
+    /// A file was renamed or moved to another folder, keeping its id.
+    Move,
+}
+
+impl WebhookEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookEvent::Upload => "upload",
+            WebhookEvent::Delete => "delete",
+            WebhookEvent::Version => "version",
+            WebhookEvent::Move => "move",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "upload" => Some(WebhookEvent::Upload),
+            "delete" => Some(WebhookEvent::Delete),
+            "version" => Some(WebhookEvent::Version),
+            "move" => Some(WebhookEvent::Move),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for WebhookEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    /// Key deliveries are signed with. Shown once when the webhook is created.
+    #[serde(skip_serializing)]
+    pub secret: String,
+    /// Comma-separated event names.
+    pub events: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Webhook {
+    /// Creates a webhook with a fresh signing secret.
+    pub fn new(url: String, events: &[WebhookEvent]) -> Self {
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+
+        Self {
+            id: Uuid::new_v7(uuid::timestamp::Timestamp::now(uuid::NoContext)).to_string(),
+            url,
+            secret: format!("{}{}", SECRET_PREFIX, hex::encode(secret)),
+            events: events
+                .iter()
+                .map(WebhookEvent::as_str)
+                .collect::<Vec<_>>()
+                .join(","),
+            created_at: Utc::now(),
+        }
+    }
+
+    pub fn subscribes_to(&self, event: WebhookEvent) -> bool {
+        self.events.split(',').any(|name| name == event.as_str())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhookResponse {
+    pub id: String,
+    pub url: String,
+    pub events: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    /// Only returned when the webhook is created.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+impl From<Webhook> for WebhookResponse {
+    fn from(webhook: Webhook) -> Self {
+        Self {
+            events: webhook.events.split(',').map(str::to_string).collect(),
+            id: webhook.id,
+            url: webhook.url,
+            created_at: webhook.created_at,
+            secret: None,
+        }
+    }
+}
+
+/// Delivery states. Pending deliveries form the outbox; delivered and failed
+/// ones are kept as the delivery log.
+pub const DELIVERY_PENDING: &str = "pending";
+pub const DELIVERY_DELIVERED: &str = "delivered";
+pub const DELIVERY_FAILED: &str = "failed";
+
+/// One event for one webhook, along with the outcome of its latest attempt.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct WebhookDelivery {
+    pub id: String,
+    pub webhook_id: String,
+    pub event: String,
+    pub file_id: String,
+    /// The JSON body, identical for every attempt so that it can be verified
+    /// against the signature.
+    #[serde(skip_serializing)]
+    pub payload: String,
+    pub status: String,
+    pub attempts: i64,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_status_code: Option<i64>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+impl WebhookDelivery {
+    pub fn new(webhook_id: String, event: WebhookEvent, file_id: String, payload: String) -> Self {
+        let now = Utc::now();
+        Self {
+            id: Uuid::new_v7(uuid::timestamp::Timestamp::now(uuid::NoContext)).to_string(),
+            webhook_id,
+            event: event.to_string(),
+            file_id,
+            payload,
+            status: DELIVERY_PENDING.to_string(),
+            attempts: 0,
+            next_attempt_at: now,
+            last_status_code: None,
+            last_error: None,
+            created_at: now,
+            delivered_at: None,
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use anyhow::Result;
+use futures::StreamExt;
+use serde::Serialize;
+use std::{collections::HashSet, path::Path};
+use tokio::{fs, io::AsyncWriteExt};
+
+use crate::{
+    database::{get_all_files, get_file_labels, DbPool},
+    models::{File, FileLabels},
+    storage::{Storage, StorageRegistry},
+};
+
+#[derive(Debug, Serialize)]
+pub struct ExportedFile {
+    pub exported_as: String,
+    #[serde(flatten)]
+    pub file: File,
+    #[serde(flatten)]
+    pub labels: FileLabels,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ExportReport {
+    pub exported: usize,
+    pub exported_bytes: u64,
+    pub failed: Vec<String>,
+}
+
+/// Writes every stored file into `dir` under its original name, plus a
+/// `manifest.json` describing each exported row. Name clashes get the file id
+/// appended.
+pub async fn run(db: &DbPool, storages: &StorageRegistry, dir: &Path) -> Result<ExportReport> {
+    fs::create_dir_all(dir).await?;
+
+    let mut report = ExportReport::default();
+    let mut manifest = Vec::new();
+    let mut used_names = HashSet::new();
+
+    for file in get_all_files(db).await? {
+        if file.is_broken {
+            continue;
+        }
+
+        let storage = match storages.require(&file.storage_type) {
+            Ok(storage) => storage,
+            Err(e) => {
+                tracing::error!("Failed to read {}: {}", file.id, e);
+                report.failed.push(file.id);
+                continue;
+            }
+        };
+        let exported_as = unique_name(&file, &mut used_names);
+        let size = match write_content(storage, &file, &dir.join(&exported_as)).await {
+            Ok(size) => size,
+            Err(e) => {
+                tracing::error!("Failed to export {} ({}): {}", file.id, file.path, e);
+                report.failed.push(file.id);
+                continue;
+            }
+        };
+
+        let labels = get_file_labels(db, &[&file.id])
+            .await?
+            .remove(&file.id)
+            .unwrap_or_default();
+
+        report.exported += 1;
+        report.exported_bytes += size;
+        manifest.push(ExportedFile {
+            exported_as,
+            file,
+            labels,
+        });
+    }
+
+    fs::write(
+        dir.join("manifest.json"),
+        serde_json::to_vec_pretty(&manifest)?,
+    )
+    .await?;
+
+    Ok(report)
+}
+
+fn unique_name(file: &File, used_names: &mut HashSet<String>) -> String {
+    // Only keep the final path component so a crafted name cannot escape `dir`.
+    let name = Path::new(&file.name)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .filter(|name| name != "manifest.json")
+        .unwrap_or_else(|| file.id.clone());
+
+    if used_names.insert(name.clone()) {
+        return name;
+    }
+
+    let path = Path::new(&name);
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let renamed = match path.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, file.id, ext.to_string_lossy()),
+        None => format!("{}-{}", stem, file.id),
+    };
+    used_names.insert(renamed.clone());
+    renamed
+}
+
+/// Streams the original content of `file` to `path`, removing the partial
+/// copy on failure, and returns its size.
+async fn write_content(storage: &Storage, file: &File, path: &Path) -> Result<u64> {
+    let result = async {
+        let mut data = storage
+            .get_content(&file.path, file.blob_key().as_ref(), file.codec())
+            .await?;
+        let mut output = fs::File::create(path).await?;
+        let mut size = 0;
+        while let Some(chunk) = data.next().await {
+            let chunk = chunk?;
+            size += chunk.len() as u64;
+            output.write_all(&chunk).await?;
+        }
+        output.flush().await?;
+        Ok(size)
+    }
+    .await;
+
+    if result.is_err() {
+        let _ = fs::remove_file(path).await;
+    }
+    result
+}
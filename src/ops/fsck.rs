@@ -0,0 +1,123 @@
+use anyhow::Result;
+use chrono::{Duration, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::{
+    database::{get_files_by_storage_type, mark_file_broken, DbPool},
+    storage::{Storage, StoredObject},
+};
+
+/// Blobs younger than this are skipped when looking for orphans, since an
+/// upload writes its blob before inserting the `files` row.
+const ORPHAN_GRACE_PERIOD_MINUTES: i64 = 10;
+
+#[derive(Debug, Serialize)]
+pub struct MissingBlob {
+    pub file_id: String,
+    pub path: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SizeMismatch {
+    pub file_id: String,
+    pub path: String,
+    pub expected_size: i64,
+    pub actual_size: i64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct FsckReport {
+    pub storage_type: String,
+    pub checked_files: usize,
+    pub checked_blobs: usize,
+    pub orphaned_blobs: Vec<StoredObject>,
+    pub missing_blobs: Vec<MissingBlob>,
+    pub size_mismatches: Vec<SizeMismatch>,
+    pub deleted_orphans: usize,
+    pub marked_broken: usize,
+}
+
+/// Compares the blobs in `storage` against the `files` rows that point at it.
+/// With `repair`, orphaned blobs are deleted and rows whose blob is missing or
+/// has the wrong size are marked broken.
+pub async fn run(db: &DbPool, storage: &Storage, repair: bool) -> Result<FsckReport> {
+    let storage_type = storage.name().to_string();
+    let files = get_files_by_storage_type(db, &storage_type).await?;
+    let blobs = storage.list_files().await?;
+
+    let mut report = FsckReport {
+        storage_type,
+        checked_files: files.len(),
+        checked_blobs: blobs.len(),
+        ..Default::default()
+    };
+
+    let mut blobs_by_path: HashMap<String, StoredObject> = blobs
+        .into_iter()
+        .map(|blob| (blob.path.clone(), blob))
+        .collect();
+
+    for file in files {
+        let expected_size = file.stored_size();
+        match blobs_by_path.remove(&file.path) {
+            None => report.missing_blobs.push(MissingBlob {
+                file_id: file.id,
+                path: file.path,
+            }),
+            Some(blob) if blob.size != expected_size => report.size_mismatches.push(SizeMismatch {
+                file_id: file.id,
+                path: file.path,
+                expected_size,
+                actual_size: blob.size,
+            }),
+            Some(_) => {}
+        }
+    }
+
+    let grace_cutoff = Utc::now() - Duration::minutes(ORPHAN_GRACE_PERIOD_MINUTES);
+    report.orphaned_blobs = blobs_by_path
+        .into_values()
+        .filter(|blob| {
+            blob.modified_at
+                .is_none_or(|modified| modified < grace_cutoff)
+        })
+        .collect();
+    report.orphaned_blobs.sort_by(|a, b| a.path.cmp(&b.path));
+
+    if repair {
+        for blob in &report.orphaned_blobs {
+            match storage.delete_file(&blob.path).await {
+                Ok(()) => report.deleted_orphans += 1,
+                Err(e) => tracing::error!("Failed to delete orphaned blob {}: {}", blob.path, e),
+            }
+        }
+
+        let broken_ids = report
+            .missing_blobs
+            .iter()
+            .map(|missing| &missing.file_id)
+            .chain(
+                report
+                    .size_mismatches
+                    .iter()
+                    .map(|mismatch| &mismatch.file_id),
+            );
+        for file_id in broken_ids {
+            if mark_file_broken(db, file_id).await? {
+                report.marked_broken += 1;
+            }
+        }
+    }
+
+    tracing::info!(
+        "fsck checked {} files and {} blobs: {} orphaned, {} missing, {} size mismatches",
+        report.checked_files,
+        report.checked_blobs,
+        report.orphaned_blobs.len(),
+        report.missing_blobs.len(),
+        report.size_mismatches.len()
+    );
+
+    Ok(report)
+}
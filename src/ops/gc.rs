@@ -0,0 +1,39 @@
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::{audit, config::Config, database::DbPool, ops::fsck, storage::StorageRegistry};
+
+#[derive(Debug, Default, Serialize)]
+pub struct GcReport {
+    pub deleted_orphans: usize,
+    pub purged_audit_events: u64,
+}
+
+/// Reclaims space: removes partially written files and orphaned blobs, and
+/// purges audit events past `FILE_SERVER_AUDIT_RETENTION_DAYS`. Unlike
+/// `fsck --repair` it never touches `files` rows.
+///
+/// Safe to run against a live deployment: partial files and orphans younger
+/// than ten minutes are kept, since they may belong to uploads still in
+/// progress on a running server.
+pub async fn run(db: &DbPool, storages: &StorageRegistry, config: &Config) -> Result<GcReport> {
+    let mut report = GcReport::default();
+
+    for storage in storages.backends() {
+        storage.cleanup_partial_uploads().await;
+
+        let fsck_report = fsck::run(db, storage, false).await?;
+        for blob in &fsck_report.orphaned_blobs {
+            match storage.delete_file(&blob.path).await {
+                Ok(()) => report.deleted_orphans += 1,
+                Err(e) => tracing::error!("Failed to delete orphaned blob {}: {}", blob.path, e),
+            }
+        }
+    }
+
+    if let Some(retention_days) = config.audit_retention_days {
+        report.purged_audit_events = audit::purge_expired(db, retention_days).await?;
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,134 @@
+use anyhow::Result;
+use futures::StreamExt;
+use serde::Serialize;
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+};
+use tokio::fs;
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    config::Config,
+    database::{create_file, DbPool},
+    models::{File, FileLabels},
+    storage::{self, Compression, Storage, StorageRegistry},
+};
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub imported_bytes: u64,
+    pub skipped: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+/// Ingests every regular file below `dir` into storage and the `files` table.
+/// Files over `max_file_size` are skipped; symlinks are not followed. Each
+/// file goes to the backend chosen by the routing rules, compressed like an
+/// upload of the same type would be.
+pub async fn run(
+    db: &DbPool,
+    storages: &StorageRegistry,
+    config: &Config,
+    dir: &Path,
+) -> Result<ImportReport> {
+    let mut report = ImportReport::default();
+    let compression = Compression::from_config(config);
+    let mut pending_dirs: Vec<PathBuf> = vec![dir.to_path_buf()];
+
+    while let Some(current) = pending_dirs.pop() {
+        let mut entries = fs::read_dir(&current).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let file_type = entry.file_type().await?;
+
+            if file_type.is_dir() {
+                pending_dirs.push(path);
+                continue;
+            }
+            if !file_type.is_file() {
+                report.skipped.push(path.display().to_string());
+                continue;
+            }
+
+            let size = entry.metadata().await?.len();
+            if size > config.max_file_size {
+                tracing::warn!(
+                    "Skipping {}: larger than the maximum file size",
+                    path.display()
+                );
+                report.skipped.push(path.display().to_string());
+                continue;
+            }
+
+            let mime_type = mime_guess::from_path(&path)
+                .first_or_octet_stream()
+                .to_string();
+            let storage = storages.route(size, &mime_type, None);
+            let compression = compression
+                .as_ref()
+                .filter(|compression| compression.applies_to(&mime_type));
+
+            match import_file(db, storage, compression, &path, config.max_file_size).await {
+                Ok(file) => {
+                    tracing::info!("Imported {} as {}", path.display(), file.id);
+                    report.imported += 1;
+                    report.imported_bytes += size;
+                }
+                Err(e) => {
+                    tracing::error!("Failed to import {}: {}", path.display(), e);
+                    report.failed.push(path.display().to_string());
+                }
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+async fn import_file(
+    db: &DbPool,
+    storage: &Storage,
+    compression: Option<&Compression>,
+    path: &Path,
+    max_file_size: u64,
+) -> Result<File> {
+    let filename = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "file".to_string());
+    // Read as it is stored, still bounded in case the file grows meanwhile.
+    let data = ReaderStream::new(fs::File::open(path).await?).boxed();
+    let (data, bytes_read) = storage::limit_stream(data, max_file_size);
+
+    let (stored, compressed) = match compression {
+        Some(compression) => {
+            let (data, compressed_size) = compression.compress(data);
+            let stored = storage.store_stream(&filename, data).await?;
+            let compressed = (compression.codec, compressed_size.load(Ordering::Relaxed));
+            (stored, Some(compressed))
+        }
+        None => (storage.store_stream(&filename, data).await?, None),
+    };
+    let size = bytes_read.load(Ordering::Relaxed) as i64;
+    let mut file = File::new(
+        stored.path.clone(),
+        filename,
+        size,
+        storage.name().to_string(),
+    );
+    file.set_blob_key(stored.key);
+    file.set_compression(compressed);
+
+    match create_file(db, &file, &FileLabels::default()).await {
+        Ok(file) => Ok(file),
+        Err(e) => {
+            if let Err(cleanup_error) = storage.delete_file(&stored.path).await {
+                tracing::error!("Failed to remove blob {}: {}", stored.path, cleanup_error);
+            }
+            Err(e)
+        }
+    }
+}
@@ -0,0 +1,324 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    database::{get_files_by_storage_type, update_file_location, DbPool},
+    models::File,
+    storage::{ByteStream, Storage},
+};
+
+/// Only the first errors are kept in the progress report.
+const MAX_REPORTED_ERRORS: usize = 100;
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Verify {
+    /// Compare the size of the copy with the recorded size
+    #[default]
+    Size,
+    /// Read the copy back and compare its SHA-256 with the source
+    Checksum,
+}
+
+#[derive(Debug, Clone, Deserialize, clap::Args)]
+pub struct MigrationOptions {
+    /// Name of the backend to move files off
+    #[clap(long)]
+    pub from: String,
+
+    /// Name of the backend to move files to
+    #[clap(long)]
+    pub to: String,
+
+    #[clap(long, default_value = "4")]
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+
+    #[clap(long)]
+    #[serde(default)]
+    pub delete_source: bool,
+
+    #[clap(long, default_value = "size")]
+    #[serde(default)]
+    pub verify: Verify,
+}
+
+fn default_concurrency() -> usize {
+    4
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MigrationStatus {
+    Running,
+    Completed,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MigrationError {
+    pub file_id: String,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MigrationProgress {
+    pub from: String,
+    pub to: String,
+    pub status: MigrationStatus,
+    pub total: usize,
+    pub migrated: usize,
+    pub failed: usize,
+    pub bytes_copied: u64,
+    pub errors: Vec<MigrationError>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+/// Shares the progress of the current (or last) migration with the admin API.
+#[derive(Debug, Clone, Default)]
+pub struct MigrationTracker {
+    progress: Arc<Mutex<Option<MigrationProgress>>>,
+}
+
+impl MigrationTracker {
+    pub fn snapshot(&self) -> Option<MigrationProgress> {
+        self.progress.lock().unwrap().clone()
+    }
+
+    /// Marks a new migration as running, unless one already is. Must be
+    /// called before [`run`].
+    pub fn start(&self, options: &MigrationOptions) -> Result<()> {
+        if options.from == options.to {
+            anyhow::bail!("Source and target storage must differ");
+        }
+
+        let mut progress = self.progress.lock().unwrap();
+        if progress
+            .as_ref()
+            .is_some_and(|progress| progress.status == MigrationStatus::Running)
+        {
+            anyhow::bail!("A storage migration is already running");
+        }
+
+        *progress = Some(MigrationProgress {
+            from: options.from.clone(),
+            to: options.to.clone(),
+            status: MigrationStatus::Running,
+            total: 0,
+            migrated: 0,
+            failed: 0,
+            bytes_copied: 0,
+            errors: Vec::new(),
+            started_at: Utc::now(),
+            finished_at: None,
+        });
+        Ok(())
+    }
+
+    fn update(&self, f: impl FnOnce(&mut MigrationProgress)) {
+        if let Some(progress) = self.progress.lock().unwrap().as_mut() {
+            f(progress);
+        }
+    }
+}
+
+/// Copies every file on `source` to `target` and points its row at the copy.
+///
+/// Rows are updated one at a time, so an interrupted migration can simply be
+/// run again: files already moved no longer match `from` and are skipped. A
+/// crash between copying and updating the row leaves an orphan on the target
+/// that `gc` removes.
+pub async fn run(
+    db: &DbPool,
+    source: &Storage,
+    target: &Storage,
+    options: &MigrationOptions,
+    tracker: &MigrationTracker,
+) -> Result<MigrationProgress> {
+    let files = match get_files_by_storage_type(db, &options.from).await {
+        Ok(files) => files,
+        Err(e) => {
+            tracker.update(|progress| {
+                progress.status = MigrationStatus::Failed;
+                progress.finished_at = Some(Utc::now());
+            });
+            return Err(e);
+        }
+    };
+    let files: Vec<File> = files.into_iter().filter(|file| !file.is_broken).collect();
+
+    let total = files.len();
+    tracker.update(|progress| progress.total = total);
+    tracing::info!(
+        "Migrating {} files from {} to {}",
+        total,
+        options.from,
+        options.to
+    );
+
+    futures::stream::iter(files)
+        .for_each_concurrent(options.concurrency.max(1), |file| async move {
+            let result = migrate_file(db, source, target, &file, options).await;
+
+            tracker.update(|progress| {
+                match result {
+                    Ok(bytes) => {
+                        progress.migrated += 1;
+                        progress.bytes_copied += bytes;
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to migrate {}: {}", file.id, e);
+                        progress.failed += 1;
+                        if progress.errors.len() < MAX_REPORTED_ERRORS {
+                            progress.errors.push(MigrationError {
+                                file_id: file.id.clone(),
+                                error: e.to_string(),
+                            });
+                        }
+                    }
+                }
+
+                let done = progress.migrated + progress.failed;
+                if done % 100 == 0 || done == progress.total {
+                    tracing::info!(
+                        "Storage migration progress: {}/{} ({} failed)",
+                        done,
+                        progress.total,
+                        progress.failed
+                    );
+                }
+            });
+        })
+        .await;
+
+    tracker.update(|progress| {
+        progress.status = MigrationStatus::Completed;
+        progress.finished_at = Some(Utc::now());
+    });
+
+    Ok(tracker.snapshot().expect("migration progress is set"))
+}
+
+async fn migrate_file(
+    db: &DbPool,
+    source: &Storage,
+    target: &Storage,
+    file: &File,
+    options: &MigrationOptions,
+) -> Result<u64> {
+    // Encrypted blobs are copied as they are, so the row's data key still
+    // applies to the copy. The blob is streamed, counted and hashed on the way.
+    let mut size = 0;
+    let mut hasher = matches!(options.verify, Verify::Checksum).then(Sha256::new);
+    let data = source.get_stream(&file.path, None).await?;
+    let new_path = target
+        .store_raw(&file.name, measure(data, &mut size, hasher.as_mut()))
+        .await?;
+
+    let verified = if size as i64 != file.stored_size() {
+        Err(anyhow::anyhow!(
+            "source blob is {} bytes but {} bytes are expected",
+            size,
+            file.stored_size()
+        ))
+    } else {
+        let checksum = hasher.map(Sha256::finalize);
+        verify_copy(target, &new_path, file.stored_size(), checksum.as_deref()).await
+    };
+
+    let updated = match verified {
+        Ok(()) => update_file_location(db, &file.id, &file.path, &new_path, target.name()).await,
+        Err(e) => Err(e),
+    };
+
+    match updated {
+        Ok(true) => {}
+        Ok(false) => {
+            discard_copy(target, &new_path).await;
+            anyhow::bail!("file was changed or deleted during the migration");
+        }
+        Err(e) => {
+            discard_copy(target, &new_path).await;
+            return Err(e);
+        }
+    }
+
+    if options.delete_source {
+        if let Err(e) = source.delete_file(&file.path).await {
+            tracing::warn!(
+                "Migrated {} but failed to delete the source: {}",
+                file.id,
+                e
+            );
+        }
+    }
+
+    Ok(file.size as u64)
+}
+
+async fn verify_copy(
+    target: &Storage,
+    path: &str,
+    expected_size: i64,
+    expected_checksum: Option<&[u8]>,
+) -> Result<()> {
+    match expected_checksum {
+        None => {
+            let size = target.file_size(path).await?;
+            if size != expected_size {
+                anyhow::bail!("copy is {} bytes, expected {}", size, expected_size);
+            }
+        }
+        Some(expected_checksum) => {
+            let mut size = 0;
+            let mut hasher = Sha256::new();
+            let mut copy = measure(
+                target.get_stream(path, None).await?,
+                &mut size,
+                Some(&mut hasher),
+            );
+            while let Some(chunk) = copy.next().await {
+                chunk?;
+            }
+            drop(copy);
+
+            if size as i64 != expected_size {
+                anyhow::bail!("copy is {} bytes, expected {}", size, expected_size);
+            }
+            if hasher.finalize().as_slice() != expected_checksum {
+                anyhow::bail!("checksum of the copy does not match the source");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Passes `data` through, adding its length to `size` and feeding it to
+/// `hasher` if given.
+fn measure<'a>(
+    data: ByteStream<'a>,
+    size: &'a mut u64,
+    mut hasher: Option<&'a mut Sha256>,
+) -> ByteStream<'a> {
+    data.inspect(move |chunk| {
+        if let Ok(chunk) = chunk {
+            *size += chunk.len() as u64;
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(chunk);
+            }
+        }
+    })
+    .boxed()
+}
+
+async fn discard_copy(target: &Storage, path: &str) {
+    if let Err(e) = target.delete_file(path).await {
+        tracing::error!("Failed to remove copy {}: {}", path, e);
+    }
+}
@@ -0,0 +1,7 @@
+pub mod export;
+pub mod fsck;
+pub mod gc;
+pub mod import;
+pub mod migrate_storage;
+pub mod rotate_keys;
+pub mod stats;
@@ -0,0 +1,98 @@
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::{
+    database::{get_files_not_encrypted_with, update_file_key, DbPool},
+    models::File,
+    storage::{Keyring, StorageRegistry},
+};
+
+#[derive(Debug, Default, Serialize)]
+pub struct RotationReport {
+    pub key_id: String,
+    pub rewrapped: usize,
+    pub encrypted: usize,
+    pub unencrypted: usize,
+    pub failed: Vec<String>,
+}
+
+/// Wraps every data key with the current master key, so that previous keys
+/// can be removed from `FILE_SERVER_PREVIOUS_ENCRYPTION_KEYS` afterwards. Only
+/// the wrapped keys change, not the blobs. With `encrypt_existing`, blobs
+/// written before encryption was enabled are re-uploaded encrypted.
+pub async fn run(
+    db: &DbPool,
+    storages: &StorageRegistry,
+    encrypt_existing: bool,
+) -> Result<RotationReport> {
+    let keyring = storages
+        .keyring()
+        .ok_or_else(|| anyhow::anyhow!("No encryption key is configured"))?;
+
+    let mut report = RotationReport {
+        key_id: keyring.current_id().to_string(),
+        ..Default::default()
+    };
+
+    for file in get_files_not_encrypted_with(db, keyring.current_id()).await? {
+        let result = match file.blob_key() {
+            Some(_) => rewrap(db, keyring, &file)
+                .await
+                .map(|()| &mut report.rewrapped),
+            None if encrypt_existing && !file.is_broken => encrypt(db, storages, &file)
+                .await
+                .map(|()| &mut report.encrypted),
+            None => Ok(&mut report.unencrypted),
+        };
+
+        match result {
+            Ok(count) => *count += 1,
+            Err(e) => {
+                tracing::error!("Failed to rotate the key of {}: {}", file.id, e);
+                report.failed.push(file.id);
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+async fn rewrap(db: &DbPool, keyring: &Keyring, file: &File) -> Result<()> {
+    let key = file
+        .blob_key()
+        .ok_or_else(|| anyhow::anyhow!("file is not encrypted"))?;
+    let rewrapped = keyring.rewrap(&key)?;
+
+    if !update_file_key(db, &file.id, &file.path, &file.path, &rewrapped).await? {
+        anyhow::bail!("file was changed or deleted during the rotation");
+    }
+    Ok(())
+}
+
+async fn encrypt(db: &DbPool, storages: &StorageRegistry, file: &File) -> Result<()> {
+    let storage = storages.require(&file.storage_type)?;
+    let data = storage.get_stream(&file.path, None).await?;
+    let blob = storage.store_stream(&file.name, data).await?;
+    let key = blob
+        .key
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("storage did not encrypt the blob"))?;
+
+    let updated = update_file_key(db, &file.id, &file.path, &blob.path, key).await;
+    if !matches!(updated, Ok(true)) {
+        if let Err(e) = storage.delete_file(&blob.path).await {
+            tracing::error!("Failed to remove copy {}: {}", blob.path, e);
+        }
+        updated?;
+        anyhow::bail!("file was changed or deleted during the rotation");
+    }
+
+    if let Err(e) = storage.delete_file(&file.path).await {
+        tracing::warn!(
+            "Encrypted {} but failed to delete the unencrypted blob: {}",
+            file.id,
+            e
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,42 @@
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::database::{
+    count_api_keys, count_audit_events, get_file_totals_by_storage_type, DbPool,
+};
+
+#[derive(Debug, Serialize)]
+pub struct StorageTypeStats {
+    pub storage_type: String,
+    pub files: i64,
+    pub bytes: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub files: i64,
+    pub bytes: i64,
+    pub by_storage_type: Vec<StorageTypeStats>,
+    pub api_keys: i64,
+    pub audit_events: i64,
+}
+
+pub async fn collect(db: &DbPool) -> Result<Stats> {
+    let by_storage_type: Vec<StorageTypeStats> = get_file_totals_by_storage_type(db)
+        .await?
+        .into_iter()
+        .map(|(storage_type, files, bytes)| StorageTypeStats {
+            storage_type,
+            files,
+            bytes,
+        })
+        .collect();
+
+    Ok(Stats {
+        files: by_storage_type.iter().map(|stats| stats.files).sum(),
+        bytes: by_storage_type.iter().map(|stats| stats.bytes).sum(),
+        by_storage_type,
+        api_keys: count_api_keys(db).await?,
+        audit_events: count_audit_events(db).await?,
+    })
+}
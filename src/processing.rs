@@ -0,0 +1,111 @@
+use anyhow::Result;
+use futures::StreamExt;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    database::{finish_file_processing, get_file_by_id, start_file_processing},
+    events::{FileChange, FileEvent},
+    handlers::AppState,
+    models::{
+        file::{STATUS_FAILED, STATUS_READY},
+        File, FileResponse, Job,
+    },
+    webhooks,
+};
+
+/// Payload of a [`JOB_PROCESS_FILE`](crate::models::job::JOB_PROCESS_FILE)
+/// job.
+#[derive(Debug, Deserialize)]
+struct ProcessFile {
+    file_id: String,
+}
+
+/// Reads the file's content back, checks its size and records its SHA-256.
+/// Errors are retried by the job queue until the job is dead, when
+/// [`fail_file`] marks the file as failed.
+pub async fn process_file(state: &AppState, job: &Job) -> Result<()> {
+    let ProcessFile { file_id } = serde_json::from_str(&job.payload)?;
+
+    // Deleted, or replaced by a newer upload with a job of its own.
+    if !start_file_processing(&state.db, &file_id, &job.id).await? {
+        return Ok(());
+    }
+    let Some(file) = announce(state, &file_id).await? else {
+        return Ok(());
+    };
+
+    let sha256 = checksum(state, &file).await?;
+    if finish_file_processing(
+        &state.db,
+        &file_id,
+        &job.id,
+        STATUS_READY,
+        Some(&sha256),
+        None,
+    )
+    .await?
+    {
+        announce(state, &file_id).await?;
+    }
+    Ok(())
+}
+
+/// Marks the file of a dead processing job as failed, with the job's error.
+pub async fn fail_file(state: &AppState, job: &Job) -> Result<()> {
+    let ProcessFile { file_id } = serde_json::from_str(&job.payload)?;
+
+    if finish_file_processing(
+        &state.db,
+        &file_id,
+        &job.id,
+        STATUS_FAILED,
+        None,
+        job.last_error.as_deref(),
+    )
+    .await?
+    {
+        announce(state, &file_id).await?;
+    }
+    Ok(())
+}
+
+/// Publishes the file's current status on the live feed.
+async fn announce(state: &AppState, file_id: &str) -> Result<Option<File>> {
+    let Some(file) = get_file_by_id(&state.db, file_id).await? else {
+        return Ok(None);
+    };
+
+    if !file.is_private {
+        let labels = webhooks::fetch_labels(&state.db, &file).await;
+        state.events.publish(FileEvent {
+            change: FileChange::Updated,
+            file: FileResponse {
+                labels,
+                ..FileResponse::from(file.clone())
+            },
+        });
+    }
+    Ok(Some(file))
+}
+
+/// Hex SHA-256 of the file's content as uploaded.
+async fn checksum(state: &AppState, file: &File) -> Result<String> {
+    let storage = state.storage.require(&file.storage_type)?;
+    let mut content = storage
+        .get_content(&file.path, file.blob_key().as_ref(), file.codec())
+        .await?;
+
+    let mut hasher = Sha256::new();
+    let mut size = 0u64;
+    while let Some(chunk) = content.next().await {
+        let chunk = chunk?;
+        size += chunk.len() as u64;
+        hasher.update(&chunk);
+    }
+    if size != file.size as u64 {
+        anyhow::bail!("content is {} bytes, expected {}", size, file.size);
+    }
+
+    Ok(hex::encode(hasher.finalize()))
+}
@@ -0,0 +1,307 @@
+use axum::{
+    extract::DefaultBodyLimit,
+    middleware as axum_middleware,
+    routing::{any, delete, get, post},
+    Router,
+};
+use std::{future::IntoFuture, net::SocketAddr, time::Duration};
+use tokio::net::TcpListener;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tower::ServiceBuilder;
+use tower_http::{
+    cors::CorsLayer,
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+
+use crate::{
+    audit,
+    config::{Config, StorageConfig},
+    database::{count_api_keys, create_pool},
+    handlers::{
+        create_archive, create_webhook_handler, delete_file, delete_webhook_handler, file_events,
+        file_status_events, get_file_by_id_handler, get_file_status, get_job_handler,
+        get_storage_migration, list_audit_events, list_files, list_jobs, list_webhook_deliveries,
+        list_webhooks, liveness, readiness, retry_job_handler, retry_webhook_delivery_handler,
+        run_fsck, s3, serve_style_css, serve_upload_page, start_storage_migration, upload_file,
+        webdav, AppState,
+    },
+    jobs, metrics,
+    middleware::{
+        attach_request_id, make_request_span, require_auth, require_sigv4, REQUEST_ID_HEADER,
+    },
+    shutdown,
+    storage::{Keyring, StorageRegistry},
+    webhooks,
+};
+
+pub async fn run(config: Config) -> anyhow::Result<()> {
+    let storage_config = StorageConfig::load(&config)?;
+    let storage = StorageRegistry::from_config(&storage_config).await?;
+    serve(config, storage).await
+}
+
+/// Runs the server with a prepared set of storage backends, e.g. one that
+/// includes a custom backend added with [`StorageRegistry::register`].
+pub async fn serve(config: Config, mut storage: StorageRegistry) -> anyhow::Result<()> {
+    tracing::info!("Starting file server with config: {:?}", config);
+
+    if let Some(keyring) = Keyring::from_config(&config)? {
+        tracing::info!(
+            "Encrypting new uploads with master key {}",
+            keyring.current_id()
+        );
+        storage.set_keyring(keyring);
+    }
+
+    let metrics_handle = metrics::install_recorder()?;
+
+    let db_pool = create_pool(config.database_url.expose()).await?;
+    tracing::info!("Database connection established");
+
+    for backend in storage.backends() {
+        tracing::info!(
+            "Storage initialized: {} ({})",
+            backend.name(),
+            backend.kind()
+        );
+        backend.cleanup_partial_uploads().await;
+    }
+    tracing::info!(
+        "New uploads default to storage: {}",
+        storage.default_backend().name()
+    );
+
+    let shutdown = CancellationToken::new();
+    let background_tasks = TaskTracker::new();
+
+    let app_state = AppState::new(
+        db_pool,
+        storage,
+        config.clone(),
+        metrics_handle,
+        shutdown.clone(),
+    );
+
+    if let Some(retention_days) = config.audit_retention_days {
+        tracing::info!("Audit events are kept for {} days", retention_days);
+        background_tasks.spawn(audit::run_retention(
+            app_state.db.clone(),
+            retention_days,
+            shutdown.clone(),
+        ));
+    }
+
+    background_tasks.spawn(jobs::run(app_state.clone(), shutdown.clone()));
+
+    background_tasks.spawn(webhooks::run_deliveries(
+        app_state.db.clone(),
+        app_state.webhooks.clone(),
+        app_state.config.clone(),
+        shutdown.clone(),
+    ));
+
+    let api_key_count = count_api_keys(&app_state.db).await?;
+    if config.auth_token.is_some() || api_key_count > 0 {
+        tracing::info!(
+            "Auth at /upload, /admin and /dav is enabled ({} API keys). This is recommended for prod.",
+            api_key_count
+        );
+    } else {
+        tracing::warn!(
+            "Auth at /upload, /admin and /dav is disabled until an API key is created. This is not recommended for prod."
+        );
+    }
+
+    let app = router(app_state.clone());
+
+    let addr = format!("{}:{}", config.host, config.port);
+    let listener = TcpListener::bind(&addr).await?;
+
+    tracing::info!("Server running on http://{}", addr);
+
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown::signal().await;
+            shutdown.cancel();
+        }
+    });
+
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown.clone().cancelled_owned());
+
+    let drain_timeout = Duration::from_secs(config.shutdown_timeout);
+    let drained = tokio::select! {
+        result = server.into_future() => {
+            result?;
+            true
+        }
+        _ = async {
+            shutdown.cancelled().await;
+            tracing::info!("Waiting up to {:?} for in-flight requests", drain_timeout);
+            tokio::time::sleep(drain_timeout).await;
+        } => {
+            tracing::warn!("In-flight requests did not finish within {:?}", drain_timeout);
+            false
+        }
+    };
+
+    // Connections that outlived the drain are still being served until the
+    // process exits, and may yet write blobs and commit rows. Cleaning up
+    // under them would delete blobs that are about to be referenced, so their
+    // leftovers are left to `gc` instead.
+    if drained {
+        app_state.pending_uploads.cleanup(&app_state.storage).await;
+        for backend in app_state.storage.backends() {
+            backend.cleanup_partial_uploads().await;
+        }
+    } else {
+        tracing::warn!(
+            "Skipping cleanup of interrupted uploads; run `file-server-rs gc` to remove what they leave behind"
+        );
+    }
+
+    background_tasks.close();
+    background_tasks.wait().await;
+
+    if drained {
+        app_state.db.close().await;
+    }
+    tracing::info!("Shutdown complete");
+    Ok(())
+}
+
+/// All routes of the server with their middleware, serving `app_state`.
+pub fn router(app_state: AppState) -> Router {
+    let config = app_state.config.clone();
+
+    let mut app = Router::new()
+        .route("/files/uploads/:id", get(get_file_by_id_handler))
+        .route("/files/uploads", get(list_files))
+        .route("/files/archive", post(create_archive))
+        .route("/files/uploads/:id", delete(delete_file))
+        .route("/files/uploads/:id/status", get(get_file_status))
+        .route("/files/uploads/:id/status/events", get(file_status_events))
+        .route("/events", get(file_events))
+        .route("/style.css", get(serve_style_css))
+        .route("/metrics", get(metrics::serve_metrics))
+        .route("/healthz", get(liveness))
+        .route("/readyz", get(readiness))
+        .with_state(app_state.clone());
+
+    let upload_router = Router::new()
+        .route("/upload", post(upload_file))
+        .layer(DefaultBodyLimit::max(upload_body_limit(&config)))
+        .with_state(app_state.clone());
+
+    let admin_router = Router::new()
+        .route("/admin/audit-events", get(list_audit_events))
+        .route("/admin/fsck", post(run_fsck))
+        .route(
+            "/admin/storage-migrations",
+            get(get_storage_migration).post(start_storage_migration),
+        )
+        .route(
+            "/admin/webhooks",
+            get(list_webhooks).post(create_webhook_handler),
+        )
+        .route("/admin/webhooks/:id", delete(delete_webhook_handler))
+        .route(
+            "/admin/webhooks/:id/deliveries",
+            get(list_webhook_deliveries),
+        )
+        .route(
+            "/admin/webhooks/:id/deliveries/:delivery_id/retry",
+            post(retry_webhook_delivery_handler),
+        )
+        .route("/admin/jobs", get(list_jobs))
+        .route("/admin/jobs/:id", get(get_job_handler))
+        .route("/admin/jobs/:id/retry", post(retry_job_handler))
+        .with_state(app_state.clone());
+
+    let mut webdav_router = Router::new();
+    if config.enable_webdav {
+        tracing::info!("WebDAV is served at {}", webdav::MOUNT);
+        webdav_router = webdav_router
+            .route(webdav::MOUNT, any(webdav::handle))
+            .route(&format!("{}/", webdav::MOUNT), any(webdav::handle))
+            .route(&format!("{}/*path", webdav::MOUNT), any(webdav::handle));
+    }
+    let webdav_router = webdav_router.with_state(app_state.clone());
+
+    // Whether credentials are required is decided per request, so an API key
+    // created while the server runs takes effect immediately.
+    let auth = axum_middleware::from_fn_with_state(app_state.clone(), require_auth);
+    app = app
+        .merge(upload_router.layer(auth.clone()))
+        .merge(admin_router.layer(auth.clone()));
+    let webdav_router = webdav_router
+        .layer(auth)
+        .layer(axum_middleware::from_fn(webdav::challenge));
+
+    if config.enable_s3_api {
+        tracing::info!(
+            "The S3 API is served at {} with bucket {}",
+            s3::MOUNT,
+            config.s3_api_bucket
+        );
+        let bucket = format!("{}/:bucket", s3::MOUNT);
+        let s3_router = Router::new()
+            .route(s3::MOUNT, any(s3::not_implemented))
+            .route(&bucket, get(s3::list_objects).fallback(s3::not_implemented))
+            .route(
+                &format!("{}/", bucket),
+                get(s3::list_objects).fallback(s3::not_implemented),
+            )
+            .route(
+                &format!("{}/*key", bucket),
+                get(s3::get_object)
+                    .head(s3::head_object)
+                    .put(s3::put_object)
+                    .delete(s3::delete_object)
+                    .fallback(s3::not_implemented),
+            )
+            .layer(axum_middleware::from_fn_with_state(
+                app_state.clone(),
+                require_sigv4,
+            ))
+            .with_state(app_state.clone());
+        app = app.merge(s3_router);
+    }
+
+    if !config.disable_upload_page {
+        tracing::warn!(
+            "Running with upload page enabled, in prod you may wanna disable this. Set the env variable FILE_SERVER_DISABLE_UPLOAD_PAGE=true"
+        );
+        app = app.route("/", get(serve_upload_page));
+    }
+
+    // CORS preflight handling would answer WebDAV's OPTIONS requests, so the
+    // WebDAV routes are added after it.
+    app = app.layer(CorsLayer::permissive()).merge(webdav_router);
+
+    app.layer(
+        ServiceBuilder::new()
+            .layer(SetRequestIdLayer::new(
+                REQUEST_ID_HEADER.clone(),
+                MakeRequestUuid,
+            ))
+            .layer(TraceLayer::new_for_http().make_span_with(make_request_span))
+            .layer(PropagateRequestIdLayer::new(REQUEST_ID_HEADER.clone()))
+            .layer(axum_middleware::from_fn(attach_request_id))
+            .layer(axum_middleware::from_fn(metrics::track_http)),
+    )
+}
+
+/// Uploads are streamed and checked against `max_file_size` while reading;
+/// the request as a whole may additionally carry multipart framing and other
+/// form fields.
+fn upload_body_limit(config: &Config) -> usize {
+    const MULTIPART_OVERHEAD: u64 = 1024 * 1024;
+
+    usize::try_from(config.max_file_size.saturating_add(MULTIPART_OVERHEAD)).unwrap_or(usize::MAX)
+}
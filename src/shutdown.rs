@@ -0,0 +1,124 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use crate::storage::{Storage, StorageRegistry};
+
+/// Resolves when the process receives SIGINT or SIGTERM.
+pub async fn signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => tracing::info!("Received SIGINT, shutting down"),
+        _ = terminate => tracing::info!("Received SIGTERM, shutting down"),
+    }
+}
+
+/// Blobs that have been written to storage but whose `files` row has not been
+/// committed yet. Anything left here when the server stops is deleted so that
+/// an interrupted upload does not leave an orphaned blob behind.
+#[derive(Debug, Clone, Default)]
+pub struct PendingUploads {
+    /// Backend name and path of each blob.
+    paths: Arc<Mutex<HashSet<(String, String)>>>,
+}
+
+impl PendingUploads {
+    pub fn track(&self, storage: &Storage, path: &str) -> PendingUpload {
+        self.paths
+            .lock()
+            .unwrap()
+            .insert((storage.name().to_string(), path.to_string()));
+        PendingUpload {
+            pending: self.clone(),
+            storage: storage.clone(),
+            path: path.to_string(),
+            committed: false,
+        }
+    }
+
+    /// Deletes every blob whose upload never completed.
+    pub async fn cleanup(&self, storages: &StorageRegistry) {
+        let paths: Vec<(String, String)> = self.paths.lock().unwrap().drain().collect();
+
+        for (backend, path) in paths {
+            tracing::warn!("Removing blob of interrupted upload: {}", path);
+            let result = match storages.require(&backend) {
+                Ok(storage) => storage.delete_file(&path).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                tracing::error!("Failed to remove blob {}: {}", path, e);
+            }
+        }
+    }
+
+    fn remove(&self, storage: &Storage, path: &str) {
+        self.paths
+            .lock()
+            .unwrap()
+            .remove(&(storage.name().to_string(), path.to_string()));
+    }
+}
+
+/// Guard for a single upload. Dropping it without calling [`PendingUpload::commit`]
+/// (because the DB insert failed or the request was cancelled) deletes the blob.
+pub struct PendingUpload {
+    pending: PendingUploads,
+    storage: Storage,
+    path: String,
+    committed: bool,
+}
+
+impl PendingUpload {
+    pub fn commit(mut self) {
+        self.committed = true;
+        self.pending.remove(&self.storage, &self.path);
+    }
+}
+
+impl Drop for PendingUpload {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+
+        // The runtime is gone once the process is exiting; the blob is then
+        // left to `PendingUploads::cleanup`.
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+
+        let pending = self.pending.clone();
+        let storage = self.storage.clone();
+        let path = self.path.clone();
+        runtime.spawn(async move {
+            match storage.delete_file(&path).await {
+                Ok(()) => pending.remove(&storage, &path),
+                Err(e) => tracing::error!("Failed to remove blob {}: {}", path, e),
+            }
+        });
+    }
+}
@@ -0,0 +1,462 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::BytesMut;
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, TryStreamExt};
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{header, Client, Method, Request, RequestBuilder, Response, Url};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use super::{backend::fill, ByteStream, StorageBackend, StoredObject};
+
+const API_VERSION: &str = "2021-08-06";
+const KEY_PREFIX: &str = "uploads/";
+
+/// Uploads larger than this are staged as blocks of this size and committed
+/// with a block list. Uncommitted blocks of a failed upload are discarded by
+/// Azure after a week.
+const BLOCK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Characters escaped in blob names; `/` is kept so names map to paths.
+const BLOB_NAME: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// Azure Blob Storage with Shared Key authentication. Works against Azurite
+/// when `endpoint_url` points at it, e.g.
+/// `http://127.0.0.1:10000/devstoreaccount1`.
+#[derive(Clone)]
+pub struct AzureBlobStorage {
+    client: Client,
+    account: String,
+    key: Arc<Vec<u8>>,
+    container: String,
+    endpoint: String,
+}
+
+impl std::fmt::Debug for AzureBlobStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AzureBlobStorage")
+            .field("account", &self.account)
+            .field("container", &self.container)
+            .field("endpoint", &self.endpoint)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct EnumerationResults {
+    blobs: Blobs,
+    next_marker: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Blobs {
+    #[serde(rename = "Blob", default)]
+    blobs: Vec<Blob>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Blob {
+    name: String,
+    properties: BlobProperties,
+}
+
+#[derive(Deserialize)]
+struct BlobProperties {
+    #[serde(rename = "Last-Modified")]
+    last_modified: Option<String>,
+    #[serde(rename = "Content-Length")]
+    content_length: i64,
+}
+
+impl AzureBlobStorage {
+    /// `access_key` is the base64 account key from the portal or Azurite.
+    pub fn new(
+        account: String,
+        access_key: &str,
+        container: String,
+        endpoint_url: Option<String>,
+    ) -> Result<Self> {
+        let key = STANDARD
+            .decode(access_key)
+            .context("Azure storage key is not valid base64")?;
+        let endpoint = endpoint_url
+            .unwrap_or_else(|| format!("https://{}.blob.core.windows.net", account))
+            .trim_end_matches('/')
+            .to_string();
+
+        Ok(Self {
+            client: Client::new(),
+            account,
+            key: Arc::new(key),
+            container,
+            endpoint,
+        })
+    }
+
+    fn container_url(&self) -> String {
+        format!("{}/{}", self.endpoint, self.container)
+    }
+
+    fn blob_url(&self, name: &str) -> String {
+        format!(
+            "{}/{}",
+            self.container_url(),
+            utf8_percent_encode(name, BLOB_NAME)
+        )
+    }
+
+    fn hmac(&self, message: &str) -> Result<String> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key)?;
+        mac.update(message.as_bytes());
+        Ok(STANDARD.encode(mac.finalize().into_bytes()))
+    }
+
+    /// Adds the `Authorization: SharedKey` header. Requests with a body must
+    /// already carry their `Content-Length`, since it is part of the signature.
+    fn sign(&self, request: &mut Request) -> Result<()> {
+        let headers = request.headers_mut();
+        headers.insert(
+            "x-ms-date",
+            Utc::now()
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+                .to_string()
+                .parse()?,
+        );
+        headers.insert("x-ms-version", API_VERSION.parse()?);
+
+        let headers = request.headers();
+        let value = |name: header::HeaderName| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("")
+        };
+        let content_length = match value(header::CONTENT_LENGTH) {
+            "0" => "",
+            length => length,
+        };
+
+        let mut canonical_headers: Vec<(String, &str)> = headers
+            .iter()
+            .filter(|(name, _)| name.as_str().starts_with("x-ms-"))
+            .map(|(name, value)| (name.as_str().to_string(), value.to_str().unwrap_or("")))
+            .collect();
+        canonical_headers.sort();
+
+        let mut canonical_resource = format!("/{}{}", self.account, request.url().path());
+        let mut query: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (name, value) in request.url().query_pairs() {
+            query
+                .entry(name.to_lowercase())
+                .or_default()
+                .push(value.to_string());
+        }
+        for (name, mut values) in query {
+            values.sort();
+            canonical_resource.push_str(&format!("\n{}:{}", name, values.join(",")));
+        }
+
+        let string_to_sign = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}\n\n{}\n{}\n{}\n{}\n{}\n{}{}",
+            request.method(),
+            value(header::CONTENT_ENCODING),
+            value(header::CONTENT_LANGUAGE),
+            content_length,
+            value(header::HeaderName::from_static("content-md5")),
+            value(header::CONTENT_TYPE),
+            value(header::IF_MODIFIED_SINCE),
+            value(header::IF_MATCH),
+            value(header::IF_NONE_MATCH),
+            value(header::IF_UNMODIFIED_SINCE),
+            value(header::RANGE),
+            canonical_headers
+                .iter()
+                .map(|(name, value)| format!("{}:{}\n", name, value))
+                .collect::<String>(),
+            canonical_resource
+        );
+
+        let authorization = format!("SharedKey {}:{}", self.account, self.hmac(&string_to_sign)?);
+        request
+            .headers_mut()
+            .insert(header::AUTHORIZATION, authorization.parse()?);
+        Ok(())
+    }
+
+    async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        let mut request = request.build()?;
+        self.sign(&mut request)?;
+        check(self.client.execute(request).await?).await
+    }
+
+    async fn put_blocks(
+        &self,
+        name: &str,
+        content_type: &str,
+        buffer: &mut BytesMut,
+        data: &mut ByteStream<'_>,
+    ) -> Result<()> {
+        let url = self.blob_url(name);
+        let mut block_ids = Vec::new();
+
+        loop {
+            fill(data, buffer, BLOCK_SIZE).await?;
+            if buffer.is_empty() {
+                break;
+            }
+
+            let block = buffer.split_to(BLOCK_SIZE.min(buffer.len())).freeze();
+            // Block ids must all have the same length.
+            let block_id = STANDARD.encode(format!("{:08}", block_ids.len()));
+            self.send(
+                self.client
+                    .request(Method::PUT, &url)
+                    .query(&[("comp", "block"), ("blockid", &block_id)])
+                    .header(header::CONTENT_LENGTH, block.len())
+                    .body(block),
+            )
+            .await?;
+            block_ids.push(block_id);
+        }
+
+        let block_list = format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?><BlockList>{}</BlockList>",
+            block_ids
+                .iter()
+                .map(|id| format!("<Latest>{}</Latest>", id))
+                .collect::<String>()
+        );
+        self.send(
+            self.client
+                .request(Method::PUT, &url)
+                .query(&[("comp", "blocklist")])
+                .header("x-ms-blob-content-type", content_type)
+                .header(header::CONTENT_LENGTH, block_list.len())
+                .body(block_list),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// A read-only service SAS for a single blob.
+    fn sas_url(&self, name: &str, expires_in: Duration) -> Result<String> {
+        let expiry = (Utc::now() + chrono::Duration::from_std(expires_in)?)
+            .format("%Y-%m-%dT%H:%M:%SZ")
+            .to_string();
+        let resource = format!("/blob/{}/{}/{}", self.account, self.container, name);
+
+        // Fields: permissions, start, expiry, resource, identifier, IP,
+        // protocol, version, resource type, snapshot time, encryption scope,
+        // then five response header overrides.
+        let string_to_sign = format!(
+            "r\n\n{}\n{}\n\n\n\n{}\nb\n\n\n\n\n\n\n",
+            expiry, resource, API_VERSION
+        );
+        let signature = self.hmac(&string_to_sign)?;
+
+        let mut url = Url::parse(&self.blob_url(name))?;
+        url.query_pairs_mut()
+            .append_pair("sv", API_VERSION)
+            .append_pair("sr", "b")
+            .append_pair("sp", "r")
+            .append_pair("se", &expiry)
+            .append_pair("sig", &signature);
+        Ok(url.to_string())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for AzureBlobStorage {
+    fn kind(&self) -> &'static str {
+        "azure"
+    }
+
+    async fn put(&self, key: &str, content_type: &str, mut data: ByteStream<'_>) -> Result<String> {
+        let name = format!("{}{}", KEY_PREFIX, key);
+
+        let mut buffer = BytesMut::new();
+        fill(&mut data, &mut buffer, BLOCK_SIZE).await?;
+
+        if buffer.len() < BLOCK_SIZE {
+            self.send(
+                self.client
+                    .request(Method::PUT, self.blob_url(&name))
+                    .header("x-ms-blob-type", "BlockBlob")
+                    .header(header::CONTENT_TYPE, content_type)
+                    .header(header::CONTENT_LENGTH, buffer.len())
+                    .body(buffer.freeze()),
+            )
+            .await?;
+        } else {
+            self.put_blocks(&name, content_type, &mut buffer, &mut data)
+                .await?;
+        }
+
+        Ok(format!("/{}", name))
+    }
+
+    async fn get(&self, path: &str) -> Result<ByteStream<'static>> {
+        let name = path.trim_start_matches('/');
+
+        let response = self
+            .send(self.client.request(Method::GET, self.blob_url(name)))
+            .await?;
+
+        Ok(response
+            .bytes_stream()
+            .map_err(std::io::Error::other)
+            .boxed())
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        let name = path.trim_start_matches('/');
+
+        self.send(self.client.request(Method::DELETE, self.blob_url(name)))
+            .await?;
+        Ok(())
+    }
+
+    async fn head(&self, path: &str) -> Result<StoredObject> {
+        let name = path.trim_start_matches('/');
+
+        let response = self
+            .send(self.client.request(Method::HEAD, self.blob_url(name)))
+            .await?;
+        let header = |name: header::HeaderName| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+
+        Ok(StoredObject {
+            path: path.to_string(),
+            size: header(header::CONTENT_LENGTH)
+                .and_then(|length| length.parse().ok())
+                .unwrap_or_default(),
+            modified_at: header(header::LAST_MODIFIED).and_then(parse_http_date),
+        })
+    }
+
+    async fn list(&self) -> Result<Vec<StoredObject>> {
+        let mut objects = Vec::new();
+        let mut marker: Option<String> = None;
+
+        loop {
+            let mut request = self
+                .client
+                .request(Method::GET, self.container_url())
+                .query(&[
+                    ("restype", "container"),
+                    ("comp", "list"),
+                    ("prefix", KEY_PREFIX),
+                ]);
+            if let Some(marker) = &marker {
+                request = request.query(&[("marker", marker)]);
+            }
+
+            let body = self.send(request).await?.text().await?;
+            let page: EnumerationResults = quick_xml::de::from_str(&body)?;
+            for blob in page.blobs.blobs {
+                objects.push(StoredObject {
+                    path: format!("/{}", blob.name),
+                    size: blob.properties.content_length,
+                    modified_at: blob
+                        .properties
+                        .last_modified
+                        .as_deref()
+                        .and_then(parse_http_date),
+                });
+            }
+
+            match page.next_marker {
+                Some(next) if !next.is_empty() => marker = Some(next),
+                _ => break,
+            }
+        }
+
+        Ok(objects)
+    }
+
+    /// Copies server-side, waiting for the copy to finish if Azure runs it
+    /// asynchronously.
+    async fn copy(&self, path: &str, key: &str, _content_type: &str) -> Result<String> {
+        let source = path.trim_start_matches('/');
+        let name = format!("{}{}", KEY_PREFIX, key);
+
+        let response = self
+            .send(
+                self.client
+                    .request(Method::PUT, self.blob_url(&name))
+                    .header("x-ms-copy-source", self.blob_url(source))
+                    .header(header::CONTENT_LENGTH, 0),
+            )
+            .await?;
+
+        let mut status = copy_status(&response);
+        while status.as_deref() == Some("pending") {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            let response = self
+                .send(self.client.request(Method::HEAD, self.blob_url(&name)))
+                .await?;
+            status = copy_status(&response);
+        }
+        if let Some(status) = status.filter(|status| status != "success") {
+            anyhow::bail!("Azure copy of {} ended with status {}", source, status);
+        }
+
+        Ok(format!("/{}", name))
+    }
+
+    async fn presign(&self, path: &str, expires_in: Duration) -> Result<Option<String>> {
+        let name = path.trim_start_matches('/');
+        self.sas_url(name, expires_in).map(Some)
+    }
+
+    async fn check_ready(&self) -> Result<()> {
+        self.send(
+            self.client
+                .request(Method::HEAD, self.container_url())
+                .query(&[("restype", "container")]),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+fn copy_status(response: &Response) -> Option<String> {
+    response
+        .headers()
+        .get("x-ms-copy-status")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+fn parse_http_date(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(value)
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+/// Turns an error status into an error that includes the response body.
+async fn check(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let body = response.text().await.unwrap_or_default();
+    anyhow::bail!("Azure request failed with {}: {}", status, body)
+}
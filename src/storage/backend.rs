@@ -0,0 +1,120 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
+use std::{fmt::Debug, future::Future, time::Duration};
+
+use super::StoredObject;
+
+/// A stream of blob contents. Uploads borrow from the request, so the
+/// lifetime is only `'static` for data read back from a backend.
+pub type ByteStream<'a> = BoxStream<'a, std::io::Result<Bytes>>;
+
+/// A place blobs can be kept. `Storage` wraps an implementation with a name,
+/// metrics and tracing; implement this trait to add a backend without
+/// touching the rest of the server.
+///
+/// Backends choose the `path` of a blob when it is written and are handed that
+/// path back for every later operation, so it may be any string they can
+/// interpret.
+#[async_trait]
+pub trait StorageBackend: Debug + Send + Sync {
+    /// Short description of the implementation, e.g. `local` or `s3`.
+    fn kind(&self) -> &'static str;
+
+    /// Writes `data` under the unique `key` and returns the blob's path. A
+    /// failed stream must not leave a blob behind.
+    async fn put(&self, key: &str, content_type: &str, data: ByteStream<'_>) -> Result<String>;
+
+    async fn get(&self, path: &str) -> Result<ByteStream<'static>>;
+
+    async fn delete(&self, path: &str) -> Result<()>;
+
+    async fn head(&self, path: &str) -> Result<StoredObject>;
+
+    /// Every blob written by [`StorageBackend::put`].
+    async fn list(&self) -> Result<Vec<StoredObject>>;
+
+    /// Duplicates a blob under a new `key` and returns the copy's path.
+    async fn copy(&self, path: &str, key: &str, content_type: &str) -> Result<String> {
+        let data = self.get(path).await?;
+        self.put(key, content_type, data).await
+    }
+
+    /// A URL that grants temporary read access to the blob without going
+    /// through the server, if the backend can issue one.
+    async fn presign(&self, _path: &str, _expires_in: Duration) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// Fails if the backend cannot currently serve reads and writes.
+    async fn check_ready(&self) -> Result<()>;
+
+    /// Removes leftovers of interrupted writes last modified before
+    /// `modified_before` and returns how many were removed. Younger ones may
+    /// belong to a write that is still running, possibly in another process
+    /// sharing the storage. Only needed by backends whose writes are not
+    /// atomic.
+    async fn cleanup_partial(&self, _modified_before: DateTime<Utc>) -> Result<usize> {
+        Ok(0)
+    }
+}
+
+/// Collects a stream into memory.
+pub async fn collect(data: ByteStream<'_>) -> std::io::Result<Vec<u8>> {
+    data.try_fold(Vec::new(), |mut buffer, chunk| async move {
+        buffer.extend_from_slice(&chunk);
+        Ok(buffer)
+    })
+    .await
+}
+
+/// A stream yielding `data` as a single chunk.
+pub fn once(data: Bytes) -> ByteStream<'static> {
+    futures::stream::once(async move { Ok(data) }).boxed()
+}
+
+/// Reads from `data` until `buffer` holds at least `size` bytes or the stream
+/// ends. Used by backends that upload large blobs in parts.
+pub async fn fill(data: &mut ByteStream<'_>, buffer: &mut BytesMut, size: usize) -> Result<()> {
+    while buffer.len() < size {
+        match data.next().await {
+            Some(chunk) => buffer.extend_from_slice(&chunk?),
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+/// Runs `operation` until it succeeds, fails with an error `is_transient`
+/// rejects, or has been retried `retries` times. The delay between attempts
+/// starts at 200 ms and doubles each time.
+pub async fn retry<T, F, Fut>(
+    retries: u32,
+    is_transient: impl Fn(&anyhow::Error) -> bool,
+    mut operation: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut delay = Duration::from_millis(200);
+    let mut attempt = 0;
+    loop {
+        match operation().await {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                attempt += 1;
+                tracing::warn!(
+                    "Storage request failed, retrying ({}/{}): {}",
+                    attempt,
+                    retries,
+                    e
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
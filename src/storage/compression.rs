@@ -0,0 +1,105 @@
+use async_compression::{
+    tokio::bufread::{GzipDecoder, GzipEncoder, ZstdDecoder, ZstdEncoder},
+    Level,
+};
+use futures::StreamExt;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::config::{mime_matches, Config};
+
+use super::ByteStream;
+
+/// Codecs blobs can be compressed with. The names are HTTP content codings,
+/// so a compressed blob can be served as it is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Codec {
+    Gzip,
+    Zstd,
+}
+
+impl Codec {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Codec::Gzip => "gzip",
+            Codec::Zstd => "zstd",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "gzip" => Some(Codec::Gzip),
+            "zstd" => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Compression of new uploads, from `FILE_SERVER_COMPRESSION`.
+#[derive(Debug, Clone)]
+pub struct Compression {
+    pub codec: Codec,
+    level: Level,
+    types: Vec<String>,
+}
+
+impl Compression {
+    /// Returns `None` when compression is disabled.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        Some(Self {
+            codec: config.compression?,
+            level: config
+                .compression_level
+                .map(Level::Precise)
+                .unwrap_or_default(),
+            types: config.compressible_types_vec(),
+        })
+    }
+
+    /// Whether uploads of `mime_type` are worth compressing.
+    pub fn applies_to(&self, mime_type: &str) -> bool {
+        self.types
+            .iter()
+            .any(|pattern| mime_matches(pattern, mime_type))
+    }
+
+    /// Compresses `data`. Once the stream has been read to the end, the
+    /// counter holds the compressed size.
+    pub fn compress<'a>(&self, data: ByteStream<'a>) -> (ByteStream<'a>, Arc<AtomicU64>) {
+        let reader = StreamReader::new(data);
+        let compressed = match self.codec {
+            Codec::Gzip => ReaderStream::new(GzipEncoder::with_quality(reader, self.level)).boxed(),
+            Codec::Zstd => ReaderStream::new(ZstdEncoder::with_quality(reader, self.level)).boxed(),
+        };
+
+        let counter = Arc::new(AtomicU64::new(0));
+        let written = counter.clone();
+        let compressed = compressed
+            .inspect(move |chunk| {
+                if let Ok(chunk) = chunk {
+                    written.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+                }
+            })
+            .boxed();
+
+        (compressed, counter)
+    }
+}
+
+/// Restores the original content of a blob compressed with `codec`.
+pub fn decompress(data: ByteStream<'_>, codec: Codec) -> ByteStream<'_> {
+    let reader = StreamReader::new(data);
+    match codec {
+        Codec::Gzip => ReaderStream::new(GzipDecoder::new(reader)).boxed(),
+        Codec::Zstd => ReaderStream::new(ZstdDecoder::new(reader)).boxed(),
+    }
+}
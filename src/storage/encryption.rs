@@ -0,0 +1,318 @@
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use sha2::{Digest, Sha256};
+use std::io;
+
+use crate::config::Config;
+
+use super::ByteStream;
+
+/// Blobs are encrypted in segments of this much plaintext, so that neither
+/// writing nor reading holds more than one segment in memory.
+pub const SEGMENT_SIZE: usize = 64 * 1024;
+
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+/// A file's data key, encrypted with the master key `key_id`. This is what the
+/// `files` table stores; the data key itself is never persisted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WrappedKey {
+    pub key_id: String,
+    pub wrapped: String,
+}
+
+struct MasterKey {
+    id: String,
+    key: LessSafeKey,
+}
+
+impl MasterKey {
+    fn parse(encoded: &str) -> Result<Self> {
+        let bytes = STANDARD
+            .decode(encoded.trim())
+            .map_err(|_| anyhow::anyhow!("Encryption keys must be base64"))?;
+        if bytes.len() != KEY_LEN {
+            anyhow::bail!("Encryption keys must be {} bytes", KEY_LEN);
+        }
+
+        // Derived from the key so that it is stable and identifies the key
+        // without revealing it.
+        let id = hex::encode(Sha256::digest(&bytes))[..16].to_string();
+        Ok(Self {
+            id,
+            key: aes_key(&bytes)?,
+        })
+    }
+}
+
+/// The master key new data keys are wrapped with, plus retired master keys
+/// that are only used to unwrap existing data keys until they are rotated.
+pub struct Keyring {
+    current: MasterKey,
+    previous: Vec<MasterKey>,
+    random: SystemRandom,
+}
+
+impl std::fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Keyring")
+            .field("current", &self.current.id)
+            .field(
+                "previous",
+                &self.previous.iter().map(|key| &key.id).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl Keyring {
+    /// Builds the keyring from base64-encoded 256-bit keys.
+    pub fn new(current: &str, previous: &[&str]) -> Result<Self> {
+        Ok(Self {
+            current: MasterKey::parse(current)?,
+            previous: previous
+                .iter()
+                .map(|key| MasterKey::parse(key))
+                .collect::<Result<_>>()?,
+            random: SystemRandom::new(),
+        })
+    }
+
+    /// Reads the master key from `FILE_SERVER_ENCRYPTION_KEY` or
+    /// `FILE_SERVER_ENCRYPTION_KEY_FILE`. Returns `None` when neither is set,
+    /// in which case blobs are stored as is.
+    pub fn from_config(config: &Config) -> Result<Option<Self>> {
+        let previous: Vec<&str> = config
+            .previous_encryption_keys
+            .as_ref()
+            .map(|keys| {
+                keys.expose()
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|key| !key.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let current = match (&config.encryption_key, &config.encryption_key_file) {
+            (Some(_), Some(_)) => anyhow::bail!(
+                "Only one of FILE_SERVER_ENCRYPTION_KEY and FILE_SERVER_ENCRYPTION_KEY_FILE may be set"
+            ),
+            (Some(key), None) => key.expose().to_string(),
+            (None, Some(path)) => std::fs::read_to_string(path).map_err(|e| {
+                anyhow::anyhow!("Failed to read encryption key {}: {}", path.display(), e)
+            })?,
+            (None, None) if previous.is_empty() => return Ok(None),
+            (None, None) => anyhow::bail!(
+                "FILE_SERVER_PREVIOUS_ENCRYPTION_KEYS requires a current encryption key"
+            ),
+        };
+
+        Self::new(&current, &previous).map(Some)
+    }
+
+    /// Id of the master key new data keys are wrapped with.
+    pub fn current_id(&self) -> &str {
+        &self.current.id
+    }
+
+    /// Encrypts `data` under a fresh data key and returns the ciphertext
+    /// stream along with the wrapped key.
+    pub fn encrypt<'a>(&self, data: ByteStream<'a>) -> Result<(ByteStream<'a>, WrappedKey)> {
+        let mut data_key = [0u8; KEY_LEN];
+        self.random
+            .fill(&mut data_key)
+            .map_err(|_| anyhow::anyhow!("Failed to generate a data key"))?;
+
+        let wrapped = self.wrap(&data_key)?;
+        Ok((seal_segments(data, aes_key(&data_key)?), wrapped))
+    }
+
+    /// Decrypts a blob written by [`Keyring::encrypt`]. The stream fails if
+    /// the blob was modified or truncated.
+    pub fn decrypt<'a>(&self, data: ByteStream<'a>, key: &WrappedKey) -> Result<ByteStream<'a>> {
+        let data_key = self.unwrap(key)?;
+        Ok(open_segments(data, aes_key(&data_key)?))
+    }
+
+    /// Wraps the data key in `key` with the current master key. The blob
+    /// itself does not change.
+    pub fn rewrap(&self, key: &WrappedKey) -> Result<WrappedKey> {
+        self.wrap(&self.unwrap(key)?)
+    }
+
+    fn master(&self, id: &str) -> Result<&MasterKey> {
+        std::iter::once(&self.current)
+            .chain(&self.previous)
+            .find(|key| key.id == id)
+            .ok_or_else(|| anyhow::anyhow!("Encryption key {} is not configured", id))
+    }
+
+    /// Stored as base64 of the nonce followed by the sealed key. The master
+    /// key id is authenticated, so a wrapped key cannot be relabelled.
+    fn wrap(&self, data_key: &[u8]) -> Result<WrappedKey> {
+        let mut nonce = [0u8; NONCE_LEN];
+        self.random
+            .fill(&mut nonce)
+            .map_err(|_| anyhow::anyhow!("Failed to generate a nonce"))?;
+
+        let mut sealed = data_key.to_vec();
+        self.current
+            .key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(self.current.id.as_bytes()),
+                &mut sealed,
+            )
+            .map_err(|_| anyhow::anyhow!("Failed to wrap data key"))?;
+
+        let mut wrapped = nonce.to_vec();
+        wrapped.extend_from_slice(&sealed);
+        Ok(WrappedKey {
+            key_id: self.current.id.clone(),
+            wrapped: STANDARD.encode(wrapped),
+        })
+    }
+
+    fn unwrap(&self, key: &WrappedKey) -> Result<Vec<u8>> {
+        let master = self.master(&key.key_id)?;
+        let mut wrapped = STANDARD
+            .decode(&key.wrapped)
+            .map_err(|_| anyhow::anyhow!("Wrapped data key is not valid base64"))?;
+        if wrapped.len() != NONCE_LEN + KEY_LEN + TAG_LEN {
+            anyhow::bail!("Wrapped data key has the wrong length");
+        }
+
+        let mut sealed = wrapped.split_off(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&wrapped)
+            .map_err(|_| anyhow::anyhow!("Wrapped data key has an invalid nonce"))?;
+        let data_key = master
+            .key
+            .open_in_place(nonce, Aad::from(master.id.as_bytes()), &mut sealed)
+            .map_err(|_| anyhow::anyhow!("Failed to unwrap data key with key {}", master.id))?;
+        Ok(data_key.to_vec())
+    }
+}
+
+/// Size of the blob written for `size` bytes of plaintext: every segment
+/// carries a tag, and even an empty file has one segment.
+pub fn encrypted_size(size: u64) -> u64 {
+    let segments = size.div_ceil(SEGMENT_SIZE as u64).max(1);
+    size + segments * TAG_LEN as u64
+}
+
+fn aes_key(bytes: &[u8]) -> Result<LessSafeKey> {
+    let key =
+        UnboundKey::new(&AES_256_GCM, bytes).map_err(|_| anyhow::anyhow!("Invalid AES-256 key"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+/// Nonces are the segment index with a flag on the last segment, so segments
+/// cannot be reordered and a blob cut at a segment boundary fails to decrypt.
+/// Every blob has its own data key, so nonces never repeat under one key.
+fn segment_nonce(index: u64, last: bool) -> Nonce {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..8].copy_from_slice(&index.to_be_bytes());
+    nonce[NONCE_LEN - 1] = last as u8;
+    Nonce::assume_unique_for_key(nonce)
+}
+
+struct Segments<'a> {
+    data: ByteStream<'a>,
+    buffer: BytesMut,
+    key: LessSafeKey,
+    index: u64,
+    done: bool,
+}
+
+/// Reads until `buffer` holds more than `size` bytes, which shows that the
+/// next segment is not the last one. Returns whether the stream ended first.
+async fn fill_segment(
+    data: &mut ByteStream<'_>,
+    buffer: &mut BytesMut,
+    size: usize,
+) -> io::Result<bool> {
+    while buffer.len() <= size {
+        match data.next().await {
+            Some(chunk) => buffer.extend_from_slice(&chunk?),
+            None => return Ok(true),
+        }
+    }
+    Ok(false)
+}
+
+fn seal_segments(data: ByteStream<'_>, key: LessSafeKey) -> ByteStream<'_> {
+    let segments = Segments {
+        data,
+        buffer: BytesMut::new(),
+        key,
+        index: 0,
+        done: false,
+    };
+
+    futures::stream::try_unfold(segments, |mut segments| async move {
+        if segments.done {
+            return Ok(None);
+        }
+
+        let last = fill_segment(&mut segments.data, &mut segments.buffer, SEGMENT_SIZE).await?;
+        let length = SEGMENT_SIZE.min(segments.buffer.len());
+        let mut segment = segments.buffer.split_to(length).to_vec();
+        segments
+            .key
+            .seal_in_place_append_tag(
+                segment_nonce(segments.index, last),
+                Aad::empty(),
+                &mut segment,
+            )
+            .map_err(|_| io::Error::other("Failed to encrypt blob"))?;
+
+        segments.index += 1;
+        segments.done = last;
+        Ok(Some((Bytes::from(segment), segments)))
+    })
+    .boxed()
+}
+
+fn open_segments(data: ByteStream<'_>, key: LessSafeKey) -> ByteStream<'_> {
+    let segments = Segments {
+        data,
+        buffer: BytesMut::new(),
+        key,
+        index: 0,
+        done: false,
+    };
+
+    futures::stream::try_unfold(segments, |mut segments| async move {
+        if segments.done {
+            return Ok(None);
+        }
+
+        let sealed_size = SEGMENT_SIZE + TAG_LEN;
+        let last = fill_segment(&mut segments.data, &mut segments.buffer, sealed_size).await?;
+        let length = sealed_size.min(segments.buffer.len());
+        let mut segment = segments.buffer.split_to(length).to_vec();
+        let plaintext = segments
+            .key
+            .open_in_place(
+                segment_nonce(segments.index, last),
+                Aad::empty(),
+                &mut segment,
+            )
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Blob failed to decrypt"))?
+            .len();
+        segment.truncate(plaintext);
+
+        segments.index += 1;
+        segments.done = last;
+        Ok(Some((Bytes::from(segment), segments)))
+    })
+    .boxed()
+}
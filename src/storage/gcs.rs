@@ -0,0 +1,576 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use bytes::BytesMut;
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, TryStreamExt};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{header, Client, Method, RequestBuilder, Response, StatusCode, Url};
+use ring::{
+    rand::SystemRandom,
+    signature::{RsaKeyPair, RSA_PKCS1_SHA256},
+};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+use super::{backend::fill, ByteStream, StorageBackend, StoredObject};
+
+const DEFAULT_ENDPOINT: &str = "https://storage.googleapis.com";
+const KEY_PREFIX: &str = "uploads/";
+const SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+const METADATA_TOKEN_URL: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+
+/// Uploads larger than this use a resumable upload sent in chunks of this
+/// size. GCS requires chunks to be a multiple of 256 KiB.
+const CHUNK_SIZE: usize = 32 * 256 * 1024;
+
+/// Unreserved characters; everything else is escaped in object names.
+const UNRESERVED: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// Google Cloud Storage through the JSON API. Works against fake-gcs-server
+/// when `endpoint_url` points at it.
+#[derive(Debug, Clone)]
+pub struct GcsStorage {
+    client: Client,
+    endpoint: String,
+    bucket: String,
+    auth: Arc<Auth>,
+}
+
+#[derive(Debug)]
+struct Auth {
+    credentials: Credentials,
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+#[derive(Debug)]
+enum Credentials {
+    /// No authentication, for emulators.
+    Anonymous,
+    /// Tokens from the GCE/GKE metadata server.
+    Metadata,
+    ServiceAccount(Box<ServiceAccount>),
+}
+
+struct ServiceAccount {
+    client_email: String,
+    token_uri: String,
+    key: RsaKeyPair,
+}
+
+impl std::fmt::Debug for ServiceAccount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServiceAccount")
+            .field("client_email", &self.client_email)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Deserialize)]
+struct ServiceAccountFile {
+    client_email: String,
+    private_key: String,
+    #[serde(default = "default_token_uri")]
+    token_uri: String,
+}
+
+fn default_token_uri() -> String {
+    "https://oauth2.googleapis.com/token".to_string()
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+#[derive(Deserialize)]
+struct Object {
+    name: String,
+    /// The JSON API encodes 64-bit integers as strings.
+    size: String,
+    updated: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ObjectList {
+    #[serde(default)]
+    items: Vec<Object>,
+    next_page_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RewriteResponse {
+    done: bool,
+    rewrite_token: Option<String>,
+}
+
+impl Object {
+    fn into_stored(self) -> Result<StoredObject> {
+        Ok(StoredObject {
+            path: format!("/{}", self.name),
+            size: self.size.parse()?,
+            modified_at: self.updated,
+        })
+    }
+}
+
+impl ServiceAccount {
+    async fn load(path: &PathBuf) -> Result<Self> {
+        let contents = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read GCS credentials {}", path.display()))?;
+        let file: ServiceAccountFile = serde_json::from_str(&contents)
+            .with_context(|| format!("Invalid GCS credentials {}", path.display()))?;
+
+        let der: String = file
+            .private_key
+            .lines()
+            .filter(|line| !line.starts_with("-----"))
+            .collect();
+        let key = RsaKeyPair::from_pkcs8(&STANDARD.decode(der)?)
+            .map_err(|e| anyhow::anyhow!("Invalid GCS private key: {}", e))?;
+
+        Ok(Self {
+            client_email: file.client_email,
+            token_uri: file.token_uri,
+            key,
+        })
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        let mut signature = vec![0; self.key.public().modulus_len()];
+        self.key
+            .sign(
+                &RSA_PKCS1_SHA256,
+                &SystemRandom::new(),
+                message,
+                &mut signature,
+            )
+            .map_err(|_| anyhow::anyhow!("Failed to sign with the GCS private key"))?;
+        Ok(signature)
+    }
+
+    /// A self-signed JWT exchanged for an access token.
+    fn assertion(&self) -> Result<String> {
+        let now = Utc::now().timestamp();
+        let header = URL_SAFE_NO_PAD.encode(json!({"alg": "RS256", "typ": "JWT"}).to_string());
+        let claims = URL_SAFE_NO_PAD.encode(
+            json!({
+                "iss": self.client_email,
+                "scope": SCOPE,
+                "aud": self.token_uri,
+                "iat": now,
+                "exp": now + 3600,
+            })
+            .to_string(),
+        );
+
+        let message = format!("{}.{}", header, claims);
+        let signature = URL_SAFE_NO_PAD.encode(self.sign(message.as_bytes())?);
+        Ok(format!("{}.{}", message, signature))
+    }
+}
+
+impl Auth {
+    /// Returns a cached access token, refreshing it shortly before it expires.
+    async fn token(&self, client: &Client) -> Result<Option<String>> {
+        if matches!(self.credentials, Credentials::Anonymous) {
+            return Ok(None);
+        }
+
+        let mut cached = self.token.lock().await;
+        if let Some((token, expires_at)) = cached.as_ref() {
+            if Instant::now() + Duration::from_secs(60) < *expires_at {
+                return Ok(Some(token.clone()));
+            }
+        }
+
+        let request = match &self.credentials {
+            Credentials::Anonymous => return Ok(None),
+            Credentials::Metadata => client
+                .get(METADATA_TOKEN_URL)
+                .header("Metadata-Flavor", "Google"),
+            Credentials::ServiceAccount(account) => client.post(&account.token_uri).form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", &account.assertion()?),
+            ]),
+        };
+        let response: TokenResponse = check(request.send().await?).await?.json().await?;
+
+        *cached = Some((
+            response.access_token.clone(),
+            Instant::now() + Duration::from_secs(response.expires_in),
+        ));
+        Ok(Some(response.access_token))
+    }
+}
+
+impl GcsStorage {
+    /// Authenticates with the service account in `credentials_file`, or else
+    /// with the metadata server. Requests to a custom `endpoint_url` without a
+    /// credentials file are sent unauthenticated, as emulators expect.
+    pub async fn new(
+        bucket: String,
+        endpoint_url: Option<String>,
+        credentials_file: Option<PathBuf>,
+    ) -> Result<Self> {
+        let credentials = match (&credentials_file, &endpoint_url) {
+            (Some(path), _) => {
+                Credentials::ServiceAccount(Box::new(ServiceAccount::load(path).await?))
+            }
+            (None, Some(_)) => Credentials::Anonymous,
+            (None, None) => Credentials::Metadata,
+        };
+
+        Ok(Self {
+            client: Client::new(),
+            endpoint: endpoint_url
+                .unwrap_or_else(|| DEFAULT_ENDPOINT.to_string())
+                .trim_end_matches('/')
+                .to_string(),
+            bucket,
+            auth: Arc::new(Auth {
+                credentials,
+                token: Mutex::new(None),
+            }),
+        })
+    }
+
+    fn bucket_url(&self) -> String {
+        format!(
+            "{}/storage/v1/b/{}",
+            self.endpoint,
+            utf8_percent_encode(&self.bucket, UNRESERVED)
+        )
+    }
+
+    fn object_url(&self, name: &str) -> String {
+        format!(
+            "{}/o/{}",
+            self.bucket_url(),
+            utf8_percent_encode(name, UNRESERVED)
+        )
+    }
+
+    fn upload_url(&self) -> String {
+        format!(
+            "{}/upload/storage/v1/b/{}/o",
+            self.endpoint,
+            utf8_percent_encode(&self.bucket, UNRESERVED)
+        )
+    }
+
+    async fn request(&self, method: Method, url: &str) -> Result<RequestBuilder> {
+        let mut request = self.client.request(method, url);
+        if let Some(token) = self.auth.token(&self.client).await? {
+            request = request.bearer_auth(token);
+        }
+        Ok(request)
+    }
+
+    async fn put_resumable(
+        &self,
+        name: &str,
+        content_type: &str,
+        buffer: &mut BytesMut,
+        data: &mut ByteStream<'_>,
+    ) -> Result<()> {
+        let response = self
+            .request(Method::POST, &self.upload_url())
+            .await?
+            .query(&[("uploadType", "resumable"), ("name", name)])
+            .header("X-Upload-Content-Type", content_type)
+            .json(&json!({"contentType": content_type}))
+            .send()
+            .await?;
+        let session_url = check(response)
+            .await?
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .ok_or_else(|| anyhow::anyhow!("GCS did not return an upload session"))?
+            .to_string();
+
+        let uploaded = async {
+            let mut offset = 0u64;
+            loop {
+                fill(data, buffer, CHUNK_SIZE).await?;
+                let last = buffer.len() < CHUNK_SIZE;
+                let chunk = buffer.split_to(CHUNK_SIZE.min(buffer.len())).freeze();
+                let end = offset + chunk.len() as u64;
+
+                let range = match (last, chunk.is_empty()) {
+                    (true, true) => format!("bytes */{}", end),
+                    (true, false) => format!("bytes {}-{}/{}", offset, end - 1, end),
+                    (false, _) => format!("bytes {}-{}/*", offset, end - 1),
+                };
+                let response = self
+                    .request(Method::PUT, &session_url)
+                    .await?
+                    .header(header::CONTENT_RANGE, range)
+                    .body(chunk)
+                    .send()
+                    .await?;
+
+                if last {
+                    check(response).await?;
+                    return anyhow::Ok(());
+                }
+                // 308 acknowledges an intermediate chunk.
+                if response.status() != StatusCode::PERMANENT_REDIRECT {
+                    check(response).await?;
+                    anyhow::bail!("GCS finished the upload before the last chunk");
+                }
+                offset = end;
+            }
+        }
+        .await;
+
+        if uploaded.is_err() {
+            if let Err(e) = self.client.delete(&session_url).send().await {
+                tracing::error!("Failed to cancel GCS upload session: {}", e);
+            }
+        }
+
+        uploaded
+    }
+
+    /// Builds a V4 signed URL. Only possible with a service account key.
+    fn signed_url(
+        &self,
+        account: &ServiceAccount,
+        name: &str,
+        expires_in: Duration,
+    ) -> Result<String> {
+        let endpoint = Url::parse(&self.endpoint)?;
+        let host = match (endpoint.host_str(), endpoint.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => anyhow::bail!("GCS endpoint has no host"),
+        };
+
+        let now = Utc::now();
+        let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let scope = format!("{}/auto/storage/goog4_request", now.format("%Y%m%d"));
+        let path = format!(
+            "/{}/{}",
+            utf8_percent_encode(&self.bucket, UNRESERVED),
+            name.split('/')
+                .map(|segment| utf8_percent_encode(segment, UNRESERVED).to_string())
+                .collect::<Vec<_>>()
+                .join("/")
+        );
+
+        let query = [
+            ("X-Goog-Algorithm", "GOOG4-RSA-SHA256".to_string()),
+            (
+                "X-Goog-Credential",
+                format!("{}/{}", account.client_email, scope),
+            ),
+            ("X-Goog-Date", timestamp.clone()),
+            ("X-Goog-Expires", expires_in.as_secs().to_string()),
+            ("X-Goog-SignedHeaders", "host".to_string()),
+        ]
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, utf8_percent_encode(value, UNRESERVED)))
+        .collect::<Vec<_>>()
+        .join("&");
+
+        let canonical_request = format!(
+            "GET\n{}\n{}\nhost:{}\n\nhost\nUNSIGNED-PAYLOAD",
+            path, query, host
+        );
+        let string_to_sign = format!(
+            "GOOG4-RSA-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let signature = hex::encode(account.sign(string_to_sign.as_bytes())?);
+
+        Ok(format!(
+            "{}{}?{}&X-Goog-Signature={}",
+            self.endpoint, path, query, signature
+        ))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for GcsStorage {
+    fn kind(&self) -> &'static str {
+        "gcs"
+    }
+
+    async fn put(&self, key: &str, content_type: &str, mut data: ByteStream<'_>) -> Result<String> {
+        let name = format!("{}{}", KEY_PREFIX, key);
+
+        let mut buffer = BytesMut::new();
+        fill(&mut data, &mut buffer, CHUNK_SIZE).await?;
+
+        if buffer.len() < CHUNK_SIZE {
+            let response = self
+                .request(Method::POST, &self.upload_url())
+                .await?
+                .query(&[("uploadType", "media"), ("name", &name)])
+                .header(header::CONTENT_TYPE, content_type)
+                .body(buffer.freeze())
+                .send()
+                .await?;
+            check(response).await?;
+        } else {
+            self.put_resumable(&name, content_type, &mut buffer, &mut data)
+                .await?;
+        }
+
+        Ok(format!("/{}", name))
+    }
+
+    async fn get(&self, path: &str) -> Result<ByteStream<'static>> {
+        let name = path.trim_start_matches('/');
+
+        let response = self
+            .request(Method::GET, &self.object_url(name))
+            .await?
+            .query(&[("alt", "media")])
+            .send()
+            .await?;
+
+        Ok(check(response)
+            .await?
+            .bytes_stream()
+            .map_err(std::io::Error::other)
+            .boxed())
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        let name = path.trim_start_matches('/');
+
+        let response = self
+            .request(Method::DELETE, &self.object_url(name))
+            .await?
+            .send()
+            .await?;
+        check(response).await?;
+
+        Ok(())
+    }
+
+    async fn head(&self, path: &str) -> Result<StoredObject> {
+        let name = path.trim_start_matches('/');
+
+        let response = self
+            .request(Method::GET, &self.object_url(name))
+            .await?
+            .send()
+            .await?;
+        let object: Object = check(response).await?.json().await?;
+
+        object.into_stored()
+    }
+
+    async fn list(&self) -> Result<Vec<StoredObject>> {
+        let mut objects = Vec::new();
+        let mut page_token: Option<String> = None;
+
+        loop {
+            let mut request = self
+                .request(Method::GET, &format!("{}/o", self.bucket_url()))
+                .await?
+                .query(&[("prefix", KEY_PREFIX)]);
+            if let Some(page_token) = &page_token {
+                request = request.query(&[("pageToken", page_token)]);
+            }
+
+            let page: ObjectList = check(request.send().await?).await?.json().await?;
+            for object in page.items {
+                objects.push(object.into_stored()?);
+            }
+
+            match page.next_page_token {
+                Some(token) if !token.is_empty() => page_token = Some(token),
+                _ => break,
+            }
+        }
+
+        Ok(objects)
+    }
+
+    /// Copies server-side; large objects may take several rewrite calls.
+    async fn copy(&self, path: &str, key: &str, content_type: &str) -> Result<String> {
+        let source = path.trim_start_matches('/');
+        let name = format!("{}{}", KEY_PREFIX, key);
+        let url = format!(
+            "{}/rewriteTo/b/{}/o/{}",
+            self.object_url(source),
+            utf8_percent_encode(&self.bucket, UNRESERVED),
+            utf8_percent_encode(&name, UNRESERVED)
+        );
+
+        let mut rewrite_token: Option<String> = None;
+        loop {
+            let mut request = self
+                .request(Method::POST, &url)
+                .await?
+                .json(&json!({"contentType": content_type}));
+            if let Some(token) = &rewrite_token {
+                request = request.query(&[("rewriteToken", token)]);
+            }
+
+            let response: RewriteResponse = check(request.send().await?).await?.json().await?;
+            if response.done {
+                break;
+            }
+            rewrite_token = response.rewrite_token;
+        }
+
+        Ok(format!("/{}", name))
+    }
+
+    async fn presign(&self, path: &str, expires_in: Duration) -> Result<Option<String>> {
+        let Credentials::ServiceAccount(account) = &self.auth.credentials else {
+            return Ok(None);
+        };
+
+        let name = path.trim_start_matches('/');
+        self.signed_url(account, name, expires_in).map(Some)
+    }
+
+    async fn check_ready(&self) -> Result<()> {
+        let response = self
+            .request(Method::GET, &self.bucket_url())
+            .await?
+            .send()
+            .await?;
+        check(response).await?;
+        Ok(())
+    }
+}
+
+/// Turns an error status into an error that includes the response body.
+async fn check(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let body = response.text().await.unwrap_or_default();
+    anyhow::bail!("GCS request failed with {}: {}", status, body)
+}
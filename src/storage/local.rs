@@ -1,10 +1,17 @@
 use anyhow::Result;
-use bytes::Bytes;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
 use std::path::{Path, PathBuf};
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
 use uuid::Uuid;
 
+use super::{ByteStream, StorageBackend, StoredObject};
+
+const PARTIAL_SUFFIX: &str = ".partial";
+
 #[derive(Debug, Clone)]
 pub struct LocalStorage {
     base_path: PathBuf,
@@ -15,60 +22,147 @@ impl LocalStorage {
         Self { base_path }
     }
 
-    pub async fn store_file(&self, filename: &str, data: Bytes) -> Result<String> {
-        let file_extension = Path::new(filename)
-            .extension()
-            .and_then(|ext| ext.to_str())
-            .unwrap_or("");
-
-        let name_without_ext = Path::new(filename)
-            .file_stem()
-            .and_then(|stem| stem.to_str())
-            .unwrap_or("file");
-
-        let unique_filename = if file_extension.is_empty() {
-            format!(
-                "{}-{}",
-                name_without_ext,
-                Uuid::new_v7(uuid::timestamp::Timestamp::now(uuid::NoContext))
-            )
-        } else {
-            format!(
-                "{}-{}.{}",
-                name_without_ext,
-                Uuid::new_v7(uuid::timestamp::Timestamp::now(uuid::NoContext)),
-                file_extension
-            )
-        };
-
-        let file_path = self.base_path.join(&unique_filename);
-
+    /// Writes `data` under a temporary name and moves it into place once it
+    /// has been synced, so an interrupted upload never leaves a truncated
+    /// file at the final path.
+    async fn write_atomically(&self, file_path: &Path, mut data: ByteStream<'_>) -> Result<()> {
         if let Some(parent) = file_path.parent() {
             fs::create_dir_all(parent).await?;
         }
 
-        let mut file = fs::File::create(&file_path).await?;
-        file.write_all(&data).await?;
-        file.sync_all().await?;
+        let mut partial_path = file_path.as_os_str().to_owned();
+        partial_path.push(PARTIAL_SUFFIX);
+        let partial_path = PathBuf::from(partial_path);
+
+        let mut file = fs::File::create(&partial_path).await?;
+        let written = async {
+            while let Some(chunk) = data.next().await {
+                file.write_all(&chunk?).await?;
+            }
+            file.sync_all().await?;
+            anyhow::Ok(())
+        }
+        .await;
+
+        if let Err(e) = written {
+            let _ = fs::remove_file(&partial_path).await;
+            return Err(e);
+        }
+        fs::rename(&partial_path, file_path).await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalStorage {
+    fn kind(&self) -> &'static str {
+        "local"
+    }
+
+    async fn put(&self, key: &str, _content_type: &str, data: ByteStream<'_>) -> Result<String> {
+        let file_path = self.base_path.join(key);
+        self.write_atomically(&file_path, data).await?;
 
         Ok(file_path.to_string_lossy().to_string())
     }
 
-    pub async fn get_file(&self, path: &str) -> Result<Vec<u8>> {
-        let file_path = Path::new(path);
-        let data = fs::read(file_path).await?;
-        Ok(data)
+    async fn get(&self, path: &str) -> Result<ByteStream<'static>> {
+        let file = fs::File::open(Path::new(path)).await?;
+        Ok(ReaderStream::new(file).boxed())
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        fs::remove_file(Path::new(path)).await?;
+        Ok(())
+    }
+
+    async fn head(&self, path: &str) -> Result<StoredObject> {
+        let metadata = fs::metadata(Path::new(path)).await?;
+        Ok(StoredObject {
+            path: path.to_string(),
+            size: metadata.len() as i64,
+            modified_at: metadata.modified().ok().map(DateTime::<Utc>::from),
+        })
+    }
+
+    async fn list(&self) -> Result<Vec<StoredObject>> {
+        let mut objects = Vec::new();
+        let mut entries = match fs::read_dir(&self.base_path).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(objects),
+            Err(e) => return Err(e.into()),
+        };
+
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') || name.ends_with(PARTIAL_SUFFIX) {
+                continue;
+            }
+
+            let metadata = entry.metadata().await?;
+            if !metadata.is_file() {
+                continue;
+            }
+
+            objects.push(StoredObject {
+                path: entry.path().to_string_lossy().to_string(),
+                size: metadata.len() as i64,
+                modified_at: metadata.modified().ok().map(DateTime::<Utc>::from),
+            });
+        }
+
+        Ok(objects)
     }
 
-    pub async fn delete_file(&self, path: &str) -> Result<()> {
-        let file_path = Path::new(path);
-        fs::remove_file(file_path).await?;
+    async fn copy(&self, path: &str, key: &str, _content_type: &str) -> Result<String> {
+        let file_path = self.base_path.join(key);
+        let source = fs::File::open(Path::new(path)).await?;
+        self.write_atomically(&file_path, ReaderStream::new(source).boxed())
+            .await?;
+
+        Ok(file_path.to_string_lossy().to_string())
+    }
+
+    /// Verifies that the storage directory is writable by creating and
+    /// removing a probe file.
+    async fn check_ready(&self) -> Result<()> {
+        let probe_path = self.base_path.join(format!(
+            ".readyz-{}",
+            Uuid::new_v7(uuid::timestamp::Timestamp::now(uuid::NoContext))
+        ));
+
+        fs::write(&probe_path, b"ok").await?;
+        fs::remove_file(&probe_path).await?;
         Ok(())
     }
 
-    pub fn get_mime_type(&self, path: &str) -> String {
-        mime_guess::from_path(path)
-            .first_or_octet_stream()
-            .to_string()
+    /// Removes temporary files left behind by uploads that were interrupted
+    /// mid-write.
+    async fn cleanup_partial(&self, modified_before: DateTime<Utc>) -> Result<usize> {
+        let mut removed = 0;
+        let mut entries = match fs::read_dir(&self.base_path).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        while let Some(entry) = entries.next_entry().await? {
+            if !entry
+                .file_name()
+                .to_string_lossy()
+                .ends_with(PARTIAL_SUFFIX)
+            {
+                continue;
+            }
+            let modified_at = entry.metadata().await?.modified().ok();
+            if modified_at.is_none_or(|modified| DateTime::<Utc>::from(modified) < modified_before)
+            {
+                fs::remove_file(entry.path()).await?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
     }
 }
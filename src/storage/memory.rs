@@ -0,0 +1,97 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use super::{backend, ByteStream, StorageBackend, StoredObject};
+
+/// Keeps blobs in process memory. Contents are lost on restart, so this is
+/// meant for tests and throwaway instances.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    objects: Arc<RwLock<HashMap<String, MemoryObject>>>,
+}
+
+#[derive(Debug, Clone)]
+struct MemoryObject {
+    data: Bytes,
+    modified_at: DateTime<Utc>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn object(&self, path: &str) -> Result<MemoryObject> {
+        self.objects
+            .read()
+            .unwrap()
+            .get(path)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("No such blob: {}", path))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MemoryStorage {
+    fn kind(&self) -> &'static str {
+        "memory"
+    }
+
+    async fn put(&self, key: &str, _content_type: &str, data: ByteStream<'_>) -> Result<String> {
+        let data = backend::collect(data).await?;
+        self.objects.write().unwrap().insert(
+            key.to_string(),
+            MemoryObject {
+                data: data.into(),
+                modified_at: Utc::now(),
+            },
+        );
+        Ok(key.to_string())
+    }
+
+    async fn get(&self, path: &str) -> Result<ByteStream<'static>> {
+        Ok(backend::once(self.object(path)?.data))
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        self.objects
+            .write()
+            .unwrap()
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| anyhow::anyhow!("No such blob: {}", path))
+    }
+
+    async fn head(&self, path: &str) -> Result<StoredObject> {
+        let object = self.object(path)?;
+        Ok(StoredObject {
+            path: path.to_string(),
+            size: object.data.len() as i64,
+            modified_at: Some(object.modified_at),
+        })
+    }
+
+    async fn list(&self) -> Result<Vec<StoredObject>> {
+        Ok(self
+            .objects
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(path, object)| StoredObject {
+                path: path.clone(),
+                size: object.data.len() as i64,
+                modified_at: Some(object.modified_at),
+            })
+            .collect())
+    }
+
+    async fn check_ready(&self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -1,74 +1,424 @@
+pub mod azure;
+pub mod backend;
+pub mod compression;
+pub mod encryption;
+pub mod gcs;
 pub mod local;
+pub mod memory;
+pub mod registry;
 pub mod s3;
+pub mod sftp;
+pub mod webdav;
 
-use crate::config::{Config, StorageType};
+use crate::config::{BackendConfig, BackendKind};
+use crate::metrics::observe_storage;
 use anyhow::Result;
+use aws_sdk_s3::config::Credentials;
 use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use serde::Serialize;
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use uuid::Uuid;
 
+pub use azure::AzureBlobStorage;
+pub use backend::{ByteStream, StorageBackend};
+pub use compression::{Codec, Compression};
+pub use encryption::{encrypted_size, Keyring, WrappedKey};
+pub use gcs::GcsStorage;
 pub use local::LocalStorage;
-pub use s3::S3Storage;
+pub use memory::MemoryStorage;
+pub use registry::{StorageRegistry, ROUTING_READ_AHEAD_LIMIT};
+pub use s3::{S3Encryption, S3Storage};
+pub use sftp::{SftpSettings, SftpStorage};
 
+/// Partially written files younger than this are left alone by cleanups, as
+/// their upload may still be running on this or another replica sharing the
+/// storage.
+pub const PARTIAL_UPLOAD_GRACE_PERIOD_MINUTES: i64 = 10;
+pub use webdav::WebDavStorage;
+
+/// A blob as seen by the storage backend, independent of the `files` table.
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredObject {
+    pub path: String,
+    pub size: i64,
+    pub modified_at: Option<DateTime<Utc>>,
+}
+
+/// A newly written blob and, if it was encrypted, the key to read it back.
+#[derive(Debug, Clone)]
+pub struct StoredBlob {
+    pub path: String,
+    pub key: Option<WrappedKey>,
+}
+
+/// A named storage backend. The name is what `File::storage_type` records.
+/// With a keyring, new blobs are encrypted before they reach the backend.
 #[derive(Debug, Clone)]
-pub enum Storage {
-    Local(LocalStorage),
-    S3(S3Storage),
+pub struct Storage {
+    name: String,
+    backend: Arc<dyn StorageBackend>,
+    keyring: Option<Arc<Keyring>>,
 }
 
 impl Storage {
-    pub async fn from_config(config: &Config) -> Result<Self> {
-        match config.storage_type {
-            StorageType::Local => {
-                let storage = LocalStorage::new(config.storage_path.clone());
-                Ok(Storage::Local(storage))
-            }
-            StorageType::S3 => {
-                let bucket = config
-                    .s3_bucket
-                    .clone()
-                    .ok_or_else(|| anyhow::anyhow!("S3 bucket not configured"))?;
+    pub async fn new(config: &BackendConfig) -> Result<Self> {
+        let backend: Arc<dyn StorageBackend> = match &config.kind {
+            BackendKind::Local { path } => Arc::new(LocalStorage::new(path.clone())),
+            BackendKind::S3 {
+                bucket,
+                region,
+                endpoint_url,
+                access_key_id,
+                secret_access_key,
+                sse,
+                sse_kms_key_id,
+                sse_customer_key,
+            } => {
+                let credentials = access_key_id.as_ref().zip(secret_access_key.as_ref()).map(
+                    |(access_key_id, secret_access_key)| {
+                        Credentials::new(
+                            access_key_id,
+                            secret_access_key.expose(),
+                            None,
+                            None,
+                            "file-server-config",
+                        )
+                    },
+                );
                 let storage = S3Storage::new(
-                    bucket,
-                    config.s3_region.clone(),
-                    config.aws_endpoint_url.clone(),
+                    bucket.clone(),
+                    region.clone(),
+                    endpoint_url.clone(),
+                    credentials,
+                    S3Encryption::new(*sse, sse_kms_key_id.clone(), sse_customer_key.as_ref())?,
                 )
                 .await?;
-                Ok(Storage::S3(storage))
+                Arc::new(storage)
             }
-        }
+            BackendKind::Gcs {
+                bucket,
+                endpoint_url,
+                credentials_file,
+            } => Arc::new(
+                GcsStorage::new(
+                    bucket.clone(),
+                    endpoint_url.clone(),
+                    credentials_file.clone(),
+                )
+                .await?,
+            ),
+            BackendKind::Azure {
+                account,
+                access_key,
+                container,
+                endpoint_url,
+            } => Arc::new(AzureBlobStorage::new(
+                account.clone(),
+                access_key.expose(),
+                container.clone(),
+                endpoint_url.clone(),
+            )?),
+            BackendKind::WebDav {
+                url,
+                prefix,
+                username,
+                password,
+                pool_size,
+                retries,
+            } => Arc::new(WebDavStorage::new(
+                url,
+                prefix,
+                username.clone(),
+                password.clone(),
+                *pool_size,
+                *retries,
+            )?),
+            BackendKind::Sftp {
+                host,
+                port,
+                username,
+                password,
+                private_key,
+                known_hosts,
+                prefix,
+                pool_size,
+                retries,
+            } => {
+                let settings = SftpSettings {
+                    host: host.clone(),
+                    port: *port,
+                    username: username.clone(),
+                    password: password.clone(),
+                    private_key: private_key.clone(),
+                    known_hosts: known_hosts.clone(),
+                };
+                Arc::new(SftpStorage::new(settings, prefix, *pool_size, *retries))
+            }
+            BackendKind::Memory => Arc::new(MemoryStorage::new()),
+        };
+
+        Ok(Self::from_backend(config.name.clone(), backend))
     }
 
-    pub async fn store_file(&self, filename: &str, data: Bytes) -> Result<String> {
-        match self {
-            Storage::Local(storage) => storage.store_file(filename, data).await,
-            Storage::S3(storage) => storage.store_file(filename, data).await,
+    /// Wraps a custom backend, e.g. one implemented outside this crate.
+    pub fn from_backend(name: impl Into<String>, backend: Arc<dyn StorageBackend>) -> Self {
+        Self {
+            name: name.into(),
+            backend,
+            keyring: None,
         }
     }
 
-    pub async fn get_file(&self, path: &str) -> Result<Vec<u8>> {
-        match self {
-            Storage::Local(storage) => storage.get_file(path).await,
-            Storage::S3(storage) => storage.get_file(path).await,
+    /// Encrypts blobs written from now on, and allows reading encrypted ones.
+    pub fn set_keyring(&mut self, keyring: Arc<Keyring>) {
+        self.keyring = Some(keyring);
+    }
+
+    #[tracing::instrument(skip(self, data), fields(backend = %self.name))]
+    pub async fn store_file(&self, filename: &str, data: Bytes) -> Result<StoredBlob> {
+        self.store_stream(filename, backend::once(data)).await
+    }
+
+    /// Writes a new blob for a file called `filename`, encrypted under a
+    /// fresh data key if a keyring is set.
+    #[tracing::instrument(skip(self, data), fields(backend = %self.name))]
+    pub async fn store_stream(&self, filename: &str, data: ByteStream<'_>) -> Result<StoredBlob> {
+        let (data, key) = match &self.keyring {
+            Some(keyring) => {
+                let (data, key) = keyring.encrypt(data)?;
+                (data, Some(key))
+            }
+            None => (data, None),
+        };
+
+        let path = self.store_raw(filename, data).await?;
+        Ok(StoredBlob { path, key })
+    }
+
+    /// Writes `data` exactly as given, e.g. a blob that is already encrypted,
+    /// and returns its path.
+    #[tracing::instrument(skip(self, data), fields(backend = %self.name))]
+    pub async fn store_raw(&self, filename: &str, data: ByteStream<'_>) -> Result<String> {
+        let key = unique_key(filename);
+        let content_type = self.get_mime_type(filename);
+        observe_storage(
+            &self.name,
+            "store",
+            self.backend.put(&key, &content_type, data),
+        )
+        .await
+    }
+
+    /// Reads a whole blob into memory; prefer [`Storage::get_stream`] for
+    /// anything that may be large.
+    #[tracing::instrument(skip(self, key), fields(backend = %self.name))]
+    pub async fn get_file(&self, path: &str, key: Option<&WrappedKey>) -> Result<Vec<u8>> {
+        let data = self.get_stream(path, key).await?;
+        Ok(backend::collect(data).await?)
+    }
+
+    /// Reads a blob, decrypting it with `key` if the file has one. Pass
+    /// `None` to read the blob as stored.
+    #[tracing::instrument(skip(self, key), fields(backend = %self.name))]
+    pub async fn get_stream(
+        &self,
+        path: &str,
+        key: Option<&WrappedKey>,
+    ) -> Result<ByteStream<'static>> {
+        let data = observe_storage(&self.name, "get", self.backend.get(path)).await?;
+        match (key, &self.keyring) {
+            (None, _) => Ok(data),
+            (Some(key), Some(keyring)) => keyring.decrypt(data, key),
+            (Some(_), None) => {
+                anyhow::bail!(
+                    "Blob {} is encrypted but no encryption key is configured",
+                    path
+                )
+            }
         }
     }
 
+    /// Reads a file's content as it was uploaded, decrypting and decompressing
+    /// the blob as needed.
+    pub async fn get_content(
+        &self,
+        path: &str,
+        key: Option<&WrappedKey>,
+        codec: Option<Codec>,
+    ) -> Result<ByteStream<'static>> {
+        let data = self.get_stream(path, key).await?;
+        Ok(match codec {
+            Some(codec) => compression::decompress(data, codec),
+            None => data,
+        })
+    }
+
+    #[tracing::instrument(skip(self), fields(backend = %self.name))]
     pub async fn delete_file(&self, path: &str) -> Result<()> {
-        match self {
-            Storage::Local(storage) => storage.delete_file(path).await,
-            Storage::S3(storage) => storage.delete_file(path).await,
-        }
+        observe_storage(&self.name, "delete", self.backend.delete(path)).await
     }
 
-    pub fn get_mime_type(&self, path: &str) -> String {
-        match self {
-            Storage::Local(storage) => storage.get_mime_type(path),
-            Storage::S3(storage) => storage.get_mime_type(path),
+    #[tracing::instrument(skip(self), fields(backend = %self.name))]
+    pub async fn file_size(&self, path: &str) -> Result<i64> {
+        Ok(self.head(path).await?.size)
+    }
+
+    #[tracing::instrument(skip(self), fields(backend = %self.name))]
+    pub async fn head(&self, path: &str) -> Result<StoredObject> {
+        observe_storage(&self.name, "head", self.backend.head(path)).await
+    }
+
+    #[tracing::instrument(skip(self), fields(backend = %self.name))]
+    pub async fn list_files(&self) -> Result<Vec<StoredObject>> {
+        observe_storage(&self.name, "list", self.backend.list()).await
+    }
+
+    /// Duplicates a blob as a new file called `filename` and returns the
+    /// copy's path.
+    #[tracing::instrument(skip(self), fields(backend = %self.name))]
+    pub async fn copy_file(&self, path: &str, filename: &str) -> Result<String> {
+        let key = unique_key(filename);
+        let content_type = self.get_mime_type(filename);
+        observe_storage(
+            &self.name,
+            "copy",
+            self.backend.copy(path, &key, &content_type),
+        )
+        .await
+    }
+
+    /// A temporary direct download URL, if the backend supports them. Never
+    /// issued with a keyring, since the URL would serve ciphertext.
+    #[tracing::instrument(skip(self), fields(backend = %self.name))]
+    pub async fn presign(&self, path: &str, expires_in: Duration) -> Result<Option<String>> {
+        if self.keyring.is_some() {
+            return Ok(None);
         }
+        observe_storage(
+            &self.name,
+            "presign",
+            self.backend.presign(path, expires_in),
+        )
+        .await
     }
 
-    pub fn storage_type(&self) -> String {
-        match self {
-            Storage::Local(_) => "local".to_string(),
-            Storage::S3(_) => "s3".to_string(),
+    #[tracing::instrument(skip(self), fields(backend = %self.name))]
+    pub async fn check_ready(&self) -> Result<()> {
+        observe_storage(&self.name, "check", self.backend.check_ready()).await
+    }
+
+    /// Removes leftovers of interrupted writes that are older than
+    /// [`PARTIAL_UPLOAD_GRACE_PERIOD_MINUTES`].
+    pub async fn cleanup_partial_uploads(&self) {
+        let cutoff = Utc::now() - chrono::Duration::minutes(PARTIAL_UPLOAD_GRACE_PERIOD_MINUTES);
+        match self.backend.cleanup_partial(cutoff).await {
+            Ok(0) => {}
+            Ok(removed) => tracing::info!(
+                "Removed {} partially written files from {}",
+                removed,
+                self.name
+            ),
+            Err(e) => tracing::error!(
+                "Failed to remove partially written files from {}: {}",
+                self.name,
+                e
+            ),
         }
     }
+
+    pub fn get_mime_type(&self, path: &str) -> String {
+        mime_guess::from_path(path)
+            .first_or_octet_stream()
+            .to_string()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The kind of backend, e.g. `local` or `s3`.
+    pub fn kind(&self) -> &'static str {
+        self.backend.kind()
+    }
+}
+
+/// Builds a key that keeps the original name recognisable but cannot collide,
+/// e.g. `report-<uuid>.pdf`.
+fn unique_key(filename: &str) -> String {
+    let file_extension = Path::new(filename)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("");
+
+    let name_without_ext = Path::new(filename)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("file");
+
+    let id = Uuid::new_v7(uuid::timestamp::Timestamp::now(uuid::NoContext));
+    if file_extension.is_empty() {
+        format!("{}-{}", name_without_ext, id)
+    } else {
+        format!("{}-{}.{}", name_without_ext, id, file_extension)
+    }
+}
+
+/// Wraps `data` so that it fails once more than `limit` bytes have been read.
+/// The counter reports how many bytes were read, so callers can tell an
+/// oversized upload apart from other errors.
+pub fn limit_stream(data: ByteStream<'_>, limit: u64) -> (ByteStream<'_>, Arc<AtomicU64>) {
+    let counter = Arc::new(AtomicU64::new(0));
+    let read = counter.clone();
+
+    let data = data
+        .map(move |chunk| {
+            let chunk = chunk?;
+            let total = read.fetch_add(chunk.len() as u64, Ordering::Relaxed) + chunk.len() as u64;
+            if total > limit {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::FileTooLarge,
+                    "File too large",
+                ));
+            }
+            Ok(chunk)
+        })
+        .boxed();
+
+    (data, counter)
+}
+
+/// The `len` bytes of `data` starting at `start`, for serving a byte range.
+pub fn slice_stream(data: ByteStream<'static>, start: u64, len: u64) -> ByteStream<'static> {
+    futures::stream::try_unfold(
+        (data, start, len),
+        |(mut data, mut skip, mut remaining)| async move {
+            while remaining > 0 {
+                let Some(chunk) = data.next().await else {
+                    break;
+                };
+                let chunk = chunk?;
+                if skip >= chunk.len() as u64 {
+                    skip -= chunk.len() as u64;
+                    continue;
+                }
+
+                let mut chunk = chunk.slice(skip as usize..);
+                chunk.truncate(remaining.min(chunk.len() as u64) as usize);
+                skip = 0;
+                remaining -= chunk.len() as u64;
+                return Ok(Some((chunk, (data, skip, remaining))));
+            }
+            Ok(None)
+        },
+    )
+    .boxed()
 }
@@ -0,0 +1,142 @@
+use anyhow::Result;
+use bytes::BytesMut;
+use futures::StreamExt;
+use std::{collections::HashMap, sync::Arc};
+
+use crate::config::{RoutingRule, StorageConfig};
+
+use super::{ByteStream, Keyring, Storage};
+
+/// The most of an upload [`StorageRegistry::route_stream`] holds in memory
+/// to evaluate size conditions. Size thresholds of routing rules may not
+/// exceed it.
+pub const ROUTING_READ_AHEAD_LIMIT: u64 = 16 * 1024 * 1024;
+
+/// Every configured backend, keyed by name. Reads dispatch on the row's
+/// `storage_type`; writes go to the first backend whose rule matches.
+#[derive(Debug, Clone)]
+pub struct StorageRegistry {
+    backends: HashMap<String, Storage>,
+    default: String,
+    rules: Vec<RoutingRule>,
+    keyring: Option<Arc<Keyring>>,
+}
+
+impl StorageRegistry {
+    pub async fn from_config(config: &StorageConfig) -> Result<Self> {
+        let mut backends = HashMap::new();
+        for backend in &config.backends {
+            backends.insert(backend.name.clone(), Storage::new(backend).await?);
+        }
+
+        Ok(Self {
+            backends,
+            default: config.default.clone(),
+            rules: config.rules.clone(),
+            keyring: None,
+        })
+    }
+
+    /// Adds a backend that is not described by the storage configuration,
+    /// such as a custom [`super::StorageBackend`], replacing any backend of
+    /// the same name.
+    pub fn register(&mut self, mut storage: Storage) {
+        if let Some(keyring) = &self.keyring {
+            storage.set_keyring(keyring.clone());
+        }
+        self.backends.insert(storage.name().to_string(), storage);
+    }
+
+    /// Encrypts new blobs on every backend, including ones registered later.
+    pub fn set_keyring(&mut self, keyring: Keyring) {
+        let keyring = Arc::new(keyring);
+        for storage in self.backends.values_mut() {
+            storage.set_keyring(keyring.clone());
+        }
+        self.keyring = Some(keyring);
+    }
+
+    pub fn keyring(&self) -> Option<&Keyring> {
+        self.keyring.as_deref()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Storage> {
+        self.backends.get(name)
+    }
+
+    /// Looks up a backend by name, failing if it is not configured.
+    pub fn require(&self, name: &str) -> Result<&Storage> {
+        self.get(name)
+            .ok_or_else(|| anyhow::anyhow!("Storage backend {} is not configured", name))
+    }
+
+    pub fn default_backend(&self) -> &Storage {
+        &self.backends[&self.default]
+    }
+
+    /// Picks the backend for a new upload. `uploader` is the audit actor, if
+    /// the upload came through the API.
+    pub fn route(&self, size: u64, mime_type: &str, uploader: Option<&str>) -> &Storage {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(size, mime_type, uploader))
+            .map(|rule| &self.backends[&rule.backend])
+            .unwrap_or_else(|| self.default_backend())
+    }
+
+    /// Routes an upload whose size is not known up front. Only as much of
+    /// `data` is buffered as the size conditions of the rules need: once the
+    /// stream is longer than every threshold, its exact size no longer
+    /// changes which rule matches. Never more than
+    /// [`ROUTING_READ_AHEAD_LIMIT`] is buffered; a longer stream is routed as
+    /// larger than any threshold. Returns the backend and the full stream.
+    pub async fn route_stream<'a>(
+        &self,
+        mut data: ByteStream<'a>,
+        mime_type: &str,
+        uploader: Option<&str>,
+    ) -> std::io::Result<(&Storage, ByteStream<'a>)> {
+        let threshold = self
+            .rules
+            .iter()
+            .flat_map(|rule| {
+                [
+                    rule.min_size,
+                    rule.max_size.map(|max| max.saturating_add(1)),
+                ]
+            })
+            .flatten()
+            .max()
+            .unwrap_or(0);
+        let read_ahead = threshold.min(ROUTING_READ_AHEAD_LIMIT + 1);
+
+        let mut buffer = BytesMut::new();
+        let mut ended = false;
+        while (buffer.len() as u64) < read_ahead {
+            match data.next().await {
+                Some(chunk) => buffer.extend_from_slice(&chunk?),
+                None => {
+                    ended = true;
+                    break;
+                }
+            }
+        }
+
+        let size = match buffer.len() as u64 {
+            size if !ended && size < threshold => u64::MAX,
+            size => size,
+        };
+        let storage = self.route(size, mime_type, uploader);
+        let data = futures::stream::once(async move { Ok(buffer.freeze()) })
+            .chain(data)
+            .boxed();
+        Ok((storage, data))
+    }
+
+    /// All backends, sorted by name.
+    pub fn backends(&self) -> Vec<&Storage> {
+        let mut backends: Vec<&Storage> = self.backends.values().collect();
+        backends.sort_by(|a, b| a.name().cmp(b.name()));
+        backends
+    }
+}
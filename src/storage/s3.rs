@@ -1,15 +1,123 @@
 use anyhow::Result;
+use async_trait::async_trait;
 use aws_config::BehaviorVersion;
-use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::config::Credentials;
+use aws_sdk_s3::presigning::PresigningConfig;
+use aws_sdk_s3::primitives;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart, ServerSideEncryption};
 use aws_sdk_s3::Client;
-use bytes::Bytes;
-use std::path::Path;
-use uuid::Uuid;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::BytesMut;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use md5::{Digest, Md5};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::time::Duration;
+use tokio_util::io::ReaderStream;
+
+use super::{backend::fill, ByteStream, StorageBackend, StoredObject};
+use crate::config::{S3ServerSideEncryption, Secret};
+
+const KEY_PREFIX: &str = "uploads/";
+
+/// Uploads larger than this are sent as a multipart upload in parts of this
+/// size, so that no more than one part is held in memory. S3 requires parts
+/// of at least 5 MiB.
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// Characters left unescaped in the `x-amz-copy-source` header.
+const COPY_SOURCE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// Encryption applied by S3 itself, as an alternative to encrypting blobs
+/// before upload.
+#[derive(Debug, Clone, Default)]
+pub enum S3Encryption {
+    #[default]
+    None,
+    /// SSE-S3 or SSE-KMS, set when an object is written.
+    Managed {
+        algorithm: ServerSideEncryption,
+        kms_key_id: Option<String>,
+    },
+    /// SSE-C. S3 does not keep the key, so every request for an object must
+    /// carry it.
+    CustomerKey { key: Secret, key_md5: String },
+}
+
+impl S3Encryption {
+    pub fn new(
+        sse: Option<S3ServerSideEncryption>,
+        kms_key_id: Option<String>,
+        customer_key: Option<&Secret>,
+    ) -> Result<Self> {
+        Ok(match (sse, customer_key) {
+            (Some(_), Some(_)) => {
+                anyhow::bail!("S3 server-side encryption and a customer key are exclusive")
+            }
+            (Some(S3ServerSideEncryption::Aes256), None) => Self::Managed {
+                algorithm: ServerSideEncryption::Aes256,
+                kms_key_id: None,
+            },
+            (Some(S3ServerSideEncryption::AwsKms), None) => Self::Managed {
+                algorithm: ServerSideEncryption::AwsKms,
+                kms_key_id,
+            },
+            (None, Some(key)) => {
+                let raw = STANDARD
+                    .decode(key.expose())
+                    .map_err(|_| anyhow::anyhow!("S3 customer key must be base64"))?;
+                Self::CustomerKey {
+                    key: key.clone(),
+                    key_md5: STANDARD.encode(Md5::digest(raw)),
+                }
+            }
+            (None, None) => Self::None,
+        })
+    }
+
+    fn algorithm(&self) -> Option<ServerSideEncryption> {
+        match self {
+            Self::Managed { algorithm, .. } => Some(algorithm.clone()),
+            _ => None,
+        }
+    }
+
+    fn kms_key_id(&self) -> Option<String> {
+        match self {
+            Self::Managed { kms_key_id, .. } => kms_key_id.clone(),
+            _ => None,
+        }
+    }
+
+    fn customer_algorithm(&self) -> Option<String> {
+        matches!(self, Self::CustomerKey { .. }).then(|| "AES256".to_string())
+    }
+
+    fn customer_key(&self) -> Option<String> {
+        match self {
+            Self::CustomerKey { key, .. } => Some(key.expose().to_string()),
+            _ => None,
+        }
+    }
+
+    fn customer_key_md5(&self) -> Option<String> {
+        match self {
+            Self::CustomerKey { key_md5, .. } => Some(key_md5.clone()),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct S3Storage {
     client: Client,
     bucket: String,
+    encryption: S3Encryption,
 }
 
 impl S3Storage {
@@ -17,9 +125,15 @@ impl S3Storage {
         bucket: String,
         region: Option<String>,
         endpoint_url: Option<String>,
+        credentials: Option<Credentials>,
+        encryption: S3Encryption,
     ) -> Result<Self> {
         let mut config_loader = aws_config::defaults(BehaviorVersion::latest());
 
+        if let Some(credentials) = credentials {
+            config_loader = config_loader.credentials_provider(credentials);
+        }
+
         if let Some(region) = region {
             config_loader = config_loader.region(aws_config::Region::new(region));
         }
@@ -38,54 +152,140 @@ impl S3Storage {
             Client::new(&config)
         };
 
-        Ok(Self { client, bucket })
+        Ok(Self {
+            client,
+            bucket,
+            encryption,
+        })
     }
 
-    pub async fn store_file(&self, filename: &str, data: Bytes) -> Result<String> {
-        let file_extension = Path::new(filename)
-            .extension()
-            .and_then(|ext| ext.to_str())
-            .unwrap_or("");
+    async fn put_multipart(
+        &self,
+        key: &str,
+        content_type: &str,
+        buffer: &mut BytesMut,
+        data: &mut ByteStream<'_>,
+    ) -> Result<()> {
+        let upload = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .content_type(content_type)
+            .set_server_side_encryption(self.encryption.algorithm())
+            .set_ssekms_key_id(self.encryption.kms_key_id())
+            .set_sse_customer_algorithm(self.encryption.customer_algorithm())
+            .set_sse_customer_key(self.encryption.customer_key())
+            .set_sse_customer_key_md5(self.encryption.customer_key_md5())
+            .send()
+            .await?;
+        let upload_id = upload
+            .upload_id()
+            .ok_or_else(|| anyhow::anyhow!("S3 did not return an upload id"))?
+            .to_string();
 
-        let name_without_ext = Path::new(filename)
-            .file_stem()
-            .and_then(|stem| stem.to_str())
-            .unwrap_or("file");
+        let uploaded = async {
+            let mut parts = Vec::new();
+            loop {
+                fill(data, buffer, PART_SIZE).await?;
+                if buffer.is_empty() {
+                    break;
+                }
 
-        let unique_filename = if file_extension.is_empty() {
-            format!(
-                "{}-{}",
-                name_without_ext,
-                Uuid::new_v7(uuid::timestamp::Timestamp::now(uuid::NoContext))
-            )
-        } else {
-            format!(
-                "{}-{}.{}",
-                name_without_ext,
-                Uuid::new_v7(uuid::timestamp::Timestamp::now(uuid::NoContext)),
-                file_extension
-            )
-        };
+                let part = buffer.split_to(PART_SIZE.min(buffer.len())).freeze();
+                let part_number = parts.len() as i32 + 1;
+                let response = self
+                    .client
+                    .upload_part()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .upload_id(&upload_id)
+                    .part_number(part_number)
+                    .set_sse_customer_algorithm(self.encryption.customer_algorithm())
+                    .set_sse_customer_key(self.encryption.customer_key())
+                    .set_sse_customer_key_md5(self.encryption.customer_key_md5())
+                    .body(primitives::ByteStream::from(part))
+                    .send()
+                    .await?;
 
-        let key = format!("uploads/{}", unique_filename);
+                parts.push(
+                    CompletedPart::builder()
+                        .part_number(part_number)
+                        .set_e_tag(response.e_tag().map(str::to_string))
+                        .build(),
+                );
+            }
 
-        let content_type = mime_guess::from_path(filename)
-            .first_or_octet_stream()
-            .to_string();
+            self.client
+                .complete_multipart_upload()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(&upload_id)
+                .multipart_upload(
+                    CompletedMultipartUpload::builder()
+                        .set_parts(Some(parts))
+                        .build(),
+                )
+                .send()
+                .await?;
+            anyhow::Ok(())
+        }
+        .await;
 
-        self.client
-            .put_object()
-            .bucket(&self.bucket)
-            .key(&key)
-            .body(ByteStream::from(data))
-            .content_type(content_type)
-            .send()
-            .await?;
+        if uploaded.is_err() {
+            if let Err(e) = self
+                .client
+                .abort_multipart_upload()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(&upload_id)
+                .send()
+                .await
+            {
+                tracing::error!("Failed to abort multipart upload {}: {}", upload_id, e);
+            }
+        }
+
+        uploaded
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Storage {
+    fn kind(&self) -> &'static str {
+        "s3"
+    }
+
+    async fn put(&self, key: &str, content_type: &str, mut data: ByteStream<'_>) -> Result<String> {
+        let key = format!("{}{}", KEY_PREFIX, key);
+
+        let mut buffer = BytesMut::new();
+        fill(&mut data, &mut buffer, PART_SIZE).await?;
+
+        if buffer.len() < PART_SIZE {
+            // The whole blob fits in one part.
+            self.client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(&key)
+                .body(primitives::ByteStream::from(buffer.freeze()))
+                .content_type(content_type)
+                .set_server_side_encryption(self.encryption.algorithm())
+                .set_ssekms_key_id(self.encryption.kms_key_id())
+                .set_sse_customer_algorithm(self.encryption.customer_algorithm())
+                .set_sse_customer_key(self.encryption.customer_key())
+                .set_sse_customer_key_md5(self.encryption.customer_key_md5())
+                .send()
+                .await?;
+        } else {
+            self.put_multipart(&key, content_type, &mut buffer, &mut data)
+                .await?;
+        }
 
         Ok(format!("/{}", key))
     }
 
-    pub async fn get_file(&self, path: &str) -> Result<Vec<u8>> {
+    async fn get(&self, path: &str) -> Result<ByteStream<'static>> {
         let key = path.trim_start_matches('/');
 
         let response = self
@@ -93,14 +293,16 @@ impl S3Storage {
             .get_object()
             .bucket(&self.bucket)
             .key(key)
+            .set_sse_customer_algorithm(self.encryption.customer_algorithm())
+            .set_sse_customer_key(self.encryption.customer_key())
+            .set_sse_customer_key_md5(self.encryption.customer_key_md5())
             .send()
             .await?;
 
-        let data = response.body.collect().await?;
-        Ok(data.into_bytes().to_vec())
+        Ok(ReaderStream::new(response.body.into_async_read()).boxed())
     }
 
-    pub async fn delete_file(&self, path: &str) -> Result<()> {
+    async fn delete(&self, path: &str) -> Result<()> {
         let key = path.trim_start_matches('/');
 
         self.client
@@ -113,9 +315,113 @@ impl S3Storage {
         Ok(())
     }
 
-    pub fn get_mime_type(&self, path: &str) -> String {
-        mime_guess::from_path(path)
-            .first_or_octet_stream()
-            .to_string()
+    async fn head(&self, path: &str) -> Result<StoredObject> {
+        let key = path.trim_start_matches('/');
+
+        let response = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .set_sse_customer_algorithm(self.encryption.customer_algorithm())
+            .set_sse_customer_key(self.encryption.customer_key())
+            .set_sse_customer_key_md5(self.encryption.customer_key_md5())
+            .send()
+            .await?;
+
+        Ok(StoredObject {
+            path: path.to_string(),
+            size: response.content_length().unwrap_or_default(),
+            modified_at: response
+                .last_modified()
+                .and_then(|time| DateTime::<Utc>::from_timestamp(time.secs(), 0)),
+        })
+    }
+
+    async fn list(&self) -> Result<Vec<StoredObject>> {
+        let mut objects = Vec::new();
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(KEY_PREFIX)
+            .into_paginator()
+            .send();
+
+        while let Some(page) = pages.next().await {
+            for object in page?.contents() {
+                let Some(key) = object.key() else {
+                    continue;
+                };
+
+                objects.push(StoredObject {
+                    path: format!("/{}", key),
+                    size: object.size().unwrap_or_default(),
+                    modified_at: object
+                        .last_modified()
+                        .and_then(|time| DateTime::<Utc>::from_timestamp(time.secs(), 0)),
+                });
+            }
+        }
+
+        Ok(objects)
+    }
+
+    /// Copies server-side. A single `CopyObject` is limited to 5 GiB.
+    async fn copy(&self, path: &str, key: &str, content_type: &str) -> Result<String> {
+        let source_key = path.trim_start_matches('/');
+        let key = format!("{}{}", KEY_PREFIX, key);
+
+        self.client
+            .copy_object()
+            .bucket(&self.bucket)
+            .copy_source(format!(
+                "{}/{}",
+                self.bucket,
+                utf8_percent_encode(source_key, COPY_SOURCE)
+            ))
+            .key(&key)
+            .content_type(content_type)
+            .metadata_directive(aws_sdk_s3::types::MetadataDirective::Replace)
+            .set_server_side_encryption(self.encryption.algorithm())
+            .set_ssekms_key_id(self.encryption.kms_key_id())
+            .set_sse_customer_algorithm(self.encryption.customer_algorithm())
+            .set_sse_customer_key(self.encryption.customer_key())
+            .set_sse_customer_key_md5(self.encryption.customer_key_md5())
+            .set_copy_source_sse_customer_algorithm(self.encryption.customer_algorithm())
+            .set_copy_source_sse_customer_key(self.encryption.customer_key())
+            .set_copy_source_sse_customer_key_md5(self.encryption.customer_key_md5())
+            .send()
+            .await?;
+
+        Ok(format!("/{}", key))
+    }
+
+    /// Not available with SSE-C, since the URL would have to carry the key.
+    async fn presign(&self, path: &str, expires_in: Duration) -> Result<Option<String>> {
+        if matches!(self.encryption, S3Encryption::CustomerKey { .. }) {
+            return Ok(None);
+        }
+
+        let key = path.trim_start_matches('/');
+
+        let request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .presigned(PresigningConfig::expires_in(expires_in)?)
+            .await?;
+
+        Ok(Some(request.uri().to_string()))
+    }
+
+    async fn check_ready(&self) -> Result<()> {
+        self.client
+            .head_bucket()
+            .bucket(&self.bucket)
+            .send()
+            .await?;
+        Ok(())
     }
 }
@@ -0,0 +1,415 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use ssh2::{CheckResult, ErrorCode, KnownHostFileKind, Session, Sftp};
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{OnceCell, OwnedSemaphorePermit, Semaphore};
+use uuid::Uuid;
+
+use super::{backend::retry, ByteStream, StorageBackend, StoredObject};
+use crate::config::Secret;
+
+const PARTIAL_SUFFIX: &str = ".partial";
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+const TIMEOUT_MS: u32 = 30_000;
+
+/// How to reach and log in to the SFTP server.
+#[derive(Debug, Clone)]
+pub struct SftpSettings {
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub password: Option<Secret>,
+    /// Unencrypted private key file. Without a key or password, the SSH agent
+    /// is asked.
+    pub private_key: Option<PathBuf>,
+    /// OpenSSH `known_hosts` file the server's key must appear in. The key is
+    /// not verified when unset.
+    pub known_hosts: Option<PathBuf>,
+}
+
+/// A directory on an SFTP server. Blobs are written under `prefix`, which is
+/// created on first use, and renamed into place once fully uploaded.
+///
+/// libssh2 is blocking, so every call runs on the blocking thread pool with a
+/// connection taken from a pool of at most `pool_size` sessions.
+#[derive(Debug, Clone)]
+pub struct SftpStorage {
+    pool: Arc<Pool>,
+    prefix: String,
+    retries: u32,
+    directories: Arc<OnceCell<()>>,
+}
+
+struct Connection {
+    // Keeps the SSH session alive for as long as the SFTP channel is used.
+    _session: Session,
+    sftp: Sftp,
+}
+
+struct Pool {
+    settings: SftpSettings,
+    idle: Mutex<Vec<Arc<Connection>>>,
+    permits: Arc<Semaphore>,
+}
+
+impl std::fmt::Debug for Pool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pool")
+            .field("settings", &self.settings)
+            .field("available", &self.permits.available_permits())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A connection checked out of the pool. It is returned on drop unless it
+/// was marked broken.
+struct Pooled {
+    connection: Arc<Connection>,
+    pool: Arc<Pool>,
+    broken: bool,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Drop for Pooled {
+    fn drop(&mut self) {
+        if !self.broken {
+            if let Ok(mut idle) = self.pool.idle.lock() {
+                idle.push(self.connection.clone());
+            }
+        }
+    }
+}
+
+impl Pool {
+    async fn get(self: &Arc<Self>) -> Result<Pooled> {
+        let permit = self.permits.clone().acquire_owned().await?;
+        let idle = self.idle.lock().ok().and_then(|mut idle| idle.pop());
+        let connection = match idle {
+            Some(connection) => connection,
+            None => {
+                let settings = self.settings.clone();
+                Arc::new(tokio::task::spawn_blocking(move || connect(&settings)).await??)
+            }
+        };
+
+        Ok(Pooled {
+            connection,
+            pool: self.clone(),
+            broken: false,
+            _permit: permit,
+        })
+    }
+}
+
+fn connect(settings: &SftpSettings) -> Result<Connection> {
+    let tcp = TcpStream::connect((settings.host.as_str(), settings.port))?;
+    let mut session = Session::new()?;
+    session.set_tcp_stream(tcp);
+    session.set_timeout(TIMEOUT_MS);
+    session.handshake()?;
+
+    if let Some(path) = &settings.known_hosts {
+        let mut known_hosts = session.known_hosts()?;
+        known_hosts.read_file(path, KnownHostFileKind::OpenSSH)?;
+        let (key, _) = session
+            .host_key()
+            .context("SFTP server did not send a host key")?;
+        match known_hosts.check_port(&settings.host, settings.port, key) {
+            CheckResult::Match => {}
+            CheckResult::Mismatch => {
+                anyhow::bail!("Host key of {} does not match known_hosts", settings.host)
+            }
+            CheckResult::NotFound | CheckResult::Failure => {
+                anyhow::bail!("Host key of {} is not in known_hosts", settings.host)
+            }
+        }
+    }
+
+    match (&settings.private_key, &settings.password) {
+        (Some(private_key), _) => {
+            session.userauth_pubkey_file(&settings.username, None, private_key, None)?
+        }
+        (None, Some(password)) => {
+            session.userauth_password(&settings.username, password.expose())?
+        }
+        (None, None) => session.userauth_agent(&settings.username)?,
+    }
+    if !session.authenticated() {
+        anyhow::bail!("SFTP authentication as {} failed", settings.username);
+    }
+
+    let sftp = session.sftp()?;
+    Ok(Connection {
+        _session: session,
+        sftp,
+    })
+}
+
+/// Whether an error came from the connection rather than the SFTP server
+/// rejecting the request, in which case the connection is dropped and the
+/// request retried.
+fn is_transient(e: &anyhow::Error) -> bool {
+    match e.downcast_ref::<ssh2::Error>() {
+        Some(e) => matches!(e.code(), ErrorCode::Session(_)),
+        None => e.is::<std::io::Error>(),
+    }
+}
+
+fn stored_object(path: &Path, stat: &ssh2::FileStat) -> StoredObject {
+    StoredObject {
+        path: path.to_string_lossy().to_string(),
+        size: stat.size.unwrap_or_default() as i64,
+        modified_at: stat
+            .mtime
+            .and_then(|mtime| DateTime::<Utc>::from_timestamp(mtime as i64, 0)),
+    }
+}
+
+impl SftpStorage {
+    /// `prefix` is resolved against the login directory unless absolute.
+    pub fn new(settings: SftpSettings, prefix: &str, pool_size: usize, retries: u32) -> Self {
+        let prefix = match prefix.trim_end_matches('/') {
+            "" if prefix.starts_with('/') => "/".to_string(),
+            "" => ".".to_string(),
+            prefix => prefix.to_string(),
+        };
+
+        Self {
+            pool: Arc::new(Pool {
+                settings,
+                idle: Mutex::new(Vec::new()),
+                permits: Arc::new(Semaphore::new(pool_size.max(1))),
+            }),
+            prefix,
+            retries,
+            directories: Arc::new(OnceCell::new()),
+        }
+    }
+
+    fn blob_path(&self, key: &str) -> PathBuf {
+        Path::new(&self.prefix).join(key)
+    }
+
+    /// Runs `operation` on a pooled connection, reconnecting and retrying if
+    /// the connection fails.
+    async fn run<T, F>(&self, operation: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: Fn(&Sftp) -> Result<T> + Clone + Send + 'static,
+    {
+        retry(self.retries, is_transient, || async {
+            let mut pooled = self.pool.get().await?;
+            let connection = pooled.connection.clone();
+            let operation = operation.clone();
+            let result = tokio::task::spawn_blocking(move || operation(&connection.sftp)).await?;
+            if result.as_ref().is_err_and(is_transient) {
+                pooled.broken = true;
+            }
+            result
+        })
+        .await
+    }
+
+    /// Creates the directories leading up to `prefix`, once per process.
+    async fn ensure_directories(&self) -> Result<()> {
+        self.directories
+            .get_or_try_init(|| {
+                let prefix = PathBuf::from(&self.prefix);
+                self.run(move |sftp| {
+                    let mut path = PathBuf::new();
+                    for component in prefix.components() {
+                        path.push(component);
+                        if sftp.stat(&path).is_err() {
+                            sftp.mkdir(&path, 0o755)?;
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Streams `data` into a new file at `path` on a single connection.
+    async fn upload(&self, path: PathBuf, mut data: ByteStream<'_>) -> Result<()> {
+        let mut pooled = self.pool.get().await?;
+        let connection = pooled.connection.clone();
+
+        let written = async {
+            let sftp_connection = connection.clone();
+            let mut file =
+                tokio::task::spawn_blocking(move || sftp_connection.sftp.create(&path)).await??;
+            while let Some(chunk) = data.next().await {
+                let chunk: Bytes = chunk?;
+                file = tokio::task::spawn_blocking(move || {
+                    file.write_all(&chunk)?;
+                    anyhow::Ok(file)
+                })
+                .await??;
+            }
+            // Dropping the handle closes it, which blocks.
+            tokio::task::spawn_blocking(move || drop(file)).await?;
+            anyhow::Ok(())
+        }
+        .await;
+
+        if written.as_ref().is_err_and(is_transient) {
+            pooled.broken = true;
+        }
+        written
+    }
+
+    async fn list_entries(&self) -> Result<Vec<(PathBuf, ssh2::FileStat)>> {
+        let prefix = PathBuf::from(&self.prefix);
+        self.run(move |sftp| match sftp.readdir(&prefix) {
+            Ok(entries) => Ok(entries),
+            // LIBSSH2_FX_NO_SUCH_FILE
+            Err(e) if matches!(e.code(), ErrorCode::SFTP(2)) => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl StorageBackend for SftpStorage {
+    fn kind(&self) -> &'static str {
+        "sftp"
+    }
+
+    async fn put(&self, key: &str, _content_type: &str, data: ByteStream<'_>) -> Result<String> {
+        self.ensure_directories().await?;
+
+        let path = self.blob_path(key);
+        let mut partial_path = path.as_os_str().to_owned();
+        partial_path.push(PARTIAL_SUFFIX);
+        let partial_path = PathBuf::from(partial_path);
+
+        let stored = async {
+            self.upload(partial_path.clone(), data).await?;
+            let (from, to) = (partial_path.clone(), path.clone());
+            self.run(move |sftp| Ok(sftp.rename(&from, &to, None)?))
+                .await
+        }
+        .await;
+
+        if let Err(e) = stored {
+            let _ = self.delete(&partial_path.to_string_lossy()).await;
+            return Err(e);
+        }
+
+        Ok(path.to_string_lossy().to_string())
+    }
+
+    async fn get(&self, path: &str) -> Result<ByteStream<'static>> {
+        let path = PathBuf::from(path);
+        let (pooled, file) = retry(self.retries, is_transient, || async {
+            let mut pooled = self.pool.get().await?;
+            let connection = pooled.connection.clone();
+            let path = path.clone();
+            let file = tokio::task::spawn_blocking(move || connection.sftp.open(&path)).await?;
+            match file {
+                Ok(file) => Ok((pooled, file)),
+                Err(e) => {
+                    let e = anyhow::Error::from(e);
+                    pooled.broken = is_transient(&e);
+                    Err(e)
+                }
+            }
+        })
+        .await?;
+
+        // The connection stays checked out until the stream is dropped.
+        let stream = futures::stream::try_unfold((pooled, Some(file)), |(pooled, file)| async {
+            let Some(mut file) = file else {
+                return Ok(None);
+            };
+            let (file, chunk) = tokio::task::spawn_blocking(move || {
+                let mut chunk = vec![0; READ_CHUNK_SIZE];
+                let read = file.read(&mut chunk)?;
+                chunk.truncate(read);
+                std::io::Result::Ok((file, chunk))
+            })
+            .await
+            .map_err(std::io::Error::other)??;
+
+            if chunk.is_empty() {
+                tokio::task::spawn_blocking(move || drop(file))
+                    .await
+                    .map_err(std::io::Error::other)?;
+                return Ok(None);
+            }
+            Ok(Some((Bytes::from(chunk), (pooled, Some(file)))))
+        });
+
+        Ok(stream.boxed())
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        let path = PathBuf::from(path);
+        self.run(move |sftp| Ok(sftp.unlink(&path)?)).await
+    }
+
+    async fn head(&self, path: &str) -> Result<StoredObject> {
+        let path = PathBuf::from(path);
+        self.run(move |sftp| Ok(stored_object(&path, &sftp.stat(&path)?)))
+            .await
+    }
+
+    async fn list(&self) -> Result<Vec<StoredObject>> {
+        Ok(self
+            .list_entries()
+            .await?
+            .iter()
+            .filter(|(path, stat)| {
+                let name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy())
+                    .unwrap_or_default();
+                stat.is_file() && !name.starts_with('.') && !name.ends_with(PARTIAL_SUFFIX)
+            })
+            .map(|(path, stat)| stored_object(path, stat))
+            .collect())
+    }
+
+    /// Verifies that the directory is writable by creating and removing a
+    /// probe file.
+    async fn check_ready(&self) -> Result<()> {
+        self.ensure_directories().await?;
+
+        let probe_path = Path::new(&self.prefix).join(format!(
+            ".readyz-{}",
+            Uuid::new_v7(uuid::timestamp::Timestamp::now(uuid::NoContext))
+        ));
+        self.run(move |sftp| {
+            sftp.create(&probe_path)?.write_all(b"ok")?;
+            sftp.unlink(&probe_path)?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Removes temporary files left behind by uploads that were interrupted
+    /// before being renamed into place.
+    async fn cleanup_partial(&self, modified_before: DateTime<Utc>) -> Result<usize> {
+        let mut removed = 0;
+        for (path, stat) in self.list_entries().await? {
+            let stale = stored_object(&path, &stat)
+                .modified_at
+                .is_none_or(|modified| modified < modified_before);
+            if path.to_string_lossy().ends_with(PARTIAL_SUFFIX) && stale {
+                self.delete(&path.to_string_lossy()).await?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
@@ -0,0 +1,407 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{SinkExt, StreamExt, TryStreamExt};
+use percent_encoding::percent_decode_str;
+use quick_xml::{events::Event, Reader};
+use reqwest::{header, Body, Client, Method, RequestBuilder, Response, StatusCode, Url};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::OnceCell;
+
+use super::{backend::retry, ByteStream, StorageBackend, StoredObject};
+use crate::config::Secret;
+
+const PARTIAL_SUFFIX: &str = ".partial";
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?><propfind xmlns="DAV:"><prop><resourcetype/><getcontentlength/><getlastmodified/></prop></propfind>"#;
+
+/// A WebDAV share, e.g. on a NAS. Blobs are written under `prefix`, which is
+/// created on first use, and moved into place once fully uploaded.
+#[derive(Debug, Clone)]
+pub struct WebDavStorage {
+    client: Client,
+    base: Url,
+    prefix: String,
+    username: Option<String>,
+    password: Option<Secret>,
+    retries: u32,
+    collections: Arc<OnceCell<()>>,
+}
+
+/// One `<response>` of a PROPFIND multistatus.
+#[derive(Default)]
+struct Resource {
+    href: String,
+    size: i64,
+    modified_at: Option<DateTime<Utc>>,
+    collection: bool,
+}
+
+impl WebDavStorage {
+    /// `pool_size` limits the idle connections kept open to the server;
+    /// `retries` applies to every request except the upload itself, whose
+    /// body cannot be replayed.
+    pub fn new(
+        url: &str,
+        prefix: &str,
+        username: Option<String>,
+        password: Option<Secret>,
+        pool_size: usize,
+        retries: u32,
+    ) -> Result<Self> {
+        let base = Url::parse(url)?;
+        if base.cannot_be_a_base() {
+            anyhow::bail!("WebDAV URL {} cannot have a path", url);
+        }
+
+        let client = Client::builder()
+            .pool_max_idle_per_host(pool_size)
+            .connect_timeout(Duration::from_secs(10))
+            .build()?;
+
+        Ok(Self {
+            client,
+            base,
+            prefix: prefix.trim_matches('/').to_string(),
+            username,
+            password,
+            retries,
+            collections: Arc::new(OnceCell::new()),
+        })
+    }
+
+    /// The URL of `path`, which is relative to the base URL.
+    fn url(&self, path: &str) -> Url {
+        let mut url = self.base.clone();
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments
+                .pop_if_empty()
+                .extend(path.split('/').filter(|segment| !segment.is_empty()));
+        }
+        url
+    }
+
+    fn collection_url(&self) -> Url {
+        let mut url = self.url(&self.prefix);
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.push("");
+        }
+        url
+    }
+
+    fn blob_path(&self, key: &str) -> String {
+        if self.prefix.is_empty() {
+            format!("/{}", key)
+        } else {
+            format!("/{}/{}", self.prefix, key)
+        }
+    }
+
+    /// Maps an `href` from a PROPFIND response back to a blob path.
+    fn href_path(&self, href: &str) -> Option<String> {
+        let path = match Url::parse(href) {
+            Ok(url) => url.path().to_string(),
+            Err(_) => href.to_string(),
+        };
+        let path = percent_decode_str(&path).decode_utf8().ok()?;
+        let base = percent_decode_str(self.base.path()).decode_utf8().ok()?;
+
+        path.strip_prefix(base.trim_end_matches('/'))
+            .map(str::to_string)
+    }
+
+    fn request(&self, method: Method, url: Url) -> RequestBuilder {
+        let request = self.client.request(method, url);
+        match &self.username {
+            Some(username) => request.basic_auth(
+                username,
+                self.password.as_ref().map(|password| password.expose()),
+            ),
+            None => request,
+        }
+    }
+
+    /// Sends a request, retrying connection failures and server errors.
+    async fn execute(&self, request: impl Fn() -> RequestBuilder) -> Result<Response> {
+        retry(
+            self.retries,
+            |e| e.is::<reqwest::Error>(),
+            || async {
+                let response = request().send().await?;
+                Ok(if response.status().is_server_error() {
+                    response.error_for_status()?
+                } else {
+                    response
+                })
+            },
+        )
+        .await
+    }
+
+    async fn send(&self, request: impl Fn() -> RequestBuilder) -> Result<Response> {
+        check(self.execute(request).await?).await
+    }
+
+    async fn propfind(&self, url: Url, depth: &'static str) -> Result<Response> {
+        let method = Method::from_bytes(b"PROPFIND")?;
+        self.execute(|| {
+            self.request(method.clone(), url.clone())
+                .header("Depth", depth)
+                .header(header::CONTENT_TYPE, "application/xml")
+                .body(PROPFIND_BODY)
+        })
+        .await
+    }
+
+    /// Creates the collections leading up to `prefix`, once per process.
+    async fn ensure_collections(&self) -> Result<()> {
+        self.collections
+            .get_or_try_init(|| async {
+                let method = Method::from_bytes(b"MKCOL")?;
+                let mut path = String::new();
+                for segment in self.prefix.split('/').filter(|s| !s.is_empty()) {
+                    path.push('/');
+                    path.push_str(segment);
+
+                    let mut url = self.url(&path);
+                    if let Ok(mut segments) = url.path_segments_mut() {
+                        segments.push("");
+                    }
+                    let response = self
+                        .execute(|| self.request(method.clone(), url.clone()))
+                        .await?;
+                    // 405 means the collection already exists.
+                    if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+                        check(response).await?;
+                    }
+                }
+                anyhow::Ok(())
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Streams `data` in a single PUT.
+    async fn upload(&self, path: &str, content_type: &str, mut data: ByteStream<'_>) -> Result<()> {
+        let (mut sender, receiver) = futures::channel::mpsc::channel::<std::io::Result<Bytes>>(1);
+        let forward = async move {
+            while let Some(chunk) = data.next().await {
+                let failed = chunk.is_err();
+                if sender.send(chunk).await.is_err() || failed {
+                    break;
+                }
+            }
+        };
+        let request = self
+            .request(Method::PUT, self.url(path))
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Body::wrap_stream(receiver))
+            .send();
+
+        let ((), response) = tokio::join!(forward, request);
+        check(response?).await?;
+        Ok(())
+    }
+
+    /// MOVE or COPY `path` to `destination` without overwriting.
+    async fn transfer(&self, method: &[u8], path: &str, destination: &str) -> Result<()> {
+        let method = Method::from_bytes(method)?;
+        let destination = self.url(destination).to_string();
+        self.send(|| {
+            self.request(method.clone(), self.url(path))
+                .header("Destination", &destination)
+                .header("Overwrite", "F")
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn list_resources(&self) -> Result<Vec<(String, Resource)>> {
+        let response = self.propfind(self.collection_url(), "1").await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(Vec::new());
+        }
+
+        let body = check(response).await?.text().await?;
+        Ok(parse_multistatus(&body)?
+            .into_iter()
+            .filter(|resource| !resource.collection)
+            .filter_map(|resource| Some((self.href_path(&resource.href)?, resource)))
+            .collect())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for WebDavStorage {
+    fn kind(&self) -> &'static str {
+        "webdav"
+    }
+
+    async fn put(&self, key: &str, content_type: &str, data: ByteStream<'_>) -> Result<String> {
+        self.ensure_collections().await?;
+
+        let path = self.blob_path(key);
+        let partial_path = format!("{}{}", path, PARTIAL_SUFFIX);
+        let stored = async {
+            self.upload(&partial_path, content_type, data).await?;
+            self.transfer(b"MOVE", &partial_path, &path).await
+        }
+        .await;
+
+        if let Err(e) = stored {
+            let _ = self.delete(&partial_path).await;
+            return Err(e);
+        }
+
+        Ok(path)
+    }
+
+    async fn get(&self, path: &str) -> Result<ByteStream<'static>> {
+        let response = self
+            .send(|| self.request(Method::GET, self.url(path)))
+            .await?;
+
+        Ok(response
+            .bytes_stream()
+            .map_err(std::io::Error::other)
+            .boxed())
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        self.send(|| self.request(Method::DELETE, self.url(path)))
+            .await?;
+        Ok(())
+    }
+
+    async fn head(&self, path: &str) -> Result<StoredObject> {
+        let response = self
+            .send(|| self.request(Method::HEAD, self.url(path)))
+            .await?;
+        let header = |name: header::HeaderName| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+
+        Ok(StoredObject {
+            path: path.to_string(),
+            size: header(header::CONTENT_LENGTH)
+                .and_then(|length| length.parse().ok())
+                .unwrap_or_default(),
+            modified_at: header(header::LAST_MODIFIED).and_then(parse_http_date),
+        })
+    }
+
+    async fn list(&self) -> Result<Vec<StoredObject>> {
+        Ok(self
+            .list_resources()
+            .await?
+            .into_iter()
+            .filter(|(path, _)| !path.ends_with(PARTIAL_SUFFIX))
+            .map(|(path, resource)| StoredObject {
+                path,
+                size: resource.size,
+                modified_at: resource.modified_at,
+            })
+            .collect())
+    }
+
+    /// Copies server-side with the WebDAV COPY method.
+    async fn copy(&self, path: &str, key: &str, _content_type: &str) -> Result<String> {
+        let destination = self.blob_path(key);
+        self.transfer(b"COPY", path, &destination).await?;
+        Ok(destination)
+    }
+
+    async fn check_ready(&self) -> Result<()> {
+        self.ensure_collections().await?;
+        check(self.propfind(self.collection_url(), "0").await?).await?;
+        Ok(())
+    }
+
+    /// Removes `.partial` uploads that were interrupted before being moved
+    /// into place.
+    async fn cleanup_partial(&self, modified_before: DateTime<Utc>) -> Result<usize> {
+        let mut removed = 0;
+        for (path, resource) in self.list_resources().await? {
+            let stale = resource
+                .modified_at
+                .is_none_or(|modified| modified < modified_before);
+            if path.ends_with(PARTIAL_SUFFIX) && stale {
+                self.delete(&path).await?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Extracts the resources from a `207 Multi-Status` body. Namespace prefixes
+/// differ between servers, so elements are matched by local name.
+fn parse_multistatus(body: &str) -> Result<Vec<Resource>> {
+    let mut reader = Reader::from_str(body);
+    reader.config_mut().trim_text(true);
+
+    let mut resources = Vec::new();
+    let mut resource: Option<Resource> = None;
+    let mut element = Vec::new();
+    loop {
+        match reader.read_event()? {
+            Event::Start(start) => match start.local_name().as_ref() {
+                b"response" => resource = Some(Resource::default()),
+                b"collection" => {
+                    if let Some(resource) = resource.as_mut() {
+                        resource.collection = true;
+                    }
+                }
+                name => element = name.to_vec(),
+            },
+            Event::Empty(empty) if empty.local_name().as_ref() == b"collection" => {
+                if let Some(resource) = resource.as_mut() {
+                    resource.collection = true;
+                }
+            }
+            Event::Text(text) => {
+                let Some(resource) = resource.as_mut() else {
+                    continue;
+                };
+                let value = text.unescape()?;
+                match element.as_slice() {
+                    b"href" => resource.href = value.to_string(),
+                    b"getcontentlength" => resource.size = value.parse().unwrap_or_default(),
+                    b"getlastmodified" => resource.modified_at = parse_http_date(&value),
+                    _ => {}
+                }
+            }
+            Event::End(end) => {
+                if end.local_name().as_ref() == b"response" {
+                    resources.extend(resource.take());
+                }
+                element.clear();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(resources)
+}
+
+fn parse_http_date(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(value)
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+/// Turns an error status into an error that includes the response body.
+async fn check(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let body = response.text().await.unwrap_or_default();
+    anyhow::bail!("WebDAV request failed with {}: {}", status, body)
+}
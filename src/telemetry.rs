@@ -0,0 +1,112 @@
+use tracing::Span;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
+
+use crate::config::{Config, LogFormat};
+
+/// Installs the global tracing subscriber. With the `otel` feature enabled and
+/// an OTLP endpoint configured, spans are also exported to a collector.
+pub fn init(config: &Config) -> anyhow::Result<()> {
+    let env_filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| "file_server_rs=debug,tower_http=debug".into());
+
+    // Logs go to stderr so that admin commands can print results to stdout.
+    let fmt_layer = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    let fmt_layer = match config.log_format {
+        LogFormat::Text => fmt_layer.boxed(),
+        LogFormat::Json => fmt_layer.json().boxed(),
+    };
+
+    let registry = tracing_subscriber::registry()
+        .with(env_filter)
+        .with(fmt_layer);
+
+    #[cfg(feature = "otel")]
+    let registry = registry.with(otel::layer(config)?);
+
+    registry.init();
+
+    Ok(())
+}
+
+/// Flushes any spans that have not been exported yet.
+pub fn shutdown() {
+    #[cfg(feature = "otel")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Links the request span to the caller's trace from a W3C `traceparent` header.
+pub fn set_remote_parent<B>(span: &Span, request: &axum::http::Request<B>) {
+    #[cfg(feature = "otel")]
+    otel::set_remote_parent(span, request);
+
+    #[cfg(not(feature = "otel"))]
+    let _ = (span, request);
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use axum::http::HeaderMap;
+    use opentelemetry::{propagation::Extractor, trace::TracerProvider as _, KeyValue};
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{
+        propagation::TraceContextPropagator, runtime, trace::TracerProvider, Resource,
+    };
+    use tracing::Span;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::{registry::LookupSpan, Layer};
+
+    use crate::config::{Config, OtlpProtocol};
+
+    pub fn layer<S>(config: &Config) -> anyhow::Result<Option<impl Layer<S>>>
+    where
+        S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let Some(endpoint) = &config.otlp_endpoint else {
+            return Ok(None);
+        };
+
+        let exporter = match config.otlp_protocol {
+            OtlpProtocol::Grpc => opentelemetry_otlp::SpanExporter::builder()
+                .with_tonic()
+                .with_endpoint(endpoint)
+                .build()?,
+            OtlpProtocol::HttpProtobuf => opentelemetry_otlp::SpanExporter::builder()
+                .with_http()
+                .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+                .build()?,
+        };
+
+        let provider = TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                config.otel_service_name.clone(),
+            )]))
+            .build();
+        let tracer = provider.tracer("file-server-rs");
+
+        opentelemetry::global::set_tracer_provider(provider);
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+        Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+    }
+
+    struct HeaderExtractor<'a>(&'a HeaderMap);
+
+    impl Extractor for HeaderExtractor<'_> {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).and_then(|value| value.to_str().ok())
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            self.0.keys().map(|key| key.as_str()).collect()
+        }
+    }
+
+    pub fn set_remote_parent<B>(span: &Span, request: &axum::http::Request<B>) {
+        let parent = opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(request.headers()))
+        });
+        span.set_parent(parent);
+    }
+}
@@ -0,0 +1,252 @@
+use anyhow::Result;
+use chrono::{TimeDelta, Utc};
+use futures::StreamExt;
+use hmac::{Hmac, Mac};
+use reqwest::header;
+use serde_json::json;
+use sha2::Sha256;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::{
+    config::Config,
+    database::{
+        claim_webhook_delivery, create_webhook_delivery, get_due_webhook_deliveries,
+        get_file_labels, get_webhook, get_webhooks, update_webhook_delivery, DbPool,
+    },
+    metrics,
+    models::{
+        webhook::{DELIVERY_DELIVERED, DELIVERY_FAILED},
+        File, FileLabels, FileResponse, WebhookDelivery, WebhookEvent,
+    },
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Outbox checks without a wakeup, which pick up retries that became due and
+/// deliveries queued by other replicas.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const BATCH_SIZE: i64 = 50;
+const CONCURRENCY: usize = 8;
+const FIRST_RETRY_DELAY: TimeDelta = TimeDelta::seconds(10);
+const MAX_RETRY_DELAY: TimeDelta = TimeDelta::hours(6);
+
+/// Queues webhook deliveries and wakes the delivery worker.
+#[derive(Debug, Clone, Default)]
+pub struct Webhooks {
+    wakeup: Arc<Notify>,
+}
+
+impl Webhooks {
+    /// Queues `event` for every webhook subscribed to it. Failures are logged
+    /// rather than surfaced, so that webhooks never fail the request that
+    /// triggered them.
+    pub async fn dispatch(&self, db: &DbPool, event: WebhookEvent, file: &FileResponse) {
+        match enqueue(db, event, file).await {
+            Ok(0) => {}
+            Ok(_) => self.wake(),
+            Err(e) => tracing::error!("Failed to queue {} webhooks for {}: {}", event, file.id, e),
+        }
+    }
+
+    /// Makes the delivery worker check the outbox now.
+    pub fn wake(&self) {
+        self.wakeup.notify_one();
+    }
+}
+
+/// The tags and metadata of `file` for its event payload. Failures are
+/// logged and yield no labels rather than no event.
+pub async fn fetch_labels(db: &DbPool, file: &File) -> FileLabels {
+    match get_file_labels(db, &[&file.id]).await {
+        Ok(mut labels) => labels.remove(&file.id).unwrap_or_default(),
+        Err(e) => {
+            tracing::error!("Failed to fetch labels of {}: {}", file.id, e);
+            Default::default()
+        }
+    }
+}
+
+async fn enqueue(db: &DbPool, event: WebhookEvent, file: &FileResponse) -> Result<usize> {
+    let webhooks: Vec<_> = get_webhooks(db)
+        .await?
+        .into_iter()
+        .filter(|webhook| webhook.subscribes_to(event))
+        .collect();
+    if webhooks.is_empty() {
+        return Ok(0);
+    }
+
+    // Every webhook gets the same body, so receivers can tell duplicates
+    // apart by the event id.
+    let payload = serde_json::to_string(&json!({
+        "id": Uuid::new_v7(uuid::timestamp::Timestamp::now(uuid::NoContext)).to_string(),
+        "event": event.as_str(),
+        "created_at": Utc::now(),
+        "file": file,
+    }))?;
+
+    for webhook in &webhooks {
+        let delivery =
+            WebhookDelivery::new(webhook.id.clone(), event, file.id.clone(), payload.clone());
+        create_webhook_delivery(db, &delivery).await?;
+    }
+
+    Ok(webhooks.len())
+}
+
+/// Hex HMAC-SHA256 of `{timestamp}.{payload}` keyed with the webhook secret.
+/// The timestamp is covered so that receivers can reject replayed requests.
+pub fn sign(secret: &str, timestamp: i64, payload: &str) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(payload.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Delivers queued webhooks until `shutdown` is cancelled. Every replica runs
+/// this; each delivery is claimed before it is sent, so only one sends it.
+pub async fn run_deliveries(
+    db: DbPool,
+    webhooks: Webhooks,
+    config: Arc<Config>,
+    shutdown: CancellationToken,
+) {
+    let client = match reqwest::Client::builder()
+        .timeout(Duration::from_secs(config.webhook_timeout))
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!("Failed to create the webhook HTTP client: {}", e);
+            return;
+        }
+    };
+
+    loop {
+        tokio::select! {
+            result = deliver_due(&db, &client, &config) => {
+                if let Err(e) = result {
+                    tracing::error!("Failed to deliver webhooks: {}", e);
+                }
+            }
+            // Interrupted attempts are retried once their claim expires.
+            _ = shutdown.cancelled() => return,
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            _ = webhooks.wakeup.notified() => {}
+            _ = shutdown.cancelled() => return,
+        }
+    }
+}
+
+async fn deliver_due(db: &DbPool, client: &reqwest::Client, config: &Config) -> Result<()> {
+    loop {
+        let due = get_due_webhook_deliveries(db, Utc::now(), BATCH_SIZE).await?;
+        let count = due.len();
+
+        futures::stream::iter(due)
+            .for_each_concurrent(CONCURRENCY, |delivery| async move {
+                let id = delivery.id.clone();
+                if let Err(e) = attempt(db, client, config, delivery).await {
+                    tracing::error!("Failed to record webhook delivery {}: {}", id, e);
+                }
+            })
+            .await;
+
+        if count < BATCH_SIZE as usize {
+            return Ok(());
+        }
+    }
+}
+
+async fn attempt(
+    db: &DbPool,
+    client: &reqwest::Client,
+    config: &Config,
+    mut delivery: WebhookDelivery,
+) -> Result<()> {
+    // Long enough for the request to time out before anyone else retries it.
+    let lease_until = Utc::now() + TimeDelta::seconds(config.webhook_timeout as i64 + 30);
+    if !claim_webhook_delivery(db, &delivery, lease_until).await? {
+        return Ok(());
+    }
+    delivery.attempts += 1;
+
+    // Deleting a webhook deletes its deliveries.
+    let Some(webhook) = get_webhook(db, &delivery.webhook_id).await? else {
+        return Ok(());
+    };
+
+    let timestamp = Utc::now().timestamp();
+    let result = client
+        .post(&webhook.url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header("x-webhook-id", &delivery.id)
+        .header("x-webhook-event", &delivery.event)
+        .header("x-webhook-timestamp", timestamp)
+        .header(
+            "x-webhook-signature",
+            format!(
+                "sha256={}",
+                sign(&webhook.secret, timestamp, &delivery.payload)
+            ),
+        )
+        .body(delivery.payload.clone())
+        .send()
+        .await;
+
+    let error = match result {
+        Ok(response) => {
+            delivery.last_status_code = Some(response.status().as_u16() as i64);
+            (!response.status().is_success())
+                .then(|| format!("Unexpected status {}", response.status()))
+        }
+        Err(e) => {
+            delivery.last_status_code = e.status().map(|status| status.as_u16() as i64);
+            Some(format!("{:#}", anyhow::Error::from(e)))
+        }
+    };
+
+    let outcome = match error {
+        None => {
+            delivery.status = DELIVERY_DELIVERED.to_string();
+            delivery.delivered_at = Some(Utc::now());
+            DELIVERY_DELIVERED
+        }
+        Some(_) if delivery.attempts >= config.webhook_max_attempts as i64 => {
+            tracing::warn!(
+                "Giving up on webhook delivery {} to {} after {} attempts",
+                delivery.id,
+                webhook.url,
+                delivery.attempts
+            );
+            delivery.status = DELIVERY_FAILED.to_string();
+            DELIVERY_FAILED
+        }
+        Some(_) => {
+            delivery.next_attempt_at = Utc::now() + retry_delay(delivery.attempts);
+            "retry"
+        }
+    };
+    delivery.last_error = error;
+    metrics::record_webhook_delivery(outcome);
+
+    update_webhook_delivery(db, &delivery).await
+}
+
+/// Doubles with every failed attempt, starting at ten seconds.
+fn retry_delay(attempts: i64) -> TimeDelta {
+    let exponent = (attempts - 1).clamp(0, 20) as u32;
+    FIRST_RETRY_DELAY
+        .checked_mul(2i32.pow(exponent))
+        .unwrap_or(MAX_RETRY_DELAY)
+        .min(MAX_RETRY_DELAY)
+}
@@ -0,0 +1,277 @@
+//! Paths of entries in downloaded archives, and extraction of uploaded
+//! archives that try to escape their folder or expand without bounds.
+
+mod common;
+
+use async_compression::tokio::write::GzipEncoder;
+use async_zip::{tokio::write::ZipFileWriter, Compression, ZipEntryBuilder};
+use common::TestServer;
+use file_server_rs::{
+    archive::{entry_path, entry_paths},
+    models::{ExtractResponse, File},
+};
+use reqwest::StatusCode;
+use serde_json::Value;
+use tokio::io::AsyncWriteExt;
+use tokio_tar::{EntryType, Header};
+
+const SYMLINK: u16 = 0o120777;
+
+#[test]
+fn entry_paths_stay_inside_the_archive() {
+    for raw in [
+        "../x",
+        "a/../../x",
+        "a/./b",
+        "/etc/passwd",
+        "a\\b.txt",
+        "..\\x",
+        "a//b",
+    ] {
+        assert_eq!(entry_path(raw), None, "{}", raw);
+    }
+    assert_eq!(entry_path("./docs/a.txt").as_deref(), Some("docs/a.txt"));
+    assert_eq!(entry_path("docs/").as_deref(), Some("docs"));
+    assert_eq!(entry_path("./").as_deref(), Some(""));
+
+    // Names of stored files are never read as paths when archiving.
+    let file = |name: &str, folder: &str| {
+        let mut file = File::new(
+            "blob".to_string(),
+            name.to_string(),
+            1,
+            "memory".to_string(),
+        );
+        file.folder = folder.to_string();
+        file
+    };
+    let entries = entry_paths(
+        vec![
+            file("../x", "docs"),
+            file("..\\x", "docs"),
+            file("..", "docs/sub"),
+        ],
+        Some("docs"),
+    );
+    let paths: Vec<&str> = entries.iter().map(|entry| entry.path.as_str()).collect();
+    assert_eq!(paths[..2], [".._x", ". (2)._x"]);
+    assert_eq!(paths[2], format!("sub/{}", entries[2].file.id));
+    assert!(paths.iter().all(|path| entry_path(path).is_some()));
+}
+
+/// A zip of `(name, unix mode, content)` entries, deflated.
+async fn zip(entries: &[(&str, u16, &[u8])]) -> Vec<u8> {
+    let mut zip = ZipFileWriter::with_tokio(Vec::new());
+    for (name, mode, data) in entries {
+        let builder = ZipEntryBuilder::new(name.to_string().into(), Compression::Deflate)
+            .unix_permissions(*mode);
+        zip.write_entry_whole(builder, data).await.unwrap();
+    }
+    zip.close().await.unwrap().into_inner()
+}
+
+/// A tar.gz of `(name, type, content)` entries. Names are written into the
+/// header as they are, since the builder refuses unsafe ones.
+async fn tar_gz(entries: &[(&str, EntryType, &[u8])]) -> Vec<u8> {
+    let mut tar = tokio_tar::Builder::new(GzipEncoder::new(Vec::new()));
+    for (name, entry_type, data) in entries {
+        let mut header = Header::new_gnu();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+        header.set_entry_type(*entry_type);
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        if entry_type.is_symlink() || entry_type.is_hard_link() {
+            header.as_old_mut().linkname[..11].copy_from_slice(b"/etc/passwd");
+        }
+        header.set_cksum();
+        tar.append(&header, *data).await.unwrap();
+    }
+    let mut gzip = tar.into_inner().await.unwrap();
+    gzip.shutdown().await.unwrap();
+    gzip.into_inner()
+}
+
+async fn extract(server: &TestServer, name: &str, archive: Vec<u8>) -> reqwest::Response {
+    const BOUNDARY: &str = "archive-boundary";
+    let mut body = format!(
+        "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+        BOUNDARY, name
+    )
+    .into_bytes();
+    body.extend_from_slice(&archive);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+
+    reqwest::Client::new()
+        .post(format!("{}/upload?extract=true", server.url))
+        .header(
+            "content-type",
+            format!("multipart/form-data; boundary={}", BOUNDARY),
+        )
+        .body(body)
+        .send()
+        .await
+        .unwrap()
+}
+
+async fn extracted(server: &TestServer, name: &str, archive: Vec<u8>) -> ExtractResponse {
+    let response = extract(server, name, archive).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    response.json().await.unwrap()
+}
+
+/// The status of each entry, with the path of the files created.
+fn outcomes(response: &ExtractResponse) -> Vec<(String, String)> {
+    response
+        .entries
+        .iter()
+        .map(|entry| {
+            let outcome = match &entry.file {
+                Some(file) if file.folder.is_empty() => file.name.clone(),
+                Some(file) => format!("{}/{}", file.folder, file.name),
+                None => entry.status.clone(),
+            };
+            (entry.path.clone(), outcome)
+        })
+        .collect()
+}
+
+fn expected(outcomes: &[(&str, &str)]) -> Vec<(String, String)> {
+    outcomes
+        .iter()
+        .map(|(path, outcome)| (path.to_string(), outcome.to_string()))
+        .collect()
+}
+
+#[tokio::test]
+async fn zip_entries_cannot_escape_or_link() {
+    let server = TestServer::start().await;
+    let archive = zip(&[
+        ("../x", 0o644, b"x"),
+        ("/etc/passwd", 0o644, b"x"),
+        ("docs\\..\\..\\x", 0o644, b"x"),
+        ("docs/../../x", 0o644, b"x"),
+        ("link", SYMLINK, b"/etc/passwd"),
+        ("./docs/readme.txt", 0o644, b"readme"),
+    ])
+    .await;
+
+    let response = extracted(&server, "site.zip", archive).await;
+    assert_eq!(response.created, 1);
+    assert_eq!(response.error, None);
+    assert_eq!(
+        outcomes(&response),
+        expected(&[
+            ("../x", "skipped"),
+            ("/etc/passwd", "skipped"),
+            ("docs\\..\\..\\x", "skipped"),
+            ("docs/../../x", "skipped"),
+            ("link", "skipped"),
+            ("./docs/readme.txt", "docs/readme.txt"),
+        ])
+    );
+    assert_eq!(
+        response.entries[4].error.as_deref(),
+        Some("Links are not extracted")
+    );
+}
+
+#[tokio::test]
+async fn tar_entries_cannot_escape_or_link() {
+    let server = TestServer::start().await;
+    let archive = tar_gz(&[
+        ("../x", EntryType::Regular, b"x"),
+        ("/etc/passwd", EntryType::Regular, b"x"),
+        ("..\\x", EntryType::Regular, b"x"),
+        ("../docs/", EntryType::Directory, b""),
+        ("symlink", EntryType::Symlink, b""),
+        ("hardlink", EntryType::Link, b""),
+        ("docs/", EntryType::Directory, b""),
+        ("docs/readme.txt", EntryType::Regular, b"readme"),
+    ])
+    .await;
+
+    let response = extracted(&server, "site.tar.gz", archive).await;
+    assert_eq!(response.created, 1);
+    assert_eq!(
+        outcomes(&response),
+        expected(&[
+            ("../x", "skipped"),
+            ("/etc/passwd", "skipped"),
+            ("..\\x", "skipped"),
+            ("../docs/", "skipped"),
+            ("symlink", "skipped"),
+            ("hardlink", "skipped"),
+            ("docs/", "folder"),
+            ("docs/readme.txt", "docs/readme.txt"),
+        ])
+    );
+}
+
+#[tokio::test]
+async fn extraction_stops_at_the_entry_limit() {
+    let server = TestServer::start().await;
+    let names: Vec<String> = (0..1001).map(|i| format!("d{}/", i)).collect();
+    let entries: Vec<(&str, u16, &[u8])> = names
+        .iter()
+        .map(|name| (name.as_str(), 0o755, &b""[..]))
+        .collect();
+
+    let response = extracted(&server, "many.zip", zip(&entries).await).await;
+    assert_eq!(response.entries.len(), 1000);
+    assert_eq!(
+        response.error.as_deref(),
+        Some("Archives are extracted up to 1000 entries")
+    );
+}
+
+#[tokio::test]
+async fn archives_cannot_expand_beyond_their_limit() {
+    let server = TestServer::start().await;
+    let zeros = vec![0u8; 4 * 1024 * 1024];
+
+    // Listed sizes of a zip are checked before anything is extracted.
+    let bomb = zip(&[("small.txt", 0o644, b"small"), ("zeros", 0o644, &zeros)]).await;
+    assert!((bomb.len() as u64) * 100 < zeros.len() as u64);
+    let response = extract(&server, "bomb.zip", bomb).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body: Value = response.json().await.unwrap();
+    assert!(body["error"]
+        .as_str()
+        .unwrap()
+        .starts_with("Archive expands beyond the limit"));
+
+    // Tar sizes are only known while reading, so extraction stops at the
+    // entry that goes over and keeps what came before.
+    let bomb = tar_gz(&[
+        ("small.txt", EntryType::Regular, b"small"),
+        ("zeros", EntryType::Regular, &zeros),
+        ("after.txt", EntryType::Regular, b"after"),
+    ])
+    .await;
+    let response = extracted(&server, "bomb.tar.gz", bomb).await;
+    assert_eq!(response.created, 1);
+    assert_eq!(
+        outcomes(&response),
+        expected(&[("small.txt", "small.txt"), ("zeros", "failed")])
+    );
+    assert!(response
+        .error
+        .unwrap()
+        .starts_with("Archive expands beyond the limit"));
+
+    // The maximum file size caps the expanded size of any archive, even one
+    // that compresses too little to hit the ratio.
+    let server = TestServer::start_with(|config| config.max_file_size = 64 * 1024).await;
+    let mut content: Vec<u8> = (0..4096u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+        .collect();
+    content.resize(100 * 1024, 0);
+    let archive = tar_gz(&[("large", EntryType::Regular, &content)]).await;
+    assert!(archive.len() * 100 > 64 * 1024 && archive.len() < 64 * 1024);
+    let response = extracted(&server, "large.tar.gz", archive).await;
+    assert_eq!(response.created, 0);
+    assert_eq!(
+        response.error.as_deref(),
+        Some("Archive expands beyond the limit of 65536 bytes")
+    );
+}
@@ -0,0 +1,78 @@
+//! Credentials on the upload, admin and WebDAV routes.
+
+mod common;
+
+use common::TestServer;
+use file_server_rs::{database::create_api_key, models::ApiKey};
+use reqwest::StatusCode;
+
+async fn list_jobs(server: &TestServer, token: Option<&str>) -> StatusCode {
+    let mut request = reqwest::Client::new().get(format!("{}/admin/jobs", server.url));
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    request.send().await.unwrap().status()
+}
+
+#[tokio::test]
+async fn key_created_after_startup_is_enforced() {
+    let server = TestServer::start().await;
+    // Nothing is configured yet, so the routes are open.
+    assert_eq!(list_jobs(&server, None).await, StatusCode::OK);
+
+    let (api_key, key) = ApiKey::generate("ci".to_string());
+    create_api_key(&server.state.db, &api_key).await.unwrap();
+
+    assert_eq!(list_jobs(&server, None).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        list_jobs(&server, Some("not-a-key")).await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(list_jobs(&server, Some(&key)).await, StatusCode::OK);
+
+    let upload = reqwest::Client::new()
+        .post(format!("{}/upload", server.url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(upload.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn configured_token_is_required() {
+    let server = TestServer::start_with(|config| {
+        config.auth_token = Some("secret-token".parse().unwrap());
+        config.enable_webdav = true;
+    })
+    .await;
+
+    assert_eq!(list_jobs(&server, None).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        list_jobs(&server, Some("secret-tokem")).await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+        list_jobs(&server, Some("secret-token")).await,
+        StatusCode::OK
+    );
+
+    // WebDAV clients are asked for Basic credentials, with the token as the
+    // password.
+    let client = reqwest::Client::new();
+    let dav = format!("{}/dav/", server.url);
+    let anonymous = client
+        .request("PROPFIND".parse().unwrap(), &dav)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(anonymous.status(), StatusCode::UNAUTHORIZED);
+    assert!(anonymous.headers().contains_key("www-authenticate"));
+    let authenticated = client
+        .request("PROPFIND".parse().unwrap(), &dav)
+        .basic_auth("anyone", Some("secret-token"))
+        .header("Depth", "0")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(authenticated.status(), StatusCode::MULTI_STATUS);
+}
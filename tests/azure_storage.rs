@@ -0,0 +1,459 @@
+//! The Azure Blob Storage backend against a small in-process server that,
+//! like Azurite, checks the Shared Key signature of every request.
+
+mod common;
+
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    http::{header, HeaderMap, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use common::{check_contract, chunks, failing, read, serve};
+use file_server_rs::storage::{AzureBlobStorage, StorageBackend};
+use futures::StreamExt;
+use hmac::{Hmac, Mac};
+use percent_encoding::percent_decode_str;
+use sha2::Sha256;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+const ACCOUNT: &str = "devstoreaccount1";
+const CONTAINER: &str = "files";
+/// Blobs per page of a listing, small enough for tests to page.
+const PAGE_SIZE: usize = 2;
+const LAST_MODIFIED: &str = "Wed, 01 May 2024 12:00:00 GMT";
+
+fn account_key() -> Vec<u8> {
+    b"not a real account key, only for tests".to_vec()
+}
+
+/// Keeps one container in memory.
+#[derive(Clone, Default)]
+struct AzureServer {
+    state: Arc<Mutex<AzureState>>,
+}
+
+#[derive(Default)]
+struct AzureState {
+    blobs: BTreeMap<String, Bytes>,
+    /// Staged blocks by blob name and block id.
+    blocks: HashMap<String, HashMap<String, Bytes>>,
+    /// Blobs whose copy is still reported as pending on the next HEAD.
+    pending_copies: Vec<String>,
+    endpoint: String,
+    requests: Vec<(Method, String)>,
+}
+
+impl AzureServer {
+    async fn start(&self) -> AzureBlobStorage {
+        let app = Router::new().fallback(handle).with_state(self.clone());
+        self.state().endpoint = format!("http://{}/{}", serve(app).await, ACCOUNT);
+        self.storage(&account_key())
+    }
+
+    fn storage(&self, key: &[u8]) -> AzureBlobStorage {
+        AzureBlobStorage::new(
+            ACCOUNT.to_string(),
+            &STANDARD.encode(key),
+            CONTAINER.to_string(),
+            Some(self.state().endpoint.clone()),
+        )
+        .unwrap()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, AzureState> {
+        self.state.lock().unwrap()
+    }
+
+    fn count(&self, method: Method, query: &str) -> usize {
+        self.state()
+            .requests
+            .iter()
+            .filter(|(m, uri)| *m == method && uri.contains(query))
+            .count()
+    }
+}
+
+/// Recomputes the Shared Key signature from the request as received.
+fn signature_matches(method: &Method, uri: &Uri, headers: &HeaderMap) -> bool {
+    let value = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("")
+    };
+    let content_length = match value("content-length") {
+        "0" => "",
+        length => length,
+    };
+
+    let mut canonical_headers: Vec<(String, String)> = headers
+        .iter()
+        .filter(|(name, _)| name.as_str().starts_with("x-ms-"))
+        .map(|(name, value)| (name.to_string(), value.to_str().unwrap().to_string()))
+        .collect();
+    canonical_headers.sort();
+
+    let mut canonical_resource = format!("/{}{}", ACCOUNT, uri.path());
+    let mut query: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for pair in uri
+        .query()
+        .unwrap_or("")
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+    {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let decode = |part: &str| {
+            percent_decode_str(&part.replace('+', " "))
+                .decode_utf8()
+                .unwrap()
+                .to_string()
+        };
+        query
+            .entry(decode(name).to_lowercase())
+            .or_default()
+            .push(decode(value));
+    }
+    for (name, mut values) in query {
+        values.sort();
+        canonical_resource.push_str(&format!("\n{}:{}", name, values.join(",")));
+    }
+
+    let string_to_sign = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}\n\n{}\n{}\n{}\n{}\n{}\n{}{}",
+        method,
+        value("content-encoding"),
+        value("content-language"),
+        content_length,
+        value("content-md5"),
+        value("content-type"),
+        value("if-modified-since"),
+        value("if-match"),
+        value("if-none-match"),
+        value("if-unmodified-since"),
+        value("range"),
+        canonical_headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value))
+            .collect::<String>(),
+        canonical_resource
+    );
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(&account_key()).unwrap();
+    mac.update(string_to_sign.as_bytes());
+    let expected = format!(
+        "SharedKey {}:{}",
+        ACCOUNT,
+        STANDARD.encode(mac.finalize().into_bytes())
+    );
+    value("authorization") == expected && !value("x-ms-date").is_empty()
+}
+
+fn error(status: StatusCode, code: &str) -> Response {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?><Error><Code>{}</Code></Error>",
+        code
+    );
+    (status, [("x-ms-error-code", code.to_string())], body).into_response()
+}
+
+async fn handle(
+    State(server): State<AzureServer>,
+    method: Method,
+    uri: Uri,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let mut state = server.state();
+    state.requests.push((method.clone(), uri.to_string()));
+    if !signature_matches(&method, &uri, &headers) {
+        return error(StatusCode::FORBIDDEN, "AuthenticationFailed");
+    }
+
+    let container = format!("/{}/{}", ACCOUNT, CONTAINER);
+    let Some(rest) = uri.path().strip_prefix(&container) else {
+        return error(StatusCode::NOT_FOUND, "ContainerNotFound");
+    };
+    let name = percent_decode_str(rest.trim_start_matches('/'))
+        .decode_utf8()
+        .unwrap()
+        .to_string();
+    let comp = query.get("comp").map(String::as_str);
+
+    if name.is_empty() {
+        return match (method, comp) {
+            (Method::HEAD, None) => StatusCode::OK.into_response(),
+            (Method::GET, Some("list")) => list(&state, &query),
+            _ => error(StatusCode::BAD_REQUEST, "InvalidQueryParameterValue"),
+        };
+    }
+
+    match (method.clone(), comp) {
+        (Method::PUT, Some("block")) => {
+            let block_id = query["blockid"].clone();
+            state.blocks.entry(name).or_default().insert(block_id, body);
+            StatusCode::CREATED.into_response()
+        }
+        (Method::PUT, Some("blocklist")) => {
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            let Some(mut staged) = state.blocks.remove(&name) else {
+                return error(StatusCode::BAD_REQUEST, "InvalidBlockList");
+            };
+            let mut data = Vec::new();
+            for id in body.split("<Latest>").skip(1) {
+                let id = id.split("</Latest>").next().unwrap();
+                match staged.remove(id) {
+                    Some(block) => data.extend_from_slice(&block),
+                    None => return error(StatusCode::BAD_REQUEST, "InvalidBlockList"),
+                }
+            }
+            state.blobs.insert(name, data.into());
+            StatusCode::CREATED.into_response()
+        }
+        (Method::PUT, None) => match headers.get("x-ms-copy-source") {
+            Some(source) => {
+                let source: Uri = source.to_str().unwrap().parse().unwrap();
+                let source = percent_decode_str(
+                    source
+                        .path()
+                        .strip_prefix(&format!("{}/", container))
+                        .unwrap_or_default(),
+                )
+                .decode_utf8()
+                .unwrap()
+                .to_string();
+                let Some(data) = state.blobs.get(&source).cloned() else {
+                    return error(StatusCode::NOT_FOUND, "CannotVerifyCopySource");
+                };
+                state.blobs.insert(name.clone(), data);
+                state.pending_copies.push(name);
+                (StatusCode::ACCEPTED, [("x-ms-copy-status", "pending")]).into_response()
+            }
+            None if headers.get("x-ms-blob-type").is_some() => {
+                state.blobs.insert(name, body);
+                StatusCode::CREATED.into_response()
+            }
+            None => error(StatusCode::BAD_REQUEST, "MissingRequiredHeader"),
+        },
+        (Method::GET | Method::HEAD, None) => {
+            let Some(data) = state.blobs.get(&name).cloned() else {
+                return error(StatusCode::NOT_FOUND, "BlobNotFound");
+            };
+            let copy_status = match state.pending_copies.iter().position(|copy| *copy == name) {
+                Some(index) => {
+                    state.pending_copies.remove(index);
+                    "success"
+                }
+                None => "",
+            };
+            let mut response = ([(header::LAST_MODIFIED, LAST_MODIFIED)], data).into_response();
+            if !copy_status.is_empty() {
+                response
+                    .headers_mut()
+                    .insert("x-ms-copy-status", copy_status.parse().unwrap());
+            }
+            response
+        }
+        (Method::DELETE, None) => match state.blobs.remove(&name) {
+            Some(_) => StatusCode::ACCEPTED.into_response(),
+            None => error(StatusCode::NOT_FOUND, "BlobNotFound"),
+        },
+        _ => error(StatusCode::BAD_REQUEST, "UnsupportedHttpVerb"),
+    }
+}
+
+/// Lists blobs by prefix, paged by `PAGE_SIZE` with the offset as the
+/// marker.
+fn list(state: &AzureState, query: &HashMap<String, String>) -> Response {
+    let prefix = query.get("prefix").cloned().unwrap_or_default();
+    let start: usize = query
+        .get("marker")
+        .map(|marker| marker.parse().unwrap())
+        .unwrap_or(0);
+    let matching: Vec<(&String, &Bytes)> = state
+        .blobs
+        .iter()
+        .filter(|(name, _)| name.starts_with(&prefix))
+        .collect();
+    let next = start + PAGE_SIZE;
+
+    let mut body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?><EnumerationResults ContainerName=\"{}\"><Prefix>{}</Prefix><Blobs>",
+        CONTAINER, prefix
+    );
+    for (name, data) in matching.iter().skip(start).take(PAGE_SIZE) {
+        body.push_str(&format!(
+            "<Blob><Name>{}</Name><Properties><Last-Modified>{}</Last-Modified><Content-Length>{}</Content-Length><BlobType>BlockBlob</BlobType></Properties></Blob>",
+            name,
+            LAST_MODIFIED,
+            data.len()
+        ));
+    }
+    body.push_str("</Blobs>");
+    if next < matching.len() {
+        body.push_str(&format!("<NextMarker>{}</NextMarker>", next));
+    } else {
+        body.push_str("<NextMarker />");
+    }
+    body.push_str("</EnumerationResults>");
+    ([(header::CONTENT_TYPE, "application/xml")], body).into_response()
+}
+
+#[tokio::test]
+async fn azure_backend_meets_the_contract() {
+    let server = AzureServer::default();
+    let storage = server.start().await;
+    assert_eq!(storage.kind(), "azure");
+
+    let path = check_contract(&storage).await;
+    assert!(path.starts_with("/uploads/report-"));
+    assert!(server.state().blobs.contains_key(&path[1..]));
+    let object = storage.head(&path).await.unwrap();
+    assert_eq!(
+        object.modified_at.unwrap().to_rfc3339(),
+        "2024-05-01T12:00:00+00:00"
+    );
+    // The copy was pending at first and polled until it succeeded.
+    assert!(server.state().pending_copies.is_empty());
+
+    let sas = storage
+        .presign(&path, Duration::from_secs(60))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(sas.contains(&format!("/{}/{}{}?", ACCOUNT, CONTAINER, path)));
+    assert!(sas.contains("sp=r") && sas.contains("sig="));
+}
+
+#[tokio::test]
+async fn azure_requests_are_signed_with_the_account_key() {
+    let server = AzureServer::default();
+    let storage = server.start().await;
+    storage.check_ready().await.unwrap();
+
+    let wrong_key = server.storage(b"some other key");
+    let error = wrong_key.check_ready().await.unwrap_err();
+    assert!(error.to_string().contains("403"), "{}", error);
+    assert!(wrong_key
+        .put("a.txt", "text/plain", chunks(&[b"unsigned"]))
+        .await
+        .is_err());
+    assert!(server.state().blobs.is_empty());
+}
+
+#[tokio::test]
+async fn azure_names_are_escaped() {
+    let server = AzureServer::default();
+    let storage = server.start().await;
+
+    let path = storage
+        .put("a b+c%d.txt", "text/plain", chunks(&[b"escaped"]))
+        .await
+        .unwrap();
+    assert_eq!(path, "/uploads/a b+c%d.txt");
+    assert!(server.state().blobs.contains_key("uploads/a b+c%d.txt"));
+    assert_eq!(read(&storage, &path).await, b"escaped");
+    assert_eq!(storage.head(&path).await.unwrap().size, 7);
+    storage.delete(&path).await.unwrap();
+}
+
+#[tokio::test]
+async fn azure_listing_pages_and_keeps_to_the_prefix() {
+    let server = AzureServer::default();
+    let storage = server.start().await;
+    assert!(storage.list().await.unwrap().is_empty());
+
+    for i in 0..5 {
+        let data = vec![b'x'; i];
+        storage
+            .put(
+                &format!("{}.bin", i),
+                "application/octet-stream",
+                chunks(&[&data]),
+            )
+            .await
+            .unwrap();
+    }
+    server
+        .state()
+        .blobs
+        .insert("elsewhere/other.bin".to_string(), Bytes::from_static(b"x"));
+
+    let listed = storage.list().await.unwrap();
+    let listed: Vec<(&str, i64)> = listed
+        .iter()
+        .map(|object| (object.path.as_str(), object.size))
+        .collect();
+    assert_eq!(
+        listed,
+        [
+            ("/uploads/0.bin", 0),
+            ("/uploads/1.bin", 1),
+            ("/uploads/2.bin", 2),
+            ("/uploads/3.bin", 3),
+            ("/uploads/4.bin", 4)
+        ]
+    );
+    assert_eq!(server.count(Method::GET, "comp=list"), 4);
+}
+
+#[tokio::test]
+async fn azure_large_blobs_are_staged_as_blocks() {
+    let server = AzureServer::default();
+    let storage = server.start().await;
+
+    // Two full 8 MiB blocks and a short last one, arriving in small chunks.
+    let data: Vec<u8> = (0..17 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let stream = futures::stream::iter(
+        data.chunks(64 * 1024)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect::<Vec<_>>(),
+    )
+    .boxed();
+    let path = storage
+        .put("large.bin", "application/octet-stream", stream)
+        .await
+        .unwrap();
+
+    assert_eq!(server.count(Method::PUT, "comp=block&"), 3);
+    assert_eq!(server.count(Method::PUT, "comp=blocklist"), 1);
+    assert!(server.state().blocks.is_empty());
+    assert!(read(&storage, &path).await == data);
+}
+
+#[tokio::test]
+async fn azure_failed_block_uploads_are_not_committed() {
+    let server = AzureServer::default();
+    let storage = server.start().await;
+
+    let block = vec![0u8; 9 * 1024 * 1024];
+    assert!(storage
+        .put("broken.bin", "application/octet-stream", failing(&block))
+        .await
+        .is_err());
+
+    assert_eq!(server.count(Method::PUT, "comp=blocklist"), 0);
+    assert!(server.state().blobs.is_empty());
+}
+
+#[tokio::test]
+async fn azure_missing_blobs_are_errors() {
+    let server = AzureServer::default();
+    let storage = server.start().await;
+
+    let error = storage.get("/uploads/missing").await.err().unwrap();
+    assert!(error.to_string().contains("404"), "{}", error);
+    assert!(storage.head("/uploads/missing").await.is_err());
+    assert!(storage.delete("/uploads/missing").await.is_err());
+    assert!(storage
+        .copy("/uploads/missing", "copy.bin", "application/octet-stream")
+        .await
+        .is_err());
+}
@@ -0,0 +1,181 @@
+//! Helpers shared by the integration tests.
+#![allow(dead_code)]
+
+use axum::{extract::DefaultBodyLimit, Router};
+use bytes::Bytes;
+use clap::Parser;
+use file_server_rs::{
+    cli::Cli,
+    config::Config,
+    database::create_pool,
+    handlers::AppState,
+    jobs, server,
+    storage::{backend, ByteStream, StorageBackend, StorageRegistry},
+};
+use futures::StreamExt;
+use metrics_exporter_prometheus::PrometheusBuilder;
+use std::net::SocketAddr;
+use tempfile::TempDir;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+/// Serves `app` on an ephemeral local port for the rest of the test. Bodies
+/// are not limited, so mock servers can take multipart uploads.
+pub async fn serve(app: Router) -> SocketAddr {
+    let app = app.layer(DefaultBodyLimit::disable());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    addr
+}
+
+/// The whole HTTP server from [`server::router`] and its job worker, over a
+/// fresh SQLite database and one in-memory storage backend named `memory`.
+pub struct TestServer {
+    pub state: AppState,
+    pub url: String,
+    _dir: TempDir,
+}
+
+impl TestServer {
+    pub async fn start() -> Self {
+        Self::start_with(|_| {}).await
+    }
+
+    /// Starts with the default configuration as changed by `configure`.
+    pub async fn start_with(configure: impl FnOnce(&mut Config)) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Cli::try_parse_from(["file-server"]).unwrap().config;
+        config.database_url = format!("sqlite:{}", dir.path().join("files.db").display())
+            .parse()
+            .unwrap();
+        configure(&mut config);
+
+        let storage_config = toml::from_str(
+            r#"
+            default = "memory"
+
+            [[backends]]
+            name = "memory"
+            type = "memory"
+            "#,
+        )
+        .unwrap();
+        let state = AppState::new(
+            create_pool(config.database_url.expose()).await.unwrap(),
+            StorageRegistry::from_config(&storage_config).await.unwrap(),
+            config,
+            PrometheusBuilder::new().build_recorder().handle(),
+            CancellationToken::new(),
+        );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let app = server::router(state.clone());
+        tokio::spawn(jobs::run(state.clone(), CancellationToken::new()));
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap()
+        });
+
+        Self {
+            state,
+            url,
+            _dir: dir,
+        }
+    }
+}
+
+/// A stream yielding each of `chunks` in turn.
+pub fn chunks(chunks: &[&[u8]]) -> ByteStream<'static> {
+    let chunks: Vec<std::io::Result<Bytes>> = chunks
+        .iter()
+        .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+        .collect();
+    futures::stream::iter(chunks).boxed()
+}
+
+/// A stream that yields `data` and then fails, like an upload whose client
+/// went away.
+pub fn failing(data: &[u8]) -> ByteStream<'static> {
+    let items = vec![
+        Ok(Bytes::copy_from_slice(data)),
+        Err(std::io::Error::new(
+            std::io::ErrorKind::ConnectionReset,
+            "client went away",
+        )),
+    ];
+    futures::stream::iter(items).boxed()
+}
+
+pub async fn read(backend: &dyn StorageBackend, path: &str) -> Vec<u8> {
+    backend::collect(backend.get(path).await.unwrap())
+        .await
+        .unwrap()
+}
+
+/// Runs `backend` through what every [`StorageBackend`] has to do: write a
+/// blob from several chunks, read, stat, list, copy and delete it, and leave
+/// nothing behind when the upload stream fails. Returns the path the first
+/// blob was written to, so callers can check where it ended up.
+pub async fn check_contract(backend: &dyn StorageBackend) -> String {
+    backend.check_ready().await.unwrap();
+
+    let key = format!("report-{}.txt", Uuid::new_v4());
+    let path = backend
+        .put(
+            &key,
+            "text/plain",
+            chunks(&[b"hello ", b"storage ", b"world"]),
+        )
+        .await
+        .unwrap();
+    assert_eq!(read(backend, &path).await, b"hello storage world");
+
+    let object = backend.head(&path).await.unwrap();
+    assert_eq!(object.size, 19);
+
+    let listed = backend.list().await.unwrap();
+    let entry = listed
+        .iter()
+        .find(|object| object.path == path)
+        .unwrap_or_else(|| panic!("{} is not listed in {:?}", path, listed));
+    assert_eq!(entry.size, 19);
+
+    let copy = backend
+        .copy(&path, &format!("copy-{}.txt", Uuid::new_v4()), "text/plain")
+        .await
+        .unwrap();
+    assert_ne!(copy, path);
+    assert_eq!(read(backend, &copy).await, b"hello storage world");
+
+    let failed_key = format!("failed-{}.txt", Uuid::new_v4());
+    assert!(backend
+        .put(&failed_key, "text/plain", failing(b"partial content"))
+        .await
+        .is_err());
+    let listed = backend.list().await.unwrap();
+    assert!(
+        listed
+            .iter()
+            .all(|object| !object.path.contains(&failed_key)),
+        "failed upload left {:?}",
+        listed
+    );
+    // Nothing partial is left, not even anything younger than a grace period.
+    let later = chrono::Utc::now() + chrono::Duration::days(1);
+    assert_eq!(backend.cleanup_partial(later).await.unwrap(), 0);
+
+    backend.delete(&copy).await.unwrap();
+    assert!(backend.get(&copy).await.is_err());
+    assert!(backend.head(&copy).await.is_err());
+    let listed = backend.list().await.unwrap();
+    assert!(listed.iter().all(|object| object.path != copy));
+    assert_eq!(read(backend, &path).await, b"hello storage world");
+
+    path
+}
@@ -0,0 +1,590 @@
+//! Runs the queries in `database.rs` against SQLite and, when `DATABASE_URL`
+//! points at a Postgres server, against Postgres too. Each test gets its own
+//! fresh database on both.
+
+use chrono::{Duration, Utc};
+use file_server_rs::{
+    database::{self, DbPool},
+    models::{
+        file::{STATUS_PENDING, STATUS_PROCESSING, STATUS_READY},
+        job::{JOB_COMPLETED, JOB_DEAD, JOB_PROCESS_FILE, JOB_QUEUED, JOB_RUNNING},
+        File, FileFilter, FileLabels, Folder, JobFilter,
+    },
+};
+use sqlx::{Connection, PgConnection};
+use tempfile::TempDir;
+use uuid::Uuid;
+
+struct TestDatabase {
+    pool: DbPool,
+    cleanup: Cleanup,
+}
+
+enum Cleanup {
+    Sqlite(#[allow(dead_code)] TempDir),
+    Postgres { server_url: String, name: String },
+}
+
+impl TestDatabase {
+    async fn sqlite() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let url = format!("sqlite:{}", dir.path().join("test.db").display());
+        let pool = database::create_pool(&url).await.unwrap();
+        Self {
+            pool,
+            cleanup: Cleanup::Sqlite(dir),
+        }
+    }
+
+    /// A new database on the server `server_url` points at, so that tests
+    /// running in parallel don't see each other's rows.
+    async fn postgres(server_url: &str) -> Self {
+        let name = format!("file_server_test_{}", Uuid::new_v4().simple());
+        let mut conn = PgConnection::connect(server_url).await.unwrap();
+        sqlx::query(&format!("CREATE DATABASE {}", name))
+            .execute(&mut conn)
+            .await
+            .unwrap();
+        conn.close().await.unwrap();
+
+        let pool = database::create_pool(&with_database(server_url, &name))
+            .await
+            .unwrap();
+        Self {
+            pool,
+            cleanup: Cleanup::Postgres {
+                server_url: server_url.to_string(),
+                name,
+            },
+        }
+    }
+
+    async fn close(self) {
+        self.pool.close().await;
+        if let Cleanup::Postgres { server_url, name } = self.cleanup {
+            // Closed connections can linger on the server for a moment.
+            let mut conn = PgConnection::connect(&server_url).await.unwrap();
+            sqlx::query(&format!("DROP DATABASE IF EXISTS {} WITH (FORCE)", name))
+                .execute(&mut conn)
+                .await
+                .unwrap();
+        }
+    }
+}
+
+/// `url` with its database name replaced by `name`.
+fn with_database(url: &str, name: &str) -> String {
+    let (base, query) = match url.split_once('?') {
+        Some((base, query)) => (base, Some(query)),
+        None => (url, None),
+    };
+    let server = match base.rsplit_once('/') {
+        Some((server, _)) if !server.ends_with('/') => server,
+        _ => base,
+    };
+    match query {
+        Some(query) => format!("{}/{}?{}", server, name, query),
+        None => format!("{}/{}", server, name),
+    }
+}
+
+/// The databases each test runs against.
+async fn databases() -> Vec<TestDatabase> {
+    let mut databases = vec![TestDatabase::sqlite().await];
+    match std::env::var("DATABASE_URL") {
+        Ok(url) if url.starts_with("postgres") => {
+            databases.push(TestDatabase::postgres(&url).await)
+        }
+        _ => eprintln!("DATABASE_URL is not a Postgres URL, testing SQLite only"),
+    }
+    databases
+}
+
+fn new_file(name: &str, size: i64, storage_type: &str) -> File {
+    File::new(
+        format!("{}/{}", storage_type, Uuid::new_v4()),
+        name.to_string(),
+        size,
+        storage_type.to_string(),
+    )
+}
+
+fn labels(tags: &[&str], metadata: &[(&str, &str)]) -> FileLabels {
+    FileLabels {
+        tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        metadata: metadata
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect(),
+    }
+}
+
+#[tokio::test]
+async fn files_round_trip() {
+    for db in databases().await {
+        let pool = &db.pool;
+
+        let mut report = new_file("report.pdf", 1234, "local");
+        report.folder = "docs".to_string();
+        let report_labels = labels(&["release"], &[("build", "42")]);
+        let created = database::create_file(pool, &report, &report_labels)
+            .await
+            .unwrap();
+        assert_eq!(created.id, report.id);
+        assert_eq!(created.status, STATUS_READY);
+
+        let fetched = database::get_file_by_id(pool, &report.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(fetched.name, "report.pdf");
+        assert_eq!(fetched.folder, "docs");
+        assert_eq!(fetched.size, 1234);
+        assert_eq!(fetched.storage_type, "local");
+        assert!(!fetched.is_private);
+        assert!(database::get_file_by_id(pool, "missing")
+            .await
+            .unwrap()
+            .is_none());
+
+        let other = new_file("other.txt", 10, "local");
+        database::create_file(pool, &other, &labels(&["draft"], &[]))
+            .await
+            .unwrap();
+        let mut hidden = new_file("hidden.txt", 10, "local");
+        hidden.is_private = true;
+        database::create_file(pool, &hidden, &FileLabels::default())
+            .await
+            .unwrap();
+
+        let by_tag = FileFilter {
+            tag: Some("release".to_string()),
+            metadata: None,
+        };
+        let files = database::get_files(pool, &by_tag, 10).await.unwrap();
+        assert_eq!(ids(&files), [report.id.as_str()]);
+
+        let by_metadata = FileFilter {
+            tag: None,
+            metadata: Some(("build".to_string(), "42".to_string())),
+        };
+        let files = database::get_files(pool, &by_metadata, 10).await.unwrap();
+        assert_eq!(ids(&files), [report.id.as_str()]);
+
+        let files = database::get_files(pool, &FileFilter::default(), 1)
+            .await
+            .unwrap();
+        assert_eq!(files.len(), 1);
+
+        let files =
+            database::get_files_by_ids(pool, &[&report.id, &other.id, &hidden.id, "missing"])
+                .await
+                .unwrap();
+        let mut found = ids(&files);
+        found.sort();
+        let mut expected = vec![report.id.as_str(), other.id.as_str()];
+        expected.sort();
+        assert_eq!(found, expected);
+
+        let all_labels = database::get_file_labels(pool, &[&report.id, &other.id, &hidden.id])
+            .await
+            .unwrap();
+        assert_eq!(all_labels.get(&report.id), Some(&report_labels));
+        assert_eq!(
+            all_labels.get(&other.id).map(|labels| &labels.tags),
+            Some(&labels(&["draft"], &[]).tags)
+        );
+        assert!(!all_labels.contains_key(&hidden.id));
+
+        db.close().await;
+    }
+}
+
+#[tokio::test]
+async fn files_are_moved_replaced_and_deleted() {
+    for db in databases().await {
+        let pool = &db.pool;
+
+        let file = new_file("a.txt", 11, "local");
+        database::create_file(pool, &file, &FileLabels::default())
+            .await
+            .unwrap();
+
+        assert!(database::move_file(pool, &file.id, "notes", "b.txt")
+            .await
+            .unwrap());
+        assert!(!database::move_file(pool, "missing", "notes", "b.txt")
+            .await
+            .unwrap());
+        let moved = database::get_files_by_name(pool, "notes", "b.txt")
+            .await
+            .unwrap();
+        assert_eq!(ids(&moved), [file.id.as_str()]);
+        assert!(database::get_files_in_folder(pool, "")
+            .await
+            .unwrap()
+            .is_empty());
+
+        let mut replaced = moved[0].clone();
+        replaced.path = "local/replacement".to_string();
+        replaced.size = 17;
+        replaced.updated_at = Utc::now();
+        assert!(database::replace_file_content(pool, &replaced)
+            .await
+            .unwrap());
+        let fetched = database::get_file_by_id(pool, &file.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(fetched.path, "local/replacement");
+        assert_eq!(fetched.size, 17);
+        assert_eq!(fetched.name, "b.txt");
+
+        assert!(database::mark_file_broken(pool, &file.id).await.unwrap());
+        assert!(database::get_files_in_folder(pool, "notes")
+            .await
+            .unwrap()
+            .is_empty());
+
+        assert!(database::delete_file_by_id(pool, &file.id).await.unwrap());
+        assert!(!database::delete_file_by_id(pool, &file.id).await.unwrap());
+        assert!(database::get_file_by_id(pool, &file.id)
+            .await
+            .unwrap()
+            .is_none());
+
+        db.close().await;
+    }
+}
+
+#[tokio::test]
+async fn totals_are_summed_as_bigint() {
+    for db in databases().await {
+        let pool = &db.pool;
+
+        assert_eq!(database::get_file_totals(pool).await.unwrap(), (0, 0));
+        assert!(database::get_file_totals_by_storage_type(pool)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Past what fits in 32 bits, and summed to more than either.
+        let large = 3_000_000_000;
+        for (size, storage_type) in [(large, "local"), (large, "local"), (5, "s3")] {
+            database::create_file(
+                pool,
+                &new_file("blob", size, storage_type),
+                &FileLabels::default(),
+            )
+            .await
+            .unwrap();
+        }
+
+        assert_eq!(
+            database::get_file_totals(pool).await.unwrap(),
+            (3, 2 * large + 5)
+        );
+        let mut by_type = database::get_file_totals_by_storage_type(pool)
+            .await
+            .unwrap();
+        by_type.sort();
+        assert_eq!(
+            by_type,
+            [
+                ("local".to_string(), 2, 2 * large),
+                ("s3".to_string(), 1, 5)
+            ]
+        );
+
+        db.close().await;
+    }
+}
+
+#[tokio::test]
+async fn folders_form_a_tree() {
+    for db in databases().await {
+        let pool = &db.pool;
+
+        for path in ["docs", "docs/specs", "docs/specs/old", "docsets", "images"] {
+            assert!(
+                database::create_folder(pool, &Folder::new(path.to_string()))
+                    .await
+                    .unwrap()
+            );
+        }
+        assert!(
+            !database::create_folder(pool, &Folder::new("docs".to_string()))
+                .await
+                .unwrap()
+        );
+
+        let folder = database::get_folder(pool, "docs/specs")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(folder.parent, "docs");
+        assert!(database::get_folder(pool, "missing")
+            .await
+            .unwrap()
+            .is_none());
+
+        let top = database::get_subfolders(pool, "").await.unwrap();
+        assert_eq!(paths(&top), ["docs", "docsets", "images"]);
+
+        // A sibling sharing the prefix is not part of the tree.
+        let tree = database::get_folder_tree(pool, "docs").await.unwrap();
+        assert_eq!(paths(&tree), ["docs", "docs/specs", "docs/specs/old"]);
+        let everything = database::get_folder_tree(pool, "").await.unwrap();
+        assert_eq!(everything.len(), 5);
+
+        let mut spec = new_file("spec.md", 3, "local");
+        spec.folder = "docs/specs/old".to_string();
+        database::create_file(pool, &spec, &FileLabels::default())
+            .await
+            .unwrap();
+        let mut unrelated = new_file("set.md", 3, "local");
+        unrelated.folder = "docsets".to_string();
+        database::create_file(pool, &unrelated, &FileLabels::default())
+            .await
+            .unwrap();
+
+        let under = database::get_files_under_folder(pool, "docs")
+            .await
+            .unwrap();
+        assert_eq!(ids(&under), [spec.id.as_str()]);
+
+        database::move_folder(pool, "docs", "archive/docs", "archive")
+            .await
+            .unwrap();
+        let tree = database::get_folder_tree(pool, "archive/docs")
+            .await
+            .unwrap();
+        assert_eq!(
+            paths(&tree),
+            [
+                "archive/docs",
+                "archive/docs/specs",
+                "archive/docs/specs/old"
+            ]
+        );
+        assert_eq!(tree[0].parent, "archive");
+        assert_eq!(tree[2].parent, "archive/docs/specs");
+        assert!(database::get_folder_tree(pool, "docs")
+            .await
+            .unwrap()
+            .is_empty());
+
+        let moved = database::get_file_by_id(pool, &spec.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(moved.folder, "archive/docs/specs/old");
+        let untouched = database::get_file_by_id(pool, &unrelated.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(untouched.folder, "docsets");
+
+        assert_eq!(
+            database::delete_folder_tree(pool, "archive/docs")
+                .await
+                .unwrap(),
+            3
+        );
+        assert_eq!(
+            paths(&database::get_folder_tree(pool, "").await.unwrap()),
+            ["images", "docsets"]
+        );
+
+        db.close().await;
+    }
+}
+
+#[tokio::test]
+async fn pending_files_queue_a_job_that_is_claimed_once() {
+    for db in databases().await {
+        let pool = &db.pool;
+
+        let mut file = new_file("upload.bin", 100, "local");
+        file.start_processing();
+        database::create_file(pool, &file, &FileLabels::default())
+            .await
+            .unwrap();
+        let job_id = file.job_id.clone().unwrap();
+
+        let now = Utc::now();
+        let due = database::get_due_jobs(pool, now, 10).await.unwrap();
+        assert_eq!(due.len(), 1);
+        let job = due.into_iter().next().unwrap();
+        assert_eq!(job.id, job_id);
+        assert_eq!(job.kind, JOB_PROCESS_FILE);
+        assert_eq!(job.status, JOB_QUEUED);
+        let payload: serde_json::Value = serde_json::from_str(&job.payload).unwrap();
+        assert_eq!(payload["file_id"], file.id.as_str());
+
+        // Two workers that both saw the job queued: only one gets it.
+        let lease_until = now + Duration::minutes(5);
+        assert!(database::claim_job(pool, &job, lease_until).await.unwrap());
+        assert!(!database::claim_job(pool, &job, lease_until).await.unwrap());
+
+        let claimed = database::get_job(pool, &job_id).await.unwrap().unwrap();
+        assert_eq!(claimed.status, JOB_RUNNING);
+        assert_eq!(claimed.attempts, 1);
+        assert!(database::get_due_jobs(pool, now, 10)
+            .await
+            .unwrap()
+            .is_empty());
+        // Its lease runs out if the worker goes away.
+        let expired = database::get_due_jobs(pool, lease_until, 10).await.unwrap();
+        assert_eq!(expired.len(), 1);
+
+        assert!(database::start_file_processing(pool, &file.id, &job_id)
+            .await
+            .unwrap());
+        assert!(!database::start_file_processing(pool, &file.id, "stale")
+            .await
+            .unwrap());
+        let processing = database::get_file_by_id(pool, &file.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(processing.status, STATUS_PROCESSING);
+
+        assert!(database::finish_file_processing(
+            pool,
+            &file.id,
+            &job_id,
+            STATUS_READY,
+            Some("abc123"),
+            None
+        )
+        .await
+        .unwrap());
+        let ready = database::get_file_by_id(pool, &file.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(ready.status, STATUS_READY);
+        assert_eq!(ready.sha256.as_deref(), Some("abc123"));
+
+        let mut completed = claimed;
+        completed.status = JOB_COMPLETED.to_string();
+        completed.completed_at = Some(Utc::now());
+        completed.updated_at = Utc::now();
+        database::update_job(pool, &completed).await.unwrap();
+        assert_eq!(
+            database::count_jobs_by_status(pool).await.unwrap(),
+            [(JOB_COMPLETED.to_string(), 1)]
+        );
+        assert_eq!(
+            database::delete_completed_jobs_before(pool, Utc::now() + Duration::seconds(1))
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(database::get_job(pool, &job_id).await.unwrap().is_none());
+
+        db.close().await;
+    }
+}
+
+#[tokio::test]
+async fn replaced_content_queues_a_new_job() {
+    for db in databases().await {
+        let pool = &db.pool;
+
+        let mut file = new_file("data.csv", 10, "local");
+        file.start_processing();
+        database::create_file(pool, &file, &FileLabels::default())
+            .await
+            .unwrap();
+        let first_job = file.job_id.clone().unwrap();
+
+        file.start_processing();
+        file.size = 20;
+        assert!(database::replace_file_content(pool, &file).await.unwrap());
+        let second_job = file.job_id.clone().unwrap();
+
+        // The first job finds its content gone and leaves the file alone.
+        assert!(!database::start_file_processing(pool, &file.id, &first_job)
+            .await
+            .unwrap());
+        let fetched = database::get_file_by_id(pool, &file.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(fetched.status, STATUS_PENDING);
+        assert_eq!(fetched.job_id.as_deref(), Some(second_job.as_str()));
+
+        let jobs = database::get_jobs(pool, &JobFilter::default(), 10)
+            .await
+            .unwrap();
+        assert_eq!(jobs.len(), 2);
+
+        db.close().await;
+    }
+}
+
+#[tokio::test]
+async fn jobs_are_released_and_retried() {
+    for db in databases().await {
+        let pool = &db.pool;
+
+        let mut file = new_file("x", 1, "local");
+        file.start_processing();
+        database::create_file(pool, &file, &FileLabels::default())
+            .await
+            .unwrap();
+        let job_id = file.job_id.clone().unwrap();
+
+        let now = Utc::now();
+        let job = database::get_job(pool, &job_id).await.unwrap().unwrap();
+        assert!(database::claim_job(pool, &job, now + Duration::hours(1))
+            .await
+            .unwrap());
+
+        // Shutdown hands the running job back to the queue straight away.
+        database::release_jobs(pool, std::slice::from_ref(&job_id))
+            .await
+            .unwrap();
+        let released = database::get_job(pool, &job_id).await.unwrap().unwrap();
+        assert_eq!(released.status, JOB_QUEUED);
+        assert_eq!(
+            database::get_due_jobs(pool, Utc::now(), 10)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        assert!(!database::retry_job(pool, &job_id).await.unwrap());
+        let mut dead = released;
+        dead.status = JOB_DEAD.to_string();
+        dead.last_error = Some("boom".to_string());
+        dead.updated_at = Utc::now();
+        database::update_job(pool, &dead).await.unwrap();
+
+        let filter = JobFilter {
+            status: Some(JOB_DEAD.to_string()),
+            kind: Some(JOB_PROCESS_FILE.to_string()),
+        };
+        let dead_jobs = database::get_jobs(pool, &filter, 10).await.unwrap();
+        assert_eq!(dead_jobs.len(), 1);
+        assert_eq!(dead_jobs[0].last_error.as_deref(), Some("boom"));
+
+        assert!(database::retry_job(pool, &job_id).await.unwrap());
+        let retried = database::get_job(pool, &job_id).await.unwrap().unwrap();
+        assert_eq!(retried.status, JOB_QUEUED);
+        assert_eq!(retried.attempts, 0);
+
+        db.close().await;
+    }
+}
+
+fn ids(files: &[File]) -> Vec<&str> {
+    files.iter().map(|file| file.id.as_str()).collect()
+}
+
+fn paths(folders: &[Folder]) -> Vec<&str> {
+    folders.iter().map(|folder| folder.path.as_str()).collect()
+}
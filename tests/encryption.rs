@@ -0,0 +1,212 @@
+//! Envelope encryption of blobs and rotation of the master key.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use file_server_rs::{
+    database::{create_file, create_pool, get_file_by_id},
+    models::{File, FileLabels},
+    ops::rotate_keys,
+    storage::{
+        backend, encrypted_size, encryption::SEGMENT_SIZE, ByteStream, Keyring, StorageRegistry,
+        WrappedKey,
+    },
+};
+use futures::StreamExt;
+
+const TAG_LEN: usize = 16;
+
+fn master_key(byte: u8) -> String {
+    STANDARD.encode([byte; 32])
+}
+
+/// `data` in chunks of `chunk_size`, which rarely line up with segments.
+fn stream(data: &[u8], chunk_size: usize) -> ByteStream<'static> {
+    let chunks: Vec<std::io::Result<Bytes>> = data
+        .chunks(chunk_size)
+        .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+        .collect();
+    futures::stream::iter(chunks).boxed()
+}
+
+async fn encrypt(keyring: &Keyring, data: &[u8]) -> (Vec<u8>, WrappedKey) {
+    let (sealed, key) = keyring.encrypt(stream(data, 1000)).unwrap();
+    (backend::collect(sealed).await.unwrap(), key)
+}
+
+async fn decrypt(keyring: &Keyring, sealed: &[u8], key: &WrappedKey) -> std::io::Result<Vec<u8>> {
+    backend::collect(keyring.decrypt(stream(sealed, 4096), key).unwrap()).await
+}
+
+/// Plaintext that differs between segments, so swapped segments would not
+/// decrypt to the same bytes by accident.
+fn plaintext(size: usize) -> Vec<u8> {
+    (0..size).map(|i| (i / 7 % 251) as u8).collect()
+}
+
+#[tokio::test]
+async fn blobs_round_trip_at_segment_boundaries() {
+    let keyring = Keyring::new(&master_key(1), &[]).unwrap();
+
+    for size in [
+        0,
+        1,
+        SEGMENT_SIZE - 1,
+        SEGMENT_SIZE,
+        SEGMENT_SIZE + 1,
+        2 * SEGMENT_SIZE,
+    ] {
+        let data = plaintext(size);
+        let (sealed, key) = encrypt(&keyring, &data).await;
+        assert_eq!(sealed.len() as u64, encrypted_size(size as u64), "{}", size);
+        assert_eq!(key.key_id, keyring.current_id());
+        assert_eq!(decrypt(&keyring, &sealed, &key).await.unwrap(), data);
+    }
+}
+
+#[tokio::test]
+async fn truncated_blobs_are_rejected() {
+    let keyring = Keyring::new(&master_key(1), &[]).unwrap();
+
+    for size in [SEGMENT_SIZE + 1, 2 * SEGMENT_SIZE] {
+        let (sealed, key) = encrypt(&keyring, &plaintext(size)).await;
+
+        // Cut at a segment boundary, the first segment is complete but was
+        // not sealed as the last one.
+        let first_segment = &sealed[..SEGMENT_SIZE + TAG_LEN];
+        assert!(decrypt(&keyring, first_segment, &key).await.is_err());
+
+        let cut = &sealed[..sealed.len() - 1];
+        assert!(decrypt(&keyring, cut, &key).await.is_err());
+    }
+
+    let (sealed, key) = encrypt(&keyring, b"").await;
+    assert!(decrypt(&keyring, &[], &key).await.is_err());
+    assert!(decrypt(&keyring, &sealed[1..], &key).await.is_err());
+}
+
+#[tokio::test]
+async fn modified_or_reordered_segments_are_rejected() {
+    let keyring = Keyring::new(&master_key(1), &[]).unwrap();
+    let data = plaintext(3 * SEGMENT_SIZE);
+    let (sealed, key) = encrypt(&keyring, &data).await;
+    let segment = SEGMENT_SIZE + TAG_LEN;
+
+    let mut modified = sealed.clone();
+    modified[segment + 10] ^= 1;
+    assert!(decrypt(&keyring, &modified, &key).await.is_err());
+
+    let mut reordered = sealed.clone();
+    reordered[..2 * segment].rotate_left(segment);
+    assert!(decrypt(&keyring, &reordered, &key).await.is_err());
+
+    // Segments of another blob under the same master key have their own
+    // data key.
+    let (other, _) = encrypt(&keyring, &data).await;
+    let mut spliced = sealed.clone();
+    spliced[segment..2 * segment].copy_from_slice(&other[segment..2 * segment]);
+    assert!(decrypt(&keyring, &spliced, &key).await.is_err());
+
+    assert_eq!(decrypt(&keyring, &sealed, &key).await.unwrap(), data);
+}
+
+#[tokio::test]
+async fn data_keys_only_unwrap_with_their_master_key() {
+    let old = Keyring::new(&master_key(1), &[]).unwrap();
+    let (sealed, key) = encrypt(&old, b"attack at dawn").await;
+
+    let unrelated = Keyring::new(&master_key(2), &[]).unwrap();
+    assert!(unrelated.decrypt(stream(&sealed, 1000), &key).is_err());
+
+    // Relabelled with the id of a key that is configured, the wrapped key
+    // fails authentication.
+    let relabelled = WrappedKey {
+        key_id: unrelated.current_id().to_string(),
+        ..key.clone()
+    };
+    assert!(unrelated
+        .decrypt(stream(&sealed, 1000), &relabelled)
+        .is_err());
+
+    // After rotation the old key is only needed until data keys are rewrapped.
+    let rotated = Keyring::new(&master_key(2), &[&master_key(1)]).unwrap();
+    assert_eq!(
+        decrypt(&rotated, &sealed, &key).await.unwrap(),
+        b"attack at dawn"
+    );
+    let rewrapped = rotated.rewrap(&key).unwrap();
+    assert_eq!(rewrapped.key_id, rotated.current_id());
+    assert_eq!(
+        decrypt(&unrelated, &sealed, &rewrapped).await.unwrap(),
+        b"attack at dawn"
+    );
+    assert!(old.decrypt(stream(&sealed, 1000), &rewrapped).is_err());
+}
+
+#[tokio::test]
+async fn rotate_keys_rewraps_files_with_the_current_key() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = create_pool(&format!("sqlite:{}", dir.path().join("files.db").display()))
+        .await
+        .unwrap();
+    let mut storages = StorageRegistry::from_config(
+        &toml::from_str(
+            r#"
+            default = "memory"
+
+            [[backends]]
+            name = "memory"
+            type = "memory"
+            "#,
+        )
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+    storages.set_keyring(Keyring::new(&master_key(1), &[]).unwrap());
+
+    let storage = storages.default_backend();
+    let data = plaintext(SEGMENT_SIZE + 1);
+    let blob = storage
+        .store_stream("report.bin", stream(&data, 1000))
+        .await
+        .unwrap();
+    let key = blob.key.unwrap();
+    let mut file = File::new(
+        blob.path.clone(),
+        "report.bin".to_string(),
+        data.len() as i64,
+        "memory".to_string(),
+    );
+    file.encryption_key_id = Some(key.key_id.clone());
+    file.wrapped_key = Some(key.wrapped.clone());
+    create_file(&db, &file, &FileLabels::default())
+        .await
+        .unwrap();
+
+    storages.set_keyring(Keyring::new(&master_key(2), &[&master_key(1)]).unwrap());
+    let report = rotate_keys::run(&db, &storages, false).await.unwrap();
+    assert_eq!(report.rewrapped, 1);
+    assert!(report.failed.is_empty());
+    assert_eq!(report.key_id, storages.keyring().unwrap().current_id());
+
+    let rotated = get_file_by_id(&db, &file.id).await.unwrap().unwrap();
+    let rotated_key = rotated.blob_key().unwrap();
+    assert_eq!(rotated_key.key_id, report.key_id);
+    assert_eq!(rotated.path, blob.path);
+
+    // The previous key can be retired now.
+    storages.set_keyring(Keyring::new(&master_key(2), &[]).unwrap());
+    let storage = storages.default_backend();
+    assert_eq!(
+        storage
+            .get_file(&rotated.path, Some(&rotated_key))
+            .await
+            .unwrap(),
+        data
+    );
+    assert!(storage.get_file(&rotated.path, Some(&key)).await.is_err());
+
+    // Nothing is left to rotate.
+    let report = rotate_keys::run(&db, &storages, false).await.unwrap();
+    assert_eq!(report.rewrapped, 0);
+}
@@ -0,0 +1,352 @@
+//! The GCS backend against a small in-process server speaking the JSON API,
+//! as it would talk to an emulator.
+
+mod common;
+
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    http::{header, HeaderMap, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Json, Router,
+};
+use common::{check_contract, chunks, failing, read, serve};
+use file_server_rs::storage::{GcsStorage, StorageBackend};
+use futures::StreamExt;
+use percent_encoding::percent_decode_str;
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+const BUCKET: &str = "files";
+/// Objects per page of a listing, small enough for tests to page.
+const PAGE_SIZE: usize = 2;
+
+/// Keeps one bucket in memory.
+#[derive(Clone, Default)]
+struct GcsServer {
+    state: Arc<Mutex<GcsState>>,
+}
+
+#[derive(Default)]
+struct GcsState {
+    objects: BTreeMap<String, Bytes>,
+    /// Resumable upload sessions: object name and data received so far.
+    sessions: HashMap<String, (String, Vec<u8>)>,
+    cancelled: usize,
+    requests: Vec<(Method, String)>,
+}
+
+impl GcsServer {
+    async fn start(&self) -> GcsStorage {
+        let app = Router::new().fallback(handle).with_state(self.clone());
+        let endpoint = format!("http://{}", serve(app).await);
+        GcsStorage::new(BUCKET.to_string(), Some(endpoint), None)
+            .await
+            .unwrap()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, GcsState> {
+        self.state.lock().unwrap()
+    }
+
+    fn count(&self, method: Method, path: &str) -> usize {
+        self.state()
+            .requests
+            .iter()
+            .filter(|(m, uri)| *m == method && uri.contains(path))
+            .count()
+    }
+}
+
+fn object(name: &str, data: &Bytes) -> Value {
+    json!({
+        "bucket": BUCKET,
+        "name": name,
+        "size": data.len().to_string(),
+        "updated": "2024-05-01T12:00:00Z",
+    })
+}
+
+fn not_found() -> Response {
+    let body = json!({"error": {"code": 404, "message": "No such object"}});
+    (StatusCode::NOT_FOUND, Json(body)).into_response()
+}
+
+async fn handle(
+    State(server): State<GcsServer>,
+    method: Method,
+    uri: Uri,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let mut state = server.state();
+    state.requests.push((method.clone(), uri.to_string()));
+
+    // Segments are decoded one by one, since object names escape `/`.
+    let segments: Vec<String> = uri
+        .path()
+        .split('/')
+        .skip(1)
+        .map(|segment| {
+            percent_decode_str(segment)
+                .decode_utf8()
+                .unwrap()
+                .to_string()
+        })
+        .collect();
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+
+    match (method.clone(), segments.as_slice()) {
+        (Method::GET, ["storage", "v1", "b", BUCKET]) => {
+            Json(json!({"name": BUCKET})).into_response()
+        }
+        (Method::GET, ["storage", "v1", "b", BUCKET, "o"]) => list(&state, &query),
+        (Method::GET, ["storage", "v1", "b", BUCKET, "o", name]) => {
+            match state.objects.get(*name) {
+                Some(data) if query.get("alt").map(String::as_str) == Some("media") => {
+                    data.clone().into_response()
+                }
+                Some(data) => Json(object(name, data)).into_response(),
+                None => not_found(),
+            }
+        }
+        (Method::DELETE, ["storage", "v1", "b", BUCKET, "o", name]) => {
+            match state.objects.remove(*name) {
+                Some(_) => StatusCode::NO_CONTENT.into_response(),
+                None => not_found(),
+            }
+        }
+        (
+            Method::POST,
+            ["storage", "v1", "b", BUCKET, "o", source, "rewriteTo", "b", BUCKET, "o", name],
+        ) => {
+            let Some(data) = state.objects.get(*source).cloned() else {
+                return not_found();
+            };
+            // Takes two calls, like a large object would.
+            if !query.contains_key("rewriteToken") {
+                return Json(json!({"done": false, "rewriteToken": "more"})).into_response();
+            }
+            state.objects.insert(name.to_string(), data.clone());
+            Json(json!({"done": true, "resource": object(name, &data)})).into_response()
+        }
+        (Method::POST, ["upload", "storage", "v1", "b", BUCKET, "o"]) => {
+            let name = query["name"].clone();
+            match query["uploadType"].as_str() {
+                "media" => {
+                    let response = object(&name, &body);
+                    state.objects.insert(name, body);
+                    Json(response).into_response()
+                }
+                "resumable" => {
+                    let id = format!("session-{}", state.sessions.len() + 1);
+                    state.sessions.insert(id.clone(), (name, Vec::new()));
+                    let host = headers[header::HOST].to_str().unwrap();
+                    let location = format!("http://{}/upload/session/{}", host, id);
+                    ([(header::LOCATION, location)], "").into_response()
+                }
+                _ => StatusCode::BAD_REQUEST.into_response(),
+            }
+        }
+        (Method::PUT, ["upload", "session", id]) => {
+            let range = headers[header::CONTENT_RANGE].to_str().unwrap().to_string();
+            let Some((_, data)) = state.sessions.get_mut(*id) else {
+                return not_found();
+            };
+            let (range, total) = range
+                .strip_prefix("bytes ")
+                .unwrap()
+                .split_once('/')
+                .unwrap();
+            if range != "*" {
+                let start: usize = range.split_once('-').unwrap().0.parse().unwrap();
+                if start != data.len() {
+                    return StatusCode::BAD_REQUEST.into_response();
+                }
+                data.extend_from_slice(&body);
+            }
+            if total == "*" {
+                return StatusCode::PERMANENT_REDIRECT.into_response();
+            }
+
+            let (name, data) = state.sessions.remove(*id).unwrap();
+            let data = Bytes::from(data);
+            let response = object(&name, &data);
+            state.objects.insert(name, data);
+            Json(response).into_response()
+        }
+        (Method::DELETE, ["upload", "session", id]) => {
+            state.sessions.remove(*id);
+            state.cancelled += 1;
+            // What GCS answers to a cancelled session.
+            StatusCode::from_u16(499).unwrap().into_response()
+        }
+        _ => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Lists objects by prefix, paged by `PAGE_SIZE` with the offset as the
+/// token.
+fn list(state: &GcsState, query: &HashMap<String, String>) -> Response {
+    let prefix = query.get("prefix").cloned().unwrap_or_default();
+    let start: usize = query
+        .get("pageToken")
+        .map(|token| token.parse().unwrap())
+        .unwrap_or(0);
+    let matching: Vec<Value> = state
+        .objects
+        .iter()
+        .filter(|(name, _)| name.starts_with(&prefix))
+        .map(|(name, data)| object(name, data))
+        .collect();
+
+    let next = start + PAGE_SIZE;
+    let mut page = json!({
+        "kind": "storage#objects",
+        "items": matching.iter().skip(start).take(PAGE_SIZE).collect::<Vec<_>>(),
+    });
+    if next < matching.len() {
+        page["nextPageToken"] = json!(next.to_string());
+    }
+    Json(page).into_response()
+}
+
+#[tokio::test]
+async fn gcs_backend_meets_the_contract() {
+    let server = GcsServer::default();
+    let storage = server.start().await;
+    assert_eq!(storage.kind(), "gcs");
+
+    let path = check_contract(&storage).await;
+    assert!(path.starts_with("/uploads/report-"));
+    assert!(server.state().objects.contains_key(&path[1..]));
+    let object = storage.head(&path).await.unwrap();
+    assert_eq!(
+        object.modified_at.unwrap().to_rfc3339(),
+        "2024-05-01T12:00:00+00:00"
+    );
+    // Copies follow the rewrite token until GCS reports it is done.
+    assert_eq!(server.count(Method::POST, "/rewriteTo/"), 2);
+    // Without a service account key there is nothing to sign URLs with.
+    assert!(storage
+        .presign(&path, Duration::from_secs(60))
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn gcs_names_are_escaped() {
+    let server = GcsServer::default();
+    let storage = server.start().await;
+
+    let path = storage
+        .put("a b+c%d.txt", "text/plain", chunks(&[b"escaped"]))
+        .await
+        .unwrap();
+    assert_eq!(path, "/uploads/a b+c%d.txt");
+    assert!(server.state().objects.contains_key("uploads/a b+c%d.txt"));
+    assert_eq!(read(&storage, &path).await, b"escaped");
+    storage.delete(&path).await.unwrap();
+}
+
+#[tokio::test]
+async fn gcs_listing_pages_and_keeps_to_the_prefix() {
+    let server = GcsServer::default();
+    let storage = server.start().await;
+
+    for i in 0..5 {
+        let data = vec![b'x'; i];
+        storage
+            .put(
+                &format!("{}.bin", i),
+                "application/octet-stream",
+                chunks(&[&data]),
+            )
+            .await
+            .unwrap();
+    }
+    server
+        .state()
+        .objects
+        .insert("elsewhere/other.bin".to_string(), Bytes::from_static(b"x"));
+
+    let listed = storage.list().await.unwrap();
+    let listed: Vec<(&str, i64)> = listed
+        .iter()
+        .map(|object| (object.path.as_str(), object.size))
+        .collect();
+    assert_eq!(
+        listed,
+        [
+            ("/uploads/0.bin", 0),
+            ("/uploads/1.bin", 1),
+            ("/uploads/2.bin", 2),
+            ("/uploads/3.bin", 3),
+            ("/uploads/4.bin", 4)
+        ]
+    );
+    assert_eq!(server.count(Method::GET, "/o?"), 3);
+}
+
+#[tokio::test]
+async fn gcs_large_blobs_use_a_resumable_upload() {
+    let server = GcsServer::default();
+    let storage = server.start().await;
+
+    // Two full 8 MiB chunks and a short last one, arriving in small pieces.
+    let data: Vec<u8> = (0..17 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let stream = futures::stream::iter(
+        data.chunks(64 * 1024)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect::<Vec<_>>(),
+    )
+    .boxed();
+    let path = storage
+        .put("large.bin", "application/octet-stream", stream)
+        .await
+        .unwrap();
+
+    assert_eq!(server.count(Method::PUT, "/upload/session/"), 3);
+    assert!(server.state().sessions.is_empty());
+    assert!(read(&storage, &path).await == data);
+    assert_eq!(storage.head(&path).await.unwrap().size, data.len() as i64);
+}
+
+#[tokio::test]
+async fn gcs_failed_resumable_uploads_are_cancelled() {
+    let server = GcsServer::default();
+    let storage = server.start().await;
+
+    let chunk = vec![0u8; 9 * 1024 * 1024];
+    assert!(storage
+        .put("broken.bin", "application/octet-stream", failing(&chunk))
+        .await
+        .is_err());
+
+    let state = server.state();
+    assert_eq!(state.cancelled, 1);
+    assert!(state.sessions.is_empty());
+    assert!(state.objects.is_empty());
+}
+
+#[tokio::test]
+async fn gcs_missing_objects_are_errors() {
+    let server = GcsServer::default();
+    let storage = server.start().await;
+
+    let error = storage.get("/uploads/missing").await.err().unwrap();
+    assert!(error.to_string().contains("404"), "{}", error);
+    assert!(storage.head("/uploads/missing").await.is_err());
+    assert!(storage.delete("/uploads/missing").await.is_err());
+    assert!(storage
+        .copy("/uploads/missing", "copy.bin", "application/octet-stream")
+        .await
+        .is_err());
+    assert!(storage.list().await.unwrap().is_empty());
+}
@@ -0,0 +1,55 @@
+//! The local filesystem backend.
+
+mod common;
+
+use common::{check_contract, chunks, read};
+use file_server_rs::storage::{LocalStorage, Storage, StorageBackend};
+use std::{
+    fs,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+#[tokio::test]
+async fn local_backend_meets_the_contract() {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = LocalStorage::new(dir.path().to_path_buf());
+    assert_eq!(storage.kind(), "local");
+
+    let path = check_contract(&storage).await;
+    assert!(path.starts_with(dir.path().to_str().unwrap()));
+    assert!(fs::metadata(&path).unwrap().is_file());
+}
+
+#[tokio::test]
+async fn local_cleanup_keeps_partials_of_uploads_in_progress() {
+    let dir = tempfile::tempdir().unwrap();
+    let backend = Arc::new(LocalStorage::new(dir.path().to_path_buf()));
+    let kept = backend
+        .put("kept.txt", "text/plain", chunks(&[b"kept"]))
+        .await
+        .unwrap();
+
+    let interrupted = dir.path().join("interrupted.bin.partial");
+    fs::write(&interrupted, b"half").unwrap();
+    fs::File::options()
+        .write(true)
+        .open(&interrupted)
+        .unwrap()
+        .set_modified(SystemTime::now() - Duration::from_secs(3600))
+        .unwrap();
+    // Just written, as by another replica sharing the directory.
+    let in_progress = dir.path().join("in-progress.bin.partial");
+    fs::write(&in_progress, b"half").unwrap();
+
+    let listed = backend.list().await.unwrap();
+    let paths: Vec<&str> = listed.iter().map(|object| object.path.as_str()).collect();
+    assert_eq!(paths, [kept.as_str()]);
+
+    Storage::from_backend("local", backend.clone())
+        .cleanup_partial_uploads()
+        .await;
+    assert!(!interrupted.exists());
+    assert!(in_progress.exists());
+    assert_eq!(read(backend.as_ref(), &kept).await, b"kept");
+}
@@ -0,0 +1,152 @@
+//! Maintenance commands run against a database and storage.
+
+use bytes::Bytes;
+use clap::Parser;
+use file_server_rs::{
+    cli::{self, Cli},
+    config::{Config, StorageConfig},
+    database::{
+        create_file, create_pool, create_webhook, get_audit_events, get_file_by_id,
+        get_webhook_deliveries,
+    },
+    models::{AuditEventFilter, File, FileLabels, Webhook, WebhookEvent},
+    ops::gc,
+    storage::StorageRegistry,
+};
+use std::{
+    fs,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+use tempfile::TempDir;
+
+fn config(dir: &TempDir) -> Config {
+    let mut config = Cli::try_parse_from(["file-server"]).unwrap().config;
+    config.database_url = format!("sqlite:{}", dir.path().join("files.db").display())
+        .parse()
+        .unwrap();
+    config.storage_path = dir.path().join("files");
+    fs::create_dir_all(&config.storage_path).unwrap();
+    config
+}
+
+fn make_old(path: impl AsRef<Path>) {
+    fs::File::options()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_modified(SystemTime::now() - Duration::from_secs(3600))
+        .unwrap();
+}
+
+#[tokio::test]
+async fn gc_spares_uploads_in_progress() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = config(&dir);
+    let db = create_pool(config.database_url.expose()).await.unwrap();
+    let storages = StorageRegistry::from_config(&StorageConfig::load(&config).unwrap())
+        .await
+        .unwrap();
+    let storage = storages.default_backend();
+
+    let old_orphan = storage
+        .store_file("old.txt", Bytes::from_static(b"old"))
+        .await
+        .unwrap();
+    make_old(&old_orphan.path);
+    // Written by an upload that has not inserted its row yet.
+    let new_blob = storage
+        .store_file("new.txt", Bytes::from_static(b"new"))
+        .await
+        .unwrap();
+
+    let interrupted = config.storage_path.join("interrupted.bin.partial");
+    fs::write(&interrupted, b"half").unwrap();
+    make_old(&interrupted);
+    let in_progress = config.storage_path.join("in-progress.bin.partial");
+    fs::write(&in_progress, b"half").unwrap();
+
+    let report = gc::run(&db, &storages, &config).await.unwrap();
+    assert_eq!(report.deleted_orphans, 1);
+    assert!(!Path::new(&old_orphan.path).exists());
+    assert!(Path::new(&new_blob.path).exists());
+    assert!(!interrupted.exists());
+    assert!(in_progress.exists());
+}
+
+#[tokio::test]
+async fn cli_delete_is_audited_and_announced() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = config(&dir);
+    let db = create_pool(config.database_url.expose()).await.unwrap();
+    let storages = StorageRegistry::from_config(&StorageConfig::load(&config).unwrap())
+        .await
+        .unwrap();
+    let storage = storages.default_backend();
+
+    let blob = storage
+        .store_file("report.txt", Bytes::from_static(b"report"))
+        .await
+        .unwrap();
+    let file = File::new(
+        blob.path.clone(),
+        "report.txt".to_string(),
+        6,
+        storage.name().to_string(),
+    );
+    create_file(&db, &file, &FileLabels::default())
+        .await
+        .unwrap();
+    let webhook = Webhook::new(
+        "http://127.0.0.1:9/hooks".to_string(),
+        &[WebhookEvent::Delete],
+    );
+    create_webhook(&db, &webhook).await.unwrap();
+
+    let database_url = config.database_url.expose().to_string();
+    let storage_path = config.storage_path.display().to_string();
+    let args = [
+        "file-server",
+        "--database-url",
+        &database_url,
+        "--storage-path",
+        &storage_path,
+        "delete",
+        &file.id,
+    ];
+    cli::run(Cli::try_parse_from(args).unwrap()).await.unwrap();
+
+    assert!(get_file_by_id(&db, &file.id).await.unwrap().is_none());
+    assert!(!Path::new(&blob.path).exists());
+
+    let filter = AuditEventFilter {
+        file_id: Some(file.id.clone()),
+        ..Default::default()
+    };
+    let events = get_audit_events(&db, &filter, 10).await.unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].action, "delete");
+    assert_eq!(events[0].actor, "cli");
+    assert_eq!(events[0].status_code, 200);
+
+    // Queued for the server's delivery worker.
+    let deliveries = get_webhook_deliveries(&db, &webhook.id, None, 10)
+        .await
+        .unwrap();
+    assert_eq!(deliveries.len(), 1);
+    assert_eq!(deliveries[0].event, "delete");
+    assert_eq!(deliveries[0].file_id, file.id);
+
+    // A second delete finds nothing and records nothing.
+    let args = [
+        "file-server",
+        "--database-url",
+        &database_url,
+        "--storage-path",
+        &storage_path,
+        "delete",
+        &file.id,
+    ];
+    assert!(cli::run(Cli::try_parse_from(args).unwrap()).await.is_err());
+    assert_eq!(get_audit_events(&db, &filter, 10).await.unwrap().len(), 1);
+}
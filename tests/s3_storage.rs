@@ -0,0 +1,384 @@
+//! The S3 backend against a small in-process S3 server.
+
+mod common;
+
+use aws_sdk_s3::config::Credentials;
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    http::{header, HeaderMap, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Router,
+};
+use common::{check_contract, chunks, read, serve};
+use file_server_rs::storage::{backend, S3Encryption, S3Storage, StorageBackend};
+use futures::StreamExt;
+use percent_encoding::percent_decode_str;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+const BUCKET: &str = "files";
+/// Objects per page of a listing, small enough for tests to page.
+const PAGE_SIZE: usize = 2;
+const LAST_MODIFIED: &str = "Wed, 01 May 2024 12:00:00 GMT";
+
+/// Keeps one bucket in memory. Only the path-style requests the backend
+/// sends are understood.
+#[derive(Clone, Default)]
+struct S3Server {
+    state: Arc<Mutex<S3State>>,
+}
+
+#[derive(Default)]
+struct S3State {
+    objects: BTreeMap<String, Bytes>,
+    /// Parts of multipart uploads in progress, by upload id.
+    uploads: HashMap<String, BTreeMap<i32, Bytes>>,
+    aborted: Vec<String>,
+    /// Requests answered with 503 before the server starts working.
+    failures: u32,
+    requests: Vec<(Method, String)>,
+}
+
+impl S3Server {
+    /// Serves the bucket on an ephemeral port and returns its endpoint URL.
+    async fn start(&self) -> String {
+        let app = Router::new().fallback(handle).with_state(self.clone());
+        format!("http://{}", serve(app).await)
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, S3State> {
+        self.state.lock().unwrap()
+    }
+
+    fn count(&self, method: Method, query: &str) -> usize {
+        self.state()
+            .requests
+            .iter()
+            .filter(|(m, uri)| *m == method && uri.contains(query))
+            .count()
+    }
+}
+
+async fn storage(endpoint: &str, bucket: &str) -> S3Storage {
+    S3Storage::new(
+        bucket.to_string(),
+        Some("us-east-1".to_string()),
+        Some(endpoint.to_string()),
+        Some(Credentials::new("test", "test", None, None, "test")),
+        S3Encryption::None,
+    )
+    .await
+    .unwrap()
+}
+
+fn error(status: StatusCode, code: &str) -> Response {
+    let body = format!(
+        "<Error><Code>{}</Code><Message>{}</Message></Error>",
+        code, code
+    );
+    (status, [(header::CONTENT_TYPE, "application/xml")], body).into_response()
+}
+
+fn xml(body: String) -> Response {
+    ([(header::CONTENT_TYPE, "application/xml")], body).into_response()
+}
+
+async fn handle(
+    State(server): State<S3Server>,
+    method: Method,
+    uri: Uri,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let mut state = server.state();
+    state.requests.push((method.clone(), uri.to_string()));
+    if state.failures > 0 {
+        state.failures -= 1;
+        return error(StatusCode::SERVICE_UNAVAILABLE, "SlowDown");
+    }
+
+    let path = percent_decode_str(uri.path()).decode_utf8().unwrap();
+    let (bucket, key) = match path.trim_start_matches('/').split_once('/') {
+        Some((bucket, key)) => (bucket.to_string(), key.to_string()),
+        None => (path.trim_start_matches('/').to_string(), String::new()),
+    };
+    if bucket != BUCKET {
+        return error(StatusCode::NOT_FOUND, "NoSuchBucket");
+    }
+
+    if key.is_empty() {
+        return match method {
+            Method::HEAD => StatusCode::OK.into_response(),
+            Method::GET if query.get("list-type").map(String::as_str) == Some("2") => {
+                list(&state, &query)
+            }
+            _ => error(StatusCode::METHOD_NOT_ALLOWED, "MethodNotAllowed"),
+        };
+    }
+
+    match method {
+        Method::POST if query.contains_key("uploads") => {
+            let upload_id = format!("upload-{}", state.uploads.len() + 1);
+            state.uploads.insert(upload_id.clone(), BTreeMap::new());
+            xml(format!(
+                "<InitiateMultipartUploadResult><Bucket>{}</Bucket><Key>{}</Key><UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+                BUCKET, key, upload_id
+            ))
+        }
+        Method::POST if query.contains_key("uploadId") => {
+            let Some(parts) = state.uploads.remove(&query["uploadId"]) else {
+                return error(StatusCode::NOT_FOUND, "NoSuchUpload");
+            };
+            let data: Vec<u8> = parts.into_values().flat_map(|part| part.to_vec()).collect();
+            state.objects.insert(key.clone(), data.into());
+            xml(format!(
+                "<CompleteMultipartUploadResult><Bucket>{}</Bucket><Key>{}</Key><ETag>\"done\"</ETag></CompleteMultipartUploadResult>",
+                BUCKET, key
+            ))
+        }
+        Method::PUT if query.contains_key("uploadId") => {
+            let part_number: i32 = query["partNumber"].parse().unwrap();
+            let Some(parts) = state.uploads.get_mut(&query["uploadId"]) else {
+                return error(StatusCode::NOT_FOUND, "NoSuchUpload");
+            };
+            parts.insert(part_number, body);
+            let etag = format!("\"part-{}\"", part_number);
+            ([(header::ETAG, etag)], "").into_response()
+        }
+        Method::PUT => match headers.get("x-amz-copy-source") {
+            Some(source) => {
+                let source = percent_decode_str(source.to_str().unwrap())
+                    .decode_utf8()
+                    .unwrap()
+                    .to_string();
+                let source_key = source
+                    .trim_start_matches('/')
+                    .strip_prefix(&format!("{}/", BUCKET))
+                    .unwrap_or_default()
+                    .to_string();
+                let Some(data) = state.objects.get(&source_key).cloned() else {
+                    return error(StatusCode::NOT_FOUND, "NoSuchKey");
+                };
+                state.objects.insert(key, data);
+                xml("<CopyObjectResult><ETag>\"copy\"</ETag><LastModified>2024-05-01T12:00:00.000Z</LastModified></CopyObjectResult>".to_string())
+            }
+            None => {
+                state.objects.insert(key, body);
+                ([(header::ETAG, "\"object\"")], "").into_response()
+            }
+        },
+        Method::DELETE if query.contains_key("uploadId") => {
+            let upload_id = query["uploadId"].clone();
+            state.uploads.remove(&upload_id);
+            state.aborted.push(upload_id);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        // S3 deletes succeed whether or not the key exists.
+        Method::DELETE => {
+            state.objects.remove(&key);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Method::GET | Method::HEAD => match state.objects.get(&key) {
+            Some(data) => (
+                [
+                    (header::LAST_MODIFIED, LAST_MODIFIED),
+                    (header::CONTENT_TYPE, "application/octet-stream"),
+                ],
+                data.clone(),
+            )
+                .into_response(),
+            None if method == Method::HEAD => StatusCode::NOT_FOUND.into_response(),
+            None => error(StatusCode::NOT_FOUND, "NoSuchKey"),
+        },
+        _ => error(StatusCode::METHOD_NOT_ALLOWED, "MethodNotAllowed"),
+    }
+}
+
+/// `ListObjectsV2`, paged by `PAGE_SIZE` with the offset as the token.
+fn list(state: &S3State, query: &HashMap<String, String>) -> Response {
+    let prefix = query.get("prefix").cloned().unwrap_or_default();
+    let start: usize = query
+        .get("continuation-token")
+        .map(|token| token.parse().unwrap())
+        .unwrap_or(0);
+    let matching: Vec<(&String, &Bytes)> = state
+        .objects
+        .iter()
+        .filter(|(key, _)| key.starts_with(&prefix))
+        .collect();
+    let page = matching.iter().skip(start).take(PAGE_SIZE);
+    let next = start + PAGE_SIZE;
+
+    let mut body = format!(
+        "<ListBucketResult><Name>{}</Name><Prefix>{}</Prefix><KeyCount>{}</KeyCount><IsTruncated>{}</IsTruncated>",
+        BUCKET,
+        prefix,
+        page.len(),
+        next < matching.len()
+    );
+    for (key, data) in page {
+        body.push_str(&format!(
+            "<Contents><Key>{}</Key><Size>{}</Size><LastModified>2024-05-01T12:00:00.000Z</LastModified></Contents>",
+            key,
+            data.len()
+        ));
+    }
+    if next < matching.len() {
+        body.push_str(&format!(
+            "<NextContinuationToken>{}</NextContinuationToken>",
+            next
+        ));
+    }
+    body.push_str("</ListBucketResult>");
+    xml(body)
+}
+
+#[tokio::test]
+async fn s3_backend_meets_the_contract() {
+    let server = S3Server::default();
+    let storage = storage(&server.start().await, BUCKET).await;
+    assert_eq!(storage.kind(), "s3");
+
+    let path = check_contract(&storage).await;
+    assert!(path.starts_with("/uploads/report-"));
+    let object = storage.head(&path).await.unwrap();
+    assert_eq!(
+        object.modified_at.unwrap().to_rfc3339(),
+        "2024-05-01T12:00:00+00:00"
+    );
+    let presigned = storage
+        .presign(&path, Duration::from_secs(60))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(presigned.contains(&format!("/{}{}?", BUCKET, path)));
+    assert!(presigned.contains("X-Amz-Signature="));
+}
+
+#[tokio::test]
+async fn s3_listing_pages_and_keeps_to_the_prefix() {
+    let server = S3Server::default();
+    let storage = storage(&server.start().await, BUCKET).await;
+
+    let mut paths = Vec::new();
+    for i in 0..5 {
+        let data = vec![b'x'; i];
+        paths.push(
+            storage
+                .put(
+                    &format!("{}.bin", i),
+                    "application/octet-stream",
+                    chunks(&[&data]),
+                )
+                .await
+                .unwrap(),
+        );
+    }
+    server
+        .state()
+        .objects
+        .insert("elsewhere/other.bin".to_string(), Bytes::from_static(b"x"));
+
+    let listed = storage.list().await.unwrap();
+    let listed: Vec<(&str, i64)> = listed
+        .iter()
+        .map(|object| (object.path.as_str(), object.size))
+        .collect();
+    assert_eq!(
+        listed,
+        [
+            ("/uploads/0.bin", 0),
+            ("/uploads/1.bin", 1),
+            ("/uploads/2.bin", 2),
+            ("/uploads/3.bin", 3),
+            ("/uploads/4.bin", 4)
+        ]
+    );
+    assert_eq!(server.count(Method::GET, "list-type=2"), 3);
+}
+
+#[tokio::test]
+async fn s3_large_blobs_are_uploaded_in_parts() {
+    let server = S3Server::default();
+    let storage = storage(&server.start().await, BUCKET).await;
+
+    // Two full 8 MiB parts and a short last one, arriving in small chunks.
+    let data: Vec<u8> = (0..17 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let stream = futures::stream::iter(
+        data.chunks(64 * 1024)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect::<Vec<_>>(),
+    )
+    .boxed();
+    let path = storage
+        .put("large.bin", "application/octet-stream", stream)
+        .await
+        .unwrap();
+
+    assert_eq!(server.count(Method::PUT, "partNumber="), 3);
+    assert!(server.state().uploads.is_empty());
+    assert!(read(&storage, &path).await == data);
+    assert_eq!(storage.head(&path).await.unwrap().size, data.len() as i64);
+}
+
+#[tokio::test]
+async fn s3_failed_multipart_uploads_are_aborted() {
+    let server = S3Server::default();
+    let storage = storage(&server.start().await, BUCKET).await;
+
+    let part = vec![0u8; 9 * 1024 * 1024];
+    assert!(storage
+        .put(
+            "broken.bin",
+            "application/octet-stream",
+            common::failing(&part)
+        )
+        .await
+        .is_err());
+
+    let state = server.state();
+    assert_eq!(state.aborted.len(), 1);
+    assert!(state.uploads.is_empty());
+    assert!(state.objects.is_empty());
+}
+
+#[tokio::test]
+async fn s3_missing_objects_and_buckets() {
+    let server = S3Server::default();
+    let endpoint = server.start().await;
+    let storage = storage(&endpoint, BUCKET).await;
+
+    assert!(storage.get("/uploads/missing").await.is_err());
+    assert!(storage.head("/uploads/missing").await.is_err());
+    assert!(storage
+        .copy("/uploads/missing", "copy.bin", "application/octet-stream")
+        .await
+        .is_err());
+    assert!(storage.list().await.unwrap().is_empty());
+
+    // Deleting is idempotent in S3.
+    storage.delete("/uploads/missing").await.unwrap();
+
+    let elsewhere = self::storage(&endpoint, "other").await;
+    assert!(elsewhere.check_ready().await.is_err());
+}
+
+#[tokio::test]
+async fn s3_requests_are_retried_on_server_errors() {
+    let server = S3Server::default();
+    let storage = storage(&server.start().await, BUCKET).await;
+    let path = storage
+        .put("a.txt", "text/plain", chunks(&[b"retried"]))
+        .await
+        .unwrap();
+
+    server.state().failures = 2;
+    let data = storage.get(&path).await.unwrap();
+    assert_eq!(backend::collect(data).await.unwrap(), b"retried");
+    assert_eq!(server.count(Method::GET, "/uploads/a.txt"), 3);
+}
@@ -0,0 +1,100 @@
+//! Connection handling of the SFTP backend. There is no SSH server to run
+//! in-process, so these tests point it at a TCP listener that accepts
+//! connections and hangs up before the SSH handshake completes.
+
+use file_server_rs::storage::{SftpSettings, SftpStorage, StorageBackend};
+use std::{
+    net::TcpListener,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+/// Counts connections, and the most that were open at once. Each is held
+/// for `hold` before being closed.
+#[derive(Default)]
+struct Connections {
+    accepted: AtomicUsize,
+    open: AtomicUsize,
+    max_open: AtomicUsize,
+}
+
+fn hang_up_server(hold: Duration) -> (u16, Arc<Connections>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let connections = Arc::new(Connections::default());
+
+    let counters = connections.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            let counters = counters.clone();
+            counters.accepted.fetch_add(1, Ordering::SeqCst);
+            let open = counters.open.fetch_add(1, Ordering::SeqCst) + 1;
+            counters.max_open.fetch_max(open, Ordering::SeqCst);
+            thread::spawn(move || {
+                thread::sleep(hold);
+                counters.open.fetch_sub(1, Ordering::SeqCst);
+                drop(stream);
+            });
+        }
+    });
+
+    (port, connections)
+}
+
+fn storage(port: u16, pool_size: usize, retries: u32) -> SftpStorage {
+    let settings = SftpSettings {
+        host: "127.0.0.1".to_string(),
+        port,
+        username: "files".to_string(),
+        password: Some("secret".parse().unwrap()),
+        private_key: None,
+        known_hosts: None,
+    };
+    SftpStorage::new(settings, "uploads", pool_size, retries)
+}
+
+#[tokio::test]
+async fn sftp_connection_failures_are_retried() {
+    let (port, connections) = hang_up_server(Duration::ZERO);
+    let storage = storage(port, 2, 2);
+    assert_eq!(storage.kind(), "sftp");
+
+    assert!(storage.check_ready().await.is_err());
+    assert_eq!(connections.accepted.load(Ordering::SeqCst), 3);
+
+    assert!(storage.head("uploads/a.txt").await.is_err());
+    assert_eq!(connections.accepted.load(Ordering::SeqCst), 6);
+}
+
+#[tokio::test]
+async fn sftp_unreachable_server_fails_without_retries() {
+    let (port, connections) = hang_up_server(Duration::ZERO);
+    let storage = storage(port, 2, 0);
+
+    assert!(storage.list().await.is_err());
+    assert!(storage.get("uploads/a.txt").await.is_err());
+    assert!(storage.delete("uploads/a.txt").await.is_err());
+    assert_eq!(connections.accepted.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn sftp_connections_are_limited_to_the_pool_size() {
+    let (port, connections) = hang_up_server(Duration::from_millis(100));
+    let storage = storage(port, 1, 0);
+
+    let attempts = (0..3).map(|i| {
+        let storage = storage.clone();
+        tokio::spawn(async move { storage.head(&format!("uploads/{}.txt", i)).await })
+    });
+    for attempt in futures::future::join_all(attempts).await {
+        assert!(attempt.unwrap().is_err());
+    }
+
+    assert_eq!(connections.accepted.load(Ordering::SeqCst), 3);
+    assert_eq!(connections.max_open.load(Ordering::SeqCst), 1);
+}
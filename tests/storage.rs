@@ -0,0 +1,363 @@
+//! The in-memory backend, the `Storage` wrapper around backends and the
+//! registry routing uploads between them.
+
+mod common;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use common::{check_contract, chunks};
+use file_server_rs::{
+    config::StorageConfig,
+    storage::{
+        backend, encrypted_size, slice_stream, ByteStream, Keyring, MemoryStorage, Storage,
+        StorageBackend, StorageRegistry, ROUTING_READ_AHEAD_LIMIT,
+    },
+};
+use futures::StreamExt;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+fn memory(name: &str) -> Storage {
+    Storage::from_backend(name, Arc::new(MemoryStorage::new()))
+}
+
+fn keyring() -> Keyring {
+    Keyring::new(&STANDARD.encode([7u8; 32]), &[]).unwrap()
+}
+
+#[tokio::test]
+async fn memory_backend_meets_the_contract() {
+    let storage = MemoryStorage::new();
+    let path = check_contract(&storage).await;
+    assert!(path.starts_with("report-"));
+    assert_eq!(storage.kind(), "memory");
+}
+
+#[tokio::test]
+async fn memory_backend_rejects_missing_blobs() {
+    let storage = MemoryStorage::new();
+    assert!(storage.get("missing").await.is_err());
+    assert!(storage.head("missing").await.is_err());
+    assert!(storage.delete("missing").await.is_err());
+    assert!(storage.list().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn storage_streams_blobs_under_unique_names() {
+    let storage = memory("scratch");
+    assert_eq!(storage.name(), "scratch");
+    assert_eq!(storage.kind(), "memory");
+
+    let first = storage
+        .store_stream("report.pdf", chunks(&[b"%PDF-", b"1.7"]))
+        .await
+        .unwrap();
+    let second = storage
+        .store_stream("report.pdf", chunks(&[b"other"]))
+        .await
+        .unwrap();
+    assert!(first.key.is_none());
+    assert_ne!(first.path, second.path);
+    assert!(first.path.starts_with("report-") && first.path.ends_with(".pdf"));
+
+    let data = storage.get_stream(&first.path, None).await.unwrap();
+    assert_eq!(backend::collect(data).await.unwrap(), b"%PDF-1.7");
+    assert_eq!(
+        storage.get_file(&second.path, None).await.unwrap(),
+        b"other"
+    );
+    assert_eq!(storage.file_size(&first.path).await.unwrap(), 8);
+    assert_eq!(storage.list_files().await.unwrap().len(), 2);
+
+    let copy = storage.copy_file(&first.path, "copy.pdf").await.unwrap();
+    assert_eq!(storage.get_file(&copy, None).await.unwrap(), b"%PDF-1.7");
+    assert!(storage
+        .presign(&first.path, std::time::Duration::from_secs(60))
+        .await
+        .unwrap()
+        .is_none());
+
+    storage.delete_file(&first.path).await.unwrap();
+    assert!(storage.get_stream(&first.path, None).await.is_err());
+    assert_eq!(storage.list_files().await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn storage_encrypts_blobs_with_a_keyring() {
+    let mut storage = memory("secure");
+    storage.set_keyring(Arc::new(keyring()));
+
+    let plaintext = b"attack at dawn";
+    let stored = storage
+        .store_stream("orders.txt", chunks(&[plaintext]))
+        .await
+        .unwrap();
+    let key = stored.key.expect("blob should be encrypted");
+
+    let raw = storage.get_file(&stored.path, None).await.unwrap();
+    assert_ne!(raw, plaintext);
+    assert_eq!(
+        storage.file_size(&stored.path).await.unwrap() as u64,
+        encrypted_size(plaintext.len() as u64)
+    );
+    assert_eq!(
+        storage.get_file(&stored.path, Some(&key)).await.unwrap(),
+        plaintext
+    );
+
+    // Without the keyring the blob cannot be read back.
+    let unkeyed = memory("unkeyed");
+    let path = unkeyed
+        .store_raw("orders.txt", chunks(&[&raw]))
+        .await
+        .unwrap();
+    assert!(unkeyed.get_stream(&path, Some(&key)).await.is_err());
+}
+
+#[tokio::test]
+async fn ranges_are_sliced_across_chunks() {
+    let storage = memory("ranges");
+    let stored = storage
+        .store_stream("digits.txt", chunks(&[b"0123456789"]))
+        .await
+        .unwrap();
+
+    async fn range(storage: &Storage, path: &str, start: u64, len: u64) -> Vec<u8> {
+        let data = storage.get_stream(path, None).await.unwrap();
+        backend::collect(slice_stream(data, start, len))
+            .await
+            .unwrap()
+    }
+    assert_eq!(range(&storage, &stored.path, 0, 10).await, b"0123456789");
+    assert_eq!(range(&storage, &stored.path, 3, 4).await, b"3456");
+    assert_eq!(range(&storage, &stored.path, 7, 100).await, b"789");
+    assert!(range(&storage, &stored.path, 4, 0).await.is_empty());
+
+    let sliced = slice_stream(chunks(&[b"abc", b"def", b"ghi", b"jkl"]), 4, 6);
+    let sliced: Vec<_> = sliced.map(|chunk| chunk.unwrap()).collect().await;
+    // Chunks outside the range are skipped, the rest trimmed to fit.
+    assert_eq!(sliced, [&b"ef"[..], b"ghi", b"j"]);
+}
+
+fn registry_config() -> StorageConfig {
+    toml::from_str(
+        r#"
+        default = "small"
+
+        [[backends]]
+        name = "small"
+        type = "memory"
+
+        [[backends]]
+        name = "large"
+        type = "memory"
+
+        [[backends]]
+        name = "images"
+        type = "memory"
+
+        [[backends]]
+        name = "ci"
+        type = "memory"
+
+        [[rules]]
+        backend = "ci"
+        uploaders = ["key:ci"]
+
+        [[rules]]
+        backend = "images"
+        mime_types = ["image/*"]
+        max_size = 999
+
+        [[rules]]
+        backend = "large"
+        min_size = 1000
+        "#,
+    )
+    .unwrap()
+}
+
+#[tokio::test]
+async fn registry_routes_uploads_by_rule() {
+    let registry = StorageRegistry::from_config(&registry_config())
+        .await
+        .unwrap();
+
+    let route = |size, mime_type, uploader| registry.route(size, mime_type, uploader).name();
+    assert_eq!(route(10, "text/plain", None), "small");
+    assert_eq!(route(10, "image/png", None), "images");
+    assert_eq!(route(1000, "image/png", None), "large");
+    assert_eq!(route(5000, "text/plain", None), "large");
+    assert_eq!(route(5000, "image/png", Some("key:ci")), "ci");
+    assert_eq!(route(10, "text/plain", Some("token")), "small");
+    assert_eq!(registry.default_backend().name(), "small");
+
+    let names: Vec<&str> = registry
+        .backends()
+        .iter()
+        .map(|storage| storage.name())
+        .collect();
+    assert_eq!(names, ["ci", "images", "large", "small"]);
+    assert!(registry.require("large").is_ok());
+    assert!(registry.require("missing").is_err());
+}
+
+#[tokio::test]
+async fn registry_routes_streams_without_losing_data() {
+    let registry = StorageRegistry::from_config(&registry_config())
+        .await
+        .unwrap();
+
+    let parts: Vec<Vec<u8>> = (0..20u8).map(|i| vec![i; 100]).collect();
+    let slices: Vec<&[u8]> = parts.iter().map(Vec::as_slice).collect();
+    let (storage, data) = registry
+        .route_stream(chunks(&slices), "text/plain", None)
+        .await
+        .unwrap();
+    assert_eq!(storage.name(), "large");
+    assert_eq!(backend::collect(data).await.unwrap(), parts.concat());
+
+    let (storage, data) = registry
+        .route_stream(chunks(&[b"tiny"]), "image/gif", None)
+        .await
+        .unwrap();
+    assert_eq!(storage.name(), "images");
+    let stored = storage.store_stream("tiny.gif", data).await.unwrap();
+    assert_eq!(
+        registry
+            .require("images")
+            .unwrap()
+            .get_file(&stored.path, None)
+            .await
+            .unwrap(),
+        b"tiny"
+    );
+}
+
+/// `size` bytes in 64 KiB chunks, produced as they are read. Returns the
+/// stream and how many bytes have been read from it so far.
+fn counted(size: u64) -> (ByteStream<'static>, Arc<AtomicU64>) {
+    const CHUNK: u64 = 64 * 1024;
+    let read = Arc::new(AtomicU64::new(0));
+    let counter = read.clone();
+    let data = futures::stream::iter(0..size.div_ceil(CHUNK))
+        .map(move |i| {
+            let len = CHUNK.min(size - i * CHUNK);
+            counter.fetch_add(len, Ordering::SeqCst);
+            Ok(bytes::Bytes::from(vec![0u8; len as usize]))
+        })
+        .boxed();
+    (data, read)
+}
+
+fn size_rule_registry_config(min_size: u64) -> StorageConfig {
+    toml::from_str(&format!(
+        r#"
+        default = "small"
+
+        [[backends]]
+        name = "small"
+        type = "memory"
+
+        [[backends]]
+        name = "large"
+        type = "memory"
+
+        [[rules]]
+        backend = "large"
+        min_size = {}
+        "#,
+        min_size
+    ))
+    .unwrap()
+}
+
+#[tokio::test]
+async fn registry_reads_ahead_only_as_far_as_the_rules_need() {
+    let registry = StorageRegistry::from_config(&size_rule_registry_config(1024 * 1024))
+        .await
+        .unwrap();
+
+    let (data, read) = counted(32 * 1024 * 1024);
+    let (storage, data) = registry
+        .route_stream(data, "application/octet-stream", None)
+        .await
+        .unwrap();
+    assert_eq!(storage.name(), "large");
+    assert_eq!(read.load(Ordering::SeqCst), 1024 * 1024);
+    assert_eq!(
+        backend::collect(data).await.unwrap().len(),
+        32 * 1024 * 1024
+    );
+
+    let (data, _) = counted(1024 * 1024 - 1);
+    let (storage, _) = registry
+        .route_stream(data, "application/octet-stream", None)
+        .await
+        .unwrap();
+    assert_eq!(storage.name(), "small");
+}
+
+#[tokio::test]
+async fn registry_does_not_buffer_past_the_read_ahead_limit() {
+    // Loading the file would reject this threshold; a registry built from
+    // an unchecked configuration still caps what it buffers.
+    let registry = StorageRegistry::from_config(&size_rule_registry_config(1 << 30))
+        .await
+        .unwrap();
+
+    let (data, read) = counted(4 * ROUTING_READ_AHEAD_LIMIT);
+    let (storage, data) = registry
+        .route_stream(data, "application/octet-stream", None)
+        .await
+        .unwrap();
+    // Routed as larger than the threshold it never reached.
+    assert_eq!(storage.name(), "large");
+    assert!(read.load(Ordering::SeqCst) <= ROUTING_READ_AHEAD_LIMIT + 64 * 1024);
+    drop(data);
+
+    let (data, _) = counted(ROUTING_READ_AHEAD_LIMIT);
+    let (storage, _) = registry
+        .route_stream(data, "application/octet-stream", None)
+        .await
+        .unwrap();
+    assert_eq!(storage.name(), "small");
+}
+
+#[tokio::test]
+async fn registry_keyring_applies_to_registered_backends() {
+    let mut registry = StorageRegistry::from_config(&registry_config())
+        .await
+        .unwrap();
+    registry.set_keyring(keyring());
+    registry.register(memory("custom"));
+
+    for name in ["small", "custom"] {
+        let storage = registry.require(name).unwrap();
+        let stored = storage
+            .store_stream("notes.txt", chunks(&[b"secret"]))
+            .await
+            .unwrap();
+        let key = stored.key.expect("blob should be encrypted");
+        assert_eq!(
+            storage.get_file(&stored.path, Some(&key)).await.unwrap(),
+            b"secret"
+        );
+    }
+
+    // Registering under an existing name replaces that backend.
+    let replaced_path = registry
+        .require("small")
+        .unwrap()
+        .store_raw("x.txt", chunks(&[b"x"]))
+        .await
+        .unwrap();
+    registry.register(memory("small"));
+    assert!(registry
+        .require("small")
+        .unwrap()
+        .get_stream(&replaced_path, None)
+        .await
+        .is_err());
+}
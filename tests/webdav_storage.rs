@@ -0,0 +1,364 @@
+//! The WebDAV backend against a small in-process WebDAV server.
+
+mod common;
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{header, HeaderMap, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Router,
+};
+use chrono::{DateTime, Duration, Utc};
+use common::{check_contract, chunks, read, serve};
+use file_server_rs::{
+    config::Secret,
+    storage::{StorageBackend, WebDavStorage},
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+};
+
+/// Keeps blobs and collections in memory, keyed by URL path. Collections end
+/// with `/`.
+#[derive(Clone, Default)]
+struct DavServer {
+    state: Arc<Mutex<DavState>>,
+}
+
+#[derive(Default)]
+struct DavState {
+    blobs: BTreeMap<String, Bytes>,
+    /// Last-modified times of blobs that were not just written.
+    modified_at: BTreeMap<String, DateTime<Utc>>,
+    collections: BTreeSet<String>,
+    /// Requests answered with 503 before the server starts working.
+    failures: u32,
+    /// `Authorization` header every request must carry, if set.
+    authorization: Option<String>,
+    requests: Vec<(Method, String)>,
+}
+
+impl DavServer {
+    /// Serves the share at `/dav/` on an ephemeral port and returns its URL.
+    async fn start(&self) -> String {
+        self.state
+            .lock()
+            .unwrap()
+            .collections
+            .insert("/dav/".to_string());
+
+        let app = Router::new().fallback(handle).with_state(self.clone());
+        format!("http://{}/dav/", serve(app).await)
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, DavState> {
+        self.state.lock().unwrap()
+    }
+
+    fn requests(&self, method: &str) -> Vec<String> {
+        self.state()
+            .requests
+            .iter()
+            .filter(|(m, _)| m.as_str() == method)
+            .map(|(_, path)| path.clone())
+            .collect()
+    }
+}
+
+fn parent(path: &str) -> String {
+    let trimmed = path.trim_end_matches('/');
+    match trimmed.rsplit_once('/') {
+        Some((parent, _)) => format!("{}/", parent),
+        None => "/".to_string(),
+    }
+}
+
+async fn handle(
+    State(server): State<DavServer>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let path = uri.path().to_string();
+    let mut state = server.state();
+    state.requests.push((method.clone(), path.clone()));
+
+    if state.failures > 0 {
+        state.failures -= 1;
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    if let Some(expected) = &state.authorization {
+        let sent = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+        if sent != Some(expected.as_str()) {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    }
+
+    match method.as_str() {
+        "MKCOL" => {
+            if state.collections.contains(&path) {
+                StatusCode::METHOD_NOT_ALLOWED.into_response()
+            } else if !state.collections.contains(&parent(&path)) {
+                StatusCode::CONFLICT.into_response()
+            } else {
+                state.collections.insert(path);
+                StatusCode::CREATED.into_response()
+            }
+        }
+        "PUT" => {
+            if !state.collections.contains(&parent(&path)) {
+                return StatusCode::CONFLICT.into_response();
+            }
+            state.blobs.insert(path, body);
+            StatusCode::CREATED.into_response()
+        }
+        "GET" | "HEAD" => match state.blobs.get(&path) {
+            Some(blob) => (
+                [(header::LAST_MODIFIED, Utc::now().to_rfc2822())],
+                blob.clone(),
+            )
+                .into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        },
+        "DELETE" => match state.blobs.remove(&path) {
+            Some(_) => StatusCode::NO_CONTENT.into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        },
+        "MOVE" | "COPY" => {
+            let Some(destination) = headers
+                .get("Destination")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<Uri>().ok())
+                .map(|uri| uri.path().to_string())
+            else {
+                return StatusCode::BAD_REQUEST.into_response();
+            };
+            if state.blobs.contains_key(&destination) {
+                return StatusCode::PRECONDITION_FAILED.into_response();
+            }
+            let blob = match method.as_str() {
+                "MOVE" => state.blobs.remove(&path),
+                _ => state.blobs.get(&path).cloned(),
+            };
+            match blob {
+                Some(blob) => {
+                    state.blobs.insert(destination, blob);
+                    StatusCode::CREATED.into_response()
+                }
+                None => StatusCode::NOT_FOUND.into_response(),
+            }
+        }
+        "PROPFIND" => {
+            if !state.collections.contains(&path) {
+                return StatusCode::NOT_FOUND.into_response();
+            }
+            let mut body = format!(
+                r#"<?xml version="1.0"?><D:multistatus xmlns:D="DAV:"><D:response><D:href>{}</D:href><D:propstat><D:prop><D:resourcetype><D:collection/></D:resourcetype></D:prop></D:propstat></D:response>"#,
+                path
+            );
+            if headers.get("Depth").and_then(|value| value.to_str().ok()) == Some("1") {
+                for (blob_path, blob) in &state.blobs {
+                    if parent(blob_path) == path {
+                        let modified_at = state
+                            .modified_at
+                            .get(blob_path)
+                            .copied()
+                            .unwrap_or_else(Utc::now);
+                        body.push_str(&format!(
+                            "<D:response><D:href>{}</D:href><D:propstat><D:prop><D:resourcetype/><D:getcontentlength>{}</D:getcontentlength><D:getlastmodified>{}</D:getlastmodified></D:prop></D:propstat></D:response>",
+                            blob_path,
+                            blob.len(),
+                            modified_at.to_rfc2822()
+                        ));
+                    }
+                }
+            }
+            body.push_str("</D:multistatus>");
+            (
+                StatusCode::MULTI_STATUS,
+                [(header::CONTENT_TYPE, "application/xml")],
+                body,
+            )
+                .into_response()
+        }
+        _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+    }
+}
+
+fn storage(url: &str, prefix: &str, retries: u32) -> WebDavStorage {
+    WebDavStorage::new(url, prefix, None, None, 2, retries).unwrap()
+}
+
+#[tokio::test]
+async fn webdav_backend_meets_the_contract() {
+    let server = DavServer::default();
+    let url = server.start().await;
+    let storage = storage(&url, "uploads", 0);
+
+    let path = check_contract(&storage).await;
+    assert!(path.starts_with("/uploads/report-"));
+    assert!(server.state().blobs.contains_key(&format!("/dav{}", path)));
+    assert_eq!(storage.kind(), "webdav");
+}
+
+#[tokio::test]
+async fn webdav_blobs_are_written_under_the_prefix() {
+    let server = DavServer::default();
+    let url = server.start().await;
+    let storage = storage(&url, "/team/uploads/", 0);
+
+    let path = storage
+        .put("a.txt", "text/plain", chunks(&[b"a"]))
+        .await
+        .unwrap();
+    assert_eq!(path, "/team/uploads/a.txt");
+    assert_eq!(
+        server.requests("MKCOL"),
+        ["/dav/team/", "/dav/team/uploads/"]
+    );
+    // Written next to the blob first, then moved into place.
+    assert_eq!(server.requests("PUT"), ["/dav/team/uploads/a.txt.partial"]);
+    assert_eq!(
+        server.state().blobs.keys().collect::<Vec<_>>(),
+        ["/dav/team/uploads/a.txt"]
+    );
+
+    // The collections are only created once per process.
+    storage
+        .put("b.txt", "text/plain", chunks(&[b"b"]))
+        .await
+        .unwrap();
+    assert_eq!(server.requests("MKCOL").len(), 2);
+
+    let unprefixed = self::storage(&url, "", 0);
+    let path = unprefixed
+        .put("c.txt", "text/plain", chunks(&[b"c"]))
+        .await
+        .unwrap();
+    assert_eq!(path, "/c.txt");
+    assert_eq!(read(&unprefixed, &path).await, b"c");
+}
+
+#[tokio::test]
+async fn webdav_missing_blobs_and_collections() {
+    let server = DavServer::default();
+    let url = server.start().await;
+    let storage = storage(&url, "uploads", 0);
+
+    // Nothing has been written yet, so the prefix does not exist.
+    assert!(storage.list().await.unwrap().is_empty());
+    assert_eq!(storage.cleanup_partial(Utc::now()).await.unwrap(), 0);
+
+    let error = storage.get("/uploads/missing").await.err().unwrap();
+    assert!(error.to_string().contains("404"), "{}", error);
+    assert!(storage.head("/uploads/missing").await.is_err());
+    assert!(storage.delete("/uploads/missing").await.is_err());
+    assert!(storage
+        .copy("/uploads/missing", "copy.txt", "text/plain")
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn webdav_partial_uploads_are_hidden_and_cleaned_up() {
+    let server = DavServer::default();
+    let url = server.start().await;
+    let storage = storage(&url, "uploads", 0);
+    storage
+        .put("kept.txt", "text/plain", chunks(&[b"kept"]))
+        .await
+        .unwrap();
+    {
+        let mut state = server.state();
+        for name in ["interrupted.bin.partial", "in-progress.bin.partial"] {
+            state.blobs.insert(
+                format!("/dav/uploads/{}", name),
+                Bytes::from_static(b"half"),
+            );
+        }
+        state.modified_at.insert(
+            "/dav/uploads/interrupted.bin.partial".to_string(),
+            Utc::now() - Duration::hours(1),
+        );
+    }
+
+    let listed = storage.list().await.unwrap();
+    let paths: Vec<&str> = listed.iter().map(|object| object.path.as_str()).collect();
+    assert_eq!(paths, ["/uploads/kept.txt"]);
+
+    // An upload that is still being written, e.g. by another replica, is
+    // left alone.
+    let cutoff = Utc::now() - Duration::minutes(10);
+    assert_eq!(storage.cleanup_partial(cutoff).await.unwrap(), 1);
+    let state = server.state();
+    assert!(!state
+        .blobs
+        .contains_key("/dav/uploads/interrupted.bin.partial"));
+    assert!(state
+        .blobs
+        .contains_key("/dav/uploads/in-progress.bin.partial"));
+}
+
+#[tokio::test]
+async fn webdav_requests_are_retried_on_server_errors() {
+    let server = DavServer::default();
+    let url = server.start().await;
+    let storage = storage(&url, "uploads", 2);
+    let path = storage
+        .put("a.txt", "text/plain", chunks(&[b"retried"]))
+        .await
+        .unwrap();
+
+    server.state().failures = 2;
+    assert_eq!(read(&storage, &path).await, b"retried");
+    assert_eq!(server.requests("GET").len(), 3);
+
+    server.state().failures = 3;
+    let error = storage.head(&path).await.unwrap_err();
+    assert!(error.to_string().contains("503"), "{}", error);
+    assert_eq!(server.requests("HEAD").len(), 3);
+}
+
+#[tokio::test]
+async fn webdav_uploads_are_not_retried() {
+    let server = DavServer::default();
+    let url = server.start().await;
+    let storage = storage(&url, "uploads", 3);
+    storage.check_ready().await.unwrap();
+
+    // The body stream cannot be replayed, so a failed PUT fails the write.
+    server.state().failures = 1;
+    assert!(storage
+        .put("a.txt", "text/plain", chunks(&[b"once"]))
+        .await
+        .is_err());
+    assert_eq!(server.requests("PUT").len(), 1);
+    assert!(server.state().blobs.is_empty());
+}
+
+#[tokio::test]
+async fn webdav_credentials_are_sent() {
+    let server = DavServer::default();
+    server.state().authorization = Some("Basic dXNlcjpzZWNyZXQ=".to_string());
+    let url = server.start().await;
+
+    let anonymous = storage(&url, "uploads", 0);
+    let error = anonymous.check_ready().await.unwrap_err();
+    assert!(error.to_string().contains("401"), "{}", error);
+
+    let storage = WebDavStorage::new(
+        &url,
+        "uploads",
+        Some("user".to_string()),
+        Some("secret".parse::<Secret>().unwrap()),
+        2,
+        0,
+    )
+    .unwrap();
+    check_contract(&storage).await;
+}
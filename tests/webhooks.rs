@@ -0,0 +1,150 @@
+//! Signing and delivery of webhooks to a local receiver.
+
+mod common;
+
+use axum::{
+    http::{header, HeaderMap, StatusCode},
+    routing::post,
+    Router,
+};
+use clap::Parser;
+use file_server_rs::{
+    cli::Cli,
+    database::{create_pool, create_webhook, create_webhook_delivery, get_webhook_deliveries},
+    models::{Webhook, WebhookDelivery, WebhookEvent},
+    webhooks::{self, Webhooks},
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+const PAYLOAD: &str = r#"{"event":"upload"}"#;
+
+#[test]
+fn signatures_cover_the_timestamp_and_payload() {
+    // Computed independently: HMAC-SHA256("whsec_test", "1700000000.{payload}").
+    assert_eq!(
+        webhooks::sign("whsec_test", 1700000000, PAYLOAD),
+        "4d205bdbac3e8795b249b27233d5151bd2fc28eb4583866ccd7f26389b9942ff"
+    );
+    assert_ne!(
+        webhooks::sign("whsec_test", 1700000001, PAYLOAD),
+        webhooks::sign("whsec_test", 1700000000, PAYLOAD)
+    );
+}
+
+/// Queues one delivery of [`PAYLOAD`] to `url` and runs the delivery worker
+/// until it has been attempted. Returns the webhook and the delivery after
+/// its first attempt.
+async fn deliver(url: String) -> (Webhook, WebhookDelivery) {
+    let dir = tempfile::tempdir().unwrap();
+    let config = Cli::try_parse_from(["file-server"]).unwrap().config;
+    let db = create_pool(&format!("sqlite:{}", dir.path().join("files.db").display()))
+        .await
+        .unwrap();
+
+    let webhook = Webhook::new(url, &[WebhookEvent::Upload]);
+    create_webhook(&db, &webhook).await.unwrap();
+    let delivery = WebhookDelivery::new(
+        webhook.id.clone(),
+        WebhookEvent::Upload,
+        "file-1".to_string(),
+        PAYLOAD.to_string(),
+    );
+    create_webhook_delivery(&db, &delivery).await.unwrap();
+
+    let shutdown = CancellationToken::new();
+    let worker = tokio::spawn(webhooks::run_deliveries(
+        db.clone(),
+        Webhooks::default(),
+        Arc::new(config),
+        shutdown.clone(),
+    ));
+
+    let mut attempted = None;
+    for _ in 0..100 {
+        let deliveries = get_webhook_deliveries(&db, &webhook.id, None, 10)
+            .await
+            .unwrap();
+        if deliveries[0].attempts > 0 {
+            attempted = deliveries.into_iter().next();
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    shutdown.cancel();
+    worker.await.unwrap();
+    (webhook, attempted.expect("delivery was not attempted"))
+}
+
+#[tokio::test]
+async fn deliveries_are_signed() {
+    let (sender, mut received) = mpsc::unbounded_channel();
+    let app = Router::new().route(
+        "/hooks",
+        post(move |headers: HeaderMap, body: String| async move {
+            sender.send((headers, body)).unwrap();
+            StatusCode::NO_CONTENT
+        }),
+    );
+    let addr = common::serve(app).await;
+
+    let (webhook, delivery) = deliver(format!("http://{}/hooks", addr)).await;
+    assert_eq!(delivery.status, "delivered");
+    assert_eq!(delivery.last_status_code, Some(204));
+
+    let (headers, body) = received.recv().await.unwrap();
+    let value = |name: &str| headers[name].to_str().unwrap().to_string();
+    assert_eq!(body, PAYLOAD);
+    assert_eq!(value(header::CONTENT_TYPE.as_str()), "application/json");
+    assert_eq!(value("x-webhook-id"), delivery.id);
+    assert_eq!(value("x-webhook-event"), "upload");
+
+    // What a receiver does to verify a delivery.
+    let timestamp = value("x-webhook-timestamp");
+    let mut mac = Hmac::<Sha256>::new_from_slice(webhook.secret.as_bytes()).unwrap();
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    assert_eq!(
+        value("x-webhook-signature"),
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    );
+}
+
+#[tokio::test]
+async fn redirects_are_not_followed() {
+    let followed = Arc::new(AtomicUsize::new(0));
+    let counter = followed.clone();
+    let app = Router::new()
+        .route(
+            "/hooks",
+            post(|| async {
+                (
+                    StatusCode::TEMPORARY_REDIRECT,
+                    [(header::LOCATION, "/elsewhere")],
+                )
+            }),
+        )
+        .route(
+            "/elsewhere",
+            post(move || async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                StatusCode::NO_CONTENT
+            }),
+        );
+    let addr = common::serve(app).await;
+
+    let (_, delivery) = deliver(format!("http://{}/hooks", addr)).await;
+    assert_eq!(followed.load(Ordering::SeqCst), 0);
+    // A redirect is not a success, so it is retried like any other failure.
+    assert_eq!(delivery.status, "pending");
+    assert_eq!(delivery.last_status_code, Some(307));
+    assert_eq!(delivery.attempts, 1);
+}